@@ -14,7 +14,7 @@ fn main() -> Result<(), Error> {
         Some(outdir) => outdir,
     };
 
-    let mut cmd = Args::command();
+    let mut cmd = Cli::command();
     let shells = [
         Shell::Bash,
         Shell::Elvish,
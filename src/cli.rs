@@ -1,38 +1,540 @@
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use reqwest::Url;
 
 /// A HLS (m3u8) livestream downloader
+///
+/// Default arguments may be set in $XDG_CONFIG_HOME/livestream-dl/config (%APPDATA% on
+/// Windows) or the file given by $LIVESTREAM_DL_CONFIG. Each subcommand only uses the lines of
+/// the config file with options it has
 #[derive(Parser, Clone, Debug)]
 #[clap(version, about)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Command,
+
+    /// Log more, -v for debug messages and -vv for trace messages. LIVESTREAM_DL_LOG may also
+    /// be set to a tracing filter, which replaces -v and -q
+    #[clap(
+        short,
+        long,
+        global = true,
+        action = clap::ArgAction::Count,
+        conflicts_with = "quiet"
+    )]
+    pub verbose: u8,
+
+    /// Log less, once to leave out progress messages logged for every segment and twice to only
+    /// log warnings and errors. It has no short flag, -q is --copy-query
+    #[clap(long, global = true, action = clap::ArgAction::Count)]
+    pub quiet: u8,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Download a livestream, the default if no subcommand is given
+    Download(Args),
+
+    /// Record several livestreams at once, each into a numbered directory in the output
+    /// directory
+    Batch(BatchArgs),
+
+    /// Continue an interrupted download in its output directory, keeping the segments saved so
+    /// far. The playlist URL is read from the download's manifest
+    Resume(ResumeArgs),
+
+    /// Remux the segments of an earlier download without downloading anything, e.g. if it was
+    /// interrupted before remuxing
+    Mux(MuxArgs),
+
+    /// Print all variants and alternative renditions of a playlist and exit without downloading
+    ListStreams(ListStreamsArgs),
+
+    /// Sample the media playlist a few times, print live diagnostics (segment cadence, drift,
+    /// discontinuities, encryption, latency) and exit without downloading
+    Probe(ProbeArgs),
+}
+
+#[derive(Parser, Clone, Debug)]
+#[clap(args_override_self = true)]
 pub struct Args {
-    /// m3u8 playlist URL
-    #[clap(value_parser, value_hint = clap::ValueHint::Url)]
-    pub m3u8_url: Url,
+    /// m3u8 playlist URL or local file, or the URL of a webpage with a livestream
+    #[clap(
+        value_parser = parse_input_url,
+        value_hint = clap::ValueHint::Url,
+        required_unless_present = "stdin"
+    )]
+    pub m3u8_url: Option<Url>,
+
+    /// Read the playlist from stdin instead of fetching m3u8_url, for playlists that need a
+    /// fetch livestream-dl can't do itself. It is used as the playlist at --base-url, which
+    /// relative URIs are resolved against
+    #[clap(long, value_parser, conflicts_with = "m3u8-url", requires = "base-url")]
+    pub stdin: bool,
+
+    /// Output directory, should be non-existent. If not specified, automatically generate. May
+    /// also be a remote URL to upload to like --upload, the download is then kept in a
+    /// generated directory
+    #[clap(short, long, value_parser)]
+    pub output: Option<PathBuf>,
 
     #[clap(flatten)]
     pub download_options: DownloadOptions,
 
+    #[clap(flatten)]
+    pub mux_options: MuxOptions,
+
     #[clap(flatten)]
     pub network_options: NetworkOptions,
 }
 
+impl Args {
+    /// Playlist URL, which is --base-url if the playlist is read from stdin
+    pub fn playlist_url(&self) -> Option<&Url> {
+        match &self.m3u8_url {
+            Some(u) => Some(u),
+            None if self.stdin => self.network_options.base_url.as_ref(),
+            None => None,
+        }
+    }
+}
+
 #[derive(Parser, Clone, Debug)]
-#[clap(help_heading = "DOWNLOAD OPTIONS")]
-pub struct DownloadOptions {
-    /// Output directory, should be non-existent. If not specified, automatically generate
+#[clap(args_override_self = true)]
+pub struct BatchArgs {
+    /// m3u8 playlist URLs or local files, or URLs of webpages with livestreams
+    #[clap(value_parser = parse_input_url, value_hint = clap::ValueHint::Url, required = true)]
+    pub m3u8_urls: Vec<Url>,
+
+    /// Output directory, should be non-existent. If not specified, automatically generate. May
+    /// also be a remote URL to upload to like --upload, the download is then kept in a
+    /// generated directory
     #[clap(short, long, value_parser)]
     pub output: Option<PathBuf>,
 
-    /// Don't remux streams to mp4 after download
+    /// Maximum number of concurrent downloads across all recordings, each recording is also
+    /// limited by --max-concurrent-downloads
+    #[clap(long, value_parser, value_name = "N", default_value_t = 40)]
+    pub max_total_downloads: usize,
+
+    #[clap(flatten)]
+    pub download_options: DownloadOptions,
+
+    #[clap(flatten)]
+    pub mux_options: MuxOptions,
+
+    #[clap(flatten)]
+    pub network_options: NetworkOptions,
+}
+
+#[derive(Parser, Clone, Debug)]
+#[clap(args_override_self = true)]
+pub struct ResumeArgs {
+    /// Output directory of the interrupted download
+    #[clap(value_parser, value_hint = clap::ValueHint::DirPath)]
+    pub dir: PathBuf,
+
+    #[clap(flatten)]
+    pub download_options: DownloadOptions,
+
+    #[clap(flatten)]
+    pub mux_options: MuxOptions,
+
+    #[clap(flatten)]
+    pub network_options: NetworkOptions,
+}
+
+#[derive(Parser, Clone, Debug)]
+#[clap(args_override_self = true)]
+pub struct MuxArgs {
+    /// Output directory of the earlier download, containing the "segments" directory
+    #[clap(value_parser, value_hint = clap::ValueHint::DirPath)]
+    pub dir: PathBuf,
+
+    #[clap(flatten)]
+    pub mux_options: MuxOptions,
+}
+
+#[derive(Parser, Clone, Debug)]
+#[clap(args_override_self = true)]
+pub struct ListStreamsArgs {
+    /// m3u8 playlist URL or local file, or the URL of a webpage with a livestream
+    #[clap(value_parser = parse_input_url, value_hint = clap::ValueHint::Url)]
+    pub m3u8_url: Url,
+
+    /// Print output as JSON
     #[clap(long, value_parser)]
-    pub no_remux: bool,
+    pub json: bool,
+
+    #[clap(flatten)]
+    pub network_options: NetworkOptions,
+}
+
+#[derive(Parser, Clone, Debug)]
+#[clap(args_override_self = true)]
+pub struct ProbeArgs {
+    /// m3u8 playlist URL or local file, or the URL of a webpage with a livestream
+    #[clap(value_parser = parse_input_url, value_hint = clap::ValueHint::Url)]
+    pub m3u8_url: Url,
+
+    /// Number of times to sample the media playlist, once per target duration
+    #[clap(long, value_parser, value_name = "N", default_value_t = 5)]
+    pub samples: u32,
+
+    /// Print output as JSON
+    #[clap(long, value_parser)]
+    pub json: bool,
+
+    #[clap(flatten)]
+    pub network_options: NetworkOptions,
+}
+
+#[derive(Parser, Clone, Debug)]
+#[clap(help_heading = "DOWNLOAD OPTIONS")]
+pub struct DownloadOptions {
+    /// Remux the segments downloaded so far into the "partial" directory every MINUTES minutes of
+    /// recorded media, so a playable file exists before the stream ends
+    #[clap(
+        long,
+        value_parser,
+        value_name = "MINUTES",
+        conflicts_with = "no-remux"
+    )]
+    pub remux_interval: Option<u64>,
+
+    /// How to recognize segments that were already downloaded. "sequence" uses media sequence
+    /// numbers, "url" uses segment URLs without query strings and byte ranges for providers
+    /// that renumber segments, "content" also skips saving segments with the same size and hash
+    /// as an already saved one
+    #[clap(long, value_enum, value_name = "STRATEGY", default_value = "sequence")]
+    pub dedupe: Dedupe,
+
+    /// After the playlist ends, keep checking the original URL for MINUTES minutes and continue
+    /// recording in a new discontinuity if the stream restarts under a new playlist
+    #[clap(long, value_parser, value_name = "MINUTES")]
+    pub follow_restarts: Option<u64>,
+
+    /// Try to download up to N segments that were missed before joining the stream or during
+    /// outages, by guessing their URLs from the counter in segment URLs. Guessed URLs are
+    /// checked with HEAD requests first
+    #[clap(long, value_parser, value_name = "N", default_value_t = 0)]
+    pub backfill: u64,
+
+    /// Parse the playlist as a master playlist, for servers whose playlists aren't detected
+    /// correctly
+    #[clap(long, value_parser, conflicts_with = "force-media-playlist")]
+    pub force_master_playlist: bool,
+
+    /// Parse the playlist as a media playlist and download it directly, for servers whose
+    /// playlists aren't detected correctly
+    #[clap(long, value_parser)]
+    pub force_media_playlist: bool,
 
     /// Show interactive stream picker. If not specified, automatically choose highest bitrate
     /// stream
     #[clap(long, value_parser)]
     pub choose_stream: bool,
+
+    /// Download the variant at this position in the list printed by list-streams, counting from
+    /// 0, instead of the highest bitrate one
+    #[clap(
+        long,
+        value_parser,
+        value_name = "N",
+        conflicts_with_all = &["choose-stream", "variant-url"]
+    )]
+    pub variant_index: Option<usize>,
+
+    /// Download the variant with this playlist URI, absolute or relative to the master playlist,
+    /// instead of the highest bitrate one
+    #[clap(
+        long,
+        value_parser,
+        value_name = "URI",
+        conflicts_with = "choose-stream"
+    )]
+    pub variant_url: Option<String>,
+
+    /// Prefer variants with these video codecs over higher bitrate ones when choosing a stream,
+    /// earlier codecs first
+    #[clap(long, value_enum, value_name = "CODEC", use_value_delimiter = true)]
+    pub prefer_codec: Vec<VideoCodec>,
+
+    /// Don't choose variants with these video codecs, unless every variant has one
+    #[clap(long, value_enum, value_name = "CODEC", use_value_delimiter = true)]
+    pub avoid_codec: Vec<VideoCodec>,
+
+    /// Only download audio renditions with one of these CHARACTERISTICS, given as UTIs or their
+    /// last component (e.g. describes-video for audio description tracks)
+    #[clap(
+        long,
+        value_parser,
+        value_name = "CHARACTERISTIC",
+        use_value_delimiter = true
+    )]
+    pub audio_include: Vec<String>,
+
+    /// Don't download audio renditions with one of these CHARACTERISTICS, given as UTIs or their
+    /// last component
+    #[clap(
+        long,
+        value_parser,
+        value_name = "CHARACTERISTIC",
+        use_value_delimiter = true
+    )]
+    pub audio_exclude: Vec<String>,
+
+    /// Never prompt and answer yes to confirmations, such as downloading into an existing output
+    /// directory. --choose-stream automatically chooses the highest bitrate stream
+    #[clap(short = 'y', long, value_parser)]
+    pub yes: bool,
+
+    /// Never prompt and take safe defaults, refusing to download into an existing output
+    /// directory. --choose-stream automatically chooses the highest bitrate stream
+    #[clap(long, value_parser, conflicts_with = "yes")]
+    pub no_input: bool,
+
+    /// Append segments of each stream to large pack files with an index instead of saving each
+    /// segment to its own file, for long recordings
+    #[clap(long, value_parser)]
+    pub packfile: bool,
+
+    /// Save encrypted segments as downloaded instead of decrypting them, with their keys and IVs
+    /// in keys.json. Remuxing is disabled, the local playlists stay playable
+    #[clap(long, value_parser)]
+    pub no_decrypt: bool,
+
+    /// Gzip WebVTT subtitle segments on disk, they are decompressed when concatenated. Saved
+    /// playlists are not compressed so they stay playable
+    #[clap(long, value_parser)]
+    pub compress_text: bool,
+
+    /// Run COMMAND in a shell after each segment is saved. The segment is described by the
+    /// LIVESTREAM_DL_SEGMENT (path), LIVESTREAM_DL_STREAM, LIVESTREAM_DL_SEQ,
+    /// LIVESTREAM_DL_DISCON_SEQ, and LIVESTREAM_DL_URL environment variables
+    #[clap(long, value_parser, value_name = "COMMAND")]
+    pub exec_per_segment: Option<String>,
+
+    /// Act as a caching HLS proxy: serve the download as a live stream at ADDR (e.g.
+    /// 127.0.0.1:8080) while it is archived. Players can open http://ADDR/master.m3u8, segments
+    /// are served from the archive instead of upstream
+    #[clap(long, value_parser, value_name = "ADDR", conflicts_with = "no-decrypt")]
+    pub mirror: Option<SocketAddr>,
+
+    /// Number of most recent segments of each stream served by --mirror. Older segments are
+    /// evicted from the mirror's memory cache and playlists, they stay in the archive
+    #[clap(
+        long,
+        value_parser,
+        value_name = "N",
+        default_value_t = 10,
+        requires = "mirror"
+    )]
+    pub mirror_window: usize,
+
+    /// Maximum number of segments waiting to be downloaded across all streams
+    #[clap(long, value_parser, value_name = "N", default_value_t = 1000)]
+    pub queue_size: usize,
+
+    /// What to do when --queue-size segments are waiting to be downloaded. "block" makes playlist
+    /// fetchers wait, so segments may slide out of live playlists before they are found.
+    /// "drop-oldest" drops the queued segments closest to expiry to make room
+    #[clap(long, value_enum, value_name = "POLICY", default_value = "block")]
+    pub queue_full: QueueFull,
+
+    /// Flush segments and remuxed files to disk before moving on, for recording to unreliable
+    /// storage
+    #[clap(long, value_parser)]
+    pub fsync: bool,
+
+    /// Also download the I-frame only playlist of the selected stream
+    #[clap(long, value_parser)]
+    pub iframes: bool,
+
+    /// Download the assets of HLS interstitials (e.g. ads) into the "interstitials" directory
+    /// after the stream ends. If not specified, interstitials are only logged and written to
+    /// dateranges.json
+    #[clap(long, value_parser)]
+    pub interstitials: bool,
+
+    /// Write download statistics to summary.json in the output directory
+    #[clap(long, value_parser)]
+    pub write_summary: bool,
+
+    /// Show a full screen dashboard of each stream's queue, download speed, last playlist reload,
+    /// and errors above a scrolling log instead of logging to the terminal. Press q or Ctrl-C to
+    /// stop
+    #[clap(long, value_parser, conflicts_with = "choose-stream")]
+    pub tui: bool,
+
+    /// Show a desktop notification when the stream ends, remuxing finishes, or the download
+    /// fails (requires the desktop-notify feature)
+    #[clap(long, value_parser)]
+    pub desktop_notify: bool,
+
+    /// Write a debug level log with timestamps to download.log in the output directory, for
+    /// looking into long recordings afterwards
+    #[clap(long, value_parser)]
+    pub log_file: bool,
+
+    /// Move download.log to download.log.1 and start a new one once it is larger than MIB
+    /// mebibytes, keeping the 5 most recent ones
+    #[clap(
+        long,
+        value_parser,
+        value_name = "MIB",
+        default_value_t = 100,
+        requires = "log-file"
+    )]
+    pub log_file_size: u64,
+}
+
+#[derive(Parser, Clone, Debug)]
+#[clap(help_heading = "MUX OPTIONS")]
+pub struct MuxOptions {
+    /// Don't remux streams to mp4 after download
+    #[clap(long, value_parser)]
+    pub no_remux: bool,
+
+    /// Split remuxed output into files of MINUTES minutes each, based on segment durations
+    #[clap(long, value_parser, value_name = "MINUTES")]
+    pub split_every: Option<u64>,
+
+    /// Leave out segments of audio and subtitle streams that are outside of the main stream's
+    /// matching discontinuity by program date time, instead of only warning about them
+    #[clap(long, value_parser)]
+    pub trim_unaligned: bool,
+
+    /// Shift separate audio streams by SECONDS when remuxing, negative to play audio earlier
+    #[clap(
+        long,
+        value_parser,
+        value_name = "SECONDS",
+        default_value_t = 0.0,
+        allow_hyphen_values = true
+    )]
+    pub audio_offset: f64,
+
+    /// Measure how far the audio starts from the video in each remuxed file and re-mux with
+    /// corrected audio timestamps if it is more than SECONDS off. Measured offsets are written to
+    /// summary.json
+    #[clap(long, value_parser, value_name = "SECONDS", default_value_t = 1.0)]
+    pub max_av_offset: f64,
+
+    /// Re-encode instead of copying streams when remuxing, in VIDEO:AUDIO format (e.g. h264:aac).
+    /// Either codec may be omitted to copy it, other names are passed to ffmpeg as encoders
+    #[clap(long, value_parser, value_name = "VIDEO:AUDIO")]
+    pub recode: Option<String>,
+
+    /// Extra arguments passed to ffmpeg when remuxing, added before the output file
+    #[clap(long, value_parser, value_name = "ARGS", allow_hyphen_values = true)]
+    pub ffmpeg_args: Option<String>,
+
+    /// Title stored in the metadata of remuxed files
+    #[clap(long, value_parser, value_name = "TITLE")]
+    pub meta_title: Option<String>,
+
+    /// Comment stored in the metadata of remuxed files. If not specified, store the playlist URL
+    /// without its query string
+    #[clap(long, value_parser, value_name = "COMMENT")]
+    pub meta_comment: Option<String>,
+
+    /// Remux to fragmented mp4 with an empty moov box, so output files are playable even if
+    /// remuxing is interrupted and can be played progressively while being written
+    #[clap(long, value_parser)]
+    pub frag_output: bool,
+
+    /// Maximum number of discontinuities to remux at once. If not specified, use the number of
+    /// CPUs
+    #[clap(long, value_parser, value_name = "N")]
+    pub mux_jobs: Option<usize>,
+
+    /// Extract CEA-608/708 closed captions carried in the video stream to an SRT file next to
+    /// each remuxed video, for streams with CLOSED-CAPTIONS instead of WebVTT subtitles
+    #[clap(long, value_parser)]
+    pub extract_captions: bool,
+
+    /// Run COMMAND in a shell for each remuxed output file once remuxing finishes, with the file's
+    /// path in the LIVESTREAM_DL_OUTPUT environment variable
+    #[clap(long, value_parser, value_name = "COMMAND")]
+    pub exec: Option<String>,
+
+    /// Upload each remuxed output file to URL: s3://bucket/prefix (requires the s3 feature, with
+    /// credentials in AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY), webdav(s)://, or
+    /// ftp(s):// and sftp:// (with curl)
+    #[clap(long, value_parser, value_name = "URL")]
+    pub upload: Option<Url>,
+
+    /// Also upload each segment as soon as it is saved
+    #[clap(long, value_parser, requires = "upload")]
+    pub upload_segments: bool,
+
+    /// S3 compatible endpoint to upload to instead of AWS, e.g. for Backblaze B2, Cloudflare R2,
+    /// or MinIO
+    #[clap(long, value_parser, value_name = "URL")]
+    pub upload_endpoint: Option<Url>,
+
+    /// Region of the S3 bucket
+    #[clap(long, value_parser, value_name = "REGION", default_value = "us-east-1")]
+    pub upload_region: String,
+
+    /// Save a thumbnail of each remuxed video
+    #[clap(long, value_parser)]
+    pub thumbnail: bool,
+
+    /// Save a thumbnail every MINUTES minutes of each remuxed video instead of a single one,
+    /// implies --thumbnail
+    #[clap(long, value_parser, value_name = "MINUTES")]
+    pub thumbnail_interval: Option<u64>,
+
+    /// Generate thumbnail sprite sheets from the I-frame only playlist after download, implies
+    /// --iframes
+    #[clap(long, value_parser)]
+    pub sprite_sheet: bool,
+
+    /// Path to ffmpeg binary
+    #[clap(long, value_parser, value_name = "PATH", value_hint = clap::ValueHint::ExecutablePath)]
+    pub ffmpeg_path: Option<PathBuf>,
+
+    /// Path to ffprobe binary
+    #[clap(long, value_parser, value_name = "PATH", value_hint = clap::ValueHint::ExecutablePath)]
+    pub ffprobe_path: Option<PathBuf>,
+}
+
+/// How to recognize segments that were already downloaded
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Dedupe {
+    Sequence,
+    Url,
+    Content,
+}
+
+/// Browser imitated by --impersonate
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Browser {
+    Chrome,
+    Firefox,
+    Safari,
+}
+
+/// What to do when the download queue is full
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QueueFull {
+    /// Playlist fetchers wait for room in the queue
+    Block,
+    /// The queued segments closest to expiry are dropped
+    DropOldest,
+}
+
+/// Video codec families of variant streams
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VideoCodec {
+    H264,
+    Hevc,
+    Av1,
+    Vp9,
 }
 
 #[derive(Parser, Clone, Debug)]
@@ -42,6 +544,21 @@ pub struct NetworkOptions {
     #[clap(long, value_parser, default_value_t = 10)]
     pub max_retries: u32,
 
+    /// Maximum number of times to retry fetching a media playlist with increasing delays before
+    /// giving up on its stream
+    #[clap(long, value_parser, value_name = "N", default_value_t = 5)]
+    pub playlist_retries: u32,
+
+    /// Give up on a stream whose playlist has had no new segments for N reloads in a row without
+    /// ending. Off by default, live streams may pause for a long time, e.g. during ad breaks
+    #[clap(long, value_parser, value_name = "N")]
+    pub max_stalled_reloads: Option<u32>,
+
+    /// Skip playlist lines that fail to parse, logging them, instead of failing on playlists
+    /// with broken tags
+    #[clap(long, value_parser)]
+    pub lenient: bool,
+
     /// Network requests timeout in seconds
     #[clap(
         short,
@@ -52,20 +569,158 @@ pub struct NetworkOptions {
     )]
     pub timeout: u64,
 
+    /// Abort and retry segment downloads that transfer less than BYTES bytes per second for
+    /// --speed-time seconds, for servers that trickle data without timing out
+    #[clap(long, value_parser, value_name = "BYTES")]
+    pub speed_limit: Option<u64>,
+
+    /// Seconds a segment download may stay below --speed-limit before it is retried
+    #[clap(long, value_parser, value_name = "SECONDS", default_value_t = 30)]
+    pub speed_time: u64,
+
     /// Maximum number of concurrent downloads
     #[clap(short = 'j', long, value_parser, default_value_t = 20)]
     pub max_concurrent_downloads: usize,
 
-    /// Use cookies, path to cookies file in Netscape format
+    /// Download each segment larger than 1 MiB as N byte ranges in parallel, for streams with
+    /// few large segments. Only used if the server supports range requests
+    #[clap(long, value_parser, value_name = "N", default_value_t = 1)]
+    pub range_parts: usize,
+
+    /// Request segments at roughly real-time playback rate with random jitter, like a video
+    /// player would, instead of downloading all available segments at once
+    #[clap(long, value_parser)]
+    pub paced: bool,
+
+    /// Warn when saved segments are more than SECONDS behind the live edge of the playlist,
+    /// measured with EXT-X-PROGRAM-DATE-TIME
+    #[clap(long, value_parser, value_name = "SECONDS", default_value_t = 30)]
+    pub latency_warning: u64,
+
+    /// Use cookies, path to cookies file in Netscape, JSON, or "key=value; key=value" format
     #[clap(short, long, value_parser, value_hint = clap::ValueHint::FilePath)]
     pub cookies: Option<PathBuf>,
 
+    /// Use cookies in "key=value; key=value" format for the host of m3u8_url, may be given
+    /// multiple times
+    #[clap(long, value_parser, value_name = "COOKIES")]
+    pub cookie: Vec<String>,
+
+    /// Save cookies to file in Netscape format on exit. If --cookies is not specified and this
+    /// file exists, cookies are also loaded from it
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub cookies_save: Option<PathBuf>,
+
     /// Copy GET query parameters from m3u8_url to all subsequent network requests
     #[clap(short = 'q', long, value_parser)]
     pub copy_query: bool,
 
+    /// Command to run when segment requests return 403 Forbidden. It should print an updated
+    /// m3u8_url whose GET query parameters are copied to all subsequent network requests. If
+    /// not specified and --copy-query is set, m3u8_url is fetched again instead
+    #[clap(long, value_parser, value_name = "COMMAND")]
+    pub refresh_command: Option<String>,
+
+    /// Command to run when m3u8_url is a webpage instead of a playlist, e.g. 'yt-dlp -g
+    /// "$LIVESTREAM_DL_PAGE_URL"'. It should print the playlist url of the stream on the page.
+    /// If not specified or if it fails, the page is searched for playlist urls
+    #[clap(long, value_parser, value_name = "COMMAND")]
+    pub extractor_command: Option<String>,
+
+    /// URL that relative URIs of a local m3u8 file refer to. Playlists and segments not found
+    /// next to the local file are requested from the same relative path under URL. With
+    /// --stdin, URL of the playlist read from stdin
+    #[clap(long, value_parser, value_name = "URL", value_hint = clap::ValueHint::Url)]
+    pub base_url: Option<Url>,
+
     /// By default, every TLS connection is verified to be secure.
     /// This option allows livestream-dl to skip verification and proceed without checking.
     #[clap(short = 'k', long, value_parser)]
     pub insecure: bool,
+
+    /// Trust the CA certificates in FILE, in PEM format, in addition to the built-in roots, for
+    /// servers with a private certificate authority. May be given multiple times
+    #[clap(long, value_parser, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub ca_cert: Vec<PathBuf>,
+
+    /// Send requests like BROWSER, for CDNs that block other clients. Its User-Agent and other
+    /// headers are sent and, with the impersonate feature, its TLS cipher suites are offered in
+    /// its order and HTTP/2 is negotiated like it
+    #[clap(long, value_enum, value_name = "BROWSER", conflicts_with = "insecure")]
+    pub impersonate: Option<Browser>,
+
+    /// Save every HTTP request and response into DIR, to reproduce problems with a download
+    #[clap(
+        long,
+        value_parser,
+        value_name = "DIR",
+        conflicts_with = "replay-session"
+    )]
+    pub record_session: Option<PathBuf>,
+
+    /// Answer HTTP requests with the responses saved by --record-session instead of using the
+    /// network
+    #[clap(long, value_parser, value_name = "DIR", hide = true)]
+    pub replay_session: Option<PathBuf>,
+
+    /// Bind network requests to the network interface with the given name
+    #[clap(
+        long,
+        value_parser,
+        value_name = "NAME",
+        conflicts_with = "source-address"
+    )]
+    pub interface: Option<String>,
+
+    /// Bind network requests to the given local IP address
+    #[clap(long, value_parser, value_name = "IP")]
+    pub source_address: Option<IpAddr>,
+
+    /// Connect to ADDR instead of the addresses HOST resolves to, like curl's --resolve. Requests
+    /// to every port of HOST use ADDR, connections go to PORT only if it is in the url. May be
+    /// given multiple times
+    #[clap(long, value_parser = parse_resolve, value_name = "HOST:PORT:ADDR")]
+    pub resolve: Vec<Resolve>,
+}
+
+/// DNS override given with --resolve
+#[derive(Clone, Debug)]
+pub struct Resolve {
+    pub host: String,
+    pub addr: SocketAddr,
+}
+
+/// Parse a DNS override given as HOST:PORT:ADDR, ADDR may be an IPv6 address in brackets
+fn parse_resolve(s: &str) -> Result<Resolve, String> {
+    let invalid = || format!("{:?} is not in the form HOST:PORT:ADDR", s);
+    let (host, rest) = s.split_once(':').ok_or_else(invalid)?;
+    let (port, addr) = rest.split_once(':').ok_or_else(invalid)?;
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let port: u16 = port
+        .parse()
+        .map_err(|_| format!("invalid port {:?} in {:?}", port, s))?;
+    let ip: IpAddr = addr
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| format!("invalid IP address {:?} in {:?}", addr, s))?;
+
+    Ok(Resolve {
+        host: host.to_owned(),
+        addr: SocketAddr::new(ip, port),
+    })
+}
+
+/// Parse a playlist given as an url or a local file path
+fn parse_input_url(s: &str) -> Result<Url, String> {
+    match Url::parse(s) {
+        // Single letter schemes are Windows drive letters
+        Ok(u) if u.scheme().len() > 1 => Ok(u),
+        _ => {
+            let path = std::fs::canonicalize(s).map_err(|e| format!("{}: {}", s, e))?;
+            Url::from_file_path(&path).map_err(|_| format!("invalid file path {:?}", path))
+        }
+    }
 }
@@ -0,0 +1,227 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Command, CommandFactory};
+
+use crate::cli::Cli;
+
+/// Environment variable overriding the config file path
+const CONFIG_ENV: &str = "LIVESTREAM_DL_CONFIG";
+
+/// Subcommand used if none is given, for compatibility with earlier versions
+const DEFAULT_SUBCOMMAND: &str = "download";
+
+/// Add default arguments from the config file to the command line arguments, right after the
+/// subcommand. The download subcommand is added if no subcommand is given
+///
+/// Only config lines whose first argument is an option of the subcommand or a global option are
+/// used, so options shared by a few subcommands can be set once
+pub fn with_config_args(raw_args: Vec<OsString>) -> Result<Vec<OsString>> {
+    add_config_args(raw_args, config_args)
+}
+
+/// Add the config lines returned by read_config to raw_args, read_config is only called if a
+/// subcommand runs
+fn add_config_args(
+    mut raw_args: Vec<OsString>,
+    read_config: impl FnOnce() -> Result<Vec<Vec<OsString>>>,
+) -> Result<Vec<OsString>> {
+    let cli = Cli::command();
+
+    // Find the subcommand after any global flags, help and version flags are left alone
+    let pos = 1 + raw_args
+        .iter()
+        .skip(1)
+        .take_while(|a| has_option(&cli, a))
+        .count();
+    let first = raw_args
+        .get(pos)
+        .and_then(|a| a.to_str())
+        .unwrap_or_default();
+    let subcommand = match cli.find_subcommand(first) {
+        Some(s) => s,
+        None if ["-h", "--help", "-V", "--version", "help", ""].contains(&first) => {
+            return Ok(raw_args)
+        }
+        None => {
+            raw_args.insert(pos, DEFAULT_SUBCOMMAND.into());
+            cli.find_subcommand(DEFAULT_SUBCOMMAND).unwrap()
+        }
+    };
+
+    let config_args = read_config()?
+        .into_iter()
+        .filter(|line| has_option(subcommand, &line[0]) || has_option(&cli, &line[0]))
+        .flatten();
+    raw_args.splice(pos + 1..pos + 1, config_args);
+
+    Ok(raw_args)
+}
+
+/// Check if the subcommand has the option given as "--long", "--long=value", or "-s"
+fn has_option(subcommand: &Command, arg: &OsStr) -> bool {
+    let arg = arg.to_string_lossy();
+    if let Some(long) = arg.strip_prefix("--") {
+        let long = long.split_once('=').map_or(long, |(l, _)| l);
+        subcommand
+            .get_arguments()
+            .any(|a| a.get_long() == Some(long))
+    } else if let Some(short) = arg.strip_prefix('-') {
+        let short = short.chars().next();
+        subcommand
+            .get_arguments()
+            .any(|a| a.get_short().is_some() && a.get_short() == short)
+    } else {
+        false
+    }
+}
+
+/// Read default command line arguments from the config file, grouped by line
+///
+/// Each non-empty line not starting with '#' holds one or more arguments split with shell quoting
+/// rules, e.g. `--ffmpeg-path "/opt/ffmpeg/bin/ffmpeg"`. Arguments given on the command line
+/// override those in the config file.
+fn config_args() -> Result<Vec<Vec<OsString>>> {
+    let path = match config_path() {
+        Some(p) if p.is_file() => p,
+        _ => return Ok(Vec::new()),
+    };
+
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("error reading config file {:?}", path))?;
+    parse_config(&contents)
+        .map_err(|line| anyhow::anyhow!("invalid line in config file {:?}: {}", path, line))
+}
+
+/// Split the lines of a config file into arguments, returns the first line that can't be split
+/// if any
+fn parse_config(contents: &str) -> Result<Vec<Vec<OsString>>, &str> {
+    let mut args = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let split = shlex::split(line).filter(|s| !s.is_empty()).ok_or(line)?;
+        args.push(split.into_iter().map(OsString::from).collect());
+    }
+
+    Ok(args)
+}
+
+/// Path of config file, $LIVESTREAM_DL_CONFIG if set, otherwise livestream-dl/config in the
+/// user's config directory
+fn config_path() -> Option<PathBuf> {
+    if let Some(p) = env::var_os(CONFIG_ENV) {
+        return Some(PathBuf::from(p));
+    }
+
+    #[cfg(target_family = "windows")]
+    let config_dir = env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(target_family = "windows"))]
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+
+    Some(config_dir?.join("livestream-dl").join("config"))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn with_config(raw_args: &[&str], config: &[&[&str]]) -> Vec<OsString> {
+        let config = config.iter().map(|line| args(line)).collect();
+        add_config_args(args(raw_args), || Ok(config)).unwrap()
+    }
+
+    #[test]
+    fn adds_download_subcommand() {
+        let url = "https://example.com/a.m3u8";
+        assert_eq!(
+            with_config(&["livestream-dl", url], &[]),
+            args(&["livestream-dl", "download", url])
+        );
+        // -q is an option of download, not a global flag
+        assert_eq!(
+            with_config(&["livestream-dl", "-q", url], &[]),
+            args(&["livestream-dl", "download", "-q", url])
+        );
+        assert_eq!(
+            with_config(&["livestream-dl", "mux", "out"], &[]),
+            args(&["livestream-dl", "mux", "out"])
+        );
+    }
+
+    #[test]
+    fn skips_global_flags_before_subcommand() {
+        let url = "https://example.com/a.m3u8";
+        let raw_args = with_config(&["livestream-dl", "-vv", url], &[]);
+        assert_eq!(raw_args, args(&["livestream-dl", "-vv", "download", url]));
+        let cli = Cli::try_parse_from(raw_args).unwrap();
+        assert_eq!(cli.verbose, 2);
+
+        let raw_args = with_config(&["livestream-dl", "--quiet", "mux", "out"], &[]);
+        assert_eq!(raw_args, args(&["livestream-dl", "--quiet", "mux", "out"]));
+        assert_eq!(Cli::try_parse_from(raw_args).unwrap().quiet, 1);
+    }
+
+    #[test]
+    fn adds_config_lines_of_subcommand() {
+        let url = "https://example.com/a.m3u8";
+        let config: &[&[&str]] = &[
+            &["--no-remux"],
+            &["--verbose"],
+            &["--playlist-retries", "3"],
+            &["--no-such-option"],
+        ];
+        assert_eq!(
+            with_config(&["livestream-dl", "--quiet", url], config),
+            args(&[
+                "livestream-dl",
+                "--quiet",
+                "download",
+                "--no-remux",
+                "--verbose",
+                "--playlist-retries",
+                "3",
+                url
+            ])
+        );
+        assert_eq!(
+            with_config(&["livestream-dl", "mux", "out"], config),
+            args(&["livestream-dl", "mux", "--no-remux", "--verbose", "out"])
+        );
+    }
+
+    #[test]
+    fn parses_config_lines() {
+        let contents = "# comment\n\n  --no-remux  \n--ffmpeg-path \"/opt/ff mpeg\" -v\n";
+        assert_eq!(
+            parse_config(contents).unwrap(),
+            [
+                args(&["--no-remux"]),
+                args(&["--ffmpeg-path", "/opt/ff mpeg", "-v"]),
+            ]
+        );
+        assert_eq!(
+            parse_config("--no-remux\n--ffmpeg-path \"/opt\n"),
+            Err("--ffmpeg-path \"/opt")
+        );
+    }
+
+    #[test]
+    fn help_skips_config() {
+        let raw_args = add_config_args(args(&["livestream-dl", "-v", "--help"]), || {
+            Err(anyhow::anyhow!("config read"))
+        });
+        assert_eq!(raw_args.unwrap(), args(&["livestream-dl", "-v", "--help"]));
+    }
+}
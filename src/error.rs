@@ -1,13 +1,50 @@
 use std::fmt::Display;
+use std::path::PathBuf;
 
-use reqwest::Response;
+use reqwest::{Response, StatusCode, Url};
 
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum LivestreamDLError {
     NetworkRequest(Response),
+    SegmentFetch {
+        url: Url,
+        status: StatusCode,
+    },
+    KeyFetch {
+        url: Url,
+        source: anyhow::Error,
+    },
+    SlowTransfer {
+        url: Url,
+        bytes_per_sec: u64,
+    },
+    InvalidKey {
+        url: Url,
+        len: usize,
+    },
+    Decryption(String),
+    StreamNotFound(String),
+    TokenRefresh(String),
+    PlaylistStalled {
+        stream: String,
+        reloads: u32,
+    },
+    DiskWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    MuxFailed {
+        output: PathBuf,
+        source: anyhow::Error,
+    },
+    Ffmpeg {
+        code: Option<i32>,
+        stderr: String,
+    },
     ParseCookie(String),
     ParseM3u8(String),
+    Aborted(String),
 }
 
 impl Display for LivestreamDLError {
@@ -21,14 +58,130 @@ impl Display for LivestreamDLError {
                     r.url()
                 )
             }
+            Self::SegmentFetch { url, status } => {
+                write!(
+                    f,
+                    "segment request returned status code {} for url: {}",
+                    status.as_u16(),
+                    url
+                )
+            }
+            Self::KeyFetch { url, .. } => {
+                write!(f, "failed to fetch encryption key from url: {}", url)
+            }
+            Self::SlowTransfer { url, bytes_per_sec } => {
+                write!(
+                    f,
+                    "transfer slower than {} bytes/s for url: {}",
+                    bytes_per_sec, url
+                )
+            }
+            Self::InvalidKey { url, len } => {
+                write!(
+                    f,
+                    "expected a 16 byte encryption key, got {} bytes from url: {}",
+                    len, url
+                )
+            }
+            Self::Decryption(s) => {
+                write!(f, "failed to decrypt segment: {}", s)
+            }
+            Self::StreamNotFound(s) => {
+                write!(f, "stream not found: {}", s)
+            }
+            Self::TokenRefresh(s) => {
+                write!(f, "failed to refresh tokens: {}", s)
+            }
+            Self::PlaylistStalled { stream, reloads } => {
+                write!(
+                    f,
+                    "{} playlist stalled, no new segments after {} reloads",
+                    stream, reloads
+                )
+            }
+            Self::DiskWrite { path, .. } => {
+                write!(f, "failed to write to {:?}", path)
+            }
+            Self::MuxFailed { output, .. } => {
+                write!(f, "failed to mux {:?}", output)
+            }
+            Self::Ffmpeg { code, stderr } => {
+                match code {
+                    Some(c) => write!(f, "ffmpeg command failed with exit code {}", c)?,
+                    None => write!(f, "ffmpeg command terminated by signal")?,
+                }
+                if !stderr.is_empty() {
+                    write!(f, ", stderr:\n{}", stderr)?;
+                }
+                Ok(())
+            }
             Self::ParseCookie(s) => {
                 write!(f, "failed to parse cookie: {}", s)
             }
             Self::ParseM3u8(s) => {
                 write!(f, "failed to parse m3u8 playlist from url: {}", s)
             }
+            Self::Aborted(s) => {
+                write!(f, "aborted by user: {}", s)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LivestreamDLError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::KeyFetch { source, .. } | Self::MuxFailed { source, .. } => Some(source.as_ref()),
+            Self::DiskWrite { source, .. } => Some(source),
+            _ => None,
         }
     }
 }
 
-impl std::error::Error for LivestreamDLError {}
+/// Process exit codes for each category of failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitCode {
+    Success = 0,
+    Failure = 1,
+    Network = 2,
+    ParseM3u8 = 3,
+    FfmpegMissing = 4,
+    DiskFull = 5,
+    UserAbort = 6,
+    PartialSuccess = 7,
+}
+
+impl ExitCode {
+    /// Categorize an error by the first known error type in its chain
+    pub fn from_error(e: &anyhow::Error) -> Self {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<LivestreamDLError>() {
+                match e {
+                    LivestreamDLError::NetworkRequest(_)
+                    | LivestreamDLError::SegmentFetch { .. }
+                    | LivestreamDLError::KeyFetch { .. }
+                    | LivestreamDLError::SlowTransfer { .. }
+                    | LivestreamDLError::PlaylistStalled { .. } => return Self::Network,
+                    LivestreamDLError::ParseM3u8(_) => return Self::ParseM3u8,
+                    LivestreamDLError::Aborted(_) => return Self::UserAbort,
+                    _ => {}
+                }
+            } else if cause.is::<reqwest::Error>() || cause.is::<reqwest_middleware::Error>() {
+                return Self::Network;
+            } else if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                if e.kind() == std::io::ErrorKind::StorageFull {
+                    return Self::DiskFull;
+                }
+            } else if let Some(inquire::error::InquireError::OperationCanceled) = cause.downcast_ref() {
+                return Self::UserAbort;
+            }
+        }
+
+        Self::Failure
+    }
+
+    /// Exit the process with this code
+    pub fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
@@ -0,0 +1,122 @@
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use tokio::process;
+use tracing::{event, Level};
+
+use crate::error::LivestreamDLError;
+
+static FFMPEG_PATH: OnceLock<PathBuf> = OnceLock::new();
+static FFPROBE_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Minimum supported major version of ffmpeg and ffprobe
+const MIN_MAJOR_VERSION: u32 = 4;
+
+/// Number of stderr lines to include in errors
+const STDERR_TAIL_LINES: usize = 10;
+
+/// Set paths of ffmpeg and ffprobe binaries, must be called before any commands are created
+pub fn set_paths(ffmpeg: Option<PathBuf>, ffprobe: Option<PathBuf>) {
+    if let Some(p) = ffmpeg {
+        let _ = FFMPEG_PATH.set(p);
+    }
+    if let Some(p) = ffprobe {
+        let _ = FFPROBE_PATH.set(p);
+    }
+}
+
+/// Path of ffmpeg binary
+pub fn ffmpeg_path() -> &'static Path {
+    FFMPEG_PATH
+        .get()
+        .map(PathBuf::as_path)
+        .unwrap_or_else(|| Path::new("ffmpeg"))
+}
+
+/// Path of ffprobe binary
+pub fn ffprobe_path() -> &'static Path {
+    FFPROBE_PATH
+        .get()
+        .map(PathBuf::as_path)
+        .unwrap_or_else(|| Path::new("ffprobe"))
+}
+
+/// Create an ffmpeg command
+pub fn ffmpeg_command() -> process::Command {
+    process::Command::new(ffmpeg_path())
+}
+
+/// Create an ffprobe command
+pub fn ffprobe_command() -> process::Command {
+    process::Command::new(ffprobe_path())
+}
+
+/// Run an ffmpeg command, returning an error with the end of stderr if it fails
+pub async fn run(cmd: &mut process::Command) -> Result<Output> {
+    cmd.kill_on_drop(true);
+
+    event!(Level::TRACE, "{:?}", cmd);
+    let output = cmd.output().await?;
+    event!(
+        Level::TRACE,
+        "ffmpeg stdout: {:#?}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    event!(
+        Level::TRACE,
+        "ffmpeg stderr: {:#?}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // Check ffmpeg exit status
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines: Vec<_> = stderr.trim_end().lines().collect();
+        let tail = lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n");
+        return Err(LivestreamDLError::Ffmpeg {
+            code: output.status.code(),
+            stderr: tail,
+        }
+        .into());
+    }
+
+    Ok(output)
+}
+
+/// Check that a ffmpeg or ffprobe binary can be run and is new enough
+pub fn check_version(path: &Path) -> Result<()> {
+    let output = std::process::Command::new(path)
+        .arg("-version")
+        .output()
+        .with_context(|| format!("unable to run {:?}, make sure it is installed", path))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("{:?} -version failed", path));
+    }
+
+    // First line looks like "ffmpeg version 6.0 ..." or "ffmpeg version n6.0-...", git builds
+    // such as "ffmpeg version N-109000-g..." can't be parsed and are assumed to be new enough
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout
+        .split_whitespace()
+        .nth(2)
+        .unwrap_or_default()
+        .trim_start_matches('n');
+    let major = version
+        .split(|c: char| !c.is_ascii_digit())
+        .next()
+        .and_then(|m| m.parse::<u32>().ok());
+    if let Some(major) = major {
+        if major < MIN_MAJOR_VERSION {
+            return Err(anyhow::anyhow!(
+                "{:?} version {} is too old, at least version {} is required",
+                path,
+                version,
+                MIN_MAJOR_VERSION
+            ));
+        }
+    }
+
+    Ok(())
+}
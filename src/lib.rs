@@ -0,0 +1,15 @@
+//! Library behind the livestream-dl binary, for downloading HLS livestreams from other programs
+//!
+//! [`livestream::Livestream`] downloads a livestream given the same [`cli::Args`] as the download
+//! subcommand, and broadcasts its progress as [`livestream::DownloadEvent`]s to subscribers.
+
+pub mod cli;
+pub mod config;
+pub mod error;
+pub mod ffmpeg;
+pub mod livestream;
+pub mod log_file;
+pub mod mux;
+pub mod notify;
+pub mod tui;
+pub mod upload;
@@ -0,0 +1,115 @@
+use reqwest::Url;
+use tracing::{event, Level};
+
+use super::http_client::HttpClient;
+
+/// Segment url pattern with a counter that follows the media sequence number, inferred from two
+/// segment urls, used to guess the urls of segments that are no longer in the playlist
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UrlPattern {
+    prefix: String,
+    suffix: String,
+    /// Difference between the counter and the media sequence number
+    offset: i64,
+    /// Number of digits the counter is zero padded to, 0 if not padded
+    width: usize,
+}
+
+impl UrlPattern {
+    /// Infer the pattern from the urls of segments seq_a and seq_b. Both urls must be the same
+    /// except for a single number that differs by as much as the sequence numbers
+    pub fn infer(url_a: &Url, seq_a: u64, url_b: &Url, seq_b: u64) -> Option<Self> {
+        let (a, b) = (runs(url_a.as_str()), runs(url_b.as_str()));
+        if a.len() != b.len() || seq_a == seq_b {
+            return None;
+        }
+
+        // Exactly one run may differ, and it must be a number
+        let mut differing = a.iter().zip(&b).enumerate().filter(|(_, (x, y))| x != y);
+        let (i, (num_a, num_b)) = differing.next()?;
+        if differing.next().is_some() {
+            return None;
+        }
+        let (n_a, n_b) = (num_a.parse::<i64>().ok()?, num_b.parse::<i64>().ok()?);
+        if n_b - n_a != seq_b as i64 - seq_a as i64 {
+            return None;
+        }
+
+        let padded = num_a.len() > 1 && num_a.starts_with('0');
+        Some(Self {
+            prefix: a[..i].concat(),
+            suffix: a[i + 1..].concat(),
+            offset: n_a - seq_a as i64,
+            width: if padded { num_a.len() } else { 0 },
+        })
+    }
+
+    /// Guessed url of segment seq
+    pub fn url(&self, seq: u64) -> Option<Url> {
+        let n = seq as i64 + self.offset;
+        if n < 0 {
+            return None;
+        }
+        let url = format!(
+            "{}{:0width$}{}",
+            self.prefix,
+            n,
+            self.suffix,
+            width = self.width
+        );
+        Url::parse(&url).ok()
+    }
+}
+
+/// Split a string into alternating runs of digits and non-digits
+fn runs(s: &str) -> Vec<&str> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    let mut prev_digit = None;
+    for (i, c) in s.char_indices() {
+        let digit = c.is_ascii_digit();
+        if prev_digit.is_some_and(|p| p != digit) {
+            runs.push(&s[start..i]);
+            start = i;
+        }
+        prev_digit = Some(digit);
+    }
+    runs.push(&s[start..]);
+
+    runs
+}
+
+/// Find the guessed urls of segments seqs that exist, checked with HEAD requests from the
+/// newest segment backwards until one is missing. Returns (seq, url) pairs in playlist order
+pub async fn probe(
+    client: &HttpClient,
+    pattern: &UrlPattern,
+    seqs: impl DoubleEndedIterator<Item = u64>,
+) -> Vec<(u64, Url)> {
+    let mut found = Vec::new();
+    for seq in seqs.rev() {
+        let url = match pattern.url(seq) {
+            Some(u) => u,
+            None => break,
+        };
+        match client.head(url.clone()).send().await {
+            Ok(resp) if resp.status().is_success() => found.push((seq, url)),
+            Ok(resp) => {
+                event!(
+                    Level::DEBUG,
+                    "Stopping backfill at {}: {}",
+                    url.as_str(),
+                    resp.status()
+                );
+                break;
+            }
+            Err(e) => {
+                event!(Level::DEBUG, "Stopping backfill at {}: {}", url.as_str(), e);
+                break;
+            }
+        }
+    }
+    found.reverse();
+
+    found
+}
@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Weight of older segments in the average segment duration, higher values adapt more slowly
+const HISTORY_WEIGHT: u32 = 8;
+
+/// Delay after the expected publish time of the next segment before polling, as a fraction of
+/// the average segment duration
+const POLL_MARGIN: f64 = 0.1;
+
+/// Shortest time between playlist polls
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Estimates when a live playlist gets its next segment from the durations of recently found
+/// segments and when they appeared, so the playlist is polled just after it updates instead of
+/// at a fixed fraction of the target duration
+#[derive(Debug, Default)]
+pub struct Cadence {
+    /// Moving average of the durations of found segments
+    average: Option<Duration>,
+    /// When new segments last appeared
+    last_update: Option<Instant>,
+}
+
+impl Cadence {
+    /// Record segments with the given durations found by a playlist poll started at now
+    pub fn update(&mut self, now: Instant, durations: impl IntoIterator<Item = Duration>) {
+        let mut found = false;
+        for duration in durations.into_iter().filter(|d| !d.is_zero()) {
+            found = true;
+            self.average = Some(match self.average {
+                Some(avg) => (avg * (HISTORY_WEIGHT - 1) + duration) / HISTORY_WEIGHT,
+                None => duration,
+            });
+        }
+        if found {
+            self.last_update = Some(now);
+        }
+    }
+
+    /// When to poll the playlist next after a poll started at now
+    ///
+    /// Polls just after the next segment is expected, or every half segment duration once it is
+    /// overdue. Falls back to the target duration until segments were found
+    pub fn next_poll(&self, now: Instant, target_duration: Duration) -> Instant {
+        let (average, last_update) = match (self.average, self.last_update) {
+            (Some(a), Some(l)) => (a, l),
+            _ => return now + target_duration / 2,
+        };
+
+        let expected = last_update + average + average.mul_f64(POLL_MARGIN);
+        let next = if expected > now {
+            expected
+        } else {
+            now + average / 2
+        };
+
+        next.max(now + MIN_POLL_INTERVAL)
+    }
+}
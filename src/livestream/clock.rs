@@ -0,0 +1,44 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::{self, Instant};
+
+/// Source of time for playlist polling, retries, and pacing, so they can run on simulated time
+#[async_trait::async_trait]
+pub trait Clock: Debug + Send + Sync {
+    /// Current time
+    fn now(&self) -> Instant;
+
+    /// Wait until deadline
+    async fn sleep_until(&self, deadline: Instant);
+
+    /// Wait for duration
+    async fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration).await
+    }
+}
+
+/// Clock shared by all tasks of a download
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Clock following tokio's timer, which is simulated while tokio::time is paused
+#[derive(Debug, Default)]
+pub struct TokioClock;
+
+impl TokioClock {
+    pub fn shared() -> SharedClock {
+        Arc::new(Self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        time::sleep_until(deadline).await
+    }
+}
@@ -1,54 +1,287 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::prelude::*;
-use std::io::BufReader;
 use std::path::Path;
+use std::sync::Mutex;
 
 use anyhow::Result;
 use reqwest::cookie::{CookieStore, Jar};
 use reqwest::Url;
+use serde::Deserialize;
 use tracing::{event, Level};
 
 use crate::error::LivestreamDLError;
 
 /// Cookie provider wrapping reqwest Jar
-pub struct CookieJar(Jar);
+///
+/// Every cookie loaded from file or set by a response is also remembered so the jar can be
+/// written back to disk in Netscape format
+#[derive(Debug)]
+pub struct CookieJar {
+    jar: Jar,
+    entries: Mutex<BTreeMap<(String, String, String), CookieEntry>>,
+}
+
+/// A single cookie as represented in a Netscape cookies file
+#[derive(Clone, Debug)]
+struct CookieEntry {
+    domain: String,
+    include_subdomains: bool,
+    path: String,
+    secure: bool,
+    expires: u64,
+    name: String,
+    value: String,
+}
 
 impl CookieJar {
-    /// Parse cookies from file in Netscape format
-    pub fn parse_from_file(path: impl AsRef<Path>) -> Result<Self> {
-        let jar = Jar::default();
-
-        let file = fs::File::open(path)?;
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            let line = line?;
+    /// Create an empty cookie jar
+    pub fn new() -> Self {
+        Self {
+            jar: Jar::default(),
+            entries: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Parse cookies from file
+    ///
+    /// The format is automatically detected and may be either Netscape, a JSON export from a
+    /// browser extension, or a header-style "key=value; key=value" string. Header-style cookies
+    /// are applied to the host of url
+    pub fn parse_from_file(path: impl AsRef<Path>, url: &Url) -> Result<Self> {
+        let jar = Self::new();
+
+        let contents = fs::read_to_string(path)?;
+        let trimmed = contents.trim_start();
+        if trimmed.starts_with('[') || trimmed.starts_with('{') {
+            jar.add_json_str(&contents)?;
+        } else if trimmed.starts_with('#') || contents.contains('\t') {
+            jar.add_netscape_str(&contents);
+        } else {
+            jar.add_header_str(trimmed, url)?;
+        }
+
+        Ok(jar)
+    }
+
+    /// Add cookies in Netscape format
+    fn add_netscape_str(&self, contents: &str) {
+        for line in contents.lines() {
             // Skip empty lines and comments
             if line.trim().is_empty() || line.trim().starts_with('#') {
                 continue;
             }
 
-            let (domain, cookie) = match parse_cookie(&line) {
+            let entry = match CookieEntry::parse_netscape(line) {
                 Ok(x) => x,
                 Err(e) => {
                     event!(Level::WARN, "{}", e);
                     continue;
                 }
             };
-            jar.add_cookie_str(&cookie, &domain)
+            self.add(entry);
+        }
+    }
+
+    /// Add cookies from a JSON browser export, either a list of cookies or an object with a
+    /// "cookies" list
+    fn add_json_str(&self, contents: &str) -> Result<()> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum JsonCookies {
+            List(Vec<JsonCookie>),
+            Wrapped { cookies: Vec<JsonCookie> },
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct JsonCookie {
+            domain: String,
+            name: String,
+            value: String,
+            path: Option<String>,
+            #[serde(default)]
+            secure: bool,
+            host_only: Option<bool>,
+            #[serde(alias = "expires")]
+            expiration_date: Option<f64>,
+        }
+
+        let cookies = match serde_json::from_str(contents)
+            .map_err(|e| LivestreamDLError::ParseCookie(e.to_string()))?
+        {
+            JsonCookies::List(c) => c,
+            JsonCookies::Wrapped { cookies: c } => c,
+        };
+
+        for c in cookies {
+            let include_subdomains = !c.host_only.unwrap_or(!c.domain.starts_with('.'));
+            self.add(CookieEntry {
+                domain: c.domain,
+                include_subdomains,
+                path: c.path.unwrap_or_else(|| "/".into()),
+                secure: c.secure,
+                expires: c.expiration_date.map(|t| t.max(0.0) as u64).unwrap_or(0),
+                name: c.name,
+                value: c.value,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Add cookies from a header-style "key=value; key=value" string for the host of url
+    pub fn add_header_str(&self, cookies: &str, url: &Url) -> Result<()> {
+        let domain = url
+            .host_str()
+            .ok_or_else(|| LivestreamDLError::ParseCookie(cookies.to_owned()))?;
+
+        for pair in cookies.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            let (name, value) = match pair.split_once('=') {
+                Some(x) => x,
+                None => {
+                    event!(
+                        Level::WARN,
+                        "{}",
+                        LivestreamDLError::ParseCookie(pair.to_owned())
+                    );
+                    continue;
+                }
+            };
+            self.add(CookieEntry {
+                domain: domain.to_owned(),
+                include_subdomains: false,
+                path: "/".into(),
+                secure: false,
+                expires: 0,
+                name: name.trim().to_owned(),
+                value: value.trim().to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Add cookie to both the reqwest jar and remembered entries
+    fn add(&self, entry: CookieEntry) {
+        match entry.url() {
+            Ok(url) => {
+                self.jar.add_cookie_str(&entry.cookie_str(), &url);
+                self.insert(entry);
+            }
+            Err(_) => {
+                event!(
+                    Level::WARN,
+                    "{}",
+                    LivestreamDLError::ParseCookie(entry.to_netscape())
+                );
+            }
+        }
+    }
+
+    /// Write all known cookies to file in Netscape format
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut file = fs::File::create(path)?;
+        writeln!(file, "# Netscape HTTP Cookie File")?;
+        for entry in self.entries.lock().unwrap().values() {
+            writeln!(file, "{}", entry.to_netscape())?;
         }
 
-        Ok(Self(jar))
+        Ok(())
+    }
+
+    fn insert(&self, entry: CookieEntry) {
+        let key = (entry.domain.clone(), entry.path.clone(), entry.name.clone());
+        self.entries.lock().unwrap().insert(key, entry);
+    }
+
+    fn remove(&self, entry: &CookieEntry) {
+        let key = (entry.domain.clone(), entry.path.clone(), entry.name.clone());
+        self.entries.lock().unwrap().remove(&key);
     }
 }
 
-fn parse_cookie(line: &str) -> Result<(Url, String)> {
-    if let [domain, _, _, _, _, name, value] = line.split('\t').collect::<Vec<_>>().as_slice() {
-        let domain = Url::parse(&format!("https://{}", domain.trim_start_matches('.')))
-            .map_err(|_| LivestreamDLError::ParseCookie(line.to_owned()))?;
-        let cookie = format!("{}={}", name, value);
-        Ok((domain, cookie))
-    } else {
-        Err(LivestreamDLError::ParseCookie(line.to_owned()).into())
+impl CookieEntry {
+    fn parse_netscape(line: &str) -> Result<Self> {
+        if let [domain, include_subdomains, path, secure, expires, name, value] =
+            line.split('\t').collect::<Vec<_>>().as_slice()
+        {
+            let entry = Self {
+                domain: domain.to_string(),
+                include_subdomains: include_subdomains.eq_ignore_ascii_case("TRUE"),
+                path: path.to_string(),
+                secure: secure.eq_ignore_ascii_case("TRUE"),
+                expires: expires.parse().unwrap_or(0),
+                name: name.to_string(),
+                value: value.to_string(),
+            };
+            Ok(entry)
+        } else {
+            Err(LivestreamDLError::ParseCookie(line.to_owned()).into())
+        }
+    }
+
+    /// URL of the domain this cookie applies to
+    fn url(&self) -> Result<Url> {
+        Ok(Url::parse(&format!(
+            "https://{}",
+            self.domain.trim_start_matches('.')
+        ))?)
+    }
+
+    /// Create an entry from a Set-Cookie header value received from url
+    fn from_set_cookie(header: &str, url: &Url) -> Option<(Self, bool)> {
+        let cookie = cookie::Cookie::parse(header).ok()?;
+        let now = time::OffsetDateTime::now_utc();
+
+        let (domain, include_subdomains) = match cookie.domain() {
+            Some(d) => (format!(".{}", d.trim_start_matches('.')), true),
+            None => (url.host_str()?.to_owned(), false),
+        };
+
+        // Max-Age takes precedence over Expires, session cookies expire at 0
+        let expires = match (cookie.max_age(), cookie.expires_datetime()) {
+            (Some(age), _) => Some(now + age),
+            (None, Some(t)) => Some(t),
+            (None, None) => None,
+        };
+        let expired = expires.map(|t| t <= now).unwrap_or(false);
+
+        let entry = Self {
+            domain,
+            include_subdomains,
+            path: cookie.path().unwrap_or("/").to_owned(),
+            secure: cookie.secure().unwrap_or(false),
+            expires: expires
+                .map(|t| t.unix_timestamp().max(0) as u64)
+                .unwrap_or(0),
+            name: cookie.name().to_owned(),
+            value: cookie.value().to_owned(),
+        };
+
+        Some((entry, expired))
+    }
+
+    /// Cookie string to add to reqwest jar
+    fn cookie_str(&self) -> String {
+        let mut s = format!("{}={}; Path={}", self.name, self.value, self.path);
+        if self.include_subdomains {
+            s.push_str(&format!("; Domain={}", self.domain.trim_start_matches('.')));
+        }
+        s
+    }
+
+    fn to_netscape(&self) -> String {
+        let bool_str = |b| if b { "TRUE" } else { "FALSE" };
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.domain,
+            bool_str(self.include_subdomains),
+            self.path,
+            bool_str(self.secure),
+            self.expires,
+            self.name,
+            self.value
+        )
     }
 }
 
@@ -58,10 +291,25 @@ impl CookieStore for CookieJar {
         cookie_headers: &mut dyn Iterator<Item = &reqwest::header::HeaderValue>,
         url: &url::Url,
     ) {
-        self.0.set_cookies(cookie_headers, url)
+        let headers: Vec<_> = cookie_headers.collect();
+
+        // Remember cookies for saving later
+        for header in &headers {
+            let entry = header
+                .to_str()
+                .ok()
+                .and_then(|h| CookieEntry::from_set_cookie(h, url));
+            match entry {
+                Some((e, true)) => self.remove(&e),
+                Some((e, false)) => self.insert(e),
+                None => {}
+            }
+        }
+
+        self.jar.set_cookies(&mut headers.into_iter(), url)
     }
 
     fn cookies(&self, url: &url::Url) -> Option<reqwest::header::HeaderValue> {
-        self.0.cookies(url)
+        self.jar.cookies(url)
     }
 }
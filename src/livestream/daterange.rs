@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use serde::Serialize;
+use time::OffsetDateTime;
+use tracing::{event, Level};
+
+use super::utils::{parse_attribute_list, parse_program_date_time};
+
+/// CLASS of EXT-X-DATERANGE tags signalling HLS interstitials
+const INTERSTITIAL_CLASS: &str = "com.apple.hls.interstitial";
+
+/// EXT-X-DATERANGE tag, with its position in the output
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DateRange {
+    pub id: String,
+    pub class: Option<String>,
+    pub start_date: String,
+    pub end_date: Option<String>,
+    pub duration: Option<f64>,
+    pub planned_duration: Option<f64>,
+    pub end_on_next: bool,
+    /// SCTE35-* and X-* attributes
+    pub attributes: BTreeMap<String, String>,
+    /// Discontinuity sequence of the output file containing the start date
+    pub discontinuity: Option<u64>,
+    /// Seconds from the start of the output file to the start date
+    pub offset: Option<f64>,
+}
+
+impl DateRange {
+    /// Whether the date range is an HLS interstitial with assets to play
+    pub fn is_interstitial(&self) -> bool {
+        self.class.as_deref() == Some(INTERSTITIAL_CLASS)
+            || self.attributes.contains_key("X-ASSET-URI")
+            || self.attributes.contains_key("X-ASSET-LIST")
+    }
+}
+
+/// Collects EXT-X-DATERANGE tags found by all playlist fetchers, and the wall clock time each
+/// discontinuity's output starts at
+#[derive(Clone, Debug, Default)]
+pub struct Timeline(Arc<Mutex<TimelineState>>);
+
+#[derive(Debug, Default)]
+struct TimelineState {
+    /// Date ranges in the order they were first found
+    ranges: Vec<DateRange>,
+    ids: HashMap<String, usize>,
+    /// Program date time of the first queued segment of each discontinuity
+    anchors: BTreeMap<u64, OffsetDateTime>,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the program date time of a queued segment
+    pub fn anchor(&self, discon_seq: u64, program_date_time: OffsetDateTime) {
+        let mut state = self.0.lock().unwrap();
+        let anchor = state.anchors.entry(discon_seq).or_insert(program_date_time);
+        *anchor = (*anchor).min(program_date_time);
+    }
+
+    /// Parse the attribute list of an EXT-X-DATERANGE tag. Tags with an already seen ID update
+    /// its attributes, since playlists may add an END-DATE later
+    pub fn add(&self, attribute_list: &str) {
+        let mut range = DateRange::default();
+        for (key, value) in parse_attribute_list(attribute_list) {
+            match key.as_str() {
+                "ID" => range.id = value,
+                "CLASS" => range.class = Some(value),
+                "START-DATE" => range.start_date = value,
+                "END-DATE" => range.end_date = Some(value),
+                "DURATION" => range.duration = value.parse().ok(),
+                "PLANNED-DURATION" => range.planned_duration = value.parse().ok(),
+                "END-ON-NEXT" => range.end_on_next = value == "YES",
+                _ => {
+                    range.attributes.insert(key, value);
+                }
+            }
+        }
+        if range.id.is_empty() {
+            return;
+        }
+
+        let mut state = self.0.lock().unwrap();
+        match state.ids.get(&range.id) {
+            Some(&i) => {
+                let existing = &mut state.ranges[i];
+                existing.class = range.class.or(existing.class.take());
+                existing.end_date = range.end_date.or(existing.end_date.take());
+                existing.duration = range.duration.or(existing.duration);
+                existing.planned_duration = range.planned_duration.or(existing.planned_duration);
+                existing.end_on_next |= range.end_on_next;
+                existing.attributes.extend(range.attributes);
+            }
+            None => {
+                if range.is_interstitial() {
+                    event!(
+                        Level::INFO,
+                        "Found interstitial {} starting at {}",
+                        range.id,
+                        range.start_date
+                    );
+                }
+                let i = state.ranges.len();
+                state.ids.insert(range.id.clone(), i);
+                state.ranges.push(range);
+            }
+        }
+    }
+
+    /// Date ranges signalling HLS interstitials
+    pub fn interstitials(&self) -> Vec<DateRange> {
+        let state = self.0.lock().unwrap();
+        state
+            .ranges
+            .iter()
+            .filter(|r| r.is_interstitial())
+            .cloned()
+            .collect()
+    }
+
+    /// Write all date ranges as json with their offsets in the output files, does nothing if
+    /// there are none
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let state = self.0.lock().unwrap();
+        if state.ranges.is_empty() {
+            return Ok(());
+        }
+
+        // Map start dates to the latest discontinuity starting before them
+        let ranges: Vec<_> = state
+            .ranges
+            .iter()
+            .cloned()
+            .map(|mut r| {
+                let start = parse_program_date_time(&r.start_date);
+                let anchor = start.and_then(|s| state.anchors.iter().rev().find(|(_, &a)| a <= s));
+                if let (Some(start), Some((&discon_seq, &anchor))) = (start, anchor) {
+                    r.discontinuity = Some(discon_seq);
+                    r.offset = Some((start - anchor).as_seconds_f64());
+                }
+                r
+            })
+            .collect();
+
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, &ranges)?;
+
+        Ok(())
+    }
+}
@@ -1,12 +1,22 @@
 use std::fmt::Display;
 
-use m3u8_rs::VariantStream;
-
-pub struct DisplayableVariant<'a>(&'a VariantStream);
-
-impl<'a> From<&'a VariantStream> for DisplayableVariant<'a> {
-    fn from(v: &'a VariantStream) -> Self {
-        Self(v)
+use m3u8_rs::{AlternativeMedia, VariantStream};
+
+pub struct DisplayableVariant<'a>(&'a VariantStream, Option<&'a str>);
+
+impl<'a> DisplayableVariant<'a> {
+    /// Create a displayable variant, showing the audio channels of its alternative audio group if
+    /// available
+    pub fn new(v: &'a VariantStream, alternatives: &'a [AlternativeMedia]) -> Self {
+        let channels = v.audio.as_ref().and_then(|group| {
+            alternatives
+                .iter()
+                .filter(|a| &a.group_id == group)
+                .max_by_key(|a| a.default)?
+                .channels
+                .as_deref()
+        });
+        Self(v, channels)
     }
 }
 
@@ -37,6 +47,11 @@ impl<'a> Display for DisplayableVariant<'a> {
             s.push_str(&format!("  Codec: {}", codec));
         }
 
+        // Audio channels
+        if let Some(channels) = self.1 {
+            s.push_str(&format!("  Channels: {}", channels));
+        }
+
         write!(f, "{}", s)
     }
 }
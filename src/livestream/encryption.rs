@@ -1,21 +1,45 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
 use aes::cipher::block_padding::Pkcs7;
 use aes::cipher::{BlockDecryptMut, KeyIvInit};
 use anyhow::Result;
 use m3u8_rs::Key;
 use reqwest::Url;
+use serde::Serialize;
+use tokio::fs;
 use tracing::{event, Level};
 
 use super::http_client::HttpClient;
-use super::utils::make_absolute_url;
+use super::utils::{make_absolute_url, write_atomic};
+use super::worker_pool;
+use super::{MediaFormat, Segment, Stream};
+use crate::error::LivestreamDLError;
 
 type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
 
+/// Name of the file describing archived keys in the segments directory
+const KEYS_FILE: &str = "keys.json";
+
+/// Directory of archived key files in the segments directory
+const KEY_DIR: &str = "keys";
+
+/// Largest data decrypted on the async task, larger data is decrypted on the blocking thread pool
+const INLINE_DECRYPT_SIZE: usize = 64 * 1024;
+
 /// HLS encryption methods
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub enum Encryption {
     None,
-    Aes128 { key_uri: Url, iv: [u8; 16] },
+    Aes128 {
+        key_uri: Url,
+        iv: [u8; 16],
+        /// IV derived from the media sequence number, tried if the explicit IV fails to decrypt
+        /// since some encoders ignore the IV they advertise
+        fallback_iv: Option<[u8; 16]>,
+    },
     SampleAes,
 }
 
@@ -30,7 +54,11 @@ impl Encryption {
                     // Bail if keyformat exists but is not "identity"
                     if let Some(keyformat) = &k.keyformat {
                         if keyformat != "identity" {
-                            return Err(anyhow::anyhow!("Invalid keyformat: {}", keyformat));
+                            return Err(LivestreamDLError::Decryption(format!(
+                                "invalid keyformat: {}",
+                                keyformat
+                            ))
+                            .into());
                         }
                     }
 
@@ -38,55 +66,330 @@ impl Encryption {
                     let uri = make_absolute_url(base_url, uri)?;
 
                     // Parse IV
-                    let mut iv = [0_u8; 16];
-                    if let Some(iv_str) = &k.iv {
+                    let mut seq_iv = [0_u8; 16];
+                    seq_iv[(16 - std::mem::size_of_val(&seq))..]
+                        .copy_from_slice(&seq.to_be_bytes());
+                    let (iv, fallback_iv) = if let Some(iv_str) = &k.iv {
                         // IV is given separately
+                        let mut iv = [0_u8; 16];
                         let iv_str = iv_str.trim_start_matches("0x");
                         hex::decode_to_slice(iv_str, &mut iv as &mut [u8])?;
+                        (iv, Some(seq_iv))
                     } else {
                         // Compute IV from segment sequence
-                        iv[(16 - std::mem::size_of_val(&seq))..]
-                            .copy_from_slice(&seq.to_be_bytes());
-                    }
+                        (seq_iv, None)
+                    };
 
-                    Self::Aes128 { key_uri: uri, iv }
+                    Self::Aes128 {
+                        key_uri: uri,
+                        iv,
+                        fallback_iv,
+                    }
                 } else {
                     // Bail if no uri is found
-                    return Err(anyhow::anyhow!("No URI found for AES-128 key"));
+                    return Err(LivestreamDLError::Decryption(
+                        "no URI found for AES-128 key".into(),
+                    )
+                    .into());
                 }
             }
             k if k.method == "SAMPLE-AES" => {
-                return Err(anyhow::anyhow!(
-                    "Unimplemented encryption method: {}",
+                return Err(LivestreamDLError::Decryption(format!(
+                    "unimplemented encryption method: {}",
+                    k.method
+                ))
+                .into())
+            }
+            k => {
+                return Err(LivestreamDLError::Decryption(format!(
+                    "invalid encryption method: {}",
                     k.method
                 ))
+                .into())
             }
-            k => return Err(anyhow::anyhow!("Invalid encryption method: {}", k.method)),
         };
 
         Ok(encryption)
     }
 
     /// Decrypt the given data
-    pub async fn decrypt(&self, client: &HttpClient, data: &[u8]) -> Result<Vec<u8>> {
+    pub async fn decrypt(
+        &self,
+        client: &HttpClient,
+        keys: &KeyCache,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
         let r = match self {
             Self::None => Vec::from(data),
-            Self::Aes128 { key_uri, iv } => {
-                event!(
-                    Level::TRACE,
-                    "Fetching encryption key from {}",
-                    key_uri.as_str()
-                );
-                let body = client.get(key_uri.clone()).send().await?.bytes().await?;
-                let mut key = [0_u8; 16];
-                key.copy_from_slice(&body[..16]);
+            Self::Aes128 {
+                key_uri,
+                iv,
+                fallback_iv,
+            } => {
+                let key = keys.get(client, key_uri).await?;
 
                 event!(Level::TRACE, "Decrypting segment");
-                Aes128CbcDec::new(&key.into(), iv.into()).decrypt_padded_vec_mut::<Pkcs7>(data)?
+                match (aes128_decrypt(key, *iv, data).await, fallback_iv) {
+                    (Ok(d), _) => d,
+                    // Padding errors may come from a mislabeled IV
+                    (Err(e), Some(fallback)) => {
+                        let d = aes128_decrypt(key, *fallback, data).await.map_err(|_| e)?;
+                        event!(
+                            Level::INFO,
+                            "Given IV failed to decrypt, decrypted with the media sequence number as IV"
+                        );
+                        d
+                    }
+                    (Err(e), None) => return Err(e),
+                }
             }
             Self::SampleAes => unimplemented!(),
         };
 
         Ok(r)
     }
+
+    /// Decrypt the given data and check that the result looks like media. Otherwise the cached
+    /// key may be wrong, so it is fetched again once and the data decrypted again. Returns the
+    /// data and whether it still doesn't look like media
+    pub async fn decrypt_verified(
+        &self,
+        client: &HttpClient,
+        keys: &KeyCache,
+        data: &[u8],
+    ) -> Result<(Vec<u8>, bool)> {
+        let key_uri = match self {
+            Self::Aes128 { key_uri, .. } => key_uri,
+            _ => return Ok((self.decrypt(client, keys, data).await?, false)),
+        };
+
+        let result = self.decrypt(client, keys, data).await;
+        if matches!(&result, Ok(d) if MediaFormat::looks_like_media(d)) {
+            return Ok((result?, false));
+        }
+        if !keys.refetch(client, key_uri).await? {
+            return Ok((result?, true));
+        }
+        match &result {
+            Ok(_) => event!(
+                Level::WARN,
+                "Decrypted segment doesn't look like media, fetched key {} again",
+                key_uri
+            ),
+            Err(e) => event!(Level::WARN, "{}, fetched key {} again", e, key_uri),
+        }
+
+        let decrypted = self.decrypt(client, keys, data).await?;
+        let corrupt = !MediaFormat::looks_like_media(&decrypted);
+        Ok((decrypted, corrupt))
+    }
+
+    /// Fetch the key without decrypting anything, so it can be archived
+    pub async fn fetch_key(&self, client: &HttpClient, keys: &KeyCache) -> Result<()> {
+        if let Self::Aes128 { key_uri, .. } = self {
+            keys.get(client, key_uri).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// AES-128 keys fetched so far by key uri, shared between clones
+#[derive(Clone, Debug, Default)]
+pub struct KeyCache {
+    keys: Arc<Mutex<HashMap<Url, [u8; 16]>>>,
+    /// Keys fetched again after decrypting with them failed, each is only fetched again once
+    refetched: Arc<Mutex<HashSet<Url>>>,
+}
+
+impl KeyCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the key at uri, fetching it if it wasn't fetched before
+    pub async fn get(&self, client: &HttpClient, uri: &Url) -> Result<[u8; 16]> {
+        if let Some(key) = self.keys.lock().unwrap().get(uri) {
+            return Ok(*key);
+        }
+
+        event!(
+            Level::TRACE,
+            "Fetching encryption key from {}",
+            uri.as_str()
+        );
+        let key = fetch_key(client, uri)
+            .await
+            .map_err(|e| LivestreamDLError::KeyFetch {
+                url: uri.clone(),
+                source: e,
+            })?;
+        self.keys.lock().unwrap().insert(uri.clone(), key);
+
+        Ok(key)
+    }
+
+    /// Fetch the key at uri again, replacing the cached key. Returns false without fetching if
+    /// it was fetched again before
+    pub async fn refetch(&self, client: &HttpClient, uri: &Url) -> Result<bool> {
+        if !self.refetched.lock().unwrap().insert(uri.clone()) {
+            return Ok(false);
+        }
+        self.keys.lock().unwrap().remove(uri);
+        self.get(client, uri).await?;
+
+        Ok(true)
+    }
+
+    /// Fetch the key of an EXT-X-SESSION-KEY before any segment needs it
+    pub async fn prefetch(
+        &self,
+        client: &HttpClient,
+        m3u8_key: &Key,
+        base_url: &Url,
+    ) -> Result<()> {
+        if let Encryption::Aes128 { key_uri, .. } = Encryption::new(m3u8_key, base_url, 0).await? {
+            self.get(client, &key_uri).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Key file and IV of each segment saved without decrypting, by stream and segment id
+pub type SegmentKeys = HashMap<(Stream, String), (String, [u8; 16])>;
+
+/// Key uri and IV of each recorded segment, by stream and segment id
+type RecordedSegments = HashMap<(Stream, String), (Url, [u8; 16])>;
+
+/// Key file uri and IV of a saved encrypted segment, as written to keys.json
+#[derive(Debug, Serialize)]
+struct ArchivedSegment {
+    key: String,
+    iv: String,
+}
+
+/// Contents of keys.json
+#[derive(Debug, Serialize)]
+struct ArchivedKeys {
+    /// Hex encoded keys by key uri
+    keys: BTreeMap<String, String>,
+    /// Key uri and IV of each segment by stream directory and segment id
+    segments: BTreeMap<String, BTreeMap<String, ArchivedSegment>>,
+}
+
+/// Records the keys and IVs of segments saved without decrypting, so the archive can be
+/// decrypted later
+#[derive(Clone, Debug, Default)]
+pub struct KeyArchive {
+    segments: Arc<Mutex<RecordedSegments>>,
+}
+
+impl KeyArchive {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the encryption of a segment
+    pub fn record(&self, stream: &Stream, segment: &Segment, encryption: &Encryption) {
+        if let Encryption::Aes128 { key_uri, iv, .. } = encryption {
+            self.segments
+                .lock()
+                .unwrap()
+                .insert((stream.clone(), segment.id()), (key_uri.clone(), *iv));
+        }
+    }
+
+    /// Write the fetched keys into a keys directory and their uris and the segments' IVs into
+    /// keys.json in the segments directory. Returns the key file, relative to the segments
+    /// directory, and IV of each segment
+    pub async fn write(
+        &self,
+        segments_dir: &Path,
+        keys: &KeyCache,
+        file_names: &HashMap<Stream, String>,
+        fsync: bool,
+    ) -> Result<SegmentKeys> {
+        let fetched: BTreeMap<_, _> = keys.keys.lock().unwrap().clone().into_iter().collect();
+        let key_dir = segments_dir.join(KEY_DIR);
+        fs::create_dir_all(&key_dir).await?;
+
+        // Key files are numbered in key uri order
+        let mut key_files = HashMap::new();
+        for (i, (uri, key)) in fetched.iter().enumerate() {
+            let file_name = format!("{}.key", i);
+            write_atomic(&key_dir.join(&file_name), key, fsync).await?;
+            key_files.insert(uri.clone(), format!("{}/{}", KEY_DIR, file_name));
+        }
+
+        let mut archived = ArchivedKeys {
+            keys: fetched
+                .iter()
+                .map(|(uri, key)| (uri.to_string(), hex::encode(key)))
+                .collect(),
+            segments: BTreeMap::new(),
+        };
+        let mut segment_keys = HashMap::new();
+        for ((stream, id), (uri, iv)) in self.segments.lock().unwrap().iter() {
+            let key_file = match (key_files.get(uri), file_names.get(stream)) {
+                (Some(f), Some(dir)) => {
+                    archived.segments.entry(dir.clone()).or_default().insert(
+                        id.clone(),
+                        ArchivedSegment {
+                            key: uri.to_string(),
+                            iv: hex::encode(iv),
+                        },
+                    );
+                    f
+                }
+                _ => continue,
+            };
+            segment_keys.insert((stream.clone(), id.clone()), (key_file.clone(), *iv));
+        }
+        write_atomic(
+            &segments_dir.join(KEYS_FILE),
+            &serde_json::to_vec_pretty(&archived)?,
+            fsync,
+        )
+        .await?;
+
+        Ok(segment_keys)
+    }
+}
+
+/// Decrypt AES-128-CBC data with PKCS7 padding. Large segments are decrypted on the worker pool
+/// so high bitrate streams don't stall the async runtime, the aes crate detects
+/// AES-NI at runtime and uses it where available
+async fn aes128_decrypt(key: [u8; 16], iv: [u8; 16], data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() <= INLINE_DECRYPT_SIZE {
+        return aes128_decrypt_blocking(&key, &iv, data);
+    }
+    let data = data.to_vec();
+    worker_pool::run(move || aes128_decrypt_blocking(&key, &iv, &data)).await?
+}
+
+/// Decrypt AES-128-CBC data with PKCS7 padding, blocking
+fn aes128_decrypt_blocking(key: &[u8; 16], iv: &[u8; 16], data: &[u8]) -> Result<Vec<u8>> {
+    Aes128CbcDec::new(key.into(), iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(data)
+        .map_err(|e| LivestreamDLError::Decryption(e.to_string()).into())
+}
+
+/// Fetch a 16 byte AES-128 key
+async fn fetch_key(client: &HttpClient, uri: &Url) -> Result<[u8; 16]> {
+    let resp = client.get(uri.clone()).send().await?;
+    if !resp.status().is_success() {
+        return Err(LivestreamDLError::NetworkRequest(resp).into());
+    }
+    let body = resp.bytes().await?;
+
+    body.get(..16)
+        .and_then(|k| k.try_into().ok())
+        .ok_or_else(|| {
+            LivestreamDLError::InvalidKey {
+                url: uri.clone(),
+                len: body.len(),
+            }
+            .into()
+        })
 }
@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use super::Stream;
+
+/// Number of events kept for subscribers that fall behind, they miss older events
+const EVENT_CAPACITY: usize = 1024;
+
+/// Progress of a download, for front-ends built on the downloader
+#[derive(Clone, Debug)]
+pub enum DownloadEvent {
+    /// A segment was saved to disk
+    SegmentDownloaded {
+        stream: Stream,
+        seq: u64,
+        discon_seq: u64,
+        bytes: usize,
+        path: PathBuf,
+        /// Media duration recorded by the stream so far
+        recorded: Duration,
+    },
+    /// A stream's media playlist was reloaded
+    PlaylistRefreshed {
+        stream: Stream,
+        media_sequence: u64,
+        segments: usize,
+        /// Whether the playlist has an end tag
+        ended: bool,
+    },
+    /// A stream's playlist fetcher finished, with the error that stopped it if it failed
+    StreamEnded {
+        stream: Stream,
+        error: Option<String>,
+    },
+    /// An output file was remuxed
+    MuxProgress {
+        path: PathBuf,
+        /// Number of output files remuxed so far
+        done: usize,
+        total: usize,
+    },
+}
+
+/// Sends download events to every subscriber, events are dropped if there are none
+#[derive(Clone, Debug)]
+pub struct Events(broadcast::Sender<DownloadEvent>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self(broadcast::channel(EVENT_CAPACITY).0)
+    }
+
+    pub fn send(&self, event: DownloadEvent) {
+        let _ = self.0.send(event);
+    }
+
+    /// Receiver of the events sent from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<DownloadEvent> {
+        self.0.subscribe()
+    }
+}
@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use reqwest::Url;
+use tracing::{event, Level};
+
+use super::Extractor;
+use crate::livestream::hooks::shell_command;
+use crate::livestream::http_client::HttpClient;
+
+/// Runs an external command (e.g. yt-dlp -g) with the webpage url in LIVESTREAM_DL_PAGE_URL.
+/// The first line of its output that is a valid url is used as the playlist url
+#[derive(Debug)]
+pub struct CommandExtractor {
+    command: String,
+}
+
+impl CommandExtractor {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_owned(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Extractor for CommandExtractor {
+    fn name(&self) -> &str {
+        "command"
+    }
+
+    fn suitable(&self, _url: &Url) -> bool {
+        true
+    }
+
+    async fn extract(&self, _client: &HttpClient, url: &Url) -> Result<Url> {
+        let mut cmd = shell_command(&self.command);
+        cmd.env("LIVESTREAM_DL_PAGE_URL", url.as_str());
+        event!(Level::TRACE, "{:?}", cmd);
+        let output = cmd.output().await?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "extractor command failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|l| Url::parse(l.trim()).ok())
+            .context("extractor command did not print a valid url")
+    }
+}
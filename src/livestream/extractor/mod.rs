@@ -0,0 +1,83 @@
+mod command;
+mod page;
+
+use anyhow::Result;
+use reqwest::Url;
+use tracing::{event, Level};
+
+use self::command::CommandExtractor;
+use self::page::PageExtractor;
+use super::http_client::HttpClient;
+use crate::cli::NetworkOptions;
+
+/// File extensions of urls that are taken to be playlists without asking any extractor
+const PLAYLIST_EXTENSIONS: &[&str] = &[".m3u8", ".m3u"];
+
+/// Finds the playlist of a stream embedded in a webpage, so webpage urls can be given instead of
+/// playlist urls
+#[async_trait::async_trait]
+pub trait Extractor: Send + Sync {
+    /// Name shown in logs
+    fn name(&self) -> &str;
+
+    /// Whether this extractor handles url
+    fn suitable(&self, url: &Url) -> bool;
+
+    /// Playlist url of the stream at url
+    async fn extract(&self, client: &HttpClient, url: &Url) -> Result<Url>;
+}
+
+/// Extractors in the order they are tried. Site specific extractors go before the generic page
+/// extractor, which handles any url
+fn extractors(options: &NetworkOptions) -> Vec<Box<dyn Extractor>> {
+    let mut extractors: Vec<Box<dyn Extractor>> = Vec::new();
+    if let Some(command) = &options.extractor_command {
+        extractors.push(Box::new(CommandExtractor::new(command)));
+    }
+    extractors.push(Box::new(PageExtractor));
+
+    extractors
+}
+
+/// Playlist url of url, which is either a playlist or a webpage with a stream. Local files and
+/// urls ending in a playlist extension are returned as is
+pub async fn resolve(client: &HttpClient, url: &Url, options: &NetworkOptions) -> Result<Url> {
+    let path = url.path().to_ascii_lowercase();
+    if url.scheme() == "file" || PLAYLIST_EXTENSIONS.iter().any(|e| path.ends_with(e)) {
+        return Ok(url.clone());
+    }
+
+    let mut error = None;
+    for extractor in extractors(options) {
+        if !extractor.suitable(url) {
+            continue;
+        }
+        match extractor.extract(client, url).await {
+            Ok(u) => {
+                if &u != url {
+                    event!(
+                        Level::INFO,
+                        "Found playlist {} with {} extractor",
+                        u,
+                        extractor.name()
+                    );
+                }
+                return Ok(u);
+            }
+            Err(e) => {
+                event!(
+                    Level::DEBUG,
+                    "{} extractor failed on {}: {:?}",
+                    extractor.name(),
+                    url,
+                    e
+                );
+                error = Some(e);
+            }
+        }
+    }
+
+    Err(error
+        .unwrap_or_else(|| anyhow::anyhow!("no extractor found a playlist"))
+        .context(format!("error finding playlist of {}", url)))
+}
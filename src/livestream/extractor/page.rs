@@ -0,0 +1,72 @@
+use anyhow::Result;
+use reqwest::Url;
+
+use super::Extractor;
+use crate::error::LivestreamDLError;
+use crate::livestream::http_client::HttpClient;
+use crate::livestream::session::response_url;
+
+/// Characters ending an url embedded in a webpage
+const URL_TERMINATORS: &[char] = &['"', '\'', '`', '<', '>', '\\', ' ', '\t', '\r', '\n'];
+
+/// Fetches the url and looks for playlist urls in its content, e.g. in the source of a video
+/// player. Urls that are already playlists are returned as is
+#[derive(Debug)]
+pub struct PageExtractor;
+
+#[async_trait::async_trait]
+impl Extractor for PageExtractor {
+    fn name(&self) -> &str {
+        "generic"
+    }
+
+    fn suitable(&self, url: &Url) -> bool {
+        matches!(url.scheme(), "http" | "https")
+    }
+
+    async fn extract(&self, client: &HttpClient, url: &Url) -> Result<Url> {
+        let resp = client.get(url.clone()).send().await?;
+        if !resp.status().is_success() {
+            return Err(LivestreamDLError::NetworkRequest(resp).into());
+        }
+        let final_url = response_url(&resp).clone();
+        let body = resp.text().await?;
+        if body
+            .trim_start_matches('\u{feff}')
+            .trim_start()
+            .starts_with("#EXTM3U")
+        {
+            return Ok(url.clone());
+        }
+
+        playlist_urls(&body)
+            .into_iter()
+            .filter_map(|u| Url::parse(&u).ok())
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("no playlist url found in {}", final_url))
+    }
+}
+
+/// Absolute playlist urls found in page, with JavaScript and JSON escaping undone, master
+/// playlists first
+fn playlist_urls(page: &str) -> Vec<String> {
+    let page = page
+        .replace("\\/", "/")
+        .replace("\\u002F", "/")
+        .replace("\\u002f", "/")
+        .replace("&amp;", "&");
+
+    let mut urls = Vec::new();
+    for (start, _) in page.match_indices("http") {
+        let candidate = &page[start..];
+        let end = candidate.find(URL_TERMINATORS).unwrap_or(candidate.len());
+        let candidate = &candidate[..end];
+        let absolute = candidate.starts_with("http://") || candidate.starts_with("https://");
+        if absolute && candidate.contains(".m3u8") && !urls.iter().any(|u| u == candidate) {
+            urls.push(candidate.to_owned());
+        }
+    }
+    urls.sort_by_key(|u| !u.contains("master"));
+
+    urls
+}
@@ -0,0 +1,66 @@
+use std::path::Path;
+
+use anyhow::Result;
+use tokio::process;
+use tracing::{event, Level};
+
+use super::{Segment, SegmentFile, Stream};
+
+/// Command running command in the platform's shell
+pub fn shell_command(command: &str) -> process::Command {
+    #[cfg(target_family = "unix")]
+    let mut cmd = {
+        let mut cmd = process::Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    #[cfg(target_family = "windows")]
+    let mut cmd = {
+        let mut cmd = process::Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    };
+    cmd.arg(command).kill_on_drop(true);
+
+    cmd
+}
+
+/// Run a user command for a saved segment, describing the segment in environment variables
+pub async fn run_segment_hook(
+    command: &str,
+    stream: &Stream,
+    segment: &Segment,
+    file: &SegmentFile,
+) -> Result<()> {
+    let mut cmd = shell_command(command);
+    cmd.env("LIVESTREAM_DL_SEGMENT", &file.path)
+        .env("LIVESTREAM_DL_STREAM", stream.file_name())
+        .env("LIVESTREAM_DL_SEQ", segment.seq.to_string())
+        .env("LIVESTREAM_DL_DISCON_SEQ", segment.discon_seq.to_string())
+        .env("LIVESTREAM_DL_URL", segment.url().as_str());
+    // Packed segments are a byte range of the pack file
+    if let Some((offset, length)) = file.range {
+        cmd.env("LIVESTREAM_DL_OFFSET", offset.to_string())
+            .env("LIVESTREAM_DL_LENGTH", length.to_string());
+    }
+
+    run(cmd).await
+}
+
+/// Run a user command for a finished output file, passed in an environment variable
+pub async fn run_output_hook(command: &str, output: &Path) -> Result<()> {
+    let mut cmd = shell_command(command);
+    cmd.env("LIVESTREAM_DL_OUTPUT", output);
+
+    run(cmd).await
+}
+
+async fn run(mut cmd: process::Command) -> Result<()> {
+    event!(Level::TRACE, "{:?}", cmd);
+    let status = cmd.status().await?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("command exited with {}", status));
+    }
+
+    Ok(())
+}
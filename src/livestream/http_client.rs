@@ -1,14 +1,36 @@
 use std::fmt::Display;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use reqwest::IntoUrl;
+use reqwest::Url;
 use reqwest_middleware::{ClientWithMiddleware, RequestBuilder};
 
+use super::local_files::request_url;
+use super::remote_data::{InFlight, RangeGroups};
+
+type QueryPairs = Vec<(String, String)>;
+
+/// Minimum transfer speed of response bodies, transfers slower than bytes_per_sec over any
+/// period of time are aborted
+#[derive(Clone, Copy, Debug)]
+pub struct SpeedLimit {
+    pub bytes_per_sec: u64,
+    pub time: Duration,
+}
+
 /// Wrapper around ClientWithMiddleware to optionally add additional GET query parameters to every
 /// GET request
+///
+/// Query parameters are shared between clones and may be updated while downloading, e.g. when
+/// tokens are refreshed
 #[derive(Clone, Debug)]
 pub struct HttpClient {
     client: ClientWithMiddleware,
-    query_pairs: Option<Vec<(String, String)>>,
+    query_pairs: Arc<RwLock<Option<QueryPairs>>>,
+    speed_limit: Option<SpeedLimit>,
+    range_parts: usize,
+    in_flight: InFlight,
+    range_groups: RangeGroups,
 }
 
 impl HttpClient {
@@ -20,18 +42,81 @@ impl HttpClient {
     {
         Self {
             client,
-            query_pairs: query_pairs.map(|q| {
-                q.into_iter()
-                    .map(|(s1, s2)| (s1.to_string(), s2.to_string()))
-                    .collect()
-            }),
+            query_pairs: Arc::new(RwLock::new(query_pairs.map(collect_query_pairs))),
+            speed_limit: None,
+            range_parts: 1,
+            in_flight: InFlight::default(),
+            range_groups: RangeGroups::default(),
         }
     }
 
-    pub fn get<T: IntoUrl>(&self, url: T) -> RequestBuilder {
-        match &self.query_pairs {
+    /// Set the minimum transfer speed of segment downloads
+    pub fn with_speed_limit(mut self, speed_limit: Option<SpeedLimit>) -> Self {
+        self.speed_limit = speed_limit;
+        self
+    }
+
+    /// Minimum transfer speed of segment downloads
+    pub fn speed_limit(&self) -> Option<SpeedLimit> {
+        self.speed_limit
+    }
+
+    /// Set the number of byte ranges large segments are downloaded in
+    pub fn with_range_parts(mut self, range_parts: usize) -> Self {
+        self.range_parts = range_parts;
+        self
+    }
+
+    /// Number of byte ranges large segments are downloaded in
+    pub fn range_parts(&self) -> usize {
+        self.range_parts
+    }
+
+    /// Segment fetches in progress, shared by every stream downloaded with this client
+    pub fn in_flight(&self) -> &InFlight {
+        &self.in_flight
+    }
+
+    /// Byte range groups fetched by every stream downloaded with this client
+    pub fn range_groups(&self) -> &RangeGroups {
+        &self.range_groups
+    }
+
+    pub fn get(&self, url: Url) -> RequestBuilder {
+        let url = request_url(url);
+        match &*self.query_pairs.read().unwrap() {
             Some(q) => self.client.get(url).query(q),
             None => self.client.get(url),
         }
     }
+
+    pub fn head(&self, url: Url) -> RequestBuilder {
+        let url = request_url(url);
+        match &*self.query_pairs.read().unwrap() {
+            Some(q) => self.client.head(url).query(q),
+            None => self.client.head(url),
+        }
+    }
+
+    /// Replace the query parameters added to every GET request
+    pub fn set_query_pairs<T, U, Q>(&self, query_pairs: Option<Q>)
+    where
+        T: Display,
+        U: Display,
+        Q: IntoIterator<Item = (T, U)>,
+    {
+        *self.query_pairs.write().unwrap() = query_pairs.map(collect_query_pairs);
+    }
+}
+
+fn collect_query_pairs<T, U, Q>(query_pairs: Q) -> QueryPairs
+where
+    T: Display,
+    U: Display,
+    Q: IntoIterator<Item = (T, U)>,
+{
+    query_pairs
+        .into_iter()
+        .map(|(s1, s2)| (s1.to_string(), s2.to_string()))
+        .collect()
 }
@@ -0,0 +1,180 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::ClientBuilder;
+#[cfg(not(feature = "impersonate"))]
+use tracing::{event, Level};
+
+use crate::cli::Browser;
+
+/// Make requests look like they come from browser: send its default headers and, with the
+/// impersonate feature, offer its TLS cipher suites in its order and negotiate HTTP/2 like it.
+/// rustls can't send every TLS extension browsers do, so the handshake only approximates the
+/// browser's. Certificates in ca_certs are trusted in addition to the built-in roots
+pub fn impersonate(
+    client: ClientBuilder,
+    browser: Browser,
+    ca_certs: &[PathBuf],
+) -> Result<ClientBuilder> {
+    let client = client.default_headers(headers(browser));
+
+    #[cfg(feature = "impersonate")]
+    let client = client.use_preconfigured_tls(tls::config(browser, ca_certs)?);
+
+    #[cfg(not(feature = "impersonate"))]
+    {
+        let _ = ca_certs;
+        event!(
+            Level::WARN,
+            "livestream-dl was built without the impersonate feature, only the {:?} headers are sent",
+            browser
+        );
+    }
+
+    Ok(client)
+}
+
+/// Headers browser sends with media requests, compression is negotiated by reqwest
+fn headers(browser: Browser) -> HeaderMap {
+    let pairs: &[(&str, &str)] = match browser {
+        Browser::Chrome => &[
+            (
+                "user-agent",
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+                 Chrome/120.0.0.0 Safari/537.36",
+            ),
+            (
+                "sec-ch-ua",
+                "\"Not_A Brand\";v=\"8\", \"Chromium\";v=\"120\", \"Google Chrome\";v=\"120\"",
+            ),
+            ("sec-ch-ua-mobile", "?0"),
+            ("sec-ch-ua-platform", "\"Windows\""),
+            ("accept", "*/*"),
+            ("accept-language", "en-US,en;q=0.9"),
+            ("sec-fetch-site", "cross-site"),
+            ("sec-fetch-mode", "cors"),
+            ("sec-fetch-dest", "empty"),
+        ],
+        Browser::Firefox => &[
+            (
+                "user-agent",
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:121.0) Gecko/20100101 Firefox/121.0",
+            ),
+            ("accept", "*/*"),
+            ("accept-language", "en-US,en;q=0.5"),
+            ("sec-fetch-site", "cross-site"),
+            ("sec-fetch-mode", "cors"),
+            ("sec-fetch-dest", "empty"),
+        ],
+        Browser::Safari => &[
+            (
+                "user-agent",
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like \
+                 Gecko) Version/17.2 Safari/605.1.15",
+            ),
+            ("accept", "*/*"),
+            ("accept-language", "en-US,en;q=0.9"),
+        ],
+    };
+
+    let mut headers = HeaderMap::new();
+    for &(name, value) in pairs {
+        headers.insert(
+            HeaderName::from_static(name),
+            HeaderValue::from_static(value),
+        );
+    }
+
+    headers
+}
+
+#[cfg(feature = "impersonate")]
+mod tls {
+    use std::io::BufReader;
+    use std::path::PathBuf;
+
+    use anyhow::{Context, Result};
+    use rustls::cipher_suite::{
+        TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256,
+        TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256, TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384, TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    };
+    use rustls::kx_group::{SECP256R1, SECP384R1, X25519};
+    use rustls::version::{TLS12, TLS13};
+    use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, SupportedCipherSuite};
+
+    use crate::cli::Browser;
+
+    /// TLS configuration offering browser's cipher suites in its order, with ALPN for HTTP/2
+    pub fn config(browser: Browser, ca_certs: &[PathBuf]) -> Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        for path in ca_certs {
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("error reading CA certificate {:?}", path))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                .with_context(|| format!("error parsing CA certificate {:?}", path))?;
+            let (added, _) = roots.add_parsable_certificates(&certs);
+            if added == 0 {
+                return Err(anyhow::anyhow!("no valid CA certificate in {:?}", path));
+            }
+        }
+
+        let mut config = ClientConfig::builder()
+            .with_cipher_suites(&cipher_suites(browser))
+            .with_kx_groups(&[&X25519, &SECP256R1, &SECP384R1])
+            .with_protocol_versions(&[&TLS13, &TLS12])?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(config)
+    }
+
+    /// Cipher suites of browser supported by rustls, in the browser's order
+    fn cipher_suites(browser: Browser) -> Vec<SupportedCipherSuite> {
+        match browser {
+            Browser::Chrome => vec![
+                TLS13_AES_128_GCM_SHA256,
+                TLS13_AES_256_GCM_SHA384,
+                TLS13_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            ],
+            Browser::Firefox => vec![
+                TLS13_AES_128_GCM_SHA256,
+                TLS13_CHACHA20_POLY1305_SHA256,
+                TLS13_AES_256_GCM_SHA384,
+                TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            ],
+            Browser::Safari => vec![
+                TLS13_AES_128_GCM_SHA256,
+                TLS13_AES_256_GCM_SHA384,
+                TLS13_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            ],
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::StreamExt;
+use m3u8_rs::{AlternativeMediaType, MasterPlaylist, MediaPlaylist, MediaPlaylistType, Playlist};
+use reqwest::Url;
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::time::{sleep, Instant};
+use tracing::{event, Level};
+
+use super::build_client;
+use super::extractor::resolve;
+use super::http_client::HttpClient;
+use super::master_playlist::sorted_variants;
+use super::parse::parse_playlist;
+use super::playlist_fetcher::fetch_media_playlist;
+use super::session::response_url;
+use super::stats::{format_duration, Stats};
+use super::utils::{make_absolute_url, playlist_end_time};
+use crate::cli::{ListStreamsArgs, ProbeArgs};
+use crate::error::LivestreamDLError;
+
+/// A variant or alternative rendition of a playlist
+#[derive(Default, Serialize)]
+struct StreamEntry {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    index: Option<usize>,
+    url: Option<String>,
+    bandwidth: Option<u64>,
+    resolution: Option<String>,
+    codecs: Option<String>,
+    group: Option<String>,
+    name: Option<String>,
+    language: Option<String>,
+    channels: Option<String>,
+    characteristics: Option<String>,
+    instream_id: Option<String>,
+    encryption: Option<String>,
+    playlist_type: Option<String>,
+}
+
+/// Print all variants and alternative renditions of a playlist without downloading anything
+pub async fn list_streams(options: &ListStreamsArgs) -> Result<()> {
+    let url = &options.m3u8_url;
+    let (client, _) = build_client(url, &options.network_options, &Stats::new())?;
+    let url = &resolve(&client, url, &options.network_options).await?;
+    let (final_url, playlist) = fetch_playlist(&client, url).await?;
+
+    let entries = match playlist {
+        Playlist::MasterPlaylist(p) => {
+            // Fetch media playlists to get their encryption and type
+            let entries = master_entries(&final_url, &p)?;
+            futures::stream::iter(entries)
+                .map(|mut e| {
+                    let client = client.clone();
+                    async move {
+                        if let Some(url) = e.url.as_ref().and_then(|u| Url::parse(u).ok()) {
+                            match fetch_media_playlist(&client, &url).await {
+                                Ok(p) => describe_media_playlist(&mut e, &p),
+                                Err(err) => event!(
+                                    Level::WARN,
+                                    "Failed to fetch media playlist {}: {}",
+                                    url,
+                                    err
+                                ),
+                            }
+                        }
+                        e
+                    }
+                })
+                .buffered(options.network_options.max_concurrent_downloads)
+                .collect()
+                .await
+        }
+        Playlist::MediaPlaylist(p) => {
+            let mut e = StreamEntry {
+                kind: "media",
+                url: Some(final_url.to_string()),
+                ..Default::default()
+            };
+            describe_media_playlist(&mut e, &p);
+            vec![e]
+        }
+    };
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        print_table(&entries);
+    }
+
+    Ok(())
+}
+
+/// Live diagnostics of a media playlist
+#[derive(Serialize)]
+struct ProbeReport {
+    url: String,
+    playlist_type: String,
+    samples: u32,
+    target_duration: f32,
+    /// Number of segments in the last sampled playlist
+    window_segments: usize,
+    /// Total duration of the last sampled playlist in seconds
+    window_duration: f64,
+    average_segment_duration: Option<f64>,
+    /// Number of segments added while sampling
+    new_segments: u64,
+    /// Observed seconds between new segments
+    cadence: Option<f64>,
+    /// Observed cadence minus average segment duration, positive if the playlist falls behind
+    drift: Option<f64>,
+    discontinuity_sequence: u64,
+    /// Number of discontinuities in the last sampled playlist
+    discontinuities: usize,
+    encryption: String,
+    /// Seconds between the end of the last segment and now, based on EXT-X-PROGRAM-DATE-TIME
+    latency: Option<f64>,
+}
+
+/// Sample a media playlist a few times and print live diagnostics without downloading anything
+///
+/// If a master playlist is given, the highest bitrate variant is probed
+pub async fn probe(options: &ProbeArgs) -> Result<()> {
+    let url = &options.m3u8_url;
+    let (client, _) = build_client(url, &options.network_options, &Stats::new())?;
+    let url = &resolve(&client, url, &options.network_options).await?;
+    let samples = options.samples.max(1);
+
+    // Resolve media playlist url
+    let (mut url, playlist) = fetch_playlist(&client, url).await?;
+    if let Playlist::MasterPlaylist(p) = playlist {
+        let variant = p
+            .variants
+            .iter()
+            .filter(|v| !v.is_i_frame)
+            .filter_map(|v| Some((v.bandwidth.parse::<u64>().ok()?, v)))
+            .max_by_key(|(x, _)| *x)
+            .ok_or_else(|| LivestreamDLError::StreamNotFound("no variants in playlist".into()))?
+            .1;
+        url = make_absolute_url(&url, &variant.uri)?;
+    }
+
+    // Sample the playlist once per target duration
+    let start = Instant::now();
+    let mut playlist = fetch_media_playlist(&client, &url).await?;
+    let first_end_seq = playlist.media_sequence + playlist.segments.len() as u64;
+    let first_time = start.elapsed();
+    let mut last_time = first_time;
+    let mut sampled = 1;
+    while sampled < samples && !playlist.end_list {
+        sleep(Duration::from_secs_f32(playlist.target_duration.max(1.0))).await;
+
+        event!(
+            Level::INFO,
+            "Sampling playlist ({}/{})",
+            sampled + 1,
+            samples
+        );
+        playlist = fetch_media_playlist(&client, &url).await?;
+        last_time = start.elapsed();
+        sampled += 1;
+    }
+
+    // Compute diagnostics
+    let mut entry = StreamEntry::default();
+    describe_media_playlist(&mut entry, &playlist);
+    let window_duration: f64 = playlist.segments.iter().map(|s| s.duration as f64).sum();
+    let average_segment_duration = if playlist.segments.is_empty() {
+        None
+    } else {
+        Some(window_duration / playlist.segments.len() as f64)
+    };
+    let new_segments =
+        (playlist.media_sequence + playlist.segments.len() as u64).saturating_sub(first_end_seq);
+    let cadence = if new_segments > 0 {
+        Some((last_time - first_time).as_secs_f64() / new_segments as f64)
+    } else {
+        None
+    };
+    let report = ProbeReport {
+        url: url.to_string(),
+        playlist_type: entry.playlist_type.unwrap_or_default(),
+        samples: sampled,
+        target_duration: playlist.target_duration,
+        window_segments: playlist.segments.len(),
+        window_duration,
+        average_segment_duration,
+        new_segments,
+        cadence,
+        drift: cadence.zip(average_segment_duration).map(|(c, a)| c - a),
+        discontinuity_sequence: playlist.discontinuity_sequence,
+        discontinuities: playlist.segments.iter().filter(|s| s.discontinuity).count(),
+        encryption: entry.encryption.unwrap_or_default(),
+        latency: playlist_end_time(&playlist)
+            .map(|t| (OffsetDateTime::now_utc() - t).as_seconds_f64()),
+    };
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_probe_report(&report);
+    }
+
+    Ok(())
+}
+
+/// Print probe diagnostics as aligned key value pairs
+fn print_probe_report(r: &ProbeReport) {
+    let secs = |s: Option<f64>| s.map_or_else(|| "unknown".into(), |s| format!("{:.3}s", s));
+    let lines = [
+        ("Playlist", r.url.clone()),
+        ("Type", r.playlist_type.clone()),
+        ("Samples", r.samples.to_string()),
+        ("Target duration", format!("{:.3}s", r.target_duration)),
+        (
+            "Window",
+            format!(
+                "{} segments, {}",
+                r.window_segments,
+                format_duration(r.window_duration)
+            ),
+        ),
+        ("Average segment", secs(r.average_segment_duration)),
+        ("New segments", r.new_segments.to_string()),
+        ("Segment cadence", secs(r.cadence)),
+        (
+            "Drift",
+            r.drift
+                .map_or_else(|| "unknown".into(), |d| format!("{:+.3}s", d)),
+        ),
+        (
+            "Discontinuities",
+            format!(
+                "{} in window, sequence {}",
+                r.discontinuities, r.discontinuity_sequence
+            ),
+        ),
+        ("Encryption", r.encryption.clone()),
+        (
+            "Live latency",
+            r.latency.map_or_else(
+                || "unknown (no EXT-X-PROGRAM-DATE-TIME)".into(),
+                |l| format!("{:.3}s", l),
+            ),
+        ),
+    ];
+
+    let width = lines.iter().map(|(k, _)| k.len()).max().unwrap_or_default();
+    for (k, v) in lines {
+        println!("{:<w$}  {}", format!("{}:", k), v, w = width + 1);
+    }
+}
+
+/// Fetch and parse a master or media playlist, returns the final url and playlist
+async fn fetch_playlist(client: &HttpClient, url: &Url) -> Result<(Url, Playlist)> {
+    event!(Level::TRACE, "Fetching {}", url.as_str());
+    let resp = client.get(url.clone()).send().await?;
+    if !resp.status().is_success() {
+        return Err(LivestreamDLError::NetworkRequest(resp).into());
+    }
+    let final_url = response_url(&resp).clone();
+    let bytes = resp.bytes().await?;
+
+    match parse_playlist(&bytes, None) {
+        Some(p) => Ok((final_url, p)),
+        None => Err(LivestreamDLError::ParseM3u8(final_url.to_string()).into()),
+    }
+}
+
+/// List all variants and alternative renditions of a master playlist
+fn master_entries(base_url: &Url, playlist: &MasterPlaylist) -> Result<Vec<StreamEntry>> {
+    let mut entries = Vec::new();
+
+    // Add variants, highest bandwidth first and numbered for --variant-index, then I-frame
+    // only variants
+    let mut iframe_variants: Vec<_> = playlist.variants.iter().filter(|v| v.is_i_frame).collect();
+    iframe_variants.sort_by_key(|v| std::cmp::Reverse(v.bandwidth.parse::<u64>().ok()));
+    let variants = sorted_variants(playlist)
+        .into_iter()
+        .enumerate()
+        .map(|(i, v)| (Some(i), v))
+        .chain(iframe_variants.into_iter().map(|v| (None, v)));
+    for (index, v) in variants {
+        entries.push(StreamEntry {
+            kind: if v.is_i_frame { "iframes" } else { "variant" },
+            index,
+            url: Some(make_absolute_url(base_url, &v.uri)?.to_string()),
+            bandwidth: v.bandwidth.parse().ok(),
+            resolution: v.resolution.clone(),
+            codecs: v.codecs.clone(),
+            ..Default::default()
+        });
+    }
+
+    // Add alternative renditions
+    for a in &playlist.alternatives {
+        entries.push(StreamEntry {
+            kind: match a.media_type {
+                AlternativeMediaType::Audio => "audio",
+                AlternativeMediaType::Video => "video",
+                AlternativeMediaType::Subtitles => "subtitles",
+                AlternativeMediaType::ClosedCaptions => "closed-captions",
+            },
+            url: match &a.uri {
+                Some(uri) => Some(make_absolute_url(base_url, uri)?.to_string()),
+                None => None,
+            },
+            group: Some(a.group_id.clone()),
+            name: Some(a.name.clone()),
+            language: a.language.clone(),
+            channels: a.channels.clone(),
+            characteristics: a.characteristics.clone(),
+            instream_id: a.instream_id.clone(),
+            ..Default::default()
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Add encryption methods and playlist type of a media playlist
+fn describe_media_playlist(entry: &mut StreamEntry, playlist: &MediaPlaylist) {
+    let methods: BTreeSet<_> = playlist
+        .segments
+        .iter()
+        .filter_map(|s| s.key.as_ref())
+        .map(|k| k.method.as_str())
+        .collect();
+    entry.encryption = Some(if methods.is_empty() {
+        "NONE".into()
+    } else {
+        methods.into_iter().collect::<Vec<_>>().join(",")
+    });
+
+    entry.playlist_type = Some(
+        match (&playlist.playlist_type, playlist.end_list) {
+            (Some(MediaPlaylistType::Vod), _) | (None, true) => "VOD",
+            (Some(MediaPlaylistType::Event), _) => "EVENT",
+            (None, false) => "LIVE",
+        }
+        .into(),
+    );
+}
+
+/// Print entries as an aligned table
+fn print_table(entries: &[StreamEntry]) {
+    const HEADERS: [&str; 13] = [
+        "INDEX",
+        "TYPE",
+        "BANDWIDTH",
+        "RESOLUTION",
+        "CODECS",
+        "GROUP",
+        "NAME",
+        "LANGUAGE",
+        "CHANNELS",
+        "CHARACTERISTICS",
+        "INSTREAM-ID",
+        "ENCRYPTION",
+        "PLAYLIST",
+    ];
+
+    let rows: Vec<[String; 13]> = entries
+        .iter()
+        .map(|e| {
+            let s = |o: &Option<String>| o.clone().unwrap_or_else(|| "-".into());
+            [
+                e.index.map(|i| i.to_string()).unwrap_or_else(|| "-".into()),
+                e.kind.to_string(),
+                e.bandwidth
+                    .map(|b| b.to_string())
+                    .unwrap_or_else(|| "-".into()),
+                s(&e.resolution),
+                s(&e.codecs),
+                s(&e.group),
+                s(&e.name),
+                s(&e.language),
+                s(&e.channels),
+                s(&e.characteristics),
+                s(&e.instream_id),
+                s(&e.encryption),
+                s(&e.playlist_type),
+            ]
+        })
+        .collect();
+
+    // Width of each column is the widest cell
+    let mut widths = HEADERS.map(str::len);
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+
+    let print_row = |cells: &[&str]| {
+        let line: Vec<_> = cells
+            .iter()
+            .zip(widths)
+            .map(|(c, w)| format!("{:<w$}", c, w = w))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(&HEADERS);
+    for row in &rows {
+        print_row(&row.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,145 @@
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use reqwest::header::{self, HeaderValue};
+use reqwest::{Method, Request, Response, StatusCode, Url};
+use reqwest_middleware::{Middleware, Next};
+use task_local_extensions::Extensions;
+use tokio::fs;
+
+use super::session::set_response_url;
+
+/// Host of the urls local files are requested with, reqwest rejects file:// urls since they have
+/// no host
+const LOCAL_HOST: &str = "local-file.invalid";
+
+/// Playlist read from stdin and the url it stands for
+static STDIN_PLAYLIST: OnceLock<(Url, Vec<u8>)> = OnceLock::new();
+
+/// Answer requests for url with a playlist read from stdin instead of fetching it
+pub fn set_stdin_playlist(url: Url, playlist: Vec<u8>) {
+    let _ = STDIN_PLAYLIST.set((url, playlist));
+}
+
+/// Url to request url with, file:// urls are replaced by an http url on LOCAL_HOST with the same
+/// path
+pub fn request_url(url: Url) -> Url {
+    if url.scheme() != "file" {
+        return url;
+    }
+
+    let mut request_url = Url::parse(&format!("http://{}/", LOCAL_HOST)).unwrap();
+    request_url.set_path(url.path());
+    request_url
+}
+
+/// file:// url of a request made with a url from request_url. Query parameters added to the
+/// request are kept for files requested from the base url
+fn file_url(request_url: &Url) -> Option<Url> {
+    if request_url.host_str() != Some(LOCAL_HOST) {
+        return None;
+    }
+
+    let mut url = Url::parse("file:///").unwrap();
+    url.set_path(request_url.path());
+    url.set_query(request_url.query());
+    Some(url)
+}
+
+/// Middleware answering file:// requests with local files and requests for the stdin playlist
+/// with its content, so playlists already fetched by another tool can be downloaded. Files that
+/// don't exist are requested from the base url instead, at the same path relative to the
+/// directory of the local playlist
+#[derive(Debug)]
+pub struct LocalFiles {
+    /// Directory of the local playlist and the url it stands for
+    base: Option<(Url, Url)>,
+}
+
+impl LocalFiles {
+    pub fn new(playlist: &Url, base_url: Option<&Url>) -> Result<Self> {
+        let base = match base_url {
+            Some(b) => Some((playlist.join(".")?, b.clone())),
+            None => None,
+        };
+
+        Ok(Self { base })
+    }
+
+    /// Remote url of a local file that doesn't exist
+    fn remote_url(&self, url: &Url) -> Option<Url> {
+        let (dir, base) = self.base.as_ref()?;
+        match url.as_str().strip_prefix(dir.as_str()) {
+            Some(relative) => base.join(relative).ok(),
+            None => base.join(url.path()).ok(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for LocalFiles {
+    async fn handle(
+        &self,
+        mut req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let range = req
+            .headers()
+            .get(header::RANGE)
+            .and_then(|v| parse_range(v.to_str().ok()?));
+        let head = req.method() == Method::HEAD;
+        if let Some((url, playlist)) = STDIN_PLAYLIST.get().filter(|(u, _)| u == req.url()) {
+            let mut resp = data_response(playlist.clone(), range, head)?;
+            set_response_url(&mut resp, url.clone());
+            return Ok(resp);
+        }
+        let url = match file_url(req.url()) {
+            Some(u) => u,
+            None => return next.run(req, extensions).await,
+        };
+        let path = url
+            .to_file_path()
+            .map_err(|_| anyhow::anyhow!("invalid file url {}", url))?;
+        if !path.is_file() {
+            if let Some(url) = self.remote_url(&url) {
+                *req.url_mut() = url;
+                return next.run(req, extensions).await;
+            }
+        }
+
+        let data = fs::read(&path)
+            .await
+            .with_context(|| format!("error reading {:?}", path))?;
+        let mut resp = data_response(data, range, head)?;
+        set_response_url(&mut resp, url);
+
+        Ok(resp)
+    }
+}
+
+/// Response with data, or the inclusive byte range of it
+fn data_response(data: Vec<u8>, range: Option<(u64, u64)>, head: bool) -> Result<Response> {
+    let builder = http::Response::builder().header(header::ACCEPT_RANGES, "bytes");
+    let (builder, body) = match range {
+        Some((start, end)) if start <= end && end < data.len() as u64 => (
+            builder.status(StatusCode::PARTIAL_CONTENT),
+            data[start as usize..=end as usize].to_vec(),
+        ),
+        Some(_) => (
+            builder.status(StatusCode::RANGE_NOT_SATISFIABLE),
+            Vec::new(),
+        ),
+        None => (builder.status(StatusCode::OK), data),
+    };
+    let builder = builder.header(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    let body = if head { Vec::new() } else { body };
+
+    Ok(Response::from(builder.body(body)?))
+}
+
+/// Start and inclusive end of a "bytes=start-end" range header
+fn parse_range(value: &str) -> Option<(u64, u64)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    Some((start.parse().ok()?, end.parse().ok()?))
+}
@@ -0,0 +1,137 @@
+use std::collections::{BinaryHeap, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use m3u8_rs::{ByteRange, Key, MediaPlaylist, MediaPlaylistType, MediaSegment};
+use time::format_description::well_known::Rfc3339;
+use tracing::{event, Level};
+
+use super::encryption::SegmentKeys;
+use super::utils::write_atomic;
+use super::{Segment, SegmentFile, Stream};
+
+/// Name of the saved original master playlist in the segments directory
+const MASTER_PLAYLIST_FILE: &str = "master.m3u8";
+
+/// Save the original master playlist into the segments directory
+pub async fn write_master_playlist(segments_dir: &Path, bytes: &[u8], fsync: bool) -> Result<()> {
+    write_atomic(&segments_dir.join(MASTER_PLAYLIST_FILE), bytes, fsync).await?;
+
+    Ok(())
+}
+
+/// Write a VOD playlist of each stream's saved segments into the segments directory, named after
+/// the stream directory, so the segments can be played without remuxing
+///
+/// Segments are saved decrypted with their initialization prepended, so the playlists have no
+/// maps. Segments saved without decrypting refer to their archived key files in segment_keys.
+/// Compressed segments can't be played and are left out
+pub async fn write_local_playlists(
+    segments_dir: &Path,
+    segments: &HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>>,
+    segment_keys: Option<&SegmentKeys>,
+    fsync: bool,
+) -> Result<()> {
+    for (stream, stream_segments) in segments {
+        let stream_segments = stream_segments.clone().into_sorted_vec();
+        let dir_name = match stream_segments
+            .first()
+            .and_then(|(_, f)| f.path.parent()?.file_name())
+        {
+            Some(n) => n.to_owned(),
+            None => continue,
+        };
+
+        let keys =
+            |segment: &Segment| segment_keys.and_then(|k| k.get(&(stream.clone(), segment.id())));
+        let playlist = local_playlist(&stream_segments, keys);
+        if playlist.segments.len() < stream_segments.len() {
+            event!(
+                Level::WARN,
+                "Leaving {} compressed {} segments out of local playlist",
+                stream_segments.len() - playlist.segments.len(),
+                stream
+            );
+        }
+
+        let mut data = Vec::new();
+        playlist.write_to(&mut data)?;
+        let path = segments_dir.join(PathBuf::from(dir_name).with_extension("m3u8"));
+        write_atomic(&path, &data, fsync).await?;
+    }
+
+    Ok(())
+}
+
+/// VOD playlist of sorted segments with uris relative to the segments directory. keys gives the
+/// key file and IV of encrypted segments
+pub fn local_playlist<'a>(
+    segments: &[(Segment, SegmentFile)],
+    keys: impl Fn(&Segment) -> Option<&'a (String, [u8; 16])>,
+) -> MediaPlaylist {
+    let mut playlist_segments = Vec::new();
+    let mut last: Option<(u64, u64)> = None;
+    let mut last_key = None;
+    for (segment, file) in segments.iter().filter(|(_, f)| !f.gzip) {
+        // Only write keys when they change
+        let segment_key = keys(segment);
+        let key = (segment_key != last_key).then(|| match segment_key {
+            Some((key_file, iv)) => Key {
+                method: "AES-128".into(),
+                uri: Some(key_file.clone()),
+                iv: Some(format!("0x{}", hex::encode(iv))),
+                ..Default::default()
+            },
+            None => Key {
+                method: "NONE".into(),
+                ..Default::default()
+            },
+        });
+        last_key = segment_key;
+
+        // Mark discontinuities and gaps left by missing segments, timestamps jump at both
+        let discontinuity =
+            last.is_some_and(|(d, s)| d != segment.discon_seq || s + 1 != segment.seq);
+        last = Some((segment.discon_seq, segment.seq));
+
+        playlist_segments.push(MediaSegment {
+            uri: relative_uri(&file.path),
+            duration: segment.duration.as_secs_f32(),
+            byte_range: file.range.map(|(offset, length)| ByteRange {
+                length,
+                offset: Some(offset),
+            }),
+            discontinuity,
+            key,
+            program_date_time: segment
+                .program_date_time
+                .and_then(|t| t.format(&Rfc3339).ok()),
+            ..Default::default()
+        });
+    }
+
+    let target_duration = playlist_segments
+        .iter()
+        .map(|s| s.duration.ceil())
+        .fold(1.0, f32::max);
+    MediaPlaylist {
+        version: 4,
+        target_duration,
+        media_sequence: segments.first().map_or(0, |(s, _)| s.seq),
+        discontinuity_sequence: segments.first().map_or(0, |(s, _)| s.discon_seq),
+        segments: playlist_segments,
+        end_list: true,
+        playlist_type: Some(MediaPlaylistType::Vod),
+        ..Default::default()
+    }
+}
+
+/// Uri of a segment file relative to the segments directory, which contains the stream
+/// directories
+pub fn relative_uri(path: &Path) -> String {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    match path.parent().and_then(Path::file_name) {
+        Some(dir) => format!("{}/{}", dir.to_string_lossy(), file_name),
+        None => file_name.into_owned(),
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use itertools::Itertools;
+use m3u8_rs::{MasterPlaylist, Playlist, SessionDataField, VariantStream};
+use reqwest::Url;
+use tracing::{event, Level};
+
+use super::http_client::HttpClient;
+use super::parse::{parse_master_playlist, parse_playlist};
+use super::session::response_url;
+use super::stream::{Disposition, StreamInfo};
+use super::utils::make_absolute_url;
+use super::Stream;
+use crate::cli::{MuxOptions, VideoCodec};
+use crate::error::LivestreamDLError;
+
+/// DATA-ID of the EXT-X-SESSION-DATA holding the title of the presentation
+const SESSION_TITLE: &str = "com.apple.hls.title";
+
+/// Maximum number of master playlists followed from a variant uri before giving up
+const MAX_NESTED_MASTERS: usize = 4;
+
+/// Get the main stream of a variant and all of its alternative media streams
+pub fn variant_streams(
+    base_url: &Url,
+    playlist: &MasterPlaylist,
+    variant: &VariantStream,
+) -> Result<HashMap<Stream, (Url, StreamInfo)>> {
+    let mut streams = HashMap::new();
+
+    // Add main stream
+    let info = StreamInfo {
+        codecs: variant.codecs.clone(),
+        channels: None,
+        bandwidth: variant.bandwidth.parse().ok(),
+        characteristics: None,
+        muxed: Vec::new(),
+    };
+    streams.insert(
+        Stream::Main,
+        (make_absolute_url(base_url, &variant.uri)?, info),
+    );
+
+    // Add I-frame only stream with the same resolution, otherwise the highest bandwidth one
+    let iframe_variant = playlist
+        .variants
+        .iter()
+        .filter(|v| v.is_i_frame)
+        .filter_map(|v| Some((v.bandwidth.parse::<u64>().ok()?, v)))
+        .max_by_key(|(b, v)| (v.resolution == variant.resolution, *b));
+    if let Some((bandwidth, v)) = iframe_variant {
+        let info = StreamInfo {
+            codecs: v.codecs.clone(),
+            channels: None,
+            bandwidth: Some(bandwidth),
+            characteristics: None,
+            muxed: Vec::new(),
+        };
+        streams.insert(
+            Stream::IFrames,
+            (make_absolute_url(base_url, &v.uri)?, info),
+        );
+    }
+
+    // Closure to find alternative media with matching group id and add them to streams, media
+    // without a URI is carried inside the main stream
+    let mut muxed = Vec::new();
+    let mut add_alternative =
+        |group, codec_type, f: fn(String, Option<String>, Disposition) -> Stream| -> Result<()> {
+            for a in playlist
+                .alternatives
+                .iter()
+                .filter(|a| &a.group_id == group)
+            {
+                let stream = f(a.name.clone(), a.language.clone(), a.into());
+                match &a.uri {
+                    Some(a_url) => {
+                        let info = StreamInfo {
+                            codecs: filter_codecs(variant.codecs.as_deref(), codec_type),
+                            channels: a.channels.clone(),
+                            bandwidth: None,
+                            characteristics: a.characteristics.clone(),
+                            muxed: Vec::new(),
+                        };
+                        streams.insert(stream, (make_absolute_url(base_url, a_url)?, info));
+                    }
+                    None => muxed.push(stream),
+                }
+            }
+            Ok(())
+        };
+
+    // Add audio streams
+    if let Some(group) = &variant.audio {
+        add_alternative(group, CodecType::Audio, |n, l, d| Stream::Audio {
+            name: n,
+            lang: l,
+            disposition: d,
+        })?;
+    }
+
+    // Add video streams
+    if let Some(group) = &variant.video {
+        add_alternative(group, CodecType::Video, |n, l, d| Stream::Video {
+            name: n,
+            lang: l,
+            disposition: d,
+        })?;
+    }
+
+    // Add subtitle streams
+    if let Some(group) = &variant.subtitles {
+        add_alternative(group, CodecType::Subtitle, |n, l, d| Stream::Subtitle {
+            name: n,
+            lang: l,
+            disposition: d,
+        })?;
+    }
+
+    if let Some((_, info)) = streams.get_mut(&Stream::Main) {
+        info.muxed = muxed;
+    }
+
+    Ok(streams)
+}
+
+/// Follow a variant uri that points to another master playlist to the highest bandwidth variant
+/// of the innermost one. Urls of media playlists are returned as is
+pub async fn follow_nested_masters(client: &HttpClient, url: &Url) -> Result<Url> {
+    let mut url = url.clone();
+    for _ in 0..=MAX_NESTED_MASTERS {
+        event!(Level::TRACE, "Fetching {}", url.as_str());
+        let resp = client.get(url.clone()).send().await?;
+        if !resp.status().is_success() {
+            return Err(LivestreamDLError::NetworkRequest(resp).into());
+        }
+        let final_url = response_url(&resp).clone();
+        let bytes = resp.bytes().await?;
+
+        let playlist = match parse_playlist(&bytes, None) {
+            Some(Playlist::MasterPlaylist(p)) => p,
+            _ => return Ok(url),
+        };
+        let variant = sorted_variants(&playlist)
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                LivestreamDLError::StreamNotFound(format!(
+                    "nested master playlist {} has no variants",
+                    url
+                ))
+            })?;
+        let nested = make_absolute_url(&final_url, &variant.uri)?;
+        event!(
+            Level::INFO,
+            "{} is a master playlist, following variant {}",
+            url,
+            nested
+        );
+        url = nested;
+    }
+
+    Err(LivestreamDLError::StreamNotFound(format!(
+        "more than {} nested master playlists",
+        MAX_NESTED_MASTERS
+    ))
+    .into())
+}
+
+/// Variants without I-frame only variants, highest bandwidth first as listed by list-streams
+pub fn sorted_variants(playlist: &MasterPlaylist) -> Vec<&VariantStream> {
+    let mut variants: Vec<_> = playlist.variants.iter().filter(|v| !v.is_i_frame).collect();
+    variants.sort_by_key(|v| std::cmp::Reverse(v.bandwidth.parse::<u64>().ok()));
+    variants
+}
+
+/// Find the variant pinned by its position in sorted_variants or by its uri, None if neither
+/// is given
+pub fn pinned_variant<'a>(
+    base_url: &Url,
+    playlist: &'a MasterPlaylist,
+    index: Option<usize>,
+    uri: Option<&str>,
+) -> Result<Option<&'a VariantStream>> {
+    let variants = sorted_variants(playlist);
+    if let Some(i) = index {
+        let variant = variants.get(i).copied().ok_or_else(|| {
+            LivestreamDLError::StreamNotFound(format!(
+                "variant index {} out of range, there are {} variants",
+                i,
+                variants.len()
+            ))
+        })?;
+        return Ok(Some(variant));
+    }
+    if let Some(uri) = uri {
+        let wanted = make_absolute_url(base_url, uri)?;
+        let variant = variants
+            .into_iter()
+            .find(|v| make_absolute_url(base_url, &v.uri).is_ok_and(|u| u == wanted))
+            .ok_or_else(|| {
+                LivestreamDLError::StreamNotFound(format!("no variant with URI {}", uri))
+            })?;
+        return Ok(Some(variant));
+    }
+
+    Ok(None)
+}
+
+/// Variants that may be chosen, skipping I-frame variants and variants with avoided codecs. All
+/// variants are kept if every one has an avoided codec
+pub fn candidate_variants<'a>(
+    playlist: &'a MasterPlaylist,
+    avoid: &[VideoCodec],
+) -> Vec<&'a VariantStream> {
+    let variants: Vec<_> = playlist.variants.iter().filter(|v| !v.is_i_frame).collect();
+    let allowed: Vec<_> = variants
+        .iter()
+        .copied()
+        .filter(|v| !avoid.iter().any(|c| has_codec(v, *c)))
+        .collect();
+
+    if allowed.is_empty() && !variants.is_empty() {
+        event!(
+            Level::WARN,
+            "Every variant has an avoided codec, ignoring --avoid-codec"
+        );
+        return variants;
+    }
+    allowed
+}
+
+/// How much a variant is preferred, higher for codecs earlier in prefer and 0 without any
+pub fn codec_preference(variant: &VariantStream, prefer: &[VideoCodec]) -> usize {
+    prefer
+        .iter()
+        .position(|c| has_codec(variant, *c))
+        .map_or(0, |i| prefer.len() - i)
+}
+
+/// Check if the CODECS attribute of a variant includes codec
+fn has_codec(variant: &VariantStream, codec: VideoCodec) -> bool {
+    let prefixes: &[&str] = match codec {
+        VideoCodec::H264 => &["avc1", "avc3"],
+        VideoCodec::Hevc => &["hvc1", "hev1", "dvh1", "dvhe"],
+        VideoCodec::Av1 => &["av01", "dav1"],
+        VideoCodec::Vp9 => &["vp09"],
+    };
+    variant
+        .codecs
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(|c| {
+            c.trim()
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .to_lowercase()
+        })
+        .any(|c| prefixes.contains(&c.as_str()))
+}
+
+/// Check if a CHARACTERISTICS attribute has any of the given characteristics. Characteristics
+/// match whole UTIs or their last component, e.g. "describes-video" matches
+/// "public.accessibility.describes-video"
+pub fn has_characteristic(characteristics: Option<&str>, filters: &[String]) -> bool {
+    characteristics
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .any(|c| {
+            let last = c.rsplit('.').next().unwrap_or(c);
+            filters
+                .iter()
+                .any(|f| f.eq_ignore_ascii_case(c) || f.eq_ignore_ascii_case(last))
+        })
+}
+
+/// Values of the EXT-X-SESSION-DATA tags of a master playlist by DATA-ID. Data given by URI is
+/// skipped, the first value is kept if given in several languages
+pub fn session_data(playlist: &MasterPlaylist) -> HashMap<String, String> {
+    let mut data = HashMap::new();
+    for d in &playlist.session_data {
+        match &d.field {
+            SessionDataField::Value(v) => {
+                data.entry(d.data_id.clone()).or_insert_with(|| v.clone());
+            }
+            SessionDataField::Uri(u) => {
+                event!(Level::DEBUG, "Skipping session data {} at {}", d.data_id, u)
+            }
+        }
+    }
+
+    data
+}
+
+/// Mux options with the title taken from session data if none was given
+pub fn with_session_title(
+    options: &MuxOptions,
+    session_data: &HashMap<String, String>,
+) -> MuxOptions {
+    let mut options = options.clone();
+    if options.meta_title.is_none() {
+        options.meta_title = session_data.get(SESSION_TITLE).cloned();
+    }
+    options
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CodecType {
+    Video,
+    Audio,
+    Subtitle,
+}
+
+/// Keep only the codecs of a CODECS attribute belonging to codec_type
+fn filter_codecs(codecs: Option<&str>, codec_type: CodecType) -> Option<String> {
+    let filtered = codecs?
+        .split(',')
+        .map(str::trim)
+        .filter(|c| {
+            let prefix = c.split('.').next().unwrap_or_default().to_lowercase();
+            let t = match prefix.as_str() {
+                "avc1" | "avc3" | "hvc1" | "hev1" | "dvh1" | "dvhe" | "av01" | "vp08" | "vp09" => {
+                    CodecType::Video
+                }
+                "wvtt" | "stpp" => CodecType::Subtitle,
+                _ => CodecType::Audio,
+            };
+            t == codec_type
+        })
+        .join(",");
+
+    if filtered.is_empty() {
+        None
+    } else {
+        Some(filtered)
+    }
+}
+
+/// Re-fetches the master playlist to get fresh media playlist urls, used when the media playlist
+/// urls expire (e.g. signed urls) but the master playlist url still works
+#[derive(Debug)]
+pub struct MasterPlaylistResolver {
+    client: HttpClient,
+    url: Url,
+    variant: VariantStream,
+}
+
+impl MasterPlaylistResolver {
+    pub fn new(client: HttpClient, url: Url, variant: VariantStream) -> Self {
+        Self {
+            client,
+            url,
+            variant,
+        }
+    }
+
+    /// Fetch the master playlist again and find the new url of stream
+    pub async fn resolve(&self, stream: &Stream) -> Result<Url> {
+        event!(Level::TRACE, "Fetching {}", self.url.as_str());
+        let resp = self.client.get(self.url.clone()).send().await?;
+        if !resp.status().is_success() {
+            return Err(LivestreamDLError::NetworkRequest(resp).into());
+        }
+        let bytes = resp.bytes().await?;
+
+        let playlist = parse_master_playlist(&bytes)
+            .ok_or_else(|| LivestreamDLError::ParseM3u8(self.url.to_string()))?;
+
+        // Find the same variant in the new playlist, matching attributes first and falling back
+        // to matching the uri without query parameters
+        let uri_path = |v: &VariantStream| {
+            make_absolute_url(&self.url, &v.uri)
+                .map(|u| u.path().to_owned())
+                .ok()
+        };
+        let variant = playlist
+            .variants
+            .iter()
+            .filter(|v| !v.is_i_frame)
+            .find(|v| {
+                v.bandwidth == self.variant.bandwidth
+                    && v.resolution == self.variant.resolution
+                    && v.codecs == self.variant.codecs
+            })
+            .or_else(|| {
+                playlist
+                    .variants
+                    .iter()
+                    .filter(|v| !v.is_i_frame)
+                    .find(|v| uri_path(v) == uri_path(&self.variant))
+            })
+            .ok_or_else(|| {
+                LivestreamDLError::StreamNotFound(
+                    "variant no longer found in master playlist".into(),
+                )
+            })?;
+
+        let url = variant_streams(&self.url, &playlist, variant)?
+            .remove(stream)
+            .map(|(url, _)| url)
+            .ok_or_else(|| {
+                LivestreamDLError::StreamNotFound(format!(
+                    "{} no longer found in master playlist",
+                    stream
+                ))
+            })?;
+
+        follow_nested_masters(&self.client, &url).await
+    }
+}
@@ -3,9 +3,13 @@ use std::process::Stdio;
 use anyhow::Result;
 use serde::Deserialize;
 use tokio::io::AsyncWriteExt;
-use tokio::process;
 use tracing::{event, Level};
 
+use crate::ffmpeg::ffprobe_command;
+
+/// Number of bytes at the start of a segment used to detect its format
+pub const PROBE_SIZE: usize = 64 * 1024;
+
 #[non_exhaustive]
 #[allow(dead_code)]
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
@@ -29,7 +33,53 @@ pub enum MediaFormat {
 }
 
 impl MediaFormat {
-    pub async fn detect(data: Vec<u8>) -> Result<Self> {
+    /// Detect format from magic bytes, falling back to ffprobe if unknown. Only the start of the
+    /// data is looked at
+    pub async fn detect(data: &[u8]) -> Result<Self> {
+        let data = &data[..data.len().min(PROBE_SIZE)];
+        match Self::sniff(data) {
+            Some(format) => Ok(format),
+            None => Self::detect_ffprobe(data.to_vec()).await,
+        }
+    }
+
+    /// Detect format in process from magic bytes, None if only ffprobe may detect it
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        const TS_PACKET_SIZE: usize = 188;
+        const MP4_BOXES: [&[u8]; 7] = [
+            b"ftyp", b"styp", b"moof", b"moov", b"sidx", b"emsg", b"prft",
+        ];
+
+        // WebVTT header, optionally preceded by a byte order mark
+        let text = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+        if text.starts_with(b"WEBVTT") {
+            return Some(Self::WebVtt);
+        }
+
+        // MPEG-TS sync byte at the start of consecutive packets
+        if data.first() == Some(&0x47)
+            && data
+                .iter()
+                .step_by(TS_PACKET_SIZE)
+                .take(3)
+                .all(|b| *b == 0x47)
+        {
+            return Some(Self::MpegTs);
+        }
+
+        // Fragmented MPEG-4 starts with one of these boxes
+        if let Some(box_type) = data.get(4..8) {
+            if MP4_BOXES.contains(&box_type) {
+                return Some(Self::FMp4);
+            }
+        }
+
+        // Raw audio elementary streams can be detected from their sync words
+        Self::detect_audio_elementary_stream(data)
+    }
+
+    /// Detect format by calling ffprobe
+    async fn detect_ffprobe(data: Vec<u8>) -> Result<Self> {
         #[derive(Deserialize)]
         struct FFProbeOuput {
             format: FFProbeFormat,
@@ -40,7 +90,7 @@ impl MediaFormat {
         }
 
         // Call ffprobe to check format
-        let mut cmd = process::Command::new("ffprobe");
+        let mut cmd = ffprobe_command();
         cmd.arg("-loglevel")
             .arg("quiet")
             .arg("-show_entries")
@@ -52,7 +102,15 @@ impl MediaFormat {
             .stdout(Stdio::piped())
             .kill_on_drop(true);
         event!(Level::TRACE, "{:?}", cmd);
-        let mut child = cmd.spawn()?;
+        let mut child = match cmd.spawn() {
+            Ok(c) => c,
+            // Treat format as unknown if ffprobe is not installed
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                event!(Level::TRACE, "ffprobe not found");
+                return Ok(Self::Unknown);
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         let mut stdin = child
             .stdin
@@ -92,6 +150,9 @@ impl MediaFormat {
                 let format = match o.format.format_name.as_str().trim() {
                     "mpegts" => Self::MpegTs,
                     "mp3" => Self::Mp3,
+                    "aac" => Self::Adts,
+                    "ac3" => Self::Ac3,
+                    "eac3" => Self::EAc3,
                     "mov,mp4,m4a,3gp,3g2,mj2" => Self::FMp4,
                     "webvtt" => Self::WebVtt,
                     _ => Self::Unknown,
@@ -102,6 +163,41 @@ impl MediaFormat {
         }
     }
 
+    /// Detect ADTS, MP3, AC-3, and E-AC-3 streams, skipping any leading ID3 tags used by packed audio
+    /// segments for timestamps
+    fn detect_audio_elementary_stream(data: &[u8]) -> Option<Self> {
+        let data = skip_id3_tags(data);
+        match data {
+            // ADTS sync word with layer 0
+            [0xFF, b1, ..] if b1 & 0xF6 == 0xF0 => Some(Self::Adts),
+            // MPEG audio sync word with a non-reserved layer
+            [0xFF, b1, ..] if b1 & 0xE0 == 0xE0 && b1 & 0x06 != 0 => Some(Self::Mp3),
+            // AC-3 sync word, bitstream id distinguishes AC-3 from E-AC-3
+            [0x0B, 0x77, _, _, _, b5, ..] => match b5 >> 3 {
+                0..=10 => Some(Self::Ac3),
+                11..=16 => Some(Self::EAc3),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Check if data starts with an MPEG-4 box, so it isn't encrypted as a whole
+    pub fn is_mp4(data: &[u8]) -> bool {
+        Self::sniff(data) == Some(Self::FMp4)
+    }
+
+    /// Check if data starts like any format detected in process, a cheap sanity check of
+    /// decrypted data
+    pub fn looks_like_media(data: &[u8]) -> bool {
+        Self::sniff(data).is_some()
+    }
+
+    /// Check if format is a raw audio elementary stream without a container
+    pub fn is_audio_elementary_stream(&self) -> bool {
+        matches!(self, Self::Mp3 | Self::Adts | Self::Ac3 | Self::EAc3)
+    }
+
     pub fn extension(&self) -> String {
         match self {
             Self::MpegTs => "ts",
@@ -118,4 +214,36 @@ impl MediaFormat {
         }
         .into()
     }
+
+    /// Format of a file saved with the given extension, the inverse of extension()
+    pub fn from_extension(ext: &str) -> Self {
+        match ext {
+            "ts" => Self::MpegTs,
+            "mp4" => Self::FMp4,
+            "m4a" => Self::Aac,
+            "aac" => Self::Adts,
+            "mp3" => Self::Mp3,
+            "ac3" => Self::Ac3,
+            "eac3" => Self::EAc3,
+            "vtt" => Self::WebVtt,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Skip ID3v2 tags at the start of data
+fn skip_id3_tags(mut data: &[u8]) -> &[u8] {
+    while let [b'I', b'D', b'3', _, _, flags, s0, s1, s2, s3, ..] = *data {
+        // Tag size is a 28 bit syncsafe integer, excluding the header and optional footer
+        let size = [s0, s1, s2, s3]
+            .iter()
+            .fold(0_usize, |acc, b| (acc << 7) | (*b & 0x7F) as usize);
+        let footer = if flags & 0x10 != 0 { 10 } else { 0 };
+        match data.get(10 + size + footer..) {
+            Some(rest) => data = rest,
+            None => break,
+        }
+    }
+
+    data
 }
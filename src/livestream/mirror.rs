@@ -0,0 +1,283 @@
+use std::collections::{BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use hyper::body::Bytes;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use m3u8_rs::{AlternativeMedia, AlternativeMediaType, MasterPlaylist, VariantStream};
+use tokio::task::JoinHandle;
+use tracing::{event, Level};
+
+use super::local_playlist::{local_playlist, relative_uri};
+use super::{Segment, SegmentFile, Stream, StreamInfo};
+
+/// Path of the served master playlist
+const MASTER_PLAYLIST_PATH: &str = "master.m3u8";
+
+/// Content type of served playlists
+const PLAYLIST_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+
+/// Content type of served segments
+const SEGMENT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Caching HLS proxy serving the most recent downloaded segments as a live stream while they are
+/// archived. Each stream is served at <stream directory>.m3u8 and its segments at their path in
+/// the segments directory, all of them listed in master.m3u8
+#[derive(Debug)]
+pub struct Mirror {
+    state: Arc<Mutex<MirrorState>>,
+    server: JoinHandle<()>,
+}
+
+#[derive(Debug)]
+struct MirrorState {
+    /// Number of most recent segments of each stream that are served
+    window: usize,
+    file_names: HashMap<Stream, String>,
+    master_playlist: Bytes,
+    /// Served segments of each stream by stream directory
+    segments: HashMap<String, BTreeSet<(Segment, SegmentFile)>>,
+    /// Content of served segments that were requested, by path
+    cache: HashMap<String, Bytes>,
+    /// Whether the download ended and the playlists are complete
+    ended: bool,
+}
+
+impl Mirror {
+    /// Start serving at addr, streams are served under their file names
+    pub fn start(
+        addr: SocketAddr,
+        window: usize,
+        file_names: &HashMap<Stream, String>,
+        stream_info: &HashMap<Stream, StreamInfo>,
+    ) -> Result<Self> {
+        let state = Arc::new(Mutex::new(MirrorState {
+            window: window.max(1),
+            file_names: file_names.clone(),
+            master_playlist: master_playlist(file_names, stream_info)?.into(),
+            segments: file_names
+                .values()
+                .map(|n| (n.clone(), BTreeSet::new()))
+                .collect(),
+            cache: HashMap::new(),
+            ended: false,
+        }));
+
+        let make_service = {
+            let state = state.clone();
+            make_service_fn(move |_| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+            })
+        };
+        let server = Server::try_bind(&addr)
+            .with_context(|| format!("error binding mirror to {}", addr))?
+            .serve(make_service);
+        event!(
+            Level::INFO,
+            "Mirroring download at http://{}/{}",
+            server.local_addr(),
+            MASTER_PLAYLIST_PATH
+        );
+        let server = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                event!(Level::WARN, "Mirror server failed: {:?}", e);
+            }
+        });
+
+        Ok(Self { state, server })
+    }
+
+    /// Serve a saved segment, evicting the oldest segment of its stream if the window is full
+    pub fn add_segment(&self, stream: &Stream, segment: &Segment, file: &SegmentFile) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let segments = match state
+            .file_names
+            .get(stream)
+            .and_then(|n| state.segments.get_mut(n))
+        {
+            Some(s) => s,
+            None => return,
+        };
+
+        segments.insert((segment.clone(), file.clone()));
+        while segments.len() > state.window {
+            if let Some((_, file)) = segments.pop_first() {
+                state.cache.remove(&relative_uri(&file.path));
+            }
+        }
+    }
+
+    /// Mark the playlists as complete
+    pub fn end(&self) {
+        self.state.lock().unwrap().ended = true;
+    }
+}
+
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl MirrorState {
+    /// Live playlist of the served segments of a stream directory
+    fn media_playlist(&self, dir: &str) -> Result<Option<Bytes>> {
+        let segments: Vec<_> = match self.segments.get(dir) {
+            Some(s) => s.iter().cloned().collect(),
+            None => return Ok(None),
+        };
+
+        let mut playlist = local_playlist(&segments, |_| None);
+        playlist.playlist_type = None;
+        playlist.end_list = self.ended;
+        let mut data = Vec::new();
+        playlist.write_to(&mut data)?;
+
+        Ok(Some(data.into()))
+    }
+
+    /// File of a served segment by its path
+    fn segment_file(&self, path: &str) -> Option<SegmentFile> {
+        let (dir, _) = path.split_once('/')?;
+        self.segments
+            .get(dir)?
+            .iter()
+            .find(|(_, f)| relative_uri(&f.path) == path)
+            .map(|(_, f)| f.clone())
+    }
+}
+
+/// Answer a request to the mirror
+async fn handle(
+    state: Arc<Mutex<MirrorState>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path().trim_start_matches('/');
+    let resp = match respond(&state, path).await {
+        Ok(Some((content_type, body))) => Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body)),
+        Ok(None) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+        Err(e) => {
+            event!(Level::WARN, "Mirror failed to serve {}: {:?}", path, e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+        }
+    };
+
+    Ok(resp.unwrap_or_default())
+}
+
+/// Content type and body served at path, None if nothing is served there
+async fn respond(state: &Mutex<MirrorState>, path: &str) -> Result<Option<(&'static str, Bytes)>> {
+    let file = {
+        let state = state.lock().unwrap();
+        if path == MASTER_PLAYLIST_PATH {
+            return Ok(Some((PLAYLIST_CONTENT_TYPE, state.master_playlist.clone())));
+        }
+        if let Some(dir) = path.strip_suffix(".m3u8") {
+            let playlist = state.media_playlist(dir)?;
+            return Ok(playlist.map(|p| (PLAYLIST_CONTENT_TYPE, p)));
+        }
+        if let Some(data) = state.cache.get(path) {
+            return Ok(Some((SEGMENT_CONTENT_TYPE, data.clone())));
+        }
+        match state.segment_file(path) {
+            Some(f) => f,
+            None => return Ok(None),
+        }
+    };
+
+    // Read the segment from the archive, caching it only if it wasn't evicted meanwhile
+    let data = Bytes::from(file.read().await?);
+    let mut state = state.lock().unwrap();
+    if state.segment_file(path).is_some() {
+        state.cache.insert(path.to_owned(), data.clone());
+    }
+
+    Ok(Some((SEGMENT_CONTENT_TYPE, data)))
+}
+
+/// Master playlist of the mirrored streams. Main and video streams are variants, audio and
+/// subtitle streams are alternative media available to every variant
+fn master_playlist(
+    file_names: &HashMap<Stream, String>,
+    stream_info: &HashMap<Stream, StreamInfo>,
+) -> Result<Vec<u8>> {
+    let has_audio = file_names.keys().any(|s| matches!(s, Stream::Audio { .. }));
+    let has_subtitles = file_names
+        .keys()
+        .any(|s| matches!(s, Stream::Subtitle { .. }));
+
+    let mut playlist = MasterPlaylist {
+        version: 4,
+        ..Default::default()
+    };
+    let mut streams: Vec<_> = file_names.iter().collect();
+    streams.sort_by(|a, b| a.1.cmp(b.1));
+    for (stream, file_name) in streams {
+        let uri = format!("{}.m3u8", file_name);
+        let (media_type, group_id, name, lang, disposition) = match stream {
+            Stream::Main | Stream::Video { .. } => {
+                let info = stream_info.get(stream).cloned().unwrap_or_default();
+                playlist.variants.push(VariantStream {
+                    uri,
+                    bandwidth: info.bandwidth.unwrap_or_default().to_string(),
+                    codecs: info.codecs,
+                    audio: has_audio.then(|| "audio".to_owned()),
+                    subtitles: has_subtitles.then(|| "subtitles".to_owned()),
+                    ..Default::default()
+                });
+                continue;
+            }
+            Stream::IFrames => continue,
+            Stream::Audio {
+                name,
+                lang,
+                disposition,
+            } => (
+                AlternativeMediaType::Audio,
+                "audio",
+                name,
+                lang,
+                disposition,
+            ),
+            Stream::Subtitle {
+                name,
+                lang,
+                disposition,
+            } => (
+                AlternativeMediaType::Subtitles,
+                "subtitles",
+                name,
+                lang,
+                disposition,
+            ),
+        };
+        playlist.alternatives.push(AlternativeMedia {
+            media_type,
+            uri: Some(uri),
+            group_id: group_id.to_owned(),
+            language: lang.clone(),
+            name: name.clone(),
+            default: disposition.default,
+            autoselect: disposition.default,
+            forced: disposition.forced,
+            ..Default::default()
+        });
+    }
+
+    let mut data = Vec::new();
+    playlist.write_to(&mut data)?;
+
+    Ok(data)
+}
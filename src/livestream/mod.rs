@@ -1,78 +1,207 @@
+mod backfill;
+mod cadence;
+mod clock;
 mod cookies;
+mod daterange;
 mod displayable_variant;
 mod encryption;
+mod events;
+mod extractor;
 mod hashable_byte_range;
+mod hooks;
 mod http_client;
+mod impersonate;
+mod inspect;
+mod local_files;
+mod local_playlist;
+mod master_playlist;
 mod media_format;
+mod mirror;
+mod offline;
+mod pacer;
+mod packfile;
+mod parse;
 mod playlist_fetcher;
+mod prefetch;
+mod queue;
 mod remote_data;
 mod segment;
+mod session;
+mod stats;
 mod stopper;
 mod stream;
+mod token_refresher;
 mod utils;
+mod worker_pool;
 
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fmt::Display;
-use std::path::{Path, PathBuf};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use futures::channel::mpsc;
-use futures::StreamExt;
+use futures::{StreamExt, TryFutureExt};
 use itertools::Itertools;
 use lru::LruCache;
 use m3u8_rs::Playlist;
-use reqwest::{Client, Url};
+use reqwest::{Certificate, Client, Url};
 use reqwest_middleware::ClientBuilder;
 use reqwest_retry::{policies, RetryTransientMiddleware};
+use serde::Deserialize;
+use time::OffsetDateTime;
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 use tracing::{event, Level};
 
+pub use self::clock::{Clock, SharedClock, TokioClock};
 use self::cookies::CookieJar;
+use self::daterange::Timeline;
 use self::displayable_variant::DisplayableVariant;
 pub use self::encryption::Encryption;
+use self::encryption::{KeyArchive, KeyCache};
+pub use self::events::{DownloadEvent, Events};
 pub use self::hashable_byte_range::HashableByteRange;
-use self::http_client::HttpClient;
+use self::hooks::{run_output_hook, run_segment_hook};
+use self::http_client::{HttpClient, SpeedLimit};
+use self::impersonate::impersonate;
+pub use self::inspect::{list_streams, probe};
+pub use self::local_files::set_stdin_playlist;
+use self::local_files::LocalFiles;
+use self::local_playlist::{write_local_playlists, write_master_playlist};
+use self::master_playlist::{
+    candidate_variants, codec_preference, follow_nested_masters, has_characteristic,
+    pinned_variant, variant_streams, with_session_title, MasterPlaylistResolver,
+};
 pub use self::media_format::MediaFormat;
-use self::playlist_fetcher::m3u8_fetcher;
-use self::remote_data::RemoteData;
-pub use self::segment::Segment;
-pub use self::stopper::Stopper;
-pub use self::stream::Stream;
-use self::utils::make_absolute_url;
-use crate::cli::Args;
+use self::mirror::Mirror;
+use self::offline::Manifest;
+use self::offline::SavedSegments;
+pub use self::offline::{remux_download, saved_source};
+use self::pacer::Pacer;
+use self::packfile::PackWriter;
+pub use self::parse::set_lenient;
+use self::parse::{parse_playlist, PlaylistKind};
+use self::playlist_fetcher::{m3u8_fetcher, FetcherContext};
+use self::prefetch::{fetch_initialization, InitCache, Prefetcher};
+use self::queue::{QueuedSegment, SegmentQueue, SlidingWindow};
+pub use self::segment::{Segment, SegmentFile};
+use self::session::{response_url, SessionRecorder, SessionReplayer};
+use self::stats::monitor_latency;
+pub use self::stats::{format_bytes, format_duration, Stats, StreamStats};
+pub use self::stopper::{ShutdownPhase, StopReason, Stopper};
+pub use self::stream::{Disposition, Stream, StreamInfo};
+use self::token_refresher::{is_forbidden, TokenRefresher};
+use self::utils::{
+    interface_address, make_absolute_url, sanitize_file_name, write_atomic_blocking, AbortOnDrop,
+};
+use crate::cli::{Args, Dedupe, MuxOptions, NetworkOptions};
 use crate::error::LivestreamDLError;
-use crate::mux::remux;
+use crate::mux::{
+    generate_sprite_sheets, generate_thumbnails, remove_partial_remux, remux, remux_partial,
+    RemuxOptions, RemuxedFile,
+};
+use crate::notify::desktop_notify;
+use crate::upload::{self, upload_name};
+
+/// Log target of the progress messages logged for every segment, hidden with -q
+pub const PROGRESS_TARGET: &str = "livestream_dl::progress";
 
 #[derive(Debug)]
 pub struct Livestream {
+    url: Url,
     streams: HashMap<Stream, Url>,
+    stream_info: HashMap<Stream, StreamInfo>,
     client: HttpClient,
+    cookie_jar: Option<Arc<CookieJar>>,
+    token_refresher: Option<TokenRefresher>,
+    master_resolver: Option<Arc<MasterPlaylistResolver>>,
+    keys: KeyCache,
+    /// Keys and IVs of segments saved without decrypting
+    key_archive: Option<KeyArchive>,
+    session_data: HashMap<String, String>,
+    /// Original master playlist, saved with the segments
+    master_playlist: Option<Vec<u8>>,
+    stats: Stats,
+    events: Events,
     stopper: Stopper,
+    clock: SharedClock,
+    timeline: Timeline,
+    resume: bool,
+    download_limit: Option<Arc<Semaphore>>,
     options: Args,
 }
 
 type SegmentIdData = (Stream, Segment, Vec<u8>);
 
+/// Number of times a segment download is restarted after being too slow
+const MAX_SLOW_TRANSFER_RETRIES: u32 = 3;
+
 impl Stream {
     /// Name of stream if available
     pub fn name(&self) -> Option<String> {
         match self {
             Self::Main => None,
+            Self::IFrames => None,
             Self::Video { name: n, .. } => Some(n.clone()),
             Self::Audio { name: n, .. } => Some(n.clone()),
             Self::Subtitle { name: n, .. } => Some(n.clone()),
         }
     }
+
+    /// Name of stream that is safe to use in file paths, unnamed alternative media are
+    /// identified by language instead. m3u8-rs keeps the quotes of empty names
+    pub fn file_name(&self) -> String {
+        let (kind, name, lang) = match self {
+            Self::Main | Self::IFrames => return sanitize_file_name(&self.to_string()),
+            Self::Video { name, lang, .. } => ("video", name, lang),
+            Self::Audio { name, lang, .. } => ("audio", name, lang),
+            Self::Subtitle { name, lang, .. } => ("subtitle", name, lang),
+        };
+        let name = match name.trim_matches('"') {
+            "" => lang.as_deref().unwrap_or("unnamed"),
+            n => n,
+        };
+        sanitize_file_name(&format!("{}_{}", kind, name))
+    }
+
+    /// File names of streams that are unique among the given streams, names that collide after
+    /// sanitization get a numbered suffix
+    pub fn file_names<'a>(
+        streams: impl IntoIterator<Item = &'a Stream>,
+    ) -> HashMap<Stream, String> {
+        // Sort so suffixes don't depend on iteration order
+        let mut streams: Vec<_> = streams.into_iter().collect();
+        streams.sort_by_cached_key(|s| format!("{:?}", s));
+
+        let mut used = HashSet::new();
+        let mut names = HashMap::new();
+        for stream in streams {
+            let base = stream.file_name();
+            let mut name = base.clone();
+            let mut i = 2;
+            while !used.insert(name.clone()) {
+                name = format!("{}_{}", base, i);
+                i += 1;
+            }
+            names.insert(stream.clone(), name);
+        }
+
+        names
+    }
 }
 
 impl Display for Stream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Main => write!(f, "main"),
+            Self::IFrames => write!(f, "iframes"),
             Self::Video { name: n, .. } => write!(f, "video_{}", n),
             Self::Audio { name: n, .. } => write!(f, "audio_{}", n),
             Self::Subtitle { name: n, .. } => write!(f, "subtitle_{}", n),
@@ -86,38 +215,25 @@ impl Livestream {
     /// If a master playlist is given, choose the highest bitrate variant and download its stream
     /// and all of its alternative media streams
     pub async fn new(url: &Url, options: &Args) -> Result<(Self, Stopper)> {
-        // Create reqwest client
-        let client = Client::builder()
-            .timeout(Duration::from_secs(options.network_options.timeout))
-            .danger_accept_invalid_certs(options.network_options.insecure);
-
-        // Add cookie provider if needed
-        let client = if let Some(cookies_path) = &options.network_options.cookies {
-            let jar = CookieJar::parse_from_file(cookies_path)?;
-            client.cookie_provider(Arc::new(jar))
-        } else {
-            client
-        }
-        .build()?;
-
-        // Set client retry on failure
-        let retry_policy = policies::ExponentialBackoff::builder()
-            .retry_bounds(Duration::from_secs(1), Duration::from_secs(10))
-            .backoff_exponent(2)
-            .build_with_max_retries(options.network_options.max_retries);
-
-        // Build client with middleware
-        let client = ClientBuilder::new(client)
-            .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-            .build();
-
-        // Build HttpClient
-        let query_pairs = if options.network_options.copy_query {
-            Some(url.query_pairs().collect::<Vec<_>>())
-        } else {
-            None
-        };
-        let client = HttpClient::new(client, query_pairs);
+        // Create HTTP client
+        let stats = Stats::new();
+        let (client, cookie_jar) = build_client(url, &options.network_options, &stats)?;
+        let network_options = &options.network_options;
+
+        // Find the playlist if url is a webpage
+        let url = &extractor::resolve(&client, url, network_options).await?;
+
+        // Refresh query parameter tokens if needed
+        let token_refresher =
+            if network_options.copy_query || network_options.refresh_command.is_some() {
+                Some(TokenRefresher::new(
+                    client.clone(),
+                    url.clone(),
+                    network_options.refresh_command.clone(),
+                ))
+            } else {
+                None
+            };
 
         // Get m3u8 playlist
         let resp = client.get(url.clone()).send().await?;
@@ -126,109 +242,266 @@ impl Livestream {
         }
 
         // Check if m3u8 is master or media
-        let final_url = resp.url().clone();
+        let final_url = response_url(&resp).clone();
         let bytes = resp.bytes().await?;
 
         // Parse m3u8 playlist and add streams
         let mut streams = HashMap::new();
-        match m3u8_rs::parse_playlist(&bytes) {
-            Ok((_, Playlist::MasterPlaylist(p))) => {
-                let stream = if !options.download_options.choose_stream {
-                    // Pick highest bitrate stream
-                    p.variants
+        let mut stream_info = HashMap::new();
+        let mut master_resolver = None;
+        let keys = KeyCache::new();
+        let mut session_data = HashMap::new();
+        let mut master_playlist = None;
+        // Parse as the given kind of playlist if detection fails for this server
+        let download_options = &options.download_options;
+        let kind = if download_options.force_master_playlist {
+            Some(PlaylistKind::Master)
+        } else if download_options.force_media_playlist {
+            Some(PlaylistKind::Media)
+        } else {
+            None
+        };
+        match parse_playlist(&bytes, kind) {
+            Some(Playlist::MasterPlaylist(p)) => {
+                master_playlist = Some(bytes.to_vec());
+                // Prompts are disabled in non-interactive mode
+                let interactive = !(download_options.yes || download_options.no_input);
+                if download_options.choose_stream && !interactive {
+                    event!(
+                        Level::WARN,
+                        "Not showing stream picker in non-interactive mode, choosing highest bitrate stream"
+                    );
+                }
+
+                // Skip variants with avoided codecs
+                let candidates = candidate_variants(&p, &download_options.avoid_codec);
+
+                // A variant given by index or uri is used as is
+                let pinned = pinned_variant(
+                    url,
+                    &p,
+                    download_options.variant_index,
+                    download_options.variant_url.as_deref(),
+                )?;
+
+                let stream = if let Some(v) = pinned {
+                    v
+                } else if !(download_options.choose_stream && interactive) {
+                    // Pick highest bitrate stream, preferring the requested codecs
+                    candidates
                         .iter()
+                        .copied()
                         .filter_map(|v| Some((v.bandwidth.parse::<u64>().ok()?, v)))
-                        .max_by_key(|(x, _)| *x)
-                        .ok_or_else(|| anyhow::anyhow!("No streams found"))?
+                        .max_by_key(|(x, v)| {
+                            (codec_preference(v, &download_options.prefer_codec), *x)
+                        })
+                        .ok_or_else(|| {
+                            LivestreamDLError::StreamNotFound("no variants in playlist".into())
+                        })?
                         .1
                 } else {
                     // Show stream chooser
-                    let options: Vec<_> = p
-                        .variants
+                    let options: Vec<_> = candidates
                         .iter()
+                        .copied()
                         .filter_map(|v| Some((v.bandwidth.parse::<u64>().ok()?, v)))
                         .sorted_by_key(|(b, _)| *b)
                         .map(|(_, v)| v)
                         .rev()
-                        .map(DisplayableVariant::from)
+                        .map(|v| DisplayableVariant::new(v, &p.alternatives))
                         .collect();
                     let response = inquire::Select::new("Choose stream", options).prompt()?;
                     response.into()
                 };
 
-                // Add main stream
-                streams.insert(Stream::Main, make_absolute_url(url, &stream.uri)?);
-
-                // Closure to find alternative media with matching group id and add them to streams
-                let mut add_alternative =
-                    |group, f: fn(String, Option<String>) -> Stream| -> Result<()> {
-                        for a in p.alternatives.iter().filter(|a| &a.group_id == group) {
-                            if let Some(a_url) = &a.uri {
-                                streams.insert(
-                                    f(a.name.clone(), a.language.clone()),
-                                    make_absolute_url(url, a_url)?,
-                                );
-                            }
-                        }
-                        Ok(())
-                    };
+                // Add main stream and all of its alternative media streams
+                let download_iframes = download_options.iframes || options.mux_options.sprite_sheet;
+                for (s, (u, info)) in variant_streams(url, &p, stream)? {
+                    if s == Stream::IFrames && !download_iframes {
+                        continue;
+                    }
 
-                // Add audio streams
-                if let Some(group) = &stream.audio {
-                    add_alternative(group, |n, l| Stream::Audio { name: n, lang: l })?;
+                    // Filter audio renditions by their characteristics
+                    if matches!(s, Stream::Audio { .. }) {
+                        let characteristics = info.characteristics.as_deref();
+                        let included = download_options.audio_include.is_empty()
+                            || has_characteristic(characteristics, &download_options.audio_include);
+                        let excluded =
+                            has_characteristic(characteristics, &download_options.audio_exclude);
+                        if !included || excluded {
+                            event!(Level::DEBUG, "Skipping {} stream", s);
+                            continue;
+                        }
+                    }
+                    // Variant uris may point to further master playlists
+                    let u = follow_nested_masters(&client, &u).await?;
+                    streams.insert(s.clone(), u);
+                    stream_info.insert(s, info);
                 }
 
-                // Add video streams
-                if let Some(group) = &stream.video {
-                    add_alternative(group, |n, l| Stream::Video { name: n, lang: l })?;
+                // Re-resolve media playlist urls from master playlist if they expire
+                master_resolver = Some(Arc::new(MasterPlaylistResolver::new(
+                    client.clone(),
+                    url.clone(),
+                    stream.clone(),
+                )));
+
+                // Fetch session keys before segments need them
+                for key in &p.session_key {
+                    if let Err(e) = keys.prefetch(&client, &key.0, url).await {
+                        event!(Level::WARN, "Failed to prefetch session key: {:?}", e);
+                    }
                 }
 
-                // Add subtitle streams
-                if let Some(group) = &stream.subtitles {
-                    add_alternative(group, |n, l| Stream::Subtitle { name: n, lang: l })?;
+                // Keep session data for metadata
+                session_data = self::master_playlist::session_data(&p);
+                for (id, value) in &session_data {
+                    event!(Level::INFO, "Session data {}: {}", id, value);
                 }
             }
-            Ok((_, Playlist::MediaPlaylist(_))) => {
+            Some(Playlist::MediaPlaylist(_)) => {
                 streams.insert(Stream::Main, final_url);
             }
-            Err(_) => {
+            None => {
                 return Err(LivestreamDLError::ParseM3u8(final_url.to_string()).into());
             }
         }
 
+        // Log selected streams
+        for stream in streams.keys() {
+            match stream_info.get(stream) {
+                Some(info) if !info.is_empty() => {
+                    event!(Level::INFO, "Selected {} stream ({})", stream, info)
+                }
+                _ => event!(Level::INFO, "Selected {} stream", stream),
+            }
+        }
+
         let stopper = Stopper::new();
 
         Ok((
             Self {
+                url: url.clone(),
                 streams,
+                stream_info,
                 client,
+                cookie_jar,
+                token_refresher,
+                master_resolver,
+                keys,
+                key_archive: options.download_options.no_decrypt.then(KeyArchive::new),
+                session_data,
+                master_playlist,
+                stats,
+                events: Events::new(),
                 stopper: stopper.clone(),
+                clock: TokioClock::shared(),
+                timeline: Timeline::new(),
+                resume: false,
+                download_limit: None,
                 options: options.clone(),
             },
             stopper,
         ))
     }
 
+    /// Keep the segments saved in the output directory by an earlier download, they are not
+    /// downloaded again and are included when remuxing
+    pub fn resume(&mut self) {
+        self.resume = true;
+    }
+
+    /// Poll playlists, retry, and pace segments on clock instead of tokio's timer, e.g. to run
+    /// downloads on simulated time
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+    }
+
+    /// Share a limit on concurrent segment downloads with other livestreams, in addition to the
+    /// livestream's own limit
+    pub fn share_download_limit(&mut self, limit: Arc<Semaphore>) {
+        self.download_limit = Some(limit);
+    }
+
     /// Download the livestream to disk
+    ///
+    /// The returned future may be dropped to cancel the download, e.g. by a timeout. Tasks it
+    /// spawned are aborted with it, and the output directory can still be continued with resume
+    /// or remuxed with mux: the manifest is written before any segment, segment files only
+    /// appear once complete, and pack file indexes only point to complete data. Local
+    /// playlists, keys.json, the summary, and remuxed files are only written once the download
+    /// finishes
     pub async fn download(&self, output: &Path) -> Result<()> {
+        let started = OffsetDateTime::now_utc();
+
         // m3u8 reader task handles
         let mut handles = Vec::new();
 
+        // Track queued segments falling out of live playlists
+        let window = SlidingWindow::new();
+
+        // Cache initializations for each stream
+        let init_lrus: HashMap<_, InitCache> = self
+            .streams
+            .keys()
+            .map(|k| {
+                (
+                    k.clone(),
+                    Arc::new(Mutex::new(LruCache::new(
+                        self.options.network_options.max_concurrent_downloads,
+                    ))),
+                )
+            })
+            .collect();
+
         let rx = {
-            // Create channel for m3u8 fetcher <-> segment downloader tasks
-            let (tx, rx) = mpsc::unbounded();
+            // Create channel for m3u8 fetcher <-> segment downloader tasks, the segment queue
+            // holds the segments waiting to be downloaded
+            let (tx, rx) = mpsc::channel(0);
+            let ctx = FetcherContext {
+                client: self.client.clone(),
+                notify_stop: self.stopper.clone(),
+                master_resolver: self.master_resolver.clone(),
+                window: window.clone(),
+                tx,
+                playlist_retries: self.options.network_options.playlist_retries,
+                max_stalled_reloads: self.options.network_options.max_stalled_reloads,
+                timeline: self.timeline.clone(),
+                stats: self.stats.clone(),
+                events: self.events.clone(),
+                dedupe: self.options.download_options.dedupe,
+                follow_restarts: self
+                    .options
+                    .download_options
+                    .follow_restarts
+                    .map(|m| Duration::from_secs(m * 60)),
+                backfill: self.options.download_options.backfill,
+                clock: self.clock.clone(),
+                prefetcher: Prefetcher::new(
+                    self.client.clone(),
+                    self.keys.clone(),
+                    self.key_archive.is_none(),
+                    init_lrus.clone(),
+                ),
+            };
 
             // Spawn m3u8 reader task
             for (stream, url) in &self.streams {
-                let client = self.client.clone();
-                let stopper = self.stopper.clone();
-                let tx = tx.clone();
+                let ctx = ctx.clone();
+                let pacer = self
+                    .options
+                    .network_options
+                    .paced
+                    .then(|| Pacer::new(self.clock.clone()));
                 let stream = stream.clone();
                 let url = url.clone();
 
-                handles.push(tokio::spawn(async move {
-                    m3u8_fetcher(client, stopper.clone(), tx, stream, url).await
-                }));
+                handles.push((
+                    stream.clone(),
+                    AbortOnDrop(tokio::spawn(async move {
+                        m3u8_fetcher(ctx, pacer, stream, url).await
+                    })),
+                ));
             }
 
             rx
@@ -236,62 +509,278 @@ impl Livestream {
 
         // Create segments directory if needed
         let segments_directory = output.join("segments");
+        let file_names = Stream::file_names(self.streams.keys());
 
-        // Cache initializations for each stream
-        let init_lrus: HashMap<_, _> = self
-            .streams
-            .keys()
-            .map(|k| {
-                (
-                    k,
-                    Arc::new(Mutex::new(LruCache::new(
-                        self.options.network_options.max_concurrent_downloads,
-                    ))),
+        // Describe the streams so the segments can be remuxed later
+        Manifest::new(
+            &self.url,
+            started,
+            &file_names,
+            &self.stream_info,
+            &self.session_data,
+        )
+        .write(&segments_directory, self.options.download_options.fsync)
+        .await
+        .context("error writing manifest")?;
+        if let Some(bytes) = &self.master_playlist {
+            write_master_playlist(
+                &segments_directory,
+                bytes,
+                self.options.download_options.fsync,
+            )
+            .await
+            .context("error saving master playlist")?;
+        }
+
+        // Serve the download while it is archived if mirroring
+        let mirror = match self.options.download_options.mirror {
+            Some(addr) => Some(
+                Mirror::start(
+                    addr,
+                    self.options.download_options.mirror_window,
+                    &file_names,
+                    &self.stream_info,
                 )
+                .context("error starting mirror")?,
+            ),
+            None => None,
+        };
+
+        // Pack file writer of each stream if segments are packed
+        let mut packs = self.options.download_options.packfile.then(HashMap::new);
+
+        // Save paths for each downloaded segment, starting with the segments saved by an
+        // earlier run if resuming
+        let mut downloaded_segments = if self.resume {
+            let mut saved = SavedSegments::read(&segments_directory).await?.segments;
+            saved.retain(|stream, _| self.streams.contains_key(stream));
+            saved
+        } else {
+            HashMap::new()
+        };
+        let saved_ids: HashMap<Stream, HashSet<(u64, u64)>> = downloaded_segments
+            .iter()
+            .map(|(stream, segments)| {
+                let ids = segments.iter().map(|(s, _)| (s.discon_seq, s.seq));
+                (stream.clone(), ids.collect())
             })
             .collect();
 
-        // Save paths for each downloaded segment
-        let mut downloaded_segments = HashMap::new();
-
-        // Download segments
-        let mut buffered = rx
-            .map(|(stream, seg, encryption)| {
-                fetch_segment(
-                    &self.client,
-                    init_lrus[&stream].clone(),
-                    stream,
-                    seg,
-                    encryption,
-                )
+        // Download segments, closest to expiry first
+        let window_monitor = window.clone();
+        let queue = SegmentQueue::new(
+            rx,
+            window,
+            self.options.download_options.queue_size,
+            self.options.download_options.queue_full,
+            self.stats.clone(),
+        );
+        let mut buffered = queue
+            .filter(|q| {
+                let saved = saved_ids
+                    .get(&q.stream)
+                    .is_some_and(|ids| ids.contains(&(q.segment.discon_seq, q.segment.seq)));
+                futures::future::ready(!saved)
+            })
+            .map(|q: QueuedSegment| {
+                let failed_stream = q.stream.clone();
+                let expired = q.expired;
+                let download_limit = self.download_limit.clone();
+                if let Some(archive) = &self.key_archive {
+                    archive.record(&q.stream, &q.segment, &q.encryption);
+                }
+                let fetch = self.fetch_segment(
+                    init_lrus[&q.stream].clone(),
+                    q.stream,
+                    q.segment,
+                    q.encryption,
+                    q.init_encryption,
+                );
+                async move {
+                    // Hold a permit of the limit shared with other livestreams while fetching
+                    let _permit = match download_limit {
+                        Some(limit) => Some(limit.acquire_owned().await?),
+                        None => None,
+                    };
+                    let (id_data, corrupt) = fetch.await?;
+                    if corrupt {
+                        self.stats.segment_corrupt(&id_data.0);
+                    }
+                    Ok::<_, anyhow::Error>(id_data)
+                }
+                .inspect_err(move |_| {
+                    self.stats.segment_failed(&failed_stream);
+                    if expired {
+                        self.stats.segment_expired(&failed_stream);
+                    }
+                })
             })
             .buffer_unordered(self.options.network_options.max_concurrent_downloads);
 
-        // Save segments to disk in order, break if stopped
+        // Periodically remux the segments downloaded so far if requested
+        let remux_interval = self
+            .options
+            .download_options
+            .remux_interval
+            .map(|m| m as f64 * 60.0);
+        let partial_directory = output.join("partial");
+        let mut partial_remux: Option<AbortOnDrop<()>> = None;
+        let mut last_partial_remux = 0.0;
+
+        // Periodically log how far behind live the download is
+        let latency_monitor = AbortOnDrop(tokio::spawn(monitor_latency(
+            self.stats.clone(),
+            window_monitor,
+            self.options.network_options.latency_warning as f64,
+        )));
+
+        // Commands and uploads running for saved segments
+        let mut segment_hooks: Vec<AbortOnDrop<()>> = Vec::new();
+        let segment_uploader = if self.options.mux_options.upload_segments {
+            upload::from_options(&self.options.mux_options)?
+        } else {
+            None
+        };
+
+        // Size and hash of saved segments of each stream
+        let mut saved_hashes: HashMap<Stream, HashSet<(usize, u64)>> = HashMap::new();
+
+        // Save segments to disk in order. When stopped, fetchers quit and the queued segments
+        // are still saved unless remaining segments are skipped
         while let Some(x) = tokio::select! {
             y = buffered.next() => { y },
-            _ = self.stopper.wait() => { None }
+            _ = self.stopper.wait_phase(ShutdownPhase::SkipSegments) => { None }
         } {
-            // Quit immediately if stopped
-            if self.stopper.stopped().await {
+            // Quit immediately if skipping remaining segments
+            if self.stopper.phase() >= ShutdownPhase::SkipSegments {
                 break;
             }
 
             // Save the segment
             match x {
                 Ok(id_data) => {
-                    let segment = id_data.1.clone();
-                    let res =
-                        save_segment(id_data, &mut downloaded_segments, &segments_directory).await;
-
-                    // Log warning if segment failed to download
-                    if let Err(e) = res {
-                        event!(
-                            Level::WARN,
-                            "Failed to save {}, reason: {}",
-                            segment.url(),
-                            e
-                        );
+                    // Skip segments with the same content as an already saved one
+                    if self.options.download_options.dedupe == Dedupe::Content {
+                        let mut hasher = DefaultHasher::new();
+                        id_data.2.hash(&mut hasher);
+                        let key = (id_data.2.len(), hasher.finish());
+                        if !saved_hashes
+                            .entry(id_data.0.clone())
+                            .or_default()
+                            .insert(key)
+                        {
+                            event!(Level::DEBUG, "Skipping duplicate {}", id_data.1.url());
+                            continue;
+                        }
+                    }
+
+                    let (stream, segment, len) =
+                        (id_data.0.clone(), id_data.1.clone(), id_data.2.len());
+                    let res = save_segment(
+                        id_data,
+                        &mut downloaded_segments,
+                        &segments_directory,
+                        &file_names,
+                        packs.as_mut(),
+                        self.options.download_options.compress_text,
+                        self.options.download_options.fsync,
+                    )
+                    .await;
+
+                    match res {
+                        Ok(file) => {
+                            let stream_recorded =
+                                self.stats
+                                    .segment_downloaded(&stream, len, segment.duration);
+                            event!(
+                                target: PROGRESS_TARGET,
+                                Level::INFO,
+                                "Saved {} segment {}, {} recorded",
+                                stream,
+                                segment.seq,
+                                format_duration(stream_recorded)
+                            );
+                            self.events.send(DownloadEvent::SegmentDownloaded {
+                                stream: stream.clone(),
+                                seq: segment.seq,
+                                discon_seq: segment.discon_seq,
+                                bytes: len,
+                                path: file.path.clone(),
+                                recorded: Duration::from_secs_f64(stream_recorded),
+                            });
+                            if let Some(mirror) = &mirror {
+                                mirror.add_segment(&stream, &segment, &file);
+                            }
+                            if let Some(command) = &self.options.download_options.exec_per_segment {
+                                segment_hooks.retain(|h| !h.is_finished());
+                                let command = command.clone();
+                                let (stream, segment, file) =
+                                    (stream.clone(), segment.clone(), file.clone());
+                                segment_hooks.push(AbortOnDrop(tokio::spawn(async move {
+                                    if let Err(e) =
+                                        run_segment_hook(&command, &stream, &segment, &file).await
+                                    {
+                                        event!(
+                                            Level::WARN,
+                                            "Segment command failed for {}: {:?}",
+                                            segment.url(),
+                                            e
+                                        );
+                                    }
+                                })));
+                            }
+                            // Packed segments can't be uploaded one at a time
+                            if let (Some(uploader), None) = (&segment_uploader, file.range) {
+                                segment_hooks.retain(|h| !h.is_finished());
+                                let uploader = uploader.clone();
+                                let name = upload_name(output, &file.path);
+                                segment_hooks.push(AbortOnDrop(tokio::spawn(async move {
+                                    if let Err(e) = uploader.upload(&file.path, &name).await {
+                                        event!(
+                                            Level::WARN,
+                                            "Failed to upload {:?}: {:?}",
+                                            file.path,
+                                            e
+                                        );
+                                    }
+                                })));
+                            }
+                            if let Some(pdt) = segment.program_date_time {
+                                self.stats.recorded_to(&stream, pdt + segment.duration);
+                            }
+
+                            // Start a partial remux if enough media was recorded since the last
+                            // one and it finished
+                            let recorded = self.stats.recorded_duration();
+                            if let Some(interval) = remux_interval {
+                                if recorded - last_partial_remux >= interval
+                                    && partial_remux.as_ref().is_none_or(|h| h.is_finished())
+                                {
+                                    last_partial_remux = recorded;
+                                    let mut segments = downloaded_segments.clone();
+                                    segments.remove(&Stream::IFrames);
+                                    let stream_info = self.stream_info.clone();
+                                    let dir = partial_directory.clone();
+                                    partial_remux = Some(AbortOnDrop(tokio::spawn(async move {
+                                        if let Err(e) =
+                                            remux_partial(segments, &stream_info, &dir).await
+                                        {
+                                            event!(Level::WARN, "Partial remux failed: {:?}", e);
+                                        }
+                                    })));
+                                }
+                            }
+                        }
+                        // Log warning if segment failed to download
+                        Err(e) => {
+                            self.stats.segment_failed(&stream);
+                            event!(
+                                Level::WARN,
+                                "Failed to save {}, reason: {}",
+                                segment.url(),
+                                e
+                            );
+                        }
                     }
                 }
                 Err(e) => {
@@ -300,106 +789,526 @@ impl Livestream {
             }
         }
 
-        // Remux if necessary
-        if !self.options.download_options.no_remux {
-            remux(downloaded_segments, output).await?;
+        latency_monitor.abort();
+
+        // Let segment commands and uploads finish before remuxing
+        for hook in segment_hooks {
+            let _ = hook.await;
+        }
+        if let Some(mirror) = &mirror {
+            mirror.end();
         }
 
-        // Check playlist fetcher task join handles
-        for handle in handles {
-            handle.await?.context("m3u8 fetcher failed")?;
+        // Check playlist fetcher results, a failed stream doesn't abort the others. Fetchers
+        // still running when stopped are cancelled
+        let stopped = self.stopper.is_stopped();
+        for (stream, handle) in handles {
+            if stopped {
+                handle.abort();
+            }
+            let result = match handle.await {
+                Ok(r) => r,
+                Err(e) if e.is_cancelled() => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = &result {
+                event!(Level::ERROR, "{} stream failed: {:?}", stream, e);
+                self.stats.stream_failed(&stream, format!("{:#}", e));
+            }
+            self.events.send(DownloadEvent::StreamEnded {
+                stream,
+                error: result.err().map(|e| format!("{:#}", e)),
+            });
+        }
+
+        // Report download statistics
+        self.stats.log_summary();
+        if self.options.download_options.desktop_notify {
+            let body = format!(
+                "{} recorded to {:?}",
+                format_duration(self.stats.recorded_duration()),
+                output
+            );
+            desktop_notify("Stream ended".to_owned(), body).await;
+        }
+        if self.options.download_options.write_summary {
+            self.stats
+                .write_summary(output.join("summary.json"))
+                .context("error writing summary")?;
+        }
+        self.timeline
+            .write(output.join("dateranges.json"))
+            .context("error writing date ranges")?;
+
+        // Save the keys of segments that were not decrypted
+        let segment_keys = match &self.key_archive {
+            Some(archive) => Some(
+                archive
+                    .write(
+                        &segments_directory,
+                        &self.keys,
+                        &file_names,
+                        self.options.download_options.fsync,
+                    )
+                    .await
+                    .context("error writing keys")?,
+            ),
+            None => None,
+        };
+
+        // Make the saved segments playable without remuxing
+        if let Err(e) = write_local_playlists(
+            &segments_directory,
+            &downloaded_segments,
+            segment_keys.as_ref(),
+            self.options.download_options.fsync,
+        )
+        .await
+        {
+            event!(Level::WARN, "Failed to write local playlists: {:?}", e);
+        }
+
+        // Encrypted segments can't be remuxed
+        let mut mux_options = with_session_title(&self.options.mux_options, &self.session_data);
+        mux_options.no_remux |= self.key_archive.is_some();
+
+        // Cancel unfinished partial remux, it is replaced by the final remux
+        if let Some(handle) = partial_remux {
+            handle.abort();
+            let _ = handle.await;
+        }
+
+        let mut remux_options = RemuxOptions::new(
+            &mux_options,
+            self.options.download_options.fsync,
+            Some(&self.url),
+            Some(started),
+        )?;
+        remux_options.events = Some(self.events.clone());
+        let remuxed = postprocess(
+            downloaded_segments,
+            &self.stream_info,
+            output,
+            &mux_options,
+            &remux_options,
+        )
+        .await?;
+        if self.options.download_options.desktop_notify && !remuxed.is_empty() {
+            let body = format!("{} files remuxed in {:?}", remuxed.len(), output);
+            desktop_notify("Remux finished".to_owned(), body).await;
+        }
+
+        // Add the audio offsets measured after remuxing to the summary
+        for file in &remuxed {
+            if let (Some(name), Some(offset)) = (file.path.file_name(), file.av_offset) {
+                self.stats
+                    .av_offset(name.to_string_lossy().into_owned(), offset);
+            }
+        }
+        if self.options.download_options.write_summary
+            && remuxed.iter().any(|f| f.av_offset.is_some())
+        {
+            self.stats
+                .write_summary(output.join("summary.json"))
+                .context("error writing summary")?;
+        }
+
+        Ok(())
+    }
+
+    /// Download statistics, updated while downloading
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+
+    /// Receiver of the events of the download from now on
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<DownloadEvent> {
+        self.events.subscribe()
+    }
+
+    /// Number of segments that failed to download or save
+    pub fn segments_failed(&self) -> u64 {
+        self.stats.segments_failed()
+    }
+
+    /// Number of streams whose playlist fetcher failed
+    pub fn streams_failed(&self) -> u64 {
+        self.stats.streams_failed()
+    }
+
+    /// Names and playlist urls of the assets of all HLS interstitials found during download
+    pub async fn interstitial_assets(&self) -> Result<Vec<(String, Url)>> {
+        #[derive(Deserialize)]
+        struct AssetList {
+            #[serde(rename = "ASSETS")]
+            assets: Vec<Asset>,
+        }
+        #[derive(Deserialize)]
+        struct Asset {
+            #[serde(rename = "URI")]
+            uri: String,
+        }
+
+        let base_url = self.streams.get(&Stream::Main).unwrap_or(&self.url);
+        let mut assets = Vec::new();
+        for interstitial in self.timeline.interstitials() {
+            let name = sanitize_file_name(&interstitial.id);
+            if let Some(uri) = interstitial.attributes.get("X-ASSET-URI") {
+                assets.push((name, make_absolute_url(base_url, uri)?));
+            } else if let Some(uri) = interstitial.attributes.get("X-ASSET-LIST") {
+                // Asset lists are json with the uri of each asset
+                let list_url = make_absolute_url(base_url, uri)?;
+                let resp = self.client.get(list_url.clone()).send().await?;
+                if !resp.status().is_success() {
+                    return Err(LivestreamDLError::NetworkRequest(resp).into());
+                }
+                let list: AssetList = serde_json::from_slice(&resp.bytes().await?)
+                    .with_context(|| format!("invalid asset list {}", list_url))?;
+                for (i, asset) in list.assets.iter().enumerate() {
+                    assets.push((
+                        format!("{}_{}", name, i),
+                        make_absolute_url(&list_url, &asset.uri)?,
+                    ));
+                }
+            }
+        }
+
+        Ok(assets)
+    }
+
+    /// Save cookies to disk if requested
+    pub fn save_cookies(&self) -> Result<()> {
+        if let (Some(jar), Some(path)) =
+            (&self.cookie_jar, &self.options.network_options.cookies_save)
+        {
+            event!(Level::INFO, "Saving cookies to {:?}", path);
+            jar.save_to_file(path)?;
         }
 
         Ok(())
     }
+
+    /// Download segment and save to disk if necessary. Also returns whether the segment doesn't look
+    /// like media after decrypting
+    async fn fetch_segment(
+        &self,
+        lru: InitCache,
+        stream: Stream,
+        segment: Segment,
+        encryption: Encryption,
+        init_encryption: Encryption,
+    ) -> Result<(SegmentIdData, bool)> {
+        let (client, token_refresher) = (&self.client, self.token_refresher.as_ref());
+        let (keys, decrypt) = (&self.keys, self.key_archive.is_none());
+
+        // Get initialization, usually prefetched already
+        let init_bytes = match &segment.initialization {
+            Some(i) => {
+                fetch_initialization(client, &lru, keys, decrypt, i, &init_encryption).await?
+            }
+            None => Vec::new(),
+        };
+
+        // Fetch segment, restarting transfers that are too slow
+        let mut slow_retries = 0;
+        let (data_bytes, final_url) = loop {
+            let result = match (segment.fetch(client).await, token_refresher) {
+                // Refresh tokens and try again if forbidden
+                (Err(e), Some(refresher)) if is_forbidden(&e) => {
+                    refresher
+                        .refresh()
+                        .await
+                        .context("error refreshing tokens")?;
+                    segment.fetch(client).await
+                }
+                (r, _) => r,
+            };
+            match result {
+                Err(e) if is_slow_transfer(&e) && slow_retries < MAX_SLOW_TRANSFER_RETRIES => {
+                    slow_retries += 1;
+                    event!(
+                        Level::WARN,
+                        "{}, retrying ({}/{})",
+                        e,
+                        slow_retries,
+                        MAX_SLOW_TRANSFER_RETRIES
+                    );
+                }
+                r => break r.context("error fetching segment")?,
+            }
+        };
+        // Encrypted segments are saved as is without their initialization when not decrypting,
+        // the initialization can't be decrypted along with them
+        let (bytes, corrupt) = if decrypt || matches!(encryption, Encryption::None) {
+            let (decrypt_data_bytes, corrupt) = encryption
+                .decrypt_verified(client, keys, &data_bytes)
+                .await?;
+            if corrupt {
+                event!(
+                    Level::WARN,
+                    "{} doesn't look like media after decrypting, the key or IV may be wrong",
+                    final_url
+                );
+            }
+
+            // Concat initialization and segment
+            let mut bytes = init_bytes;
+            bytes.extend_from_slice(&decrypt_data_bytes);
+            (bytes, corrupt)
+        } else {
+            encryption.fetch_key(client, keys).await?;
+            (data_bytes, false)
+        };
+
+        event!(
+            Level::DEBUG,
+            "Downloaded {} {}",
+            final_url,
+            segment
+                .data
+                .byte_range_string()
+                .unwrap_or_else(|| "".into())
+        );
+
+        Ok(((stream, segment, bytes), corrupt))
+    }
 }
 
-/// Download segment and save to disk if necessary
-async fn fetch_segment(
-    client: &HttpClient,
-    lru: Arc<Mutex<LruCache<RemoteData, Vec<u8>>>>,
-    stream: Stream,
-    segment: Segment,
-    encryption: Encryption,
-) -> Result<SegmentIdData> {
-    // Get initialization
-    let init_bytes = if let Some(ref i) = segment.initialization {
-        // Get cached initialization, otherwise fetch from network
-        let mut guard = lru.lock().await;
-        let data = guard.get(i).cloned();
-        match data {
-            Some(d) => d,
-            None => {
-                let d = i
-                    .fetch(client)
+/// Remux downloaded segments into output with remux_options if needed, then generate thumbnails
+/// and sprite sheets as requested. Returns the remuxed files
+async fn postprocess(
+    mut downloaded_segments: HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>>,
+    stream_info: &HashMap<Stream, StreamInfo>,
+    output: &Path,
+    options: &MuxOptions,
+    remux_options: &RemuxOptions,
+) -> Result<Vec<RemuxedFile>> {
+    // I-frames are not muxed into the output video
+    let iframe_segments = downloaded_segments.remove(&Stream::IFrames);
+
+    // Remux if necessary
+    let mut remuxed = Vec::new();
+    if !options.no_remux {
+        remuxed = remux(downloaded_segments, stream_info, output, remux_options).await?;
+        remove_partial_remux(&output.join("partial")).await?;
+        let output_paths: Vec<_> = remuxed.iter().map(|f| f.path.clone()).collect();
+
+        // Generate thumbnails if necessary
+        if options.thumbnail || options.thumbnail_interval.is_some() {
+            generate_thumbnails(&output_paths, options.thumbnail_interval).await;
+        }
+
+        // Hand the outputs to the user's command
+        if let Some(command) = &options.exec {
+            for path in &output_paths {
+                if let Err(e) = run_output_hook(command, path).await {
+                    event!(Level::WARN, "Command failed for {:?}: {:?}", path, e);
+                }
+            }
+        }
+
+        // Upload the outputs
+        if let Some(uploader) = upload::from_options(options)? {
+            for path in &output_paths {
+                uploader
+                    .upload(path, &upload_name(output, path))
                     .await
-                    .context("error fetching segment initialization")?
-                    .0;
-                guard.put(i.clone(), d.clone());
-                d
+                    .with_context(|| format!("error uploading {:?}", path))?;
+            }
+        }
+    }
+
+    // Generate sprite sheets if necessary
+    if let (Some(segments), true) = (iframe_segments, options.sprite_sheet) {
+        generate_sprite_sheets(segments, output).await?;
+    }
+
+    Ok(remuxed)
+}
+
+/// Build the HTTP client with cookies, retries and request statistics
+fn build_client(
+    url: &Url,
+    options: &NetworkOptions,
+    stats: &Stats,
+) -> Result<(HttpClient, Option<Arc<CookieJar>>)> {
+    // Create reqwest client
+    let mut client = Client::builder()
+        .timeout(Duration::from_secs(options.timeout))
+        .danger_accept_invalid_certs(options.insecure);
+
+    // Trust extra certificate authorities
+    for path in &options.ca_cert {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("error reading CA certificate {:?}", path))?;
+        let cert = Certificate::from_pem(&pem)
+            .with_context(|| format!("error parsing CA certificate {:?}", path))?;
+        client = client.add_root_certificate(cert);
+    }
+
+    // Imitate a browser, its TLS configuration replaces the one above
+    if let Some(browser) = options.impersonate {
+        client = impersonate(client, browser, &options.ca_cert)?;
+    }
+
+    // Bind to local address if needed
+    let client = if let Some(name) = &options.interface {
+        client.local_address(interface_address(name)?)
+    } else {
+        client.local_address(options.source_address)
+    };
+
+    // Pin hostnames to addresses
+    let client = options
+        .resolve
+        .iter()
+        .fold(client, |client, r| client.resolve(&r.host, r.addr));
+
+    // Add cookie provider if needed
+    let cookie_jar = match (&options.cookies, &options.cookies_save) {
+        (Some(p), _) => Some(CookieJar::parse_from_file(p, url)?),
+        (None, Some(p)) if p.is_file() => Some(CookieJar::parse_from_file(p, url)?),
+        (None, Some(_)) => Some(CookieJar::new()),
+        (None, None) if !options.cookie.is_empty() => Some(CookieJar::new()),
+        (None, None) => None,
+    };
+    let cookie_jar = match cookie_jar {
+        Some(jar) => {
+            for c in &options.cookie {
+                jar.add_header_str(c, url)?;
             }
+            Some(Arc::new(jar))
         }
+        None => None,
+    };
+    let client = if let Some(jar) = &cookie_jar {
+        client.cookie_provider(jar.clone())
+    } else {
+        client
+    }
+    .build()?;
+
+    // Set client retry on failure
+    let retry_policy = policies::ExponentialBackoff::builder()
+        .retry_bounds(Duration::from_secs(1), Duration::from_secs(10))
+        .backoff_exponent(2)
+        .build_with_max_retries(options.max_retries);
+
+    // Build client with middleware, counting requests before and after retrying. Local files
+    // are read before anything else
+    let mut client = ClientBuilder::new(client)
+        .with(LocalFiles::new(url, options.base_url.as_ref())?)
+        .with(stats.request_counter())
+        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+        .with(stats.attempt_counter());
+
+    // Record or replay every request attempt if requested
+    if let Some(dir) = &options.record_session {
+        client = client.with(SessionRecorder::new(dir)?);
+    } else if let Some(dir) = &options.replay_session {
+        client = client.with(SessionReplayer::load(dir)?);
+    }
+    let client = client.build();
+
+    // Build HttpClient
+    let query_pairs = if options.copy_query {
+        Some(url.query_pairs().collect::<Vec<_>>())
     } else {
-        Vec::new()
+        None
     };
+    let speed_limit = options.speed_limit.map(|bytes_per_sec| SpeedLimit {
+        bytes_per_sec,
+        time: Duration::from_secs(options.speed_time.max(1)),
+    });
+    let client = HttpClient::new(client, query_pairs)
+        .with_speed_limit(speed_limit)
+        .with_range_parts(options.range_parts.max(1));
 
-    // Fetch segment
-    let (data_bytes, final_url) = segment
-        .data
-        .fetch(client)
-        .await
-        .context("error fetching segment")?;
-    let decrypt_data_bytes = encryption.decrypt(client, &data_bytes).await?;
-
-    // Concat initialization and segment
-    let bytes = init_bytes
-        .into_iter()
-        .chain(decrypt_data_bytes.into_iter())
-        .collect();
-
-    event!(
-        Level::INFO,
-        "Downloaded {} {}",
-        final_url,
-        segment
-            .data
-            .byte_range_string()
-            .unwrap_or_else(|| "".into())
-    );
-
-    Ok((stream, segment, bytes))
+    Ok((client, cookie_jar))
+}
+
+/// Check if an error is a transfer aborted for being too slow
+fn is_slow_transfer(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<LivestreamDLError>(),
+        Some(LivestreamDLError::SlowTransfer { .. })
+    )
 }
 
 async fn save_segment<P>(
     (stream, mut segment, bytes): SegmentIdData,
-    downloaded_segments: &mut HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+    downloaded_segments: &mut HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>>,
     segments_directory: P,
-) -> Result<()>
+    file_names: &HashMap<Stream, String>,
+    packs: Option<&mut HashMap<Stream, PackWriter>>,
+    compress_text: bool,
+    fsync: bool,
+) -> Result<SegmentFile>
 where
     P: AsRef<Path>,
 {
-    // Detect segment format
-    segment.format = MediaFormat::detect(bytes.clone()).await?;
-
-    // Create directory if neeeded
-    fs::create_dir_all(segments_directory.as_ref()).await?;
-
-    // Save segment to disk
-    let file_path = segments_directory.as_ref().join(format!(
-        "segment_{}_{}.{}",
-        stream,
-        segment.id(),
-        segment.format.extension()
-    ));
-    event!(Level::TRACE, "saving to {:?}", &file_path);
-    let mut file = fs::File::create(&file_path).await?;
-    file.write_all(&bytes).await?;
+    // Each stream has its own directory, create it if needed
+    let stream_directory = segments_directory.as_ref().join(&file_names[&stream]);
+    fs::create_dir_all(&stream_directory).await?;
+
+    // Detect segment format and compress subtitles, they are small and many but compress well.
+    // Formats only ffprobe detects are never subtitles
+    let (format, gzip, bytes) = worker_pool::run(move || -> std::io::Result<_> {
+        let format = MediaFormat::sniff(&bytes);
+        if !(compress_text && format == Some(MediaFormat::WebVtt)) {
+            return Ok((format, false, bytes));
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&bytes)?;
+        Ok((format, true, encoder.finish()?))
+    })
+    .await?
+    .map_err(|e| LivestreamDLError::DiskWrite {
+        path: stream_directory.clone(),
+        source: e,
+    })?;
+    segment.format = match format {
+        Some(f) => f,
+        None => MediaFormat::detect(&bytes).await?,
+    };
+
+    // Append segment to the stream's pack file, or save it to its own file
+    let mut file = match packs {
+        Some(packs) => {
+            let pack = packs
+                .entry(stream.clone())
+                .or_insert_with(|| PackWriter::new(stream_directory.clone()));
+            pack.append(&segment.id(), &bytes, fsync)
+                .await
+                .map_err(|e| LivestreamDLError::DiskWrite {
+                    path: stream_directory.clone(),
+                    source: e,
+                })?
+        }
+        None => {
+            let mut file_name = format!("segment_{}.{}", segment.id(), segment.format.extension());
+            if gzip {
+                file_name.push_str(".gz");
+            }
+            let file_path = stream_directory.join(file_name);
+            event!(Level::TRACE, "saving to {:?}", &file_path);
+            let path = file_path.clone();
+            worker_pool::run(move || write_atomic_blocking(&path, &bytes, fsync))
+                .await?
+                .map_err(|e| LivestreamDLError::DiskWrite {
+                    path: file_path.clone(),
+                    source: e,
+                })?;
+            file_path.into()
+        }
+    };
+    file.gzip = gzip;
 
     // Remember path
     downloaded_segments
         .entry(stream)
         .or_default()
-        .push((segment, file_path));
+        .push((segment, file.clone()));
 
-    Ok(())
+    Ok(file)
 }
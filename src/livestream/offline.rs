@@ -0,0 +1,322 @@
+use std::collections::{BinaryHeap, HashMap};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::fs;
+use tracing::{event, Level};
+
+use super::master_playlist::with_session_title;
+use super::media_format::PROBE_SIZE;
+use super::packfile::read_index;
+use super::remote_data::RemoteData;
+use super::utils::write_atomic;
+use super::{postprocess, MediaFormat, Segment, SegmentFile, Stream, StreamInfo};
+use crate::cli::MuxOptions;
+use crate::mux::RemuxOptions;
+
+/// Name of the manifest in the segments directory
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Magic bytes of gzip compressed data
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Describes the streams of a download and where their segments are saved, so the segments
+/// directory can be remuxed later
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Playlist URL the download was started from
+    source: Option<String>,
+    /// Start of the download in RFC 3339 format
+    started: Option<String>,
+    streams: Vec<ManifestStream>,
+    /// Values of the master playlist's EXT-X-SESSION-DATA by DATA-ID
+    #[serde(default)]
+    session_data: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestStream {
+    /// Directory of the stream's segments, relative to the segments directory
+    directory: String,
+    stream: Stream,
+    #[serde(default)]
+    info: StreamInfo,
+}
+
+impl Manifest {
+    pub fn new(
+        source: &Url,
+        started: OffsetDateTime,
+        file_names: &HashMap<Stream, String>,
+        stream_info: &HashMap<Stream, StreamInfo>,
+        session_data: &HashMap<String, String>,
+    ) -> Self {
+        let streams = file_names
+            .iter()
+            .map(|(stream, directory)| ManifestStream {
+                directory: directory.clone(),
+                stream: stream.clone(),
+                info: stream_info.get(stream).cloned().unwrap_or_default(),
+            })
+            .collect();
+
+        Self {
+            source: Some(source.to_string()),
+            started: started.format(&Rfc3339).ok(),
+            streams,
+            session_data: session_data.clone(),
+        }
+    }
+
+    /// Write the manifest into the segments directory
+    pub async fn write(&self, segments_dir: &Path, fsync: bool) -> Result<()> {
+        fs::create_dir_all(segments_dir).await?;
+        let data = serde_json::to_vec_pretty(self)?;
+        write_atomic(&segments_dir.join(MANIFEST_FILE), &data, fsync).await?;
+
+        Ok(())
+    }
+
+    /// Read the manifest in the segments directory, None if there is none
+    async fn read(segments_dir: &Path) -> Result<Option<Self>> {
+        let path = segments_dir.join(MANIFEST_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let data = fs::read(&path).await?;
+        let manifest = serde_json::from_slice(&data)
+            .with_context(|| format!("invalid manifest {:?}", path))?;
+
+        Ok(Some(manifest))
+    }
+
+    /// Guess the streams from the names of the stream directories, for downloads without a
+    /// manifest
+    async fn from_directories(segments_dir: &Path) -> Result<Self> {
+        let mut streams = Vec::new();
+        let mut entries = fs::read_dir(segments_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            let directory = entry.file_name().to_string_lossy().into_owned();
+            match stream_from_file_name(&directory) {
+                Some(stream) => streams.push(ManifestStream {
+                    directory,
+                    stream,
+                    info: StreamInfo::default(),
+                }),
+                None => event!(
+                    Level::WARN,
+                    "Skipping unrecognized stream directory {:?}",
+                    directory
+                ),
+            }
+        }
+
+        Ok(Self {
+            source: None,
+            started: None,
+            streams,
+            session_data: HashMap::new(),
+        })
+    }
+}
+
+/// Stream saved in a directory named with Stream::file_name(). Names and languages can't be told
+/// apart, so the rest of the name is used as the stream name
+fn stream_from_file_name(file_name: &str) -> Option<Stream> {
+    let stream = match file_name.split_once('_') {
+        None if file_name == "main" => Stream::Main,
+        None if file_name == "iframes" => Stream::IFrames,
+        Some(("video", name)) => Stream::Video {
+            name: name.to_owned(),
+            lang: None,
+            disposition: Default::default(),
+        },
+        Some(("audio", name)) => Stream::Audio {
+            name: name.to_owned(),
+            lang: None,
+            disposition: Default::default(),
+        },
+        Some(("subtitle", name)) => Stream::Subtitle {
+            name: name.to_owned(),
+            lang: None,
+            disposition: Default::default(),
+        },
+        _ => return None,
+    };
+
+    Some(stream)
+}
+
+/// Segments saved by an earlier download and what is known about them
+#[derive(Debug)]
+pub struct SavedSegments {
+    pub source: Option<Url>,
+    pub started: Option<OffsetDateTime>,
+    pub segments: HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>>,
+    pub stream_info: HashMap<Stream, StreamInfo>,
+    pub session_data: HashMap<String, String>,
+}
+
+impl SavedSegments {
+    /// Find the segments saved in a segments directory. Streams are read from the manifest if
+    /// there is one, otherwise guessed from directory names
+    pub async fn read(segments_dir: &Path) -> Result<Self> {
+        let segments_dir = fs::canonicalize(segments_dir)
+            .await
+            .with_context(|| format!("error opening segments directory {:?}", segments_dir))?;
+        let manifest = match Manifest::read(&segments_dir).await? {
+            Some(m) => m,
+            None => {
+                event!(
+                    Level::WARN,
+                    "No manifest found, guessing streams from directory names"
+                );
+                Manifest::from_directories(&segments_dir).await?
+            }
+        };
+
+        // Find the saved segments of each stream
+        let mut segments = HashMap::new();
+        let mut stream_info = HashMap::new();
+        for s in manifest.streams {
+            let dir = segments_dir.join(&s.directory);
+            if !dir.is_dir() {
+                continue;
+            }
+            let stream_segments = scan_segments(&dir)
+                .await
+                .with_context(|| format!("error reading segments in {:?}", dir))?;
+            event!(
+                Level::INFO,
+                "Found {} segments of {} stream",
+                stream_segments.len(),
+                s.stream
+            );
+            if !stream_segments.is_empty() {
+                segments.insert(s.stream.clone(), stream_segments);
+                stream_info.insert(s.stream, s.info);
+            }
+        }
+
+        Ok(Self {
+            source: manifest.source.as_deref().and_then(|u| Url::parse(u).ok()),
+            started: manifest
+                .started
+                .as_deref()
+                .and_then(|t| OffsetDateTime::parse(t, &Rfc3339).ok()),
+            segments,
+            stream_info,
+            session_data: manifest.session_data,
+        })
+    }
+}
+
+/// Playlist URL of an earlier download in output, read from its manifest
+pub fn saved_source(output: &Path) -> Result<Url> {
+    let path = output.join("segments").join(MANIFEST_FILE);
+    let data =
+        std::fs::read(&path).with_context(|| format!("error reading manifest {:?}", path))?;
+    let manifest: Manifest =
+        serde_json::from_slice(&data).with_context(|| format!("invalid manifest {:?}", path))?;
+    let source = manifest
+        .source
+        .ok_or_else(|| anyhow::anyhow!("no playlist URL in manifest {:?}", path))?;
+
+    Ok(Url::parse(&source)?)
+}
+
+/// Remux the segments of an earlier download in output without downloading anything
+pub async fn remux_download(output: &Path, options: &MuxOptions) -> Result<()> {
+    let saved = SavedSegments::read(&output.join("segments")).await?;
+    if saved.segments.is_empty() {
+        return Err(anyhow::anyhow!("no segments found in {:?}", output));
+    }
+
+    // Segment durations are not saved
+    if options.split_every.is_some() {
+        event!(
+            Level::WARN,
+            "Segment durations are unknown, --split-every is ignored"
+        );
+    }
+
+    let options = with_session_title(options, &saved.session_data);
+    let remux_options = RemuxOptions::new(&options, false, saved.source.as_ref(), saved.started)?;
+    postprocess(
+        saved.segments,
+        &saved.stream_info,
+        output,
+        &options,
+        &remux_options,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Find the segments saved in a stream directory, either in pack files or a file per segment
+async fn scan_segments(dir: &Path) -> Result<BinaryHeap<(Segment, SegmentFile)>> {
+    let mut files = Vec::new();
+    if dir.join("index.tsv").is_file() {
+        for (id, mut file) in read_index(dir).await? {
+            // The index doesn't record compression or format
+            file.gzip = file.read_head(GZIP_MAGIC.len() as u64)? == GZIP_MAGIC;
+            let format = MediaFormat::detect(&file.read_head(PROBE_SIZE as u64)?).await?;
+            files.push((id, format, file));
+        }
+    } else {
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            // Skip temporary files left by interrupted writes
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".tmp") {
+                continue;
+            }
+            let (name, gzip) = match name.strip_suffix(".gz") {
+                Some(n) => (n, true),
+                None => (name.as_str(), false),
+            };
+            let (id, ext) = match name
+                .strip_prefix("segment_")
+                .and_then(|n| n.split_once('.'))
+            {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut file = SegmentFile::from(entry.path());
+            file.gzip = gzip;
+            files.push((id.to_owned(), MediaFormat::from_extension(ext), file));
+        }
+    }
+
+    let mut segments = BinaryHeap::new();
+    for (id, format, file) in files {
+        let (discon_seq, seq) = match Segment::parse_id(&id) {
+            Some(x) => x,
+            None => continue,
+        };
+        let url = Url::from_file_path(&file.path)
+            .map_err(|_| anyhow::anyhow!("invalid segment path {:?}", file.path))?;
+        let segment = Segment {
+            data: RemoteData::new(url, None),
+            discon_seq,
+            seq,
+            duration: Duration::ZERO,
+            format,
+            initialization: None,
+            group: None,
+            program_date_time: None,
+        };
+        segments.push((segment, file));
+    }
+
+    Ok(segments)
+}
@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::time::Instant;
+
+use super::clock::SharedClock;
+
+/// Maximum random delay added to each segment request, as a fraction of the segment duration
+const JITTER: f64 = 0.1;
+
+/// Paces segment requests of a stream to roughly real-time playback rate, like a video player
+/// would, instead of downloading all available segments at once
+#[derive(Debug)]
+pub struct Pacer {
+    clock: SharedClock,
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(clock: SharedClock) -> Self {
+        Self { clock, next: None }
+    }
+
+    /// Wait until the next segment should be requested, then reserve duration of playback time
+    /// for it
+    ///
+    /// The first request is delayed by a random fraction of the segment duration, so requests of
+    /// different streams are spread across the segment duration
+    pub async fn wait(&mut self, duration: Duration) {
+        let now = self.clock.now();
+        let jitter = rand::thread_rng().gen_range(0.0..1.0);
+        let (scheduled, delay) = match self.next {
+            // Don't burst to catch up if requests fell behind schedule
+            Some(next) => (next.max(now), duration.mul_f64(jitter * JITTER)),
+            None => (now, duration.mul_f64(jitter)),
+        };
+
+        self.clock.sleep_until(scheduled + delay).await;
+        self.next = Some(scheduled + duration);
+    }
+}
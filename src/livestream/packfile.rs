@@ -0,0 +1,123 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use super::SegmentFile;
+
+/// Size after which a new pack file is started
+const MAX_PACK_SIZE: u64 = 1 << 30;
+
+/// Appends the segments of a stream to rolling pack files instead of a file per segment. Each
+/// segment's pack file, offset, length, and id are recorded in an index
+#[derive(Debug)]
+pub struct PackWriter {
+    dir: PathBuf,
+    pack: Option<(PathBuf, fs::File)>,
+    pack_count: u32,
+    size: u64,
+    index: Option<fs::File>,
+}
+
+impl PackWriter {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            pack: None,
+            pack_count: 0,
+            size: 0,
+            index: None,
+        }
+    }
+
+    /// Append a segment, returns where it was stored
+    pub async fn append(&mut self, id: &str, data: &[u8], fsync: bool) -> io::Result<SegmentFile> {
+        fs::create_dir_all(&self.dir).await?;
+
+        // Start a new pack file if needed
+        if self.pack.is_none() || self.size + data.len() as u64 > MAX_PACK_SIZE {
+            let path = self.dir.join(format!("pack_{:04}.bin", self.pack_count));
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            self.size = file.metadata().await?.len();
+            self.pack = Some((path, file));
+            self.pack_count += 1;
+        }
+        let (path, file) = self.pack.as_mut().unwrap();
+
+        // Append data before the index entry, so the index never points past the data
+        let offset = self.size;
+        file.write_all(data).await?;
+        if fsync {
+            file.sync_data().await?;
+        }
+        self.size += data.len() as u64;
+
+        let index = match &mut self.index {
+            Some(i) => i,
+            None => self.index.insert(open_index(&self.dir).await?),
+        };
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let entry = format!("{}\t{}\t{}\t{}\n", file_name, offset, data.len(), id);
+        index.write_all(entry.as_bytes()).await?;
+        if fsync {
+            index.sync_data().await?;
+        }
+
+        Ok(SegmentFile {
+            path: path.clone(),
+            range: Some((offset, data.len() as u64)),
+            gzip: false,
+        })
+    }
+}
+
+/// Open the index in dir for appending. A line left incomplete by an interrupted write is ended
+/// first, so the next entry isn't lost with it
+async fn open_index(dir: &Path) -> io::Result<fs::File> {
+    let path = dir.join("index.tsv");
+    let incomplete = match fs::read(&path).await {
+        Ok(data) => !data.is_empty() && !data.ends_with(b"\n"),
+        Err(_) => false,
+    };
+    let mut index = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await?;
+    if incomplete {
+        index.write_all(b"\n").await?;
+    }
+
+    Ok(index)
+}
+
+/// Read the index of the pack files in dir, returns the id and location of each segment. Lines
+/// left incomplete by an interrupted write are skipped
+pub async fn read_index(dir: &Path) -> io::Result<Vec<(String, SegmentFile)>> {
+    let index = fs::read_to_string(dir.join("index.tsv")).await?;
+    let entries = index
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let file_name = fields.next()?;
+            let offset = fields.next()?.parse().ok()?;
+            let len = fields.next()?.parse().ok()?;
+            let id = fields.next()?;
+            Some((
+                id.to_owned(),
+                SegmentFile {
+                    path: dir.join(file_name),
+                    range: Some((offset, len)),
+                    gzip: false,
+                },
+            ))
+        })
+        .collect();
+
+    Ok(entries)
+}
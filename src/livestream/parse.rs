@@ -0,0 +1,145 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use m3u8_rs::{MasterPlaylist, MediaPlaylist, Playlist};
+use tracing::{event, Level};
+
+/// UTF-8 byte order mark some servers put before the playlist
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// First line of every playlist
+const HEADER: &[u8] = b"#EXTM3U";
+
+/// LHLS tag announcing a segment that is still being produced
+const PREFETCH_TAG: &[u8] = b"#EXT-X-PREFETCH:";
+
+/// LHLS tag marking the next prefetch segment as a discontinuity
+const PREFETCH_DISCONTINUITY_TAG: &[u8] = b"#EXT-X-PREFETCH-DISCONTINUITY";
+
+/// Maximum number of broken lines skipped in a playlist before giving up
+const MAX_SKIPPED_LINES: usize = 100;
+
+/// Whether broken playlist lines are skipped, set once at startup
+static LENIENT: AtomicBool = AtomicBool::new(false);
+
+/// Kind of playlist to parse a playlist as, overriding detection
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PlaylistKind {
+    Master,
+    Media,
+}
+
+/// Clean up playlist data that breaks m3u8-rs: strip a byte order mark and leading whitespace,
+/// and fix the casing of the #EXTM3U header or add it if missing
+pub fn preprocess(bytes: &[u8]) -> Vec<u8> {
+    let bytes = bytes.strip_prefix(BOM).unwrap_or(bytes);
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let mut bytes = bytes[start..].to_vec();
+
+    match bytes.get(..HEADER.len()) {
+        Some(h) if h.eq_ignore_ascii_case(HEADER) => {
+            bytes[..HEADER.len()].copy_from_slice(HEADER);
+        }
+        _ => {
+            let mut with_header = HEADER.to_vec();
+            with_header.push(b'\n');
+            with_header.extend(bytes);
+            bytes = with_header;
+        }
+    }
+
+    bytes
+}
+
+/// Skip playlist lines that fail to parse instead of failing the whole playlist
+pub fn set_lenient(lenient: bool) {
+    LENIENT.store(lenient, Ordering::Relaxed);
+}
+
+/// Parse a master or media playlist, detecting which one it is unless kind is given
+pub fn parse_playlist(bytes: &[u8], kind: Option<PlaylistKind>) -> Option<Playlist> {
+    let bytes = preprocess(bytes);
+    let kind = kind.unwrap_or(if m3u8_rs::is_master_playlist(&bytes) {
+        PlaylistKind::Master
+    } else {
+        PlaylistKind::Media
+    });
+    match kind {
+        PlaylistKind::Master => parse_master_playlist(&bytes).map(Playlist::MasterPlaylist),
+        PlaylistKind::Media => parse_media_playlist(&bytes).map(Playlist::MediaPlaylist),
+    }
+}
+
+/// Parse a master playlist
+pub fn parse_master_playlist(bytes: &[u8]) -> Option<MasterPlaylist> {
+    parse_with(bytes, |b| m3u8_rs::parse_master_playlist_res(b).ok())
+}
+
+/// Parse a media playlist
+pub fn parse_media_playlist(bytes: &[u8]) -> Option<MediaPlaylist> {
+    parse_with(&expand_prefetch(bytes), |b| {
+        m3u8_rs::parse_media_playlist_res(b).ok()
+    })
+}
+
+/// Turn LHLS #EXT-X-PREFETCH:<uri> tags into segments marked with a #EXT-X-PREFETCH tag, and
+/// #EXT-X-PREFETCH-DISCONTINUITY into a regular discontinuity. m3u8-rs drops tags after the last
+/// segment, which is where prefetch tags are
+fn expand_prefetch(bytes: &[u8]) -> Vec<u8> {
+    let mut expanded = Vec::with_capacity(bytes.len());
+    for line in bytes.split_inclusive(|b| *b == b'\n') {
+        if let Some(uri) = line.strip_prefix(PREFETCH_TAG) {
+            expanded.extend_from_slice(b"#EXT-X-PREFETCH\n");
+            expanded.extend_from_slice(uri);
+        } else if line.starts_with(PREFETCH_DISCONTINUITY_TAG) {
+            expanded.extend_from_slice(b"#EXT-X-DISCONTINUITY\n");
+        } else {
+            expanded.extend_from_slice(line);
+        }
+    }
+
+    expanded
+}
+
+/// Preprocess and parse a playlist with parser. In lenient mode, lines that make parsing fail are
+/// skipped one at a time until the rest parses
+fn parse_with<T>(bytes: &[u8], parser: fn(&[u8]) -> Option<T>) -> Option<T> {
+    let bytes = preprocess(bytes);
+    if let Some(p) = parser(&bytes) {
+        return Some(p);
+    }
+    if !LENIENT.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let mut lines: Vec<&[u8]> = bytes.split(|b| *b == b'\n').collect();
+    for _ in 0..MAX_SKIPPED_LINES {
+        // Find the first line that makes parsing fail, the header alone always parses
+        let (mut good, mut bad) = (1, lines.len());
+        while bad - good > 1 {
+            let mid = (good + bad) / 2;
+            if parser(&lines[..mid].join(&b'\n')).is_some() {
+                good = mid;
+            } else {
+                bad = mid;
+            }
+        }
+        if bad <= good {
+            return None;
+        }
+
+        let line = lines.remove(bad - 1);
+        event!(
+            Level::WARN,
+            "Skipping playlist line that failed to parse: {}",
+            String::from_utf8_lossy(line).trim_end()
+        );
+        if let Some(p) = parser(&lines.join(&b'\n')) {
+            return Some(p);
+        }
+    }
+
+    None
+}
@@ -1,134 +1,1219 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
 use futures::channel::mpsc;
-use reqwest::Url;
-use tokio::time;
+use futures::SinkExt;
+use m3u8_rs::{ByteRange, ExtTag, Key, MediaPlaylist, MediaSegment};
+use reqwest::header::{self, HeaderValue};
+use reqwest::{Response, StatusCode, Url};
 use tracing::{event, Level};
 
+use super::backfill::{probe, UrlPattern};
+use super::cadence::Cadence;
+use super::clock::{Clock, SharedClock};
+use super::daterange::Timeline;
 use super::http_client::HttpClient;
+use super::master_playlist::MasterPlaylistResolver;
+use super::pacer::Pacer;
+use super::parse::parse_media_playlist;
+use super::prefetch::Prefetcher;
+use super::queue::{QueuedSegment, SlidingWindow};
 use super::remote_data::RemoteData;
-use super::utils::make_absolute_url;
-use super::{Encryption, Segment, Stopper, Stream};
+use super::session::response_url;
+use super::stats::Stats;
+use super::utils::{
+    make_absolute_url, parse_attribute_list, parse_program_date_time, playlist_end_time,
+};
+use super::{DownloadEvent, Encryption, Events, Segment, Stopper, Stream};
+use crate::cli::Dedupe;
 use crate::error::LivestreamDLError;
 use crate::livestream::MediaFormat;
 
+/// Largest group of contiguous byte ranges fetched in one request
+const MAX_RANGE_GROUP_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Wait time before the first playlist fetch retry, doubled for each following retry
+const MIN_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Maximum wait time between playlist fetch retries
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// State shared by all m3u8 fetcher tasks
+#[derive(Clone)]
+pub struct FetcherContext {
+    pub client: HttpClient,
+    pub notify_stop: Stopper,
+    pub master_resolver: Option<Arc<MasterPlaylistResolver>>,
+    pub window: SlidingWindow,
+    pub tx: mpsc::Sender<QueuedSegment>,
+    pub playlist_retries: u32,
+    /// Number of reloads in a row without new segments before giving up, never if None
+    pub max_stalled_reloads: Option<u32>,
+    pub timeline: Timeline,
+    pub stats: Stats,
+    pub events: Events,
+    pub dedupe: Dedupe,
+    pub follow_restarts: Option<Duration>,
+    pub backfill: u64,
+    pub clock: SharedClock,
+    /// Fetches keys and initializations as soon as they are found
+    pub prefetcher: Prefetcher,
+}
+
 /// Periodically fetch m3u8 media playlist and send new segments to download task
 pub async fn m3u8_fetcher(
-    client: HttpClient,
-    notify_stop: Stopper,
-    tx: mpsc::UnboundedSender<(Stream, Segment, Encryption)>,
+    ctx: FetcherContext,
+    mut pacer: Option<Pacer>,
     stream: Stream,
-    url: Url,
+    mut url: Url,
 ) -> Result<()> {
-    let mut last_seg = None;
+    let FetcherContext {
+        client,
+        notify_stop,
+        master_resolver,
+        window,
+        mut tx,
+        playlist_retries,
+        max_stalled_reloads,
+        timeline,
+        stats,
+        events,
+        dedupe,
+        follow_restarts,
+        backfill,
+        clock,
+        prefetcher,
+    } = ctx;
+    let mut last_seg: Option<(u64, u64)> = None;
     let mut cur_init = None;
+    let mut stalled_reloads = 0;
+    let mut cadence = Cadence::default();
+
+    // Delta update state, discontinuity sequence and key of segments in the last playlist are
+    // kept to continue from segments a delta update skipped
+    let mut can_skip_until = None;
+    let mut last_reload = None;
+    let mut known: BTreeMap<u64, (u64, Option<Key>)> = BTreeMap::new();
+
+    // Sequence numbers and segments of the last playlist, and offset added to discontinuity
+    // sequence numbers after the server restarted numbering
+    let mut last_playlist: Option<LastPlaylist> = None;
+    let mut discon_epoch = 0;
+
+    // With URL dedupe, segments are numbered in the order they were first found instead of by
+    // media sequence number, which may be renumbered when the playlist is regenerated
+    let mut seen_urls: HashMap<RemoteData, u64> = HashMap::new();
+    let mut next_seq = None;
+
+    // Full playlists are always requested from the given url
+    url = with_skip_param(&url, None);
+    let original_url = url.clone();
+    let reloader = PlaylistReloader {
+        client: &client,
+        master_resolver: master_resolver.as_deref(),
+        notify_stop: &notify_stop,
+        clock: clock.as_ref(),
+        stream: &stream,
+    };
+
+    // Set when the playlist ended and a new playlist for the same stream was found
+    let mut restarted = false;
+
+    // Full playlists are requested conditionally so unchanged playlists aren't sent again
+    let mut cache = ConditionalCache::default();
+
+    // Blocking reload state, the media sequence number of the next segment to wait for and how
+    // long to wait for it. Blocking reloads are disabled for good once they fail
+    let mut blocking_reload = false;
+    let mut block_failed = false;
+    let mut next_msn: Option<u64> = None;
+    let mut block_timeout = Duration::ZERO;
 
     loop {
         // Fetch playlist
-        let now = time::Instant::now();
+        let mut now = clock.now();
         let mut found_new_segments = false;
+        let mut new_durations = Vec::new();
 
-        event!(Level::TRACE, "Fetching {}", url.as_str());
-        let resp = client.get(url.clone()).send().await?;
-        let final_url = resp.url().to_string();
-        if !resp.status().is_success() {
-            return Err(LivestreamDLError::NetworkRequest(resp).into());
+        // Request a delta update if the server supports them and the last reload is recent
+        // enough, otherwise the full playlist
+        let delta = match (can_skip_until, last_reload) {
+            (Some(skip_until), Some(last)) => now.duration_since(last) < skip_until / 2,
+            _ => false,
+        };
+        let mut media_playlist = None;
+
+        // Ask the server to hold the request until the next segment is available if it
+        // supports blocking reloads
+        let blocked = match next_msn.filter(|_| blocking_reload) {
+            Some(msn) => {
+                let block_url = with_query_param(
+                    &with_skip_param(&url, delta.then_some("YES")),
+                    "_HLS_msn",
+                    Some(&msn.to_string()),
+                );
+                let failure = tokio::select! {
+                    biased;
+
+                    _ = notify_stop.wait() => return Ok(()),
+
+                    r = fetch_media_playlist(&client, &block_url) => match r {
+                        Ok(p) if skip_resumable(&p, &known) => {
+                            media_playlist = Some(p);
+                            None
+                        }
+                        Ok(_) => {
+                            event!(
+                                Level::DEBUG,
+                                "{} delta update skipped unknown segments, fetching full playlist",
+                                stream
+                            );
+                            None
+                        }
+                        Err(e) => Some(e.to_string()),
+                    },
+
+                    _ = clock.sleep(block_timeout) => {
+                        Some(format!("no response after {:?}", block_timeout))
+                    }
+                };
+                if let Some(e) = failure {
+                    event!(
+                        Level::WARN,
+                        "Blocking reload of {} playlist failed, falling back to polling: {}",
+                        stream,
+                        e
+                    );
+                    block_failed = true;
+                }
+                now = clock.now();
+                true
+            }
+            None => false,
+        };
+
+        if delta && !blocked {
+            match fetch_media_playlist(&client, &with_skip_param(&url, Some("YES"))).await {
+                Ok(p) if skip_resumable(&p, &known) => media_playlist = Some(p),
+                Ok(_) => event!(
+                    Level::DEBUG,
+                    "{} delta update skipped unknown segments, fetching full playlist",
+                    stream
+                ),
+                Err(e) => event!(
+                    Level::DEBUG,
+                    "Failed to fetch {} delta update, fetching full playlist: {}",
+                    stream,
+                    e
+                ),
+            }
+        }
+        let media_playlist = match media_playlist {
+            Some(p) => p,
+            None => match reloader
+                .fetch_retrying(&mut url, &mut cache, playlist_retries)
+                .await
+            {
+                Ok(Some(p)) => p,
+                // Stopped while waiting to retry
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    event!(Level::ERROR, "Giving up on {} playlist: {}", stream, e);
+                    return Err(e);
+                }
+            },
+        };
+        last_reload = Some(now);
+        stats.playlist_reloaded(&stream);
+        events.send(DownloadEvent::PlaylistRefreshed {
+            stream: stream.clone(),
+            media_sequence: media_playlist.media_sequence,
+            segments: media_playlist.segments.len(),
+            ended: media_playlist.end_list,
+        });
+        can_skip_until = server_control(&media_playlist, "CAN-SKIP-UNTIL")
+            .and_then(|v| v.parse::<f32>().ok())
+            .map(|v| Duration::from_secs_f32(v.max(0.0)));
+        blocking_reload = !block_failed
+            && !media_playlist.end_list
+            && server_control(&media_playlist, "CAN-BLOCK-RELOAD").as_deref() == Some("YES");
+        // Servers must answer blocking reloads within three target durations
+        block_timeout = Duration::from_secs_f32(media_playlist.target_duration.max(1.0) * 3.0);
+
+        // Track how far behind live the download is
+        if let Some(edge) = playlist_end_time(&media_playlist) {
+            stats.live_edge(&stream, edge);
+        }
+
+        // If the playlist's sequence numbers went backwards, the server restarted its numbering.
+        // Continue in a new discontinuity after the last found segment instead of skipping every
+        // segment as already downloaded. A restarted stream always continues in a new
+        // discontinuity
+        if restarted
+            || last_playlist
+                .as_ref()
+                .is_some_and(|p| p.is_reset(&media_playlist))
+        {
+            let next_discon = last_seg.map_or(0, |(d, _)| d + 1);
+            discon_epoch = next_discon.saturating_sub(media_playlist.discontinuity_sequence);
+            if !restarted {
+                event!(
+                    Level::WARN,
+                    "{} playlist media sequence reset from {} to {}, continuing in discontinuity {}",
+                    stream,
+                    last_playlist.as_ref().map_or(0, |p| p.media_sequence),
+                    media_playlist.media_sequence,
+                    media_playlist.discontinuity_sequence + discon_epoch,
+                );
+            }
+            known.clear();
+            window.reset(&stream);
+            restarted = false;
         }
-        let bytes = resp.bytes().await?;
+        last_playlist = Some(LastPlaylist::new(&media_playlist));
+
+        // Find queued segments that were removed from the playlist
+        let next_seq = next_seq.get_or_insert(media_playlist.media_sequence);
+        let window_start = match dedupe {
+            Dedupe::Url => media_playlist
+                .segments
+                .first()
+                .map(|s| dedupe_key(&url, s))
+                .transpose()?
+                .and_then(|k| seen_urls.get(&k).copied())
+                .unwrap_or(*next_seq),
+            _ => media_playlist.media_sequence,
+        };
+        window.advance(&stream, window_start);
 
-        let media_playlist = m3u8_rs::parse_media_playlist(&bytes)
-            .map_err(|_| LivestreamDLError::ParseM3u8(final_url))?
-            .1;
+        // Segments replaced by EXT-X-SKIP continue from the last playlist
+        let first_seq = media_playlist.media_sequence + skipped_segments(&media_playlist);
+        let (discon_base, mut cur_key) = match known.get(&first_seq) {
+            Some((d, k)) if first_seq > media_playlist.media_sequence => {
+                let first_discon = media_playlist.segments.first().map(|s| s.discontinuity);
+                (d - first_discon.unwrap_or(false) as u64, k.clone())
+            }
+            _ => (media_playlist.discontinuity_sequence + discon_epoch, None),
+        };
+        known = known.split_off(&media_playlist.media_sequence);
+
+        // Try to recover segments missed before joining or during an outage
+        if backfill > 0 && dedupe != Dedupe::Url && first_seq == media_playlist.media_sequence {
+            Backfill {
+                client: &client,
+                stream: &stream,
+                limit: backfill,
+            }
+            .run(&media_playlist, &url, discon_base, last_seg, &mut tx)
+            .await?;
+        }
 
         // Loop through media segments
+        let byte_ranges = byte_range_groups(&url, &media_playlist.segments)?;
         let mut discon_offset = 0;
-        let mut encryption = Encryption::None;
-        for (seq, segment) in (media_playlist.media_sequence..).zip(media_playlist.segments.iter())
+        let mut window_offset = Duration::ZERO;
+        let mut program_date_time = None;
+        for ((seq, segment), (byte_range, group)) in (first_seq..)
+            .zip(media_playlist.segments.iter())
+            .zip(byte_ranges)
         {
             // Calculate segment discontinuity
             if segment.discontinuity {
                 discon_offset += 1;
             }
-            let discon_seq = media_playlist.discontinuity_sequence + discon_offset;
+            let discon_seq = discon_base + discon_offset;
 
-            // Skip segment if already downloaded
-            if let Some(s) = last_seg {
-                if s >= (discon_seq, seq) {
-                    continue;
-                }
+            // Prefetch segments are still being produced and have no duration yet, assume the
+            // target duration. They are downloaded as the server produces them
+            let prefetch = unknown_tags(segment, "X-PREFETCH").next().is_some();
+            let duration = if prefetch {
+                Duration::from_secs_f32(media_playlist.target_duration.max(0.0))
+            } else {
+                Duration::from_secs_f32(segment.duration.max(0.0))
+            };
+
+            // Estimate when segment slides out of a live playlist
+            window_offset += duration;
+            let deadline = (!media_playlist.end_list).then(|| now + window_offset);
+
+            // Track wall clock time of segment, and date ranges starting at it
+            program_date_time = segment
+                .program_date_time
+                .as_deref()
+                .and_then(parse_program_date_time)
+                .or(program_date_time);
+            // m3u8-rs only recognizes a misspelled tag, so correct ones end up in unknown tags
+            let dateranges = segment
+                .daterange
+                .iter()
+                .chain(unknown_tags(segment, "X-DATERANGE").filter_map(|t| t.rest.as_ref()));
+            for daterange in dateranges {
+                timeline.add(daterange);
             }
+            let segment_pdt = program_date_time;
+            program_date_time = program_date_time.map(|t| t + duration);
 
-            // Check encryption
+            // Check encryption, keys without an IV are used with the segment's sequence number
+            let encryption = match segment.key.as_ref().or(cur_key.as_ref()) {
+                Some(key) => Encryption::new(key, &url, seq).await?,
+                None => Encryption::None,
+            };
             if let Some(key) = &segment.key {
-                encryption = Encryption::new(key, &url, seq).await?;
+                cur_key = Some(key.clone());
+                prefetcher.key(&encryption);
             }
+            known.insert(seq, (discon_seq, cur_key.clone()));
+
+            // Skip segment if already downloaded
+            let out_seq = match dedupe {
+                Dedupe::Url => {
+                    let key = dedupe_key(&url, segment)?;
+                    if seen_urls.contains_key(&key) {
+                        continue;
+                    }
+                    let out_seq = *next_seq;
+                    *next_seq += 1;
+                    seen_urls.insert(key, out_seq);
+                    out_seq
+                }
+                Dedupe::Sequence | Dedupe::Content => {
+                    if let Some(s) = last_seg {
+                        if s >= (discon_seq, seq) {
+                            continue;
+                        }
+                    }
+                    seq
+                }
+            };
 
             // Segment is new
             last_seg = Some((discon_seq, seq));
             found_new_segments = true;
+            new_durations.push(duration);
+            if let Some(t) = segment_pdt {
+                timeline.anchor(discon_seq, t);
+            }
+
+            // Gap segments are unavailable, don't try to download them
+            if unknown_tags(segment, "X-GAP").next().is_some() {
+                event!(Level::DEBUG, "Skipping {} gap segment {}", stream, seq);
+                continue;
+            }
 
             // Parse URL
             let seg_url = make_absolute_url(&url, &segment.uri)?;
 
-            // Make Initialization
-            let init = if let Some(map) = &segment.map {
+            // Make Initialization, keeping the encryption in effect where it first appeared since
+            // keys may rotate while it stays the same
+            if let Some(map) = &segment.map {
                 let init =
                     RemoteData::new(make_absolute_url(&url, &map.uri)?, map.byte_range.clone());
-                cur_init = Some(init.clone());
-                Some(init)
-            } else {
-                cur_init.clone()
+                if cur_init.as_ref().map(|(i, _)| i) != Some(&init) {
+                    prefetcher.init(&stream, &init, &encryption);
+                    cur_init = Some((init, encryption.clone()));
+                }
+            }
+            let (init, init_encryption) = match &cur_init {
+                Some((i, e)) => (Some(i.clone()), e.clone()),
+                None => (None, Encryption::None),
             };
 
+            // Wait for segment's turn if paced, return if stopped
+            if let Some(pacer) = &mut pacer {
+                tokio::select! {
+                    _ = notify_stop.wait() => return Ok(()),
+                    _ = pacer.wait(duration) => {},
+                }
+            }
+
             // Download segment
-            event!(Level::TRACE, "Found new segment {}", seg_url.as_str());
-            if tx
-                .unbounded_send((
-                    stream.clone(),
-                    Segment {
-                        data: RemoteData::new(seg_url, segment.byte_range.clone()),
-                        discon_seq,
-                        seq,
-                        format: MediaFormat::Unknown,
-                        initialization: init,
-                    },
-                    encryption.clone(),
-                ))
-                .is_err()
-            {
+            if prefetch {
+                event!(Level::TRACE, "Found prefetch segment {}", seg_url.as_str());
+            } else {
+                event!(Level::TRACE, "Found new segment {}", seg_url.as_str());
+            }
+            window.queued(&stream, out_seq);
+            let queued = QueuedSegment {
+                stream: stream.clone(),
+                segment: Segment {
+                    data: RemoteData::new(seg_url, byte_range),
+                    discon_seq,
+                    seq: out_seq,
+                    duration,
+                    format: MediaFormat::Unknown,
+                    initialization: init,
+                    group,
+                    program_date_time: segment_pdt,
+                },
+                encryption: encryption.clone(),
+                init_encryption,
+                deadline,
+                expired: false,
+            };
+            // Wait for room in the download queue, return if stopped
+            let sent = tokio::select! {
+                _ = notify_stop.wait() => return Ok(()),
+                r = tx.send(queued) => r,
+            };
+            if sent.is_err() {
                 return Ok(());
             }
         }
 
-        // Return if stream ended
+        next_msn = Some(first_seq + media_playlist.segments.len() as u64);
+
+        // Return if stream ended, unless it restarts under a new playlist in time
         if media_playlist.end_list {
             event!(Level::TRACE, "Playlist ended");
-            return Ok(());
+            let window = match follow_restarts {
+                Some(w) => w,
+                None => return Ok(()),
+            };
+            event!(
+                Level::INFO,
+                "{} playlist ended, waiting up to {:?} for the stream to restart",
+                stream,
+                window
+            );
+            match reloader
+                .wait_for_restart(&original_url, &media_playlist, &url, window)
+                .await
+            {
+                Some(u) => {
+                    event!(Level::INFO, "{} stream restarted at {}", stream, u);
+                    url = with_skip_param(&u, None);
+                    restarted = true;
+                    can_skip_until = None;
+                    next_msn = None;
+                    stalled_reloads = 0;
+                    continue;
+                }
+                None => return Ok(()),
+            }
         }
 
-        let wait_duration = if found_new_segments {
-            // Wait for target duration if new segments were found
-            Duration::from_secs_f32(media_playlist.target_duration)
+        // Give up if the playlist stopped updating without ending, if requested
+        if found_new_segments {
+            stalled_reloads = 0;
         } else {
-            // Otherwise wait for half target duration
-            Duration::from_secs_f32(media_playlist.target_duration / 2.0)
-        };
+            stalled_reloads += 1;
+            if max_stalled_reloads.is_some_and(|max| stalled_reloads >= max) {
+                return Err(LivestreamDLError::PlaylistStalled {
+                    stream: stream.to_string(),
+                    reloads: stalled_reloads,
+                }
+                .into());
+            }
+        }
 
-        // Wait until next interval or if stopped
+        // Poll again just after the next segment is expected
+        cadence.update(now, new_durations);
+        let next_poll = cadence.next_poll(
+            now,
+            Duration::from_secs_f32(media_playlist.target_duration.max(0.0)),
+        );
+
+        // A blocking reload that returned without the requested segment means the server doesn't
+        // actually block, poll instead
+        if blocked && blocking_reload && !found_new_segments {
+            event!(
+                Level::WARN,
+                "{} playlist server doesn't hold blocking reloads, falling back to polling",
+                stream
+            );
+            block_failed = true;
+            blocking_reload = false;
+        }
+
+        // Blocking reloads wait on the server for the next segment instead
+        if blocking_reload {
+            if notify_stop.is_stopped() {
+                return Ok(());
+            }
+            continue;
+        }
+
+        // Wait until next poll or if stopped
         tokio::select! {
             biased;
 
-            // Not cancel safe, but this is ok because all stoppers are notified when stopped, so
-            // fairness doesn't matter
             _ = notify_stop.wait() => {},
 
-            _ = time::sleep_until(now + wait_duration) => {},
+            _ = clock.sleep_until(next_poll) => {},
         };
 
         // Return if stopped
-        if notify_stop.stopped().await {
+        if notify_stop.is_stopped() {
             return Ok(());
         }
     }
 }
+
+/// Recovers segments of a stream missed before joining or during an outage
+struct Backfill<'a> {
+    client: &'a HttpClient,
+    stream: &'a Stream,
+    /// Maximum number of segments to guess
+    limit: u64,
+}
+
+impl Backfill<'_> {
+    /// Guess the urls of segments missing before the first segment of playlist, which is in
+    /// discontinuity discon_seq, and queue the ones that exist. Only segments after the last
+    /// found segment in the same discontinuity are missing
+    async fn run(
+        &self,
+        playlist: &MediaPlaylist,
+        url: &Url,
+        discon_seq: u64,
+        last_seg: Option<(u64, u64)>,
+        tx: &mut mpsc::Sender<QueuedSegment>,
+    ) -> Result<()> {
+        let Self {
+            client,
+            stream,
+            limit,
+        } = *self;
+        let first_seq = playlist.media_sequence;
+        let (first, second) = match playlist.segments.as_slice() {
+            [first, second, ..] if !first.discontinuity && !second.discontinuity => (first, second),
+            _ => return Ok(()),
+        };
+        let start = match last_seg {
+            None => first_seq.saturating_sub(limit),
+            Some((d, s)) if d == discon_seq && s + 1 < first_seq => {
+                (s + 1).max(first_seq.saturating_sub(limit))
+            }
+            _ => return Ok(()),
+        };
+        if start >= first_seq {
+            return Ok(());
+        }
+
+        // Guess urls from the first two segments
+        let pattern = match UrlPattern::infer(
+            &make_absolute_url(url, &first.uri)?,
+            first_seq,
+            &make_absolute_url(url, &second.uri)?,
+            first_seq + 1,
+        ) {
+            Some(p) => p,
+            None => {
+                event!(
+                    Level::DEBUG,
+                    "No counter found in {} segment urls, not backfilling",
+                    stream
+                );
+                return Ok(());
+            }
+        };
+        let found = probe(client, &pattern, start..first_seq).await;
+        if found.is_empty() {
+            return Ok(());
+        }
+        event!(
+            Level::INFO,
+            "Backfilling {} missed {} segments",
+            found.len(),
+            stream
+        );
+
+        let init = match &first.map {
+            Some(map) => Some(RemoteData::new(
+                make_absolute_url(url, &map.uri)?,
+                map.byte_range.clone(),
+            )),
+            None => None,
+        };
+        for (seq, seg_url) in found {
+            let encryption = match &first.key {
+                Some(key) => Encryption::new(key, url, seq).await?,
+                None => Encryption::None,
+            };
+            let queued = QueuedSegment {
+                stream: stream.clone(),
+                segment: Segment {
+                    data: RemoteData::new(seg_url, None),
+                    discon_seq,
+                    seq,
+                    duration: Duration::from_secs_f32(first.duration.max(0.0)),
+                    format: MediaFormat::Unknown,
+                    initialization: init.clone(),
+                    group: None,
+                    program_date_time: None,
+                },
+                init_encryption: encryption.clone(),
+                encryption,
+                deadline: None,
+                expired: false,
+            };
+            if tx.send(queued).await.is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reloads the media playlist of a stream, re-resolving its url from the master playlist if
+/// available
+struct PlaylistReloader<'a> {
+    client: &'a HttpClient,
+    master_resolver: Option<&'a MasterPlaylistResolver>,
+    notify_stop: &'a Stopper,
+    clock: &'a dyn Clock,
+    stream: &'a Stream,
+}
+
+impl PlaylistReloader<'_> {
+    /// Fetch the media playlist, retrying with exponential backoff on failure. The playlist url
+    /// is re-resolved before each retry
+    ///
+    /// Returns None if stopped while waiting to retry
+    async fn fetch_retrying(
+        &self,
+        url: &mut Url,
+        cache: &mut ConditionalCache,
+        retries: u32,
+    ) -> Result<Option<MediaPlaylist>> {
+        let Self {
+            client,
+            master_resolver,
+            notify_stop,
+            clock,
+            stream,
+        } = *self;
+        let mut attempt = 0;
+        loop {
+            let e = match cache.fetch(client, url).await {
+                Ok(p) => return Ok(Some(p)),
+                Err(e) if attempt >= retries => return Err(e),
+                Err(e) => e,
+            };
+            attempt += 1;
+            event!(
+                Level::WARN,
+                "Failed to fetch {} playlist, retrying ({}/{}): {}",
+                stream,
+                attempt,
+                retries,
+                e
+            );
+
+            // Get new url from master playlist
+            if let Some(resolver) = master_resolver {
+                match resolver.resolve(stream).await {
+                    Ok(u) => *url = u,
+                    Err(e) => event!(
+                        Level::WARN,
+                        "Failed to re-resolve {} playlist from master playlist: {}",
+                        stream,
+                        e
+                    ),
+                }
+            }
+
+            // Wait before retrying, return if stopped
+            let backoff = MIN_RETRY_BACKOFF
+                .saturating_mul(2_u32.saturating_pow(attempt - 1))
+                .min(MAX_RETRY_BACKOFF);
+            tokio::select! {
+                _ = notify_stop.wait() => return Ok(None),
+                _ = clock.sleep(backoff) => {},
+            }
+        }
+    }
+
+    /// After the playlist ended, poll the original playlist url for a new playlist of the same
+    /// stream until window elapses. The url is re-resolved before each poll
+    ///
+    /// Returns the url of the new playlist, None if the stream didn't restart or if stopped
+    async fn wait_for_restart(
+        &self,
+        original_url: &Url,
+        ended: &MediaPlaylist,
+        ended_url: &Url,
+        window: Duration,
+    ) -> Option<Url> {
+        let Self {
+            client,
+            master_resolver,
+            notify_stop,
+            clock,
+            stream,
+        } = *self;
+        let ended_segments: HashSet<Url> = ended
+            .segments
+            .iter()
+            .filter_map(|s| make_absolute_url(ended_url, &s.uri).ok())
+            .collect();
+        let interval = Duration::from_secs_f32(ended.target_duration.max(1.0));
+        let deadline = clock.now() + window;
+
+        while clock.now() < deadline {
+            // Wait before polling, return if stopped
+            tokio::select! {
+                _ = notify_stop.wait() => return None,
+                _ = clock.sleep(interval) => {},
+            }
+
+            let url = match master_resolver {
+                Some(resolver) => match resolver.resolve(stream).await {
+                    Ok(u) => u,
+                    Err(e) => {
+                        event!(
+                            Level::DEBUG,
+                            "Failed to re-resolve {} playlist: {}",
+                            stream,
+                            e
+                        );
+                        continue;
+                    }
+                },
+                None => original_url.clone(),
+            };
+            let playlist = match fetch_media_playlist(client, &url).await {
+                Ok(p) => p,
+                Err(e) => {
+                    event!(Level::DEBUG, "Failed to fetch {} playlist: {}", stream, e);
+                    continue;
+                }
+            };
+
+            // The stream restarted if the playlist has segments the ended one didn't
+            let restarted = playlist.segments.iter().any(|s| {
+                make_absolute_url(&url, &s.uri).is_ok_and(|u| !ended_segments.contains(&u))
+            });
+            if restarted {
+                return Some(url);
+            }
+        }
+
+        None
+    }
+}
+
+/// Tags of a segment that m3u8-rs doesn't parse, playlist tags are included in the first
+/// segment's tags
+fn unknown_tags<'a>(segment: &'a MediaSegment, name: &'a str) -> impl Iterator<Item = &'a ExtTag> {
+    segment.unknown_tags.iter().filter(move |t| t.tag == name)
+}
+
+/// Byte ranges of segments with the offsets left out by the playlist filled in, and the group
+/// of contiguous byte ranges of the same url each is fetched as part of. Discontinuities don't end
+/// groups, packagers often insert them into one file
+fn byte_range_groups(
+    base_url: &Url,
+    segments: &[MediaSegment],
+) -> Result<Vec<(Option<ByteRange>, Option<RemoteData>)>> {
+    // A missing offset continues the previous byte range of the same url
+    let mut ranges: Vec<(Url, Option<ByteRange>)> = Vec::with_capacity(segments.len());
+    for segment in segments {
+        let url = make_absolute_url(base_url, &segment.uri)?;
+        let range = segment.byte_range.as_ref().map(|r| {
+            let offset = r.offset.or_else(|| match ranges.last() {
+                Some((u, Some(prev))) if *u == url => Some(prev.offset.unwrap_or(0) + prev.length),
+                _ => None,
+            });
+            ByteRange {
+                length: r.length,
+                offset,
+            }
+        });
+        ranges.push((url, range));
+    }
+
+    // Group runs of byte ranges each starting where the previous one ends
+    let mut groups = vec![None; ranges.len()];
+    let mut start = 0;
+    while start < ranges.len() {
+        let mut end = start + 1;
+        if let (url, Some(first)) = &ranges[start] {
+            let offset = first.offset.unwrap_or(0);
+            let mut length = first.length;
+            while let Some((u, Some(r))) = ranges.get(end) {
+                if u != url
+                    || r.offset != Some(offset + length)
+                    || length + r.length > MAX_RANGE_GROUP_SIZE
+                {
+                    break;
+                }
+                length += r.length;
+                end += 1;
+            }
+            if end - start > 1 {
+                let group = RemoteData::new(
+                    url.clone(),
+                    Some(ByteRange {
+                        length,
+                        offset: Some(offset),
+                    }),
+                );
+                groups[start..end].fill(Some(group));
+            }
+        }
+        start = end;
+    }
+
+    Ok(ranges.into_iter().map(|(_, r)| r).zip(groups).collect())
+}
+
+/// Segment URL without query string or fragment, which often contain expiring tokens, and byte
+/// range
+fn dedupe_key(base_url: &Url, segment: &MediaSegment) -> Result<RemoteData> {
+    let mut url = make_absolute_url(base_url, &segment.uri)?;
+    url.set_query(None);
+    url.set_fragment(None);
+    Ok(RemoteData::new(url, segment.byte_range.clone()))
+}
+
+/// Value of an EXT-X-SERVER-CONTROL attribute
+fn server_control(playlist: &MediaPlaylist, attribute: &str) -> Option<String> {
+    let tag = unknown_tags(playlist.segments.first()?, "X-SERVER-CONTROL").next()?;
+    parse_attribute_list(tag.rest.as_deref()?)
+        .into_iter()
+        .find_map(|(k, v)| (k == attribute).then_some(v))
+}
+
+/// Number of segments an EXT-X-SKIP tag of a delta update replaces
+fn skipped_segments(playlist: &MediaPlaylist) -> u64 {
+    playlist
+        .segments
+        .first()
+        .and_then(|s| unknown_tags(s, "X-SKIP").next())
+        .and_then(|t| parse_attribute_list(t.rest.as_deref()?).into_iter().next())
+        .filter(|(k, _)| k == "SKIPPED-SEGMENTS")
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Sequence numbers and segments of the last playlist, to recognize the server restarting its
+/// numbering
+#[derive(Debug)]
+struct LastPlaylist {
+    media_sequence: u64,
+    discontinuity_sequence: u64,
+    /// Number of segments, including those skipped by a delta update
+    len: u64,
+    uris: HashSet<String>,
+}
+
+impl LastPlaylist {
+    fn new(playlist: &MediaPlaylist) -> Self {
+        Self {
+            media_sequence: playlist.media_sequence,
+            discontinuity_sequence: playlist.discontinuity_sequence,
+            len: skipped_segments(playlist) + playlist.segments.len() as u64,
+            uris: playlist.segments.iter().map(|s| s.uri.clone()).collect(),
+        }
+    }
+
+    /// Whether the server restarted numbering since this playlist. Sequence numbers only grow
+    /// while a stream goes on, but a lagging server, e.g. a stale CDN edge, may answer with an
+    /// older playlist. Going back is only a reset if it is further back than this playlist's
+    /// segments, or if none of its segments are in playlist
+    fn is_reset(&self, playlist: &MediaPlaylist) -> bool {
+        if playlist.media_sequence >= self.media_sequence
+            && playlist.discontinuity_sequence >= self.discontinuity_sequence
+        {
+            return false;
+        }
+
+        self.media_sequence.saturating_sub(playlist.media_sequence) > self.len
+            || !playlist.segments.iter().any(|s| self.uris.contains(&s.uri))
+    }
+}
+
+/// Whether all segments skipped by a delta update were in the last playlist
+fn skip_resumable(playlist: &MediaPlaylist, known: &BTreeMap<u64, (u64, Option<Key>)>) -> bool {
+    let skipped = skipped_segments(playlist);
+    skipped == 0 || known.contains_key(&(playlist.media_sequence + skipped))
+}
+
+/// Set or remove the _HLS_skip query parameter requesting a delta update
+fn with_skip_param(url: &Url, value: Option<&str>) -> Url {
+    with_query_param(url, "_HLS_skip", value)
+}
+
+/// Set or remove a query parameter. Other parameters are kept as is, since signed urls may break
+/// if re-encoded
+fn with_query_param(url: &Url, name: &str, value: Option<&str>) -> Url {
+    let prefix = format!("{}=", name);
+    let mut params: Vec<_> = url
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|p| !p.is_empty() && !p.starts_with(&prefix))
+        .map(str::to_owned)
+        .collect();
+    if let Some(v) = value {
+        params.push(format!("{}{}", prefix, v));
+    }
+
+    let mut url = url.clone();
+    url.set_query((!params.is_empty()).then(|| params.join("&")).as_deref());
+    url
+}
+
+/// Validators of the last full playlist response and the playlist it contained, to request the
+/// playlist with If-None-Match and If-Modified-Since and reuse it if the server answers 304
+#[derive(Debug, Default)]
+struct ConditionalCache {
+    url: Option<Url>,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    playlist: Option<MediaPlaylist>,
+}
+
+impl ConditionalCache {
+    /// Fetch and parse a media playlist, or return the cached playlist if it is unchanged
+    async fn fetch(&mut self, client: &HttpClient, url: &Url) -> Result<MediaPlaylist> {
+        event!(Level::TRACE, "Fetching {}", url.as_str());
+        let mut request = client.get(url.clone());
+        if self.url.as_ref() == Some(url) && self.playlist.is_some() {
+            if let Some(etag) = &self.etag {
+                request = request.header(header::IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(last_modified) = &self.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified.clone());
+            }
+        }
+        let resp = request.send().await?;
+
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            if let Some(playlist) = self
+                .playlist
+                .as_ref()
+                .filter(|_| self.url.as_ref() == Some(url))
+            {
+                event!(Level::TRACE, "{} not modified", url.as_str());
+                return Ok(playlist.clone());
+            }
+        }
+
+        let etag = resp.headers().get(header::ETAG).cloned();
+        let last_modified = resp.headers().get(header::LAST_MODIFIED).cloned();
+        let playlist = read_media_playlist(resp).await?;
+        *self = match (etag, last_modified) {
+            (None, None) => Self::default(),
+            (etag, last_modified) => Self {
+                url: Some(url.clone()),
+                etag,
+                last_modified,
+                playlist: Some(playlist.clone()),
+            },
+        };
+
+        Ok(playlist)
+    }
+}
+
+/// Fetch and parse a media playlist
+pub async fn fetch_media_playlist(client: &HttpClient, url: &Url) -> Result<MediaPlaylist> {
+    event!(Level::TRACE, "Fetching {}", url.as_str());
+    let resp = client.get(url.clone()).send().await?;
+    read_media_playlist(resp).await
+}
+
+/// Parse the media playlist in a response
+async fn read_media_playlist(resp: Response) -> Result<MediaPlaylist> {
+    let final_url = response_url(&resp).to_string();
+    if !resp.status().is_success() {
+        return Err(LivestreamDLError::NetworkRequest(resp).into());
+    }
+    let bytes = resp.bytes().await?;
+
+    let media_playlist =
+        parse_media_playlist(&bytes).ok_or(LivestreamDLError::ParseM3u8(final_url))?;
+
+    Ok(media_playlist)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::time::Instant;
+
+    use super::*;
+
+    /// Clock returning from sleeps at once, recording how long they would have been
+    #[derive(Debug)]
+    struct FakeClock {
+        now: Mutex<Instant>,
+        sleeps: Mutex<Vec<Duration>>,
+    }
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self {
+                now: Mutex::new(Instant::now()),
+                sleeps: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        async fn sleep_until(&self, deadline: Instant) {
+            let mut now = self.now.lock().unwrap();
+            if deadline > *now {
+                self.sleeps.lock().unwrap().push(deadline - *now);
+                *now = deadline;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn playlist_retries_back_off_exponentially() {
+        let client = HttpClient::new(
+            reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build(),
+            None::<Vec<(String, String)>>,
+        );
+        let clock = FakeClock::new();
+        // Nothing listens on port 1, every fetch fails at once
+        let mut url = Url::parse("http://127.0.0.1:1/media.m3u8").unwrap();
+        let mut cache = ConditionalCache::default();
+
+        let reloader = PlaylistReloader {
+            client: &client,
+            master_resolver: None,
+            notify_stop: &Stopper::new(),
+            clock: &clock,
+            stream: &Stream::Main,
+        };
+        let result = reloader.fetch_retrying(&mut url, &mut cache, 6).await;
+
+        assert!(result.is_err());
+        let sleeps = clock.sleeps.lock().unwrap().clone();
+        assert_eq!(sleeps, [1, 2, 4, 8, 16, 30].map(Duration::from_secs));
+    }
+
+    fn playlist(media_sequence: u64, discontinuity_sequence: u64, uris: &[&str]) -> MediaPlaylist {
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:{}\n\
+             #EXT-X-DISCONTINUITY-SEQUENCE:{}\n",
+            media_sequence, discontinuity_sequence
+        );
+        for uri in uris {
+            playlist.push_str(&format!("#EXTINF:2,\n{}\n", uri));
+        }
+        parse_media_playlist(playlist.as_bytes()).unwrap()
+    }
+
+    fn is_reset(last: &MediaPlaylist, playlist: &MediaPlaylist) -> bool {
+        LastPlaylist::new(last).is_reset(playlist)
+    }
+
+    #[test]
+    fn advancing_playlist_is_not_a_reset() {
+        let last = playlist(10, 2, &["10.ts", "11.ts", "12.ts"]);
+        assert!(!is_reset(&last, &last));
+        assert!(!is_reset(
+            &last,
+            &playlist(11, 3, &["11.ts", "12.ts", "13.ts"])
+        ));
+    }
+
+    #[test]
+    fn stale_playlist_is_not_a_reset() {
+        let last = playlist(10, 2, &["10.ts", "11.ts", "12.ts"]);
+        assert!(!is_reset(
+            &last,
+            &playlist(9, 2, &["9.ts", "10.ts", "11.ts"])
+        ));
+        assert!(!is_reset(&last, &playlist(9, 1, &["9.ts", "10.ts"])));
+    }
+
+    #[test]
+    fn sequence_going_backwards_is_a_reset() {
+        let last = playlist(10, 2, &["10.ts", "11.ts", "12.ts"]);
+        assert!(is_reset(&last, &playlist(0, 2, &["0.ts", "1.ts", "2.ts"])));
+        assert!(is_reset(&last, &playlist(0, 2, &["10.ts"])));
+        assert!(is_reset(&last, &playlist(10, 0, &["a.ts", "b.ts"])));
+    }
+
+    /// Offset and length of a byte range
+    type Range = Option<(u64, u64)>;
+
+    /// Offset and length of each segment's byte range and of the group it is fetched in
+    fn groups(segments: &[(&str, &str)]) -> Vec<(Range, Range)> {
+        let mut playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:2\n".to_owned();
+        for (uri, byte_range) in segments {
+            playlist.push_str(&format!(
+                "#EXTINF:2,\n#EXT-X-BYTERANGE:{}\n{}\n",
+                byte_range, uri
+            ));
+        }
+        let playlist = parse_media_playlist(playlist.as_bytes()).unwrap();
+        let base_url = Url::parse("https://example.com/media.m3u8").unwrap();
+
+        byte_range_groups(&base_url, &playlist.segments)
+            .unwrap()
+            .into_iter()
+            .map(|(range, group)| {
+                let range = range.map(|r| (r.offset.unwrap_or(0), r.length));
+                (range, group.and_then(|g| g.range()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn byte_ranges_without_offsets_continue_the_last_one() {
+        let group = Some((0, 300));
+        assert_eq!(
+            groups(&[("a.mp4", "100@0"), ("a.mp4", "100"), ("a.mp4", "100")]),
+            [
+                (Some((0, 100)), group),
+                (Some((100, 100)), group),
+                (Some((200, 100)), group),
+            ]
+        );
+    }
+
+    #[test]
+    fn byte_range_groups_end_at_gaps_and_other_urls() {
+        assert_eq!(
+            groups(&[
+                ("a.mp4", "100@0"),
+                ("a.mp4", "100@100"),
+                ("a.mp4", "100@300"),
+                ("b.mp4", "100@400"),
+                ("b.mp4", "100@500"),
+            ]),
+            [
+                (Some((0, 100)), Some((0, 200))),
+                (Some((100, 100)), Some((0, 200))),
+                (Some((300, 100)), None),
+                (Some((400, 100)), Some((400, 200))),
+                (Some((500, 100)), Some((400, 200))),
+            ]
+        );
+    }
+
+    #[test]
+    fn byte_range_groups_are_limited_in_size() {
+        let half = MAX_RANGE_GROUP_SIZE / 2;
+        let ranges = [
+            format!("{}@0", half),
+            format!("{}", half),
+            format!("{}", half),
+        ];
+        let segments: Vec<_> = ranges.iter().map(|r| ("a.mp4", r.as_str())).collect();
+        assert_eq!(
+            groups(&segments),
+            [
+                (Some((0, half)), Some((0, 2 * half))),
+                (Some((half, half)), Some((0, 2 * half))),
+                (Some((2 * half, half)), None),
+            ]
+        );
+    }
+}
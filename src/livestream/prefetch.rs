@@ -0,0 +1,127 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use lru::LruCache;
+use reqwest::Url;
+use tracing::{event, Level};
+
+use super::encryption::KeyCache;
+use super::http_client::HttpClient;
+use super::remote_data::RemoteData;
+use super::utils::AbortOnDrop;
+use super::{Encryption, MediaFormat, Stream};
+
+/// Initializations of a stream fetched so far, decrypted if needed
+pub type InitCache = Arc<tokio::sync::Mutex<LruCache<RemoteData, Vec<u8>>>>;
+
+/// High priority lane fetching keys and initializations as soon as a playlist fetcher finds
+/// them, outside of the segment download slots. Every following segment depends on them, so a
+/// burst of segment downloads mustn't delay them. Fetches still running when the last clone is
+/// dropped are aborted, so they don't outlive the download
+#[derive(Clone, Debug)]
+pub struct Prefetcher {
+    client: HttpClient,
+    keys: KeyCache,
+    decrypt: bool,
+    inits: HashMap<Stream, InitCache>,
+    /// Key uris already prefetched
+    requested_keys: Arc<Mutex<HashSet<Url>>>,
+    /// Background fetches, aborted when dropped
+    tasks: Arc<Mutex<Vec<AbortOnDrop<()>>>>,
+}
+
+impl Prefetcher {
+    pub fn new(
+        client: HttpClient,
+        keys: KeyCache,
+        decrypt: bool,
+        inits: HashMap<Stream, InitCache>,
+    ) -> Self {
+        Self {
+            client,
+            keys,
+            decrypt,
+            inits,
+            requested_keys: Arc::new(Mutex::new(HashSet::new())),
+            tasks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Run a fetch in the background until it finishes or the prefetcher is dropped
+    fn spawn(&self, fetch: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|t| !t.is_finished());
+        tasks.push(AbortOnDrop(tokio::spawn(fetch)));
+    }
+
+    /// Fetch the key of encryption in the background if it wasn't fetched before
+    pub fn key(&self, encryption: &Encryption) {
+        let key_uri = match encryption {
+            Encryption::Aes128 { key_uri, .. } => key_uri.clone(),
+            _ => return,
+        };
+        if !self.requested_keys.lock().unwrap().insert(key_uri.clone()) {
+            return;
+        }
+
+        let (client, keys) = (self.client.clone(), self.keys.clone());
+        self.spawn(async move {
+            if let Err(e) = keys.get(&client, &key_uri).await {
+                event!(Level::DEBUG, "Failed to prefetch key: {:?}", e);
+            }
+        });
+    }
+
+    /// Fetch an initialization of stream in the background
+    pub fn init(&self, stream: &Stream, init: &RemoteData, encryption: &Encryption) {
+        let cache = match self.inits.get(stream) {
+            Some(c) => c.clone(),
+            None => return,
+        };
+
+        let (client, keys, decrypt) = (self.client.clone(), self.keys.clone(), self.decrypt);
+        let (init, encryption) = (init.clone(), encryption.clone());
+        self.spawn(async move {
+            if let Err(e) =
+                fetch_initialization(&client, &cache, &keys, decrypt, &init, &encryption).await
+            {
+                event!(Level::DEBUG, "Failed to prefetch initialization: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Get an initialization from the cache, otherwise fetch it and decrypt it if needed
+pub async fn fetch_initialization(
+    client: &HttpClient,
+    cache: &InitCache,
+    keys: &KeyCache,
+    decrypt: bool,
+    init: &RemoteData,
+    encryption: &Encryption,
+) -> Result<Vec<u8>> {
+    // Hold the lock while fetching, so an initialization is only fetched once
+    let mut guard = cache.lock().await;
+    if let Some(d) = guard.get(init) {
+        return Ok(d.clone());
+    }
+
+    let mut d = init
+        .fetch(client)
+        .await
+        .context("error fetching segment initialization")?
+        .0;
+    // An initialization is encrypted with the key in effect at its EXT-X-MAP, some servers
+    // leave it in the clear anyway
+    if decrypt && !MediaFormat::is_mp4(&d) {
+        d = encryption
+            .decrypt(client, keys, &d)
+            .await
+            .context("error decrypting segment initialization")?;
+    }
+    guard.put(init.clone(), d.clone());
+
+    Ok(d)
+}
@@ -0,0 +1,227 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::Stream as FuturesStream;
+use tokio::time::Instant;
+use tracing::{event, Level};
+
+use super::stats::Stats;
+use super::{Encryption, Segment, Stream};
+use crate::cli::QueueFull;
+
+/// A segment found by a playlist fetcher, waiting to be downloaded
+#[derive(Debug)]
+pub struct QueuedSegment {
+    pub stream: Stream,
+    pub segment: Segment,
+    pub encryption: Encryption,
+    /// Encryption in effect at the segment's EXT-X-MAP, which may differ from the segment's
+    /// after a key rotation
+    pub init_encryption: Encryption,
+    /// Estimated time the segment slides out of a live playlist, None if it never expires
+    pub deadline: Option<Instant>,
+    /// Whether the segment was removed from the playlist before its download started
+    pub expired: bool,
+}
+
+impl QueuedSegment {
+    /// Segments closest to expiry first, then segments that never expire in playlist order
+    fn priority(&self) -> (Reverse<(bool, Option<Instant>)>, Reverse<&Segment>) {
+        (
+            Reverse((self.deadline.is_none(), self.deadline)),
+            Reverse(&self.segment),
+        )
+    }
+}
+
+impl PartialEq for QueuedSegment {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedSegment {}
+
+impl PartialOrd for QueuedSegment {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedSegment {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority().cmp(&other.priority())
+    }
+}
+
+/// Yields queued segments closest to expiry first instead of in the order they were found, so a
+/// backlogged download doesn't lose the oldest segments of a live playlist
+///
+/// At most capacity segments are queued. When full, playlist fetchers wait for room or the
+/// segments closest to expiry are dropped, depending on the policy
+pub struct SegmentQueue {
+    rx: mpsc::Receiver<QueuedSegment>,
+    heap: BinaryHeap<QueuedSegment>,
+    window: SlidingWindow,
+    capacity: usize,
+    policy: QueueFull,
+    stats: Stats,
+    closed: bool,
+}
+
+impl SegmentQueue {
+    pub fn new(
+        rx: mpsc::Receiver<QueuedSegment>,
+        window: SlidingWindow,
+        capacity: usize,
+        policy: QueueFull,
+        stats: Stats,
+    ) -> Self {
+        Self {
+            rx,
+            heap: BinaryHeap::new(),
+            window,
+            capacity: capacity.max(1),
+            policy,
+            stats,
+            closed: false,
+        }
+    }
+
+    /// Drop the segments closest to expiry until the queue is within capacity
+    fn drop_oldest(&mut self) {
+        while self.heap.len() > self.capacity {
+            let s = match self.heap.pop() {
+                Some(s) => s,
+                None => break,
+            };
+            self.window.dequeued(&s.stream, s.segment.seq);
+            self.stats.segment_dequeued(&s.stream);
+            self.stats.segment_dropped(&s.stream);
+            event!(
+                Level::WARN,
+                "Download queue is full, dropped {} segment {}",
+                s.stream,
+                s.segment.url()
+            );
+        }
+    }
+}
+
+impl FuturesStream for SegmentQueue {
+    type Item = QueuedSegment;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        // Move segments found so far into the queue. Fetchers wait while it is full unless the
+        // oldest segments are dropped
+        while !this.closed
+            && (this.policy == QueueFull::DropOldest || this.heap.len() < this.capacity)
+        {
+            match Pin::new(&mut this.rx).poll_next(cx) {
+                Poll::Ready(Some(s)) => {
+                    this.stats.segment_queued(&s.stream);
+                    this.heap.push(s);
+                    this.drop_oldest();
+                }
+                Poll::Ready(None) => this.closed = true,
+                Poll::Pending => break,
+            }
+        }
+        this.stats.queue_depth(this.heap.len());
+
+        match this.heap.pop() {
+            Some(mut s) => {
+                s.expired = this.window.dequeued(&s.stream, s.segment.seq);
+                this.stats.segment_dequeued(&s.stream);
+                Poll::Ready(Some(s))
+            }
+            None if this.closed => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Tracks the sliding window of live playlists to find queued segments that were removed from
+/// the playlist before their download started
+#[derive(Clone, Debug, Default)]
+pub struct SlidingWindow(Arc<Mutex<HashMap<Stream, WindowState>>>);
+
+#[derive(Debug, Default)]
+struct WindowState {
+    /// Media sequence number of the first segment in the playlist
+    low: u64,
+    /// Segments waiting to be downloaded
+    queued: BTreeSet<u64>,
+    /// Segments removed from the playlist while waiting to be downloaded
+    expired: BTreeSet<u64>,
+}
+
+impl SlidingWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a segment waiting to be downloaded
+    pub fn queued(&self, stream: &Stream, seq: u64) {
+        let mut streams = self.0.lock().unwrap();
+        streams
+            .entry(stream.clone())
+            .or_default()
+            .queued
+            .insert(seq);
+    }
+
+    /// Number of segments waiting to be downloaded in all streams
+    pub fn queued_count(&self) -> usize {
+        let streams = self.0.lock().unwrap();
+        streams.values().map(|s| s.queued.len()).sum()
+    }
+
+    /// Move the start of the window to the playlist's media sequence number, warning about
+    /// queued segments that fell out of it
+    pub fn advance(&self, stream: &Stream, media_sequence: u64) {
+        let mut streams = self.0.lock().unwrap();
+        let state = streams.entry(stream.clone()).or_default();
+        state.low = state.low.max(media_sequence);
+
+        let rest = state.queued.split_off(&state.low);
+        let expired = std::mem::replace(&mut state.queued, rest);
+        if !expired.is_empty() {
+            event!(
+                Level::WARN,
+                stream = %stream,
+                sequences = ?expired,
+                "{} queued {} segments fell out of the playlist window before download",
+                expired.len(),
+                stream,
+            );
+            state.expired.extend(expired);
+        }
+    }
+
+    /// Forget the window of a stream whose playlist restarted its media sequence numbers, queued
+    /// segments are no longer tracked
+    pub fn reset(&self, stream: &Stream) {
+        let mut streams = self.0.lock().unwrap();
+        streams.remove(stream);
+    }
+
+    /// Record that a segment's download started, returns whether it already fell out of the
+    /// window
+    pub fn dequeued(&self, stream: &Stream, seq: u64) -> bool {
+        let mut streams = self.0.lock().unwrap();
+        match streams.get_mut(stream) {
+            Some(state) => {
+                state.queued.remove(&seq);
+                state.expired.remove(&seq)
+            }
+            None => false,
+        }
+    }
+}
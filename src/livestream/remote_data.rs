@@ -1,12 +1,27 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
 use anyhow::Result;
+use lru::LruCache;
 use m3u8_rs::ByteRange;
 use reqwest::header::{self, HeaderMap};
-use reqwest::Url;
+use reqwest::{Response, StatusCode, Url};
+use tokio::sync::OnceCell;
+use tokio::time::{self, Instant};
+use tracing::{event, Level};
 
-use super::http_client::HttpClient;
+use super::http_client::{HttpClient, SpeedLimit};
+use super::session::response_url;
 use super::HashableByteRange;
 use crate::error::LivestreamDLError;
 
+/// Smallest segment downloaded as multiple byte ranges, smaller segments aren't worth the
+/// extra requests
+const MIN_RANGE_PARTS_SIZE: u64 = 1024 * 1024;
+
+/// Number of byte range groups kept until the rest of their segments are fetched
+const RANGE_GROUP_CACHE_SIZE: usize = 8;
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct RemoteData(Url, Option<HashableByteRange>);
 
@@ -19,6 +34,12 @@ impl RemoteData {
         &self.0
     }
 
+    /// Offset and length of the byte range
+    pub fn range(&self) -> Option<(u64, u64)> {
+        let range = self.1.as_ref()?;
+        Some((range.offset.unwrap_or(0), range.length))
+    }
+
     pub fn byte_range_string(&self) -> Option<String> {
         let start = self.1.as_ref()?.offset.unwrap_or(0);
         let end = start + self.1.as_ref()?.length.saturating_sub(1);
@@ -26,26 +47,310 @@ impl RemoteData {
         Some(format!("bytes={}-{}", start, end))
     }
 
-    /// Fetch this segment and return (bytes, final url)
+    /// Fetch this segment and return (bytes, final url). Renditions sometimes reference the same
+    /// data, it is only downloaded once if it is fetched again while being downloaded
     pub async fn fetch(&self, client: &HttpClient) -> Result<(Vec<u8>, Url)> {
-        // Add byte range headers if needed
-        let mut header_map = HeaderMap::new();
-        if let Some(ref range) = self.byte_range_string() {
-            header_map.insert(header::RANGE, header::HeaderValue::from_str(range)?);
+        let fetch = client.in_flight().join(self);
+        fetch.get_or_try_init(|| self.download(client)).await?;
+
+        // Avoid copying the data if no other fetch is waiting for it
+        let data = match Arc::try_unwrap(fetch) {
+            Ok(f) => f.into_inner(),
+            Err(f) => f.get().cloned(),
+        };
+        Ok(data.expect("fetch was initialized"))
+    }
+
+    /// Fetch this segment as part of group, contiguous byte ranges of the same url fetched in one
+    /// request for all of their segments, and return (bytes, final url). Falls back to fetching
+    /// this segment alone if the group can't be fetched
+    pub async fn fetch_in_group(
+        &self,
+        client: &HttpClient,
+        group: &RemoteData,
+    ) -> Result<(Vec<u8>, Url)> {
+        match client.range_groups().fetch_part(client, group, self).await {
+            Ok(Some(data)) => return Ok(data),
+            Ok(None) => event!(
+                Level::DEBUG,
+                "Byte range group of {} doesn't contain its range",
+                self.url()
+            ),
+            Err(e) => event!(
+                Level::DEBUG,
+                "Failed to fetch byte range group of {}: {:?}",
+                self.url(),
+                e
+            ),
+        }
+
+        self.fetch(client).await
+    }
+
+    async fn download(&self, client: &HttpClient) -> Result<(Vec<u8>, Url)> {
+        if self.1.is_none() && client.range_parts() > 1 {
+            if let Some(len) = ranged_length(client, self.url()).await {
+                return fetch_parts(client, self.url(), len).await;
+            }
+        }
+
+        fetch_range(client, self.url(), self.byte_range_string()).await
+    }
+}
+
+/// Bytes and final url of data, set once by the first fetch that succeeds
+type SharedFetch = OnceCell<(Vec<u8>, Url)>;
+
+/// Fetches in progress, shared between clones
+#[derive(Clone, Debug, Default)]
+pub struct InFlight(Arc<Mutex<HashMap<RemoteData, Weak<SharedFetch>>>>);
+
+impl InFlight {
+    /// Fetch of data in progress, or a new one if there is none. A failed fetch is tried again
+    /// by the next task waiting for it
+    fn join(&self, data: &RemoteData) -> Arc<SharedFetch> {
+        let mut fetches = self.0.lock().unwrap();
+        if let Some(f) = fetches.get(data).and_then(Weak::upgrade) {
+            return f;
         }
 
-        // Fetch data
-        let resp = client
-            .get(self.url().clone())
-            .headers(header_map)
-            .send()
+        // Forget finished fetches
+        fetches.retain(|_, f| f.strong_count() > 0);
+        let f = Arc::new(OnceCell::new());
+        fetches.insert(data.clone(), Arc::downgrade(&f));
+        f
+    }
+}
+
+/// Byte range groups fetched recently or being fetched, shared between clones
+#[derive(Clone, Debug)]
+pub struct RangeGroups(Arc<Mutex<LruCache<RemoteData, Arc<SharedFetch>>>>);
+
+impl Default for RangeGroups {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(RANGE_GROUP_CACHE_SIZE))))
+    }
+}
+
+impl RangeGroups {
+    /// Fetch group if it isn't cached and return the bytes of part in it and the final url, None
+    /// if part isn't in group
+    async fn fetch_part(
+        &self,
+        client: &HttpClient,
+        group: &RemoteData,
+        part: &RemoteData,
+    ) -> Result<Option<(Vec<u8>, Url)>> {
+        let fetch = {
+            let mut groups = self.0.lock().unwrap();
+            match groups.get(group) {
+                Some(f) => f.clone(),
+                None => {
+                    let f = Arc::new(OnceCell::new());
+                    groups.put(group.clone(), f.clone());
+                    f
+                }
+            }
+        };
+        let (bytes, final_url) = fetch
+            .get_or_try_init(|| async {
+                let (bytes, final_url) =
+                    fetch_range(client, group.url(), group.byte_range_string()).await?;
+                // Servers may ignore ranges and send everything
+                if Some(bytes.len() as u64) != group.range().map(|(_, len)| len) {
+                    return Err(LivestreamDLError::SegmentFetch {
+                        url: final_url,
+                        status: StatusCode::RANGE_NOT_SATISFIABLE,
+                    }
+                    .into());
+                }
+                Ok::<_, anyhow::Error>((bytes, final_url))
+            })
             .await?;
-        if !resp.status().is_success() {
-            return Err(LivestreamDLError::NetworkRequest(resp).into());
+
+        let ((group_offset, _), (offset, len)) = match (group.range(), part.range()) {
+            (Some(g), Some(p)) if g.0 <= p.0 => (g, p),
+            _ => return Ok(None),
+        };
+        let start = (offset - group_offset) as usize;
+        Ok(bytes
+            .get(start..start + len as usize)
+            .map(|b| (b.to_vec(), final_url.clone())))
+    }
+}
+
+/// Length of the data at url if it is large enough to download in parts and the server accepts
+/// range requests
+async fn ranged_length(client: &HttpClient, url: &Url) -> Option<u64> {
+    let resp = client.head(url.clone()).send().await.ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+    let accepts_ranges = resp
+        .headers()
+        .get(header::ACCEPT_RANGES)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"bytes"));
+    let len = resp
+        .headers()
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+
+    (accepts_ranges && len >= MIN_RANGE_PARTS_SIZE).then_some(len)
+}
+
+/// Fetch len bytes at url as the client's number of byte ranges in parallel and return
+/// (bytes, final url)
+async fn fetch_parts(client: &HttpClient, url: &Url, len: u64) -> Result<(Vec<u8>, Url)> {
+    let parts = client.range_parts() as u64;
+    let part_len = len.div_ceil(parts);
+    let ranges = (0..len)
+        .step_by(part_len as usize)
+        .map(|start| (start, (start + part_len).min(len) - 1));
+    let results = futures::future::try_join_all(ranges.map(|(start, end)| async move {
+        let (bytes, final_url) =
+            fetch_range(client, url, Some(format!("bytes={}-{}", start, end))).await?;
+        // Servers may ignore ranges and send everything
+        if bytes.len() as u64 != end - start + 1 {
+            return Err(LivestreamDLError::SegmentFetch {
+                url: final_url,
+                status: StatusCode::RANGE_NOT_SATISFIABLE,
+            }
+            .into());
+        }
+        Ok::<_, anyhow::Error>((bytes, final_url))
+    }))
+    .await?;
+
+    let final_url = results
+        .first()
+        .map_or_else(|| url.clone(), |(_, u)| u.clone());
+    let bytes = results.into_iter().flat_map(|(b, _)| b).collect();
+
+    Ok((bytes, final_url))
+}
+
+/// Fetch url, optionally only a byte range, and return (bytes, final url)
+async fn fetch_range(
+    client: &HttpClient,
+    url: &Url,
+    range: Option<String>,
+) -> Result<(Vec<u8>, Url)> {
+    // Add byte range headers if needed
+    let mut header_map = HeaderMap::new();
+    if let Some(ref range) = range {
+        header_map.insert(header::RANGE, header::HeaderValue::from_str(range)?);
+    }
+
+    // Fetch data
+    let resp = client.get(url.clone()).headers(header_map).send().await?;
+    if !resp.status().is_success() {
+        return Err(LivestreamDLError::SegmentFetch {
+            url: response_url(&resp).clone(),
+            status: resp.status(),
+        }
+        .into());
+    }
+    let final_url = response_url(&resp).clone();
+    let bytes = match client.speed_limit() {
+        Some(limit) => read_body_watched(resp, &final_url, limit).await?,
+        None => resp.bytes().await?.into_iter().collect(),
+    };
+
+    Ok((bytes, final_url))
+}
+
+/// Read a response body, failing if less than the limit's minimum speed was received during
+/// any period of the limit's time
+async fn read_body_watched(mut resp: Response, url: &Url, limit: SpeedLimit) -> Result<Vec<u8>> {
+    let min_bytes = (limit.bytes_per_sec as f64 * limit.time.as_secs_f64()) as usize;
+    let mut data = Vec::new();
+    let mut deadline = Instant::now() + limit.time;
+    let mut received = 0;
+    loop {
+        // Stop waiting for the next chunk at the end of the period
+        if let Ok(chunk) = time::timeout_at(deadline, resp.chunk()).await {
+            match chunk? {
+                Some(c) => {
+                    received += c.len();
+                    data.extend_from_slice(&c);
+                }
+                None => break,
+            }
+        }
+
+        // Check speed over each period
+        if Instant::now() >= deadline {
+            if received < min_bytes {
+                return Err(LivestreamDLError::SlowTransfer {
+                    url: url.clone(),
+                    bytes_per_sec: limit.bytes_per_sec,
+                }
+                .into());
+            }
+            received = 0;
+            deadline = Instant::now() + limit.time;
         }
-        let final_url = resp.url().clone();
-        let bytes = resp.bytes().await?.into_iter().collect();
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn data(path: &str) -> RemoteData {
+        RemoteData::new(
+            Url::parse("https://example.com/")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+            None,
+        )
+    }
+
+    #[test]
+    fn in_flight_shares_fetches_of_the_same_data() {
+        let in_flight = InFlight::default();
+        let a = in_flight.join(&data("a.ts"));
+        assert!(Arc::ptr_eq(&a, &in_flight.join(&data("a.ts"))));
+        assert!(!Arc::ptr_eq(&a, &in_flight.join(&data("b.ts"))));
+
+        // Finished fetches are forgotten
+        drop(a);
+        let a = in_flight.join(&data("a.ts"));
+        assert!(!a.initialized());
+    }
+
+    #[tokio::test]
+    async fn in_flight_fetches_once_and_retries_failures() {
+        let in_flight = InFlight::default();
+        let calls = AtomicUsize::new(0);
+        let fetch = |fail: bool| {
+            let calls = &calls;
+            move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                if fail {
+                    return Err(anyhow::anyhow!("fetch failed"));
+                }
+                Ok((vec![1, 2, 3], data("a.ts").url().clone()))
+            }
+        };
+
+        // The fetch of a waiting task is tried after the first one fails
+        let (a, b) = (in_flight.join(&data("a.ts")), in_flight.join(&data("a.ts")));
+        assert!(a.get_or_try_init(fetch(true)).await.is_err());
+        assert!(b.get_or_try_init(fetch(false)).await.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
 
-        Ok((bytes, final_url))
+        // Later fetches of the data share the result
+        let c = in_flight.join(&data("a.ts"));
+        assert_eq!(c.get_or_try_init(fetch(false)).await.unwrap().0, [1, 2, 3]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
     }
 }
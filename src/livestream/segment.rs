@@ -1,5 +1,15 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use flate2::read::GzDecoder;
 use reqwest::Url;
+use time::OffsetDateTime;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
+use super::http_client::HttpClient;
 use super::remote_data::RemoteData;
 use super::MediaFormat;
 
@@ -9,8 +19,14 @@ pub struct Segment {
     pub data: RemoteData,
     pub discon_seq: u64,
     pub seq: u64,
+    pub duration: Duration,
     pub format: MediaFormat,
     pub initialization: Option<RemoteData>,
+    /// Contiguous byte ranges of the same url this segment's byte range is part of, fetched in
+    /// one request
+    pub group: Option<RemoteData>,
+    /// Wall clock time of the start of the segment from EXT-X-PROGRAM-DATE-TIME
+    pub program_date_time: Option<OffsetDateTime>,
 }
 
 impl Segment {
@@ -19,10 +35,25 @@ impl Segment {
         self.data.url()
     }
 
+    /// Fetch segment, as part of its byte range group if it has one, and return (bytes, final
+    /// url)
+    pub async fn fetch(&self, client: &HttpClient) -> Result<(Vec<u8>, Url)> {
+        match &self.group {
+            Some(group) => self.data.fetch_in_group(client, group).await,
+            None => self.data.fetch(client).await,
+        }
+    }
+
     /// String identifier of segment
     pub fn id(&self) -> String {
         format!("d{:010}s{:010}", self.discon_seq, self.seq)
     }
+
+    /// Parse the discontinuity and media sequence numbers from a segment identifier
+    pub fn parse_id(id: &str) -> Option<(u64, u64)> {
+        let (discon_seq, seq) = id.strip_prefix('d')?.split_once('s')?;
+        Some((discon_seq.parse().ok()?, seq.parse().ok()?))
+    }
 }
 
 impl PartialOrd for Segment {
@@ -36,3 +67,109 @@ impl Ord for Segment {
         (self.discon_seq, self.seq).cmp(&(other.discon_seq, other.seq))
     }
 }
+
+/// Where a downloaded segment is stored, either a whole file or a byte range of a pack file
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct SegmentFile {
+    pub path: PathBuf,
+    /// Offset and length in the pack file
+    pub range: Option<(u64, u64)>,
+    /// Whether the segment is stored gzip compressed
+    pub gzip: bool,
+}
+
+impl From<PathBuf> for SegmentFile {
+    fn from(path: PathBuf) -> Self {
+        Self {
+            path,
+            range: None,
+            gzip: false,
+        }
+    }
+}
+
+impl SegmentFile {
+    /// Size of the segment in bytes as stored on disk
+    pub fn size(&self) -> io::Result<u64> {
+        match self.range {
+            Some((_, len)) => Ok(len),
+            None => Ok(std::fs::metadata(&self.path)?.len()),
+        }
+    }
+
+    /// Read the whole segment into memory, decompressing it if needed
+    pub async fn read(&self) -> io::Result<Vec<u8>> {
+        let data = match self.range {
+            Some((offset, len)) => {
+                let mut file = fs::File::open(&self.path).await?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut data = vec![0; len as usize];
+                file.read_exact(&mut data).await?;
+                data
+            }
+            None => fs::read(&self.path).await?,
+        };
+
+        if self.gzip {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        } else {
+            Ok(data)
+        }
+    }
+
+    /// Append the segment to a file, decompressing it if needed, blocking
+    pub fn copy_to(&self, output: &mut std::fs::File) -> io::Result<u64> {
+        let mut input = std::fs::File::open(&self.path)?;
+        let len = match self.range {
+            Some((offset, len)) => {
+                input.seek(SeekFrom::Start(offset))?;
+                len
+            }
+            None => u64::MAX,
+        };
+        let mut input = input.take(len);
+
+        if self.gzip {
+            io::copy(&mut GzDecoder::new(input), output)
+        } else {
+            io::copy(&mut input, output)
+        }
+    }
+
+    /// Read up to len bytes from the start of the segment, decompressing it if needed, blocking
+    pub fn read_head(&self, len: u64) -> io::Result<Vec<u8>> {
+        let mut input = std::fs::File::open(&self.path)?;
+        let range_len = match self.range {
+            Some((offset, len)) => {
+                input.seek(SeekFrom::Start(offset))?;
+                len
+            }
+            None => u64::MAX,
+        };
+        let input = input.take(range_len);
+
+        let mut data = Vec::new();
+        if self.gzip {
+            GzDecoder::new(input).take(len).read_to_end(&mut data)?;
+        } else {
+            input.take(len).read_to_end(&mut data)?;
+        }
+        Ok(data)
+    }
+
+    /// Input url for ffmpeg, byte ranges of pack files use the subfile protocol
+    pub fn ffmpeg_input(&self, cwd: &Path) -> String {
+        let path = cwd.join(&self.path);
+        match self.range {
+            Some((offset, len)) => format!(
+                "subfile,,start,{},end,{},,:{}",
+                offset,
+                offset + len,
+                path.to_string_lossy()
+            ),
+            None => path.to_string_lossy().into_owned(),
+        }
+    }
+}
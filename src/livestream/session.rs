@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use reqwest::header::HeaderMap;
+use reqwest::{Body, Request, Response, StatusCode, Url};
+use reqwest_middleware::{Middleware, Next};
+use serde::{Deserialize, Serialize};
+use task_local_extensions::Extensions;
+use tokio::fs;
+use tracing::{event, Level};
+
+/// Final url of a rebuilt response, e.g. a recorded or replayed one. reqwest can't set the url
+/// of a rebuilt response, so it is kept in the response's extensions
+#[derive(Clone, Debug)]
+struct RecordedUrl(Url);
+
+/// Url of a response after redirects, also for recorded and replayed responses
+pub fn response_url(resp: &Response) -> &Url {
+    resp.extensions()
+        .get::<RecordedUrl>()
+        .map_or(resp.url(), |u| &u.0)
+}
+
+/// Set the url returned by response_url for a rebuilt response
+pub fn set_response_url(resp: &mut Response, url: Url) {
+    resp.extensions_mut().insert(RecordedUrl(url));
+}
+
+/// A recorded request and its response, the body is saved in a file next to it
+#[derive(Debug, Serialize, Deserialize)]
+struct Exchange {
+    method: String,
+    url: String,
+    final_url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl Exchange {
+    /// Rebuild the recorded response with the given body
+    fn response(&self, body: impl Into<Body>) -> Result<Response> {
+        let mut builder = http::Response::builder().status(StatusCode::from_u16(self.status)?);
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        let mut resp = Response::from(builder.body(body)?);
+        set_response_url(&mut resp, Url::parse(&self.final_url)?);
+
+        Ok(resp)
+    }
+}
+
+/// Middleware saving every request and response into a directory, to reproduce a download with
+/// SessionReplayer
+#[derive(Debug)]
+pub struct SessionRecorder {
+    dir: PathBuf,
+    next_id: AtomicU64,
+}
+
+impl SessionRecorder {
+    pub fn new(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("error creating session directory {:?}", dir))?;
+        Ok(Self {
+            dir: dir.to_owned(),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Save an exchange and its response body
+    async fn save(&self, exchange: &Exchange, body: &[u8]) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{:010}", id);
+        fs::write(self.dir.join(&name).with_extension("body"), body).await?;
+        fs::write(
+            self.dir.join(&name).with_extension("json"),
+            serde_json::to_vec_pretty(exchange)?,
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for SessionRecorder {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let method = req.method().to_string();
+        let url = req.url().to_string();
+        let resp = next.run(req, extensions).await?;
+
+        let exchange = Exchange {
+            method,
+            url,
+            final_url: resp.url().to_string(),
+            status: resp.status().as_u16(),
+            headers: header_pairs(resp.headers()),
+        };
+        let body = resp.bytes().await?;
+        if let Err(e) = self.save(&exchange, &body).await {
+            event!(Level::WARN, "Failed to record {}: {:?}", exchange.url, e);
+        }
+
+        Ok(exchange.response(body)?)
+    }
+}
+
+/// Recorded exchanges and their bodies in recorded order, by request method and url
+type RecordedExchanges = HashMap<(String, String), VecDeque<(Exchange, Vec<u8>)>>;
+
+/// Middleware answering requests with the responses saved by SessionRecorder instead of using
+/// the network. Responses to the same request are replayed in recorded order, the last one is
+/// repeated once they run out
+#[derive(Debug)]
+pub struct SessionReplayer {
+    exchanges: Mutex<RecordedExchanges>,
+}
+
+impl SessionReplayer {
+    /// Load the exchanges recorded in dir
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("error opening session directory {:?}", dir))?
+        {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                files.insert(path.file_stem().map(|s| s.to_owned()), path);
+            }
+        }
+
+        let mut exchanges = RecordedExchanges::new();
+        for path in files.into_values() {
+            let exchange: Exchange = serde_json::from_slice(&std::fs::read(&path)?)
+                .with_context(|| format!("invalid recorded exchange {:?}", path))?;
+            let body = std::fs::read(path.with_extension("body"))?;
+            exchanges
+                .entry((exchange.method.clone(), exchange.url.clone()))
+                .or_default()
+                .push_back((exchange, body));
+        }
+        event!(
+            Level::INFO,
+            "Replaying {} recorded requests from {:?}",
+            exchanges.values().map(VecDeque::len).sum::<usize>(),
+            dir
+        );
+
+        Ok(Self {
+            exchanges: Mutex::new(exchanges),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for SessionReplayer {
+    async fn handle(
+        &self,
+        req: Request,
+        _extensions: &mut Extensions,
+        _next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let key = (req.method().to_string(), req.url().to_string());
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let recorded = exchanges
+            .get_mut(&key)
+            .ok_or_else(|| anyhow::anyhow!("no recorded response for {} {}", key.0, key.1))?;
+        let resp = match recorded.len() {
+            0 | 1 => {
+                let (exchange, body) = recorded
+                    .front()
+                    .ok_or_else(|| anyhow::anyhow!("no recorded response for {}", key.1))?;
+                exchange.response(body.clone())?
+            }
+            _ => {
+                let (exchange, body) = recorded.pop_front().unwrap();
+                exchange.response(body)?
+            }
+        };
+
+        Ok(resp)
+    }
+}
+
+/// Headers as name and value pairs, values that aren't valid strings are skipped
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_owned())))
+        .collect()
+}
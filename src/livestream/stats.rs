@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next};
+use serde::Serialize;
+use task_local_extensions::Extensions;
+use time::OffsetDateTime;
+use tracing::{event, Level};
+
+use super::queue::SlidingWindow;
+use super::{Stream, PROGRESS_TARGET};
+
+/// Interval between logs of how far behind live the download is
+const LATENCY_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of consecutive intervals the download queue must grow for before warning
+const FALLING_BEHIND_INTERVALS: u32 = 3;
+
+/// Download statistics shared across all segment downloads
+#[derive(Clone, Debug)]
+pub struct Stats(Arc<StatsInner>);
+
+#[derive(Debug)]
+struct StatsInner {
+    start: Instant,
+    streams: Mutex<HashMap<Stream, StreamStats>>,
+    requests: AtomicU64,
+    attempts: AtomicU64,
+    max_queue_depth: AtomicU64,
+    av_offsets: Mutex<BTreeMap<String, f64>>,
+}
+
+/// Download statistics of a single stream
+#[derive(Clone, Default, Debug, Serialize)]
+pub struct StreamStats {
+    /// Total size of downloaded segments in bytes
+    pub bytes: u64,
+    /// Number of segments saved to disk
+    pub segments_downloaded: u64,
+    /// Number of segments that failed to download or save
+    pub segments_failed: u64,
+    /// Number of failed segments that were removed from the live playlist before download
+    pub segments_expired: u64,
+    /// Number of segments dropped from a full download queue
+    pub segments_dropped: u64,
+    /// Number of saved segments that don't look like media after decrypting, likely decrypted
+    /// with the wrong key or IV
+    pub segments_corrupt: u64,
+    /// Total media duration of downloaded segments in seconds
+    pub duration: f64,
+    /// Error that stopped the stream's playlist fetcher
+    pub error: Option<String>,
+    /// Seconds between the live edge of the playlist and the local clock at the last reload,
+    /// includes encoder delay and clock drift between the server and this machine
+    pub playlist_latency: Option<f64>,
+    /// Seconds between the live edge of the playlist and the end of the latest saved segment
+    pub behind_live: Option<f64>,
+    /// Largest behind_live seen during the download
+    pub max_behind_live: Option<f64>,
+    /// Number of segments waiting in the download queue
+    #[serde(skip)]
+    pub queued: u64,
+    /// Time of the last playlist reload
+    #[serde(skip)]
+    pub last_reload: Option<Instant>,
+    /// Program date time at the end of the last segment in the playlist
+    #[serde(skip)]
+    live_edge: Option<OffsetDateTime>,
+    /// Program date time at the end of the latest saved segment
+    #[serde(skip)]
+    recorded_to: Option<OffsetDateTime>,
+}
+
+/// Summary of a finished download, written to summary.json
+#[derive(Serialize)]
+struct Summary<'a> {
+    elapsed: f64,
+    retries: u64,
+    /// Largest number of segments waiting in the download queue
+    max_queue_depth: u64,
+    streams: BTreeMap<String, &'a StreamStats>,
+    /// Start of the audio minus start of the video in seconds of each remuxed file
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    av_offsets: BTreeMap<String, f64>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self(Arc::new(StatsInner {
+            start: Instant::now(),
+            streams: Mutex::new(HashMap::new()),
+            requests: AtomicU64::new(0),
+            attempts: AtomicU64::new(0),
+            max_queue_depth: AtomicU64::new(0),
+            av_offsets: Mutex::new(BTreeMap::new()),
+        }))
+    }
+
+    /// Record a segment saved to disk, returns the media duration recorded by the stream so far
+    /// in seconds
+    pub fn segment_downloaded(&self, stream: &Stream, bytes: usize, duration: Duration) -> f64 {
+        let mut streams = self.0.streams.lock().unwrap();
+        let s = streams.entry(stream.clone()).or_default();
+        s.bytes += bytes as u64;
+        s.segments_downloaded += 1;
+        s.duration += duration.as_secs_f64();
+        s.duration
+    }
+
+    /// Record the program date time at the end of a stream's playlist after reloading it
+    pub fn live_edge(&self, stream: &Stream, edge: OffsetDateTime) {
+        let mut streams = self.0.streams.lock().unwrap();
+        let s = streams.entry(stream.clone()).or_default();
+        s.live_edge = Some(edge);
+        s.playlist_latency = Some((OffsetDateTime::now_utc() - edge).as_seconds_f64());
+        s.update_behind_live();
+    }
+
+    /// Record the program date time at the end of a saved segment
+    pub fn recorded_to(&self, stream: &Stream, end: OffsetDateTime) {
+        let mut streams = self.0.streams.lock().unwrap();
+        let s = streams.entry(stream.clone()).or_default();
+        s.recorded_to = Some(s.recorded_to.map_or(end, |t| t.max(end)));
+        s.update_behind_live();
+    }
+
+    /// Log how far behind live each stream is, warning if above threshold seconds
+    pub fn log_latency(&self, threshold: f64) {
+        let streams = self.0.streams.lock().unwrap();
+        for (stream, s) in sorted(&streams) {
+            let (behind, playlist_latency) = match (s.behind_live, s.playlist_latency) {
+                (Some(b), Some(l)) => (b, l),
+                _ => continue,
+            };
+            if behind > threshold {
+                event!(
+                    Level::WARN,
+                    "{}: {:.1}s behind live, playlist is {:.1}s behind local clock",
+                    stream,
+                    behind,
+                    playlist_latency
+                );
+            } else {
+                event!(
+                    target: PROGRESS_TARGET,
+                    Level::INFO,
+                    "{}: {:.1}s behind live, playlist is {:.1}s behind local clock",
+                    stream,
+                    behind,
+                    playlist_latency
+                );
+            }
+        }
+    }
+
+    /// Record a segment that failed to download or save
+    pub fn segment_failed(&self, stream: &Stream) {
+        let mut streams = self.0.streams.lock().unwrap();
+        streams.entry(stream.clone()).or_default().segments_failed += 1;
+    }
+
+    /// Record a failed segment that was removed from the live playlist before download
+    pub fn segment_expired(&self, stream: &Stream) {
+        let mut streams = self.0.streams.lock().unwrap();
+        streams.entry(stream.clone()).or_default().segments_expired += 1;
+    }
+
+    /// Record a segment dropped from a full download queue
+    pub fn segment_dropped(&self, stream: &Stream) {
+        let mut streams = self.0.streams.lock().unwrap();
+        streams.entry(stream.clone()).or_default().segments_dropped += 1;
+    }
+
+    /// Record a segment moved into the download queue
+    pub fn segment_queued(&self, stream: &Stream) {
+        let mut streams = self.0.streams.lock().unwrap();
+        streams.entry(stream.clone()).or_default().queued += 1;
+    }
+
+    /// Record a segment taken out of the download queue, to be downloaded or dropped
+    pub fn segment_dequeued(&self, stream: &Stream) {
+        let mut streams = self.0.streams.lock().unwrap();
+        let s = streams.entry(stream.clone()).or_default();
+        s.queued = s.queued.saturating_sub(1);
+    }
+
+    /// Record a reload of a stream's playlist
+    pub fn playlist_reloaded(&self, stream: &Stream) {
+        let mut streams = self.0.streams.lock().unwrap();
+        streams.entry(stream.clone()).or_default().last_reload = Some(Instant::now());
+    }
+
+    /// Record the number of segments waiting in the download queue
+    pub fn queue_depth(&self, depth: usize) {
+        self.0
+            .max_queue_depth
+            .fetch_max(depth as u64, Ordering::Relaxed);
+    }
+
+    /// Record a saved segment that doesn't look like media after decrypting
+    pub fn segment_corrupt(&self, stream: &Stream) {
+        let mut streams = self.0.streams.lock().unwrap();
+        streams.entry(stream.clone()).or_default().segments_corrupt += 1;
+    }
+
+    /// Record a stream whose playlist fetcher failed
+    pub fn stream_failed(&self, stream: &Stream, error: String) {
+        let mut streams = self.0.streams.lock().unwrap();
+        streams.entry(stream.clone()).or_default().error = Some(error);
+    }
+
+    /// Number of streams whose playlist fetcher failed
+    pub fn streams_failed(&self) -> u64 {
+        let streams = self.0.streams.lock().unwrap();
+        streams.values().filter(|s| s.error.is_some()).count() as u64
+    }
+
+    /// Longest media duration recorded by any stream in seconds
+    pub fn recorded_duration(&self) -> f64 {
+        let streams = self.0.streams.lock().unwrap();
+        streams.values().map(|s| s.duration).fold(0.0, f64::max)
+    }
+
+    /// Total number of segments that failed to download or save
+    pub fn segments_failed(&self) -> u64 {
+        let streams = self.0.streams.lock().unwrap();
+        streams.values().map(|s| s.segments_failed).sum()
+    }
+
+    /// Statistics of every stream so far, sorted by stream
+    pub fn streams(&self) -> Vec<(Stream, StreamStats)> {
+        let streams = self.0.streams.lock().unwrap();
+        sorted(&streams)
+            .into_iter()
+            .map(|(stream, s)| (stream.clone(), s.clone()))
+            .collect()
+    }
+
+    /// Time since the download started
+    pub fn elapsed(&self) -> Duration {
+        self.0.start.elapsed()
+    }
+
+    /// Number of network requests that were retried
+    pub fn retries(&self) -> u64 {
+        let attempts = self.0.attempts.load(Ordering::Relaxed);
+        attempts.saturating_sub(self.0.requests.load(Ordering::Relaxed))
+    }
+
+    /// Middleware counting requests, should be added before the retry middleware
+    pub fn request_counter(&self) -> RequestCounter {
+        RequestCounter {
+            stats: self.clone(),
+            attempt: false,
+        }
+    }
+
+    /// Middleware counting request attempts, should be added after the retry middleware
+    pub fn attempt_counter(&self) -> RequestCounter {
+        RequestCounter {
+            stats: self.clone(),
+            attempt: true,
+        }
+    }
+
+    /// Log a summary of all streams
+    pub fn log_summary(&self) {
+        let elapsed = self.0.start.elapsed();
+        let streams = self.0.streams.lock().unwrap();
+        for (stream, s) in sorted(&streams) {
+            event!(
+                Level::INFO,
+                "{}: {} in {} segments ({} failed, {} expired), {} recorded, {} average",
+                stream,
+                format_bytes(s.bytes),
+                s.segments_downloaded,
+                s.segments_failed,
+                s.segments_expired,
+                format_duration(s.duration),
+                format_bitrate(s.bytes, s.duration),
+            );
+            if s.segments_dropped > 0 {
+                event!(
+                    Level::WARN,
+                    "{}: {} segments dropped from the full download queue",
+                    stream,
+                    s.segments_dropped
+                );
+            }
+            if s.segments_corrupt > 0 {
+                event!(
+                    Level::WARN,
+                    "{}: {} segments may be corrupt, they didn't decrypt to media",
+                    stream,
+                    s.segments_corrupt
+                );
+            }
+            if let Some(e) = &s.error {
+                event!(Level::WARN, "{}: stream failed: {}", stream, e);
+            }
+        }
+        event!(
+            Level::INFO,
+            "Finished in {}, {} network requests retried",
+            format_duration(elapsed.as_secs_f64()),
+            self.retries()
+        );
+    }
+
+    /// Record the audio offset measured in a remuxed file
+    pub fn av_offset(&self, file_name: String, offset: f64) {
+        self.0.av_offsets.lock().unwrap().insert(file_name, offset);
+    }
+
+    /// Write a summary of all streams as json
+    pub fn write_summary(&self, path: impl AsRef<Path>) -> Result<()> {
+        let streams = self.0.streams.lock().unwrap();
+        let summary = Summary {
+            elapsed: self.0.start.elapsed().as_secs_f64(),
+            retries: self.retries(),
+            max_queue_depth: self.0.max_queue_depth.load(Ordering::Relaxed),
+            streams: sorted(&streams)
+                .into_iter()
+                .map(|(stream, s)| (stream.to_string(), s))
+                .collect(),
+            av_offsets: self.0.av_offsets.lock().unwrap().clone(),
+        };
+
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, &summary)?;
+
+        Ok(())
+    }
+}
+
+impl StreamStats {
+    fn update_behind_live(&mut self) {
+        if let (Some(edge), Some(recorded)) = (self.live_edge, self.recorded_to) {
+            let behind = (edge - recorded).as_seconds_f64().max(0.0);
+            self.behind_live = Some(behind);
+            self.max_behind_live = Some(self.max_behind_live.map_or(behind, |m| m.max(behind)));
+        }
+    }
+}
+
+/// Periodically log how far behind live each stream is, and warn if the number of segments
+/// waiting to be downloaded keeps growing. Runs until aborted
+pub async fn monitor_latency(stats: Stats, window: SlidingWindow, threshold: f64) {
+    let mut interval = tokio::time::interval(LATENCY_LOG_INTERVAL);
+    interval.tick().await;
+
+    let mut last_queued = 0;
+    let mut growing = 0;
+    loop {
+        interval.tick().await;
+        stats.log_latency(threshold);
+
+        let queued = window.queued_count();
+        growing = if queued > last_queued { growing + 1 } else { 0 };
+        if growing >= FALLING_BEHIND_INTERVALS {
+            event!(
+                Level::WARN,
+                "Download is falling behind, {} segments waiting to be downloaded",
+                queued
+            );
+        }
+        last_queued = queued;
+    }
+}
+
+fn sorted(streams: &HashMap<Stream, StreamStats>) -> Vec<(&Stream, &StreamStats)> {
+    let mut v: Vec<_> = streams.iter().collect();
+    v.sort_by_key(|(stream, _)| stream.to_string());
+    v
+}
+
+/// Format bytes with binary prefixes, e.g. "1.5 GiB"
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Format seconds as hh:mm:ss
+pub fn format_duration(seconds: f64) -> String {
+    let s = seconds as u64;
+    format!("{:02}:{:02}:{:02}", s / 3600, (s / 60) % 60, s % 60)
+}
+
+fn format_bitrate(bytes: u64, seconds: f64) -> String {
+    if seconds <= 0.0 {
+        return "unknown bitrate".into();
+    }
+    format!("{:.0} kb/s", bytes as f64 * 8.0 / seconds / 1000.0)
+}
+
+/// Counts network requests or attempts, used to calculate the number of retries
+pub struct RequestCounter {
+    stats: Stats,
+    attempt: bool,
+}
+
+#[async_trait::async_trait]
+impl Middleware for RequestCounter {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let counter = if self.attempt {
+            &self.stats.0.attempts
+        } else {
+            &self.stats.0.requests
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        next.run(req, extensions).await
+    }
+}
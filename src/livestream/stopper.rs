@@ -1,30 +1,148 @@
+use std::fmt::Display;
 use std::sync::Arc;
 
-use futures::lock::Mutex;
-use tokio::sync::Notify;
+use tokio::sync::watch;
 
+/// Why a download was stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// Ctrl-C or the dashboard's stop key
+    User,
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::User => write!(f, "stopped by user"),
+        }
+    }
+}
+
+/// How far shutdown has progressed, each phase includes the ones before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownPhase {
+    /// Downloading normally
+    Running,
+    /// Playlists are no longer polled, queued segments are still downloaded and muxed
+    StopFetching,
+    /// Remaining queued segments are skipped, downloaded segments are still muxed
+    SkipSegments,
+    /// Everything is aborted
+    Abort,
+}
+
+impl ShutdownPhase {
+    fn next(self) -> Self {
+        match self {
+            Self::Running => Self::StopFetching,
+            Self::StopFetching => Self::SkipSegments,
+            Self::SkipSegments | Self::Abort => Self::Abort,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct State {
+    phase: ShutdownPhase,
+    reason: Option<StopReason>,
+}
+
+/// Used to signal m3u8 fetcher tasks and the segment downloader to shut down
 #[derive(Clone, Debug)]
-pub struct Stopper(Arc<(Notify, Mutex<bool>)>);
+pub struct Stopper {
+    tx: Arc<watch::Sender<State>>,
+    rx: watch::Receiver<State>,
+}
+
+impl Default for Stopper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-/// Used to signal m3u8 fetcher task to quit
 impl Stopper {
     pub fn new() -> Self {
-        Self(Arc::new((Notify::new(), Mutex::new(false))))
+        let (tx, rx) = watch::channel(State {
+            phase: ShutdownPhase::Running,
+            reason: None,
+        });
+        Self {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+
+    /// Wait until stopped, returns immediately if already stopped
+    ///
+    /// Cancel safe, each call waits on its own receiver
+    pub async fn wait(&self) -> StopReason {
+        self.wait_phase(ShutdownPhase::StopFetching).await
     }
 
-    /// Wait for stopper to be notified
-    pub async fn wait(&self) {
-        self.0 .0.notified().await;
+    /// Wait until shutdown reaches at least the given phase
+    ///
+    /// Cancel safe, each call waits on its own receiver
+    pub async fn wait_phase(&self, phase: ShutdownPhase) -> StopReason {
+        let mut rx = self.rx.clone();
+        loop {
+            let state = *rx.borrow_and_update();
+            if let (true, Some(reason)) = (state.phase >= phase, state.reason) {
+                return reason;
+            }
+            // The sender lives as long as any stopper, so this never fails
+            if rx.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
     }
 
     /// Check if stopped
-    pub async fn stopped(&self) -> bool {
-        *self.0 .1.lock().await
+    pub fn is_stopped(&self) -> bool {
+        self.phase() > ShutdownPhase::Running
+    }
+
+    /// Current shutdown phase
+    pub fn phase(&self) -> ShutdownPhase {
+        self.rx.borrow().phase
+    }
+
+    /// Reason the stopper was stopped, if stopped
+    pub fn reason(&self) -> Option<StopReason> {
+        self.rx.borrow().reason
+    }
+
+    /// Stop fetching playlists and notify waiters
+    pub fn stop(&self, reason: StopReason) {
+        self.advance_to(ShutdownPhase::StopFetching, reason);
+    }
+
+    /// Move shutdown to the next phase, returns the new phase
+    pub fn escalate(&self, reason: StopReason) -> ShutdownPhase {
+        self.advance_to(self.phase().next(), reason)
+    }
+
+    /// Move other through the same shutdown phases as this stopper
+    pub async fn forward_to(&self, other: &Stopper) {
+        for phase in [
+            ShutdownPhase::StopFetching,
+            ShutdownPhase::SkipSegments,
+            ShutdownPhase::Abort,
+        ] {
+            other.advance_to(phase, self.wait_phase(phase).await);
+        }
     }
 
-    /// Set to stopped and notify waiters
-    pub async fn stop(&self) {
-        *self.0 .1.lock().await = true;
-        self.0 .0.notify_waiters();
+    /// Move shutdown to at least the given phase and notify waiters, returns the new phase. The
+    /// first reason is kept if stopped multiple times
+    pub fn advance_to(&self, phase: ShutdownPhase, reason: StopReason) -> ShutdownPhase {
+        self.tx.send_if_modified(|s| {
+            if s.phase >= phase {
+                return false;
+            }
+            s.phase = phase;
+            s.reason.get_or_insert(reason);
+            true
+        });
+        self.phase()
     }
 }
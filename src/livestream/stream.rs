@@ -1,10 +1,101 @@
+use std::fmt::Display;
+
+use m3u8_rs::AlternativeMedia;
+use serde::{Deserialize, Serialize};
+
 /// Type of stream
-#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum Stream {
     Main,
 
+    // I-frame only playlist of main stream
+    IFrames,
+
     // Alternative media
-    Video { name: String, lang: Option<String> },
-    Audio { name: String, lang: Option<String> },
-    Subtitle { name: String, lang: Option<String> },
+    Video {
+        name: String,
+        lang: Option<String>,
+        disposition: Disposition,
+    },
+    Audio {
+        name: String,
+        lang: Option<String>,
+        disposition: Disposition,
+    },
+    Subtitle {
+        name: String,
+        lang: Option<String>,
+        disposition: Disposition,
+    },
+}
+
+/// DEFAULT and FORCED attributes of alternative media
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct Disposition {
+    pub default: bool,
+    pub forced: bool,
+}
+
+impl From<&AlternativeMedia> for Disposition {
+    fn from(a: &AlternativeMedia) -> Self {
+        Self {
+            default: a.default,
+            forced: a.forced,
+        }
+    }
+}
+
+impl Disposition {
+    /// Value of ffmpeg -disposition argument
+    pub fn ffmpeg_value(&self) -> &'static str {
+        match (self.default, self.forced) {
+            (true, true) => "default+forced",
+            (true, false) => "default",
+            (false, true) => "forced",
+            (false, false) => "0",
+        }
+    }
+}
+
+/// Codec, channel, bandwidth, and characteristics information of a stream from the master playlist
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct StreamInfo {
+    pub codecs: Option<String>,
+    pub channels: Option<String>,
+    pub bandwidth: Option<u64>,
+    /// CHARACTERISTICS attribute of alternative media, comma separated UTIs
+    #[serde(default)]
+    pub characteristics: Option<String>,
+    /// Alternative media without a URI, carried inside the main stream, in playlist order
+    pub muxed: Vec<Stream>,
+}
+
+impl StreamInfo {
+    /// Whether there is no codec, channel, bandwidth, or characteristics information to display
+    pub fn is_empty(&self) -> bool {
+        self.codecs.is_none()
+            && self.channels.is_none()
+            && self.bandwidth.is_none()
+            && self.characteristics.is_none()
+    }
+}
+
+impl Display for StreamInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(c) = &self.codecs {
+            parts.push(c.clone());
+        }
+        if let Some(c) = &self.channels {
+            parts.push(format!("{} channels", c));
+        }
+        if let Some(b) = self.bandwidth {
+            parts.push(format!("{} kb/s", b / 1000));
+        }
+        if let Some(c) = &self.characteristics {
+            parts.push(c.clone());
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
 }
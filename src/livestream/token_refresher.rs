@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::{StatusCode, Url};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{event, Level};
+
+use super::hooks::shell_command;
+use super::http_client::HttpClient;
+use super::session::response_url;
+use crate::error::LivestreamDLError;
+
+/// Minimum time between two token refreshes, so a burst of concurrently failing requests only
+/// triggers a single refresh
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Refreshes expiring query parameter tokens (e.g. hdnts=, wmsAuthSign=) and updates the query
+/// parameters added to every request by HttpClient
+#[derive(Debug)]
+pub struct TokenRefresher {
+    client: HttpClient,
+    url: Url,
+    command: Option<String>,
+    last_refresh: Mutex<Option<Instant>>,
+}
+
+impl TokenRefresher {
+    /// If command is given, it is run to get an updated url, otherwise url is re-fetched and the
+    /// query parameters of the final (possibly redirected) url are used
+    pub fn new(client: HttpClient, url: Url, command: Option<String>) -> Self {
+        Self {
+            client,
+            url,
+            command,
+            last_refresh: Mutex::new(None),
+        }
+    }
+
+    /// Refresh tokens, skipped if another refresh happened recently
+    pub async fn refresh(&self) -> Result<()> {
+        let mut last_refresh = self.last_refresh.lock().await;
+        if let Some(t) = *last_refresh {
+            if t.elapsed() < MIN_REFRESH_INTERVAL {
+                return Ok(());
+            }
+        }
+
+        let new_url = match &self.command {
+            Some(c) => run_refresh_command(c).await?,
+            None => {
+                let resp = self.client.get(self.url.clone()).send().await?;
+                if !resp.status().is_success() {
+                    return Err(LivestreamDLError::NetworkRequest(resp).into());
+                }
+                response_url(&resp).clone()
+            }
+        };
+
+        event!(Level::INFO, "Refreshed query parameter tokens");
+        self.client.set_query_pairs(Some(new_url.query_pairs()));
+        *last_refresh = Some(Instant::now());
+
+        Ok(())
+    }
+}
+
+/// Run refresh command in a shell and parse its output as an url
+async fn run_refresh_command(command: &str) -> Result<Url> {
+    let mut cmd = shell_command(command);
+    event!(Level::TRACE, "{:?}", cmd);
+    let output = cmd.output().await?;
+    if !output.status.success() {
+        return Err(LivestreamDLError::TokenRefresh(format!(
+            "refresh command exited with {}",
+            output.status
+        ))
+        .into());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Url::parse(stdout.trim()).context("refresh command did not print a valid url")
+}
+
+/// Check if an error was caused by a 403 Forbidden response
+pub fn is_forbidden(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<LivestreamDLError>() {
+        Some(LivestreamDLError::NetworkRequest(r)) => r.status() == StatusCode::FORBIDDEN,
+        Some(LivestreamDLError::SegmentFetch { status, .. }) => *status == StatusCode::FORBIDDEN,
+        _ => false,
+    }
+}
@@ -1,5 +1,22 @@
+use std::future::Future;
+use std::io::Write;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
 use anyhow::Result;
+use m3u8_rs::MediaPlaylist;
 use reqwest::Url;
+use time::format_description::well_known::{Iso8601, Rfc3339};
+use time::OffsetDateTime;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::task::{JoinError, JoinHandle};
+
+/// Maximum length in bytes of a sanitized file name
+const MAX_FILE_NAME_LEN: usize = 100;
 
 /// Create absolute url from a possibly relative url and a base url if needed
 pub fn make_absolute_url(base: &Url, url: &str) -> Result<Url> {
@@ -9,3 +26,137 @@ pub fn make_absolute_url(base: &Url, url: &str) -> Result<Url> {
         Err(e) => Err(e.into()),
     }
 }
+
+/// Find the IP address assigned to a network interface, preferring IPv4 addresses
+pub fn interface_address(name: &str) -> Result<IpAddr> {
+    if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|i| i.name == name)
+        .map(|i| i.ip())
+        .max_by_key(|ip| ip.is_ipv4())
+        .ok_or_else(|| anyhow::anyhow!("No address found for network interface {}", name))
+}
+
+/// Parse an EXT-X-PROGRAM-DATE-TIME value
+pub fn parse_program_date_time(s: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(s.trim(), &Rfc3339)
+        .or_else(|_| OffsetDateTime::parse(s.trim(), &Iso8601::DEFAULT))
+        .ok()
+}
+
+/// Wall clock time at the end of the last segment of a media playlist, computed from the last
+/// EXT-X-PROGRAM-DATE-TIME tag and the durations of the segments following it
+pub fn playlist_end_time(playlist: &MediaPlaylist) -> Option<OffsetDateTime> {
+    let (i, pdt) = playlist
+        .segments
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, s)| Some((i, parse_program_date_time(s.program_date_time.as_ref()?)?)))?;
+    let duration: f32 = playlist.segments[i..].iter().map(|s| s.duration).sum();
+
+    Some(pdt + time::Duration::seconds_f32(duration))
+}
+
+/// Parse a playlist tag's attribute list into names and values, with quotes removed from quoted
+/// string values
+pub fn parse_attribute_list(s: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = s.trim();
+    while let Some((name, value)) = rest.split_once('=') {
+        let (value, remaining) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((v, r)) => (v, r),
+                None => (quoted, ""),
+            },
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        attributes.push((name.trim().to_owned(), value.to_owned()));
+        rest = remaining.trim_start_matches(',').trim_start();
+    }
+
+    attributes
+}
+
+/// Make a name safe to use as a file name on all platforms. Characters other than letters,
+/// digits, '-' and '_' are replaced with '_', and long names are truncated
+pub fn sanitize_file_name(name: &str) -> String {
+    let mut sanitized = String::new();
+    for c in name.chars() {
+        let c = if c.is_alphanumeric() || c == '-' || c == '_' {
+            c
+        } else {
+            '_'
+        };
+        if sanitized.len() + c.len_utf8() > MAX_FILE_NAME_LEN {
+            break;
+        }
+        sanitized.push(c);
+    }
+
+    if sanitized.is_empty() {
+        sanitized.push('_');
+    }
+    sanitized
+}
+
+/// Write data to a temporary file next to path, then rename it to path, so an interrupted write
+/// never leaves a truncated file at path. If fsync is set, the data is flushed to disk first
+pub async fn write_atomic(path: &Path, data: &[u8], fsync: bool) -> std::io::Result<()> {
+    let tmp_path = temporary_path(path);
+    let mut file = fs::File::create(&tmp_path).await?;
+    file.write_all(data).await?;
+    if fsync {
+        file.sync_all().await?;
+    }
+    drop(file);
+
+    fs::rename(&tmp_path, path).await
+}
+
+/// Blocking version of write_atomic
+pub fn write_atomic_blocking(path: &Path, data: &[u8], fsync: bool) -> std::io::Result<()> {
+    let tmp_path = temporary_path(path);
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    if fsync {
+        file.sync_all()?;
+    }
+    drop(file);
+
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Temporary file next to path used to write it atomically
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    path.with_file_name(tmp_name)
+}
+
+/// Handle of a spawned task that aborts the task when dropped, so tasks spawned by a future
+/// don't outlive it when it is cancelled
+#[derive(Debug)]
+pub struct AbortOnDrop<T>(pub JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Deref for AbortOnDrop<T> {
+    type Target = JoinHandle<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -0,0 +1,27 @@
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use tokio::sync::Semaphore;
+
+/// Number of workers if the number of CPUs is unknown
+const DEFAULT_WORKERS: usize = 4;
+
+/// Free workers of the pool, one per CPU
+static WORKERS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Run CPU or disk heavy work on the blocking thread pool, with at most one job per CPU at a
+/// time. Callers wait for a free worker, so downloads slow down instead of piling up work when
+/// the workers can't keep up
+pub async fn run<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let workers = WORKERS.get_or_init(|| {
+        let count = std::thread::available_parallelism().map_or(DEFAULT_WORKERS, |n| n.get());
+        Semaphore::new(count)
+    });
+    let _permit = workers.acquire().await?;
+
+    Ok(tokio::task::spawn_blocking(f).await?)
+}
@@ -0,0 +1,118 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Largest amount of log kept in memory before the log file is opened
+const MAX_PENDING: usize = 1024 * 1024;
+
+/// Number of rotated log files kept next to the log file, numbered from newest to oldest
+const MAX_ROTATED: u32 = 5;
+
+/// Log records are kept in memory until the output directory is known and the log file is opened
+static LOG_FILE: Mutex<LogFile> = Mutex::new(LogFile::Pending(Vec::new()));
+
+enum LogFile {
+    Pending(Vec<u8>),
+    Open {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        max_size: u64,
+    },
+    Disabled,
+}
+
+/// Writer of the file logging layer, writes to the log file once it is opened
+#[derive(Default)]
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut log_file = LOG_FILE.lock().unwrap();
+        match &mut *log_file {
+            LogFile::Pending(pending) => {
+                if pending.len() + buf.len() <= MAX_PENDING {
+                    pending.extend_from_slice(buf);
+                }
+            }
+            LogFile::Open {
+                path,
+                file,
+                size,
+                max_size,
+            } => {
+                // Start a new file once the current one is full
+                if *size > 0 && *size + buf.len() as u64 > *max_size {
+                    rotate(path)?;
+                    *file = open(path)?;
+                    *size = 0;
+                }
+                file.write_all(buf)?;
+                *size += buf.len() as u64;
+            }
+            LogFile::Disabled => {}
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut *LOG_FILE.lock().unwrap() {
+            LogFile::Open { file, .. } => file.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Start logging to path, appending to it if it exists, with the records logged so far. Once
+/// the file is larger than max_size bytes it is moved to <path>.1 and a new one is started
+pub fn open_log_file(path: &Path, max_size: u64) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = open(path)?;
+    let mut size = file.metadata()?.len();
+
+    let mut log_file = LOG_FILE.lock().unwrap();
+    if let LogFile::Pending(pending) = &*log_file {
+        file.write_all(pending)?;
+        size += pending.len() as u64;
+    }
+    *log_file = LogFile::Open {
+        path: path.to_owned(),
+        file,
+        size,
+        max_size: max_size.max(1),
+    };
+
+    Ok(())
+}
+
+/// Stop keeping log records for a log file that is never opened
+pub fn disable_log_file() {
+    let mut log_file = LOG_FILE.lock().unwrap();
+    if let LogFile::Pending(_) = &*log_file {
+        *log_file = LogFile::Disabled;
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Shift rotated log files by one, dropping the oldest, and move the log file to <path>.1
+fn rotate(path: &Path) -> io::Result<()> {
+    let rotated = |n: u32| {
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(format!(".{}", n));
+        path.with_file_name(name)
+    };
+
+    for n in (1..MAX_ROTATED).rev() {
+        if rotated(n).exists() {
+            fs::rename(rotated(n), rotated(n + 1))?;
+        }
+    }
+    fs::rename(path, rotated(1))
+}
@@ -1,96 +1,481 @@
-mod cli;
-mod error;
-mod livestream;
-mod mux;
-
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use livestream::Livestream;
+use livestream_dl::error::{ExitCode, LivestreamDLError};
+use livestream_dl::livestream::{Livestream, ShutdownPhase, StopReason, Stopper};
+use livestream_dl::{cli, config, ffmpeg, livestream, log_file, notify, tui, upload};
+use reqwest::Url;
+use tokio::sync::Semaphore;
 use tracing::{event, Level};
-use tracing_subscriber::filter::{FilterExt, LevelFilter};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{EnvFilter, Layer};
 
 fn main() -> Result<()> {
-    // Parse CLI args
-    let args = cli::Args::parse();
+    // Parse CLI args, with default args from config file
+    let raw_args = config::with_config_args(std::env::args_os().collect())?;
+    let cli = cli::Cli::parse_from(raw_args);
 
     // Init logging
-    init_tracing()?;
+    init_tracing(cli.verbose, cli.quiet)?;
+    let downloads = matches!(
+        cli.command,
+        cli::Command::Download(_) | cli::Command::Batch(_) | cli::Command::Resume(_)
+    );
+    if !downloads {
+        log_file::disable_log_file();
+    }
+
+    let result = match cli.command {
+        cli::Command::Download(args) => download(args),
+        cli::Command::Batch(args) => batch(args),
+        cli::Command::Resume(args) => resume(args),
+        cli::Command::Mux(args) => mux(args),
+        cli::Command::ListStreams(args) => list_streams(args).map(|_| ExitCode::Success),
+        cli::Command::Probe(args) => probe(args).map(|_| ExitCode::Success),
+    };
+
+    match result {
+        Ok(code) => code.exit(),
+        Err(e) => {
+            event!(Level::ERROR, "{:?}", e);
+            ExitCode::from_error(&e).exit();
+        }
+    }
+}
+
+/// Download a livestream into a new output directory
+fn download(mut args: cli::Args) -> Result<ExitCode> {
+    // Check ffmpeg and ffprobe before downloading
+    setup_ffmpeg(&args.mux_options);
+    if args.download_options.desktop_notify {
+        notify::check_supported()?;
+    }
+    livestream::set_lenient(args.network_options.lenient);
+    use_remote_output(&mut args.output, &mut args.mux_options);
+    // Fail on unusable upload targets, e.g. without curl for FTP, before downloading
+    upload::from_options(&args.mux_options)?;
+
+    // A playlist piped into stdin is read before anything else
+    if let (true, Some(url)) = (args.stdin, args.playlist_url()) {
+        read_stdin_playlist(url)?;
+    }
 
     // Create output directory before spawning tokio runtime to use local utc offset
-    let output = gen_output_dir(&args.download_options.output)?;
+    let output = gen_output_dir(args.output.as_deref(), &args.download_options)?;
+    start_log_file(&output, &args.download_options)?;
+
+    run(args, output, false)
+}
 
-    // Run main program
-    if let Err(e) = run(args, output) {
-        event!(Level::ERROR, "{:?}", e);
-        std::process::exit(1);
+/// Record several livestreams into numbered directories of a new output directory
+fn batch(mut args: cli::BatchArgs) -> Result<ExitCode> {
+    setup_ffmpeg(&args.mux_options);
+    if args.download_options.desktop_notify {
+        notify::check_supported()?;
     }
+    livestream::set_lenient(args.network_options.lenient);
+    use_remote_output(&mut args.output, &mut args.mux_options);
+    upload::from_options(&args.mux_options)?;
+    let output = gen_output_dir(args.output.as_deref(), &args.download_options)?;
+    start_log_file(&output, &args.download_options)?;
 
-    Ok(())
+    run_batch(args, output)
+}
+
+/// Continue an interrupted download in its output directory
+fn resume(args: cli::ResumeArgs) -> Result<ExitCode> {
+    setup_ffmpeg(&args.mux_options);
+    if args.download_options.desktop_notify {
+        notify::check_supported()?;
+    }
+    livestream::set_lenient(args.network_options.lenient);
+    upload::from_options(&args.mux_options)?;
+
+    start_log_file(&args.dir, &args.download_options)?;
+    let m3u8_url = livestream::saved_source(&args.dir)?;
+    event!(Level::INFO, "Resuming download of {}", m3u8_url);
+    let output = args.dir.clone();
+    let args = cli::Args {
+        m3u8_url: Some(m3u8_url),
+        stdin: false,
+        output: Some(args.dir),
+        download_options: args.download_options,
+        mux_options: args.mux_options,
+        network_options: args.network_options,
+    };
+
+    run(args, output, true)
 }
 
 #[tokio::main]
-async fn run(args: cli::Args, output: impl AsRef<Path>) -> Result<()> {
-    let (livestream, stopper) = Livestream::new(&args.m3u8_url, &args)
+async fn run(args: cli::Args, output: impl AsRef<Path>, resume: bool) -> Result<ExitCode> {
+    let stopper = Stopper::new();
+    stop_on_ctrl_c(stopper.clone());
+    let _dashboard = start_dashboard(&args.download_options, &stopper)?;
+
+    record(&args, output.as_ref(), resume, &stopper, None).await
+}
+
+/// Record several livestreams at once, sharing a limit on concurrent downloads
+#[tokio::main]
+async fn run_batch(args: cli::BatchArgs, output: PathBuf) -> Result<ExitCode> {
+    let stopper = Stopper::new();
+    stop_on_ctrl_c(stopper.clone());
+    let _dashboard = start_dashboard(&args.download_options, &stopper)?;
+
+    // Recordings run at once, so streams can't be chosen interactively
+    let mut download_options = args.download_options.clone();
+    download_options.choose_stream = false;
+
+    let download_limit = Arc::new(Semaphore::new(args.max_total_downloads.max(1)));
+    let recordings = args.m3u8_urls.iter().enumerate().map(|(i, url)| {
+        let args = cli::Args {
+            m3u8_url: Some(url.clone()),
+            stdin: false,
+            output: None,
+            download_options: download_options.clone(),
+            mux_options: args.mux_options.clone(),
+            network_options: args.network_options.clone(),
+        };
+        let output = output.join((i + 1).to_string());
+        let stopper = &stopper;
+        let download_limit = download_limit.clone();
+        async move {
+            let result = record(&args, &output, false, stopper, Some(download_limit)).await;
+            if let Err(e) = &result {
+                event!(Level::ERROR, "Recording of {} failed: {:?}", url, e);
+            }
+            result
+        }
+    });
+    let results = futures::future::join_all(recordings).await;
+
+    // Fail only if every recording failed
+    let mut codes = Vec::new();
+    let mut error = None;
+    for result in results {
+        match result {
+            Ok(code) => codes.push(code),
+            Err(e) => error = error.or(Some(e)),
+        }
+    }
+    match (codes.is_empty(), error) {
+        (true, Some(e)) => Err(e),
+        (false, None) if codes.iter().all(|c| *c == ExitCode::Success) => Ok(ExitCode::Success),
+        _ => Ok(ExitCode::PartialSuccess),
+    }
+}
+
+/// Move shutdown to its next phase on each ctrl-c
+fn stop_on_ctrl_c(stopper: Stopper) {
+    #[cfg(target_family = "unix")]
+    let mut stream = {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::interrupt()).unwrap()
+    };
+    #[cfg(target_family = "windows")]
+    let mut stream = {
+        use tokio::signal::windows::ctrl_c;
+        ctrl_c().unwrap()
+    };
+
+    tokio::spawn(async move {
+        while stream.recv().await.is_some() {
+            escalate_stop(&stopper);
+        }
+    });
+}
+
+/// Move shutdown to its next phase, exiting if forced
+fn escalate_stop(stopper: &Stopper) {
+    match stopper.escalate(StopReason::User) {
+        ShutdownPhase::Running | ShutdownPhase::StopFetching => event!(
+            Level::WARN,
+            "Stopping download after queued segments... Press Ctrl-C again to skip them"
+        ),
+        ShutdownPhase::SkipSegments => event!(
+            Level::WARN,
+            "Skipping remaining segments... Press Ctrl-C again to force stop"
+        ),
+        ShutdownPhase::Abort => {
+            tui::close();
+            event!(Level::WARN, "Force stopping process");
+            ExitCode::UserAbort.exit();
+        }
+    }
+}
+
+/// Show the dashboard if requested. Ctrl-C is read by the dashboard while it is shown
+fn start_dashboard(
+    options: &cli::DownloadOptions,
+    stopper: &Stopper,
+) -> Result<Option<tui::Dashboard>> {
+    if !options.tui {
+        return Ok(None);
+    }
+    let stopper = stopper.clone();
+    let dashboard = tui::Dashboard::start(move || escalate_stop(&stopper))
+        .context("error starting dashboard")?;
+
+    Ok(Some(dashboard))
+}
+
+/// Read the playlist from stdin, to be used as the playlist at url
+fn read_stdin_playlist(url: &Url) -> Result<()> {
+    let mut playlist = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut playlist)
+        .context("error reading playlist from stdin")?;
+    livestream::set_stdin_playlist(url.clone(), playlist);
+
+    Ok(())
+}
+
+/// Download a livestream into output, following the shutdown phases of stopper
+async fn record(
+    args: &cli::Args,
+    output: &Path,
+    resume: bool,
+    stopper: &Stopper,
+    download_limit: Option<Arc<Semaphore>>,
+) -> Result<ExitCode> {
+    let url = args
+        .playlist_url()
+        .ok_or_else(|| anyhow::anyhow!("no playlist URL given"))?;
+    let (mut livestream, livestream_stopper) = Livestream::new(url, args)
         .await
         .context("error initializing livestream downloader")?;
+    if resume {
+        livestream.resume();
+    }
+    if let Some(limit) = download_limit {
+        livestream.share_download_limit(limit);
+    }
+    if args.download_options.tui {
+        let name = format!(
+            "{} ({})",
+            output.file_name().unwrap_or_default().to_string_lossy(),
+            url.host_str().unwrap_or_default()
+        );
+        tui::watch(name, livestream.stats());
+    }
 
-    // Gracefully exit on ctrl-c
-    {
-        #[cfg(target_family = "unix")]
-        let mut stream = {
-            use tokio::signal::unix::{signal, SignalKind};
-            signal(SignalKind::interrupt()).unwrap()
-        };
-        #[cfg(target_family = "windows")]
-        let mut stream = {
-            use tokio::signal::windows::ctrl_c;
-            ctrl_c().unwrap()
-        };
+    let forward_stop = {
+        let stopper = stopper.clone();
+        tokio::spawn(async move { stopper.forward_to(&livestream_stopper).await })
+    };
+    let result = download_livestream(&livestream, args, output, stopper).await;
+    forward_stop.abort();
+    if let (true, Err(e)) = (args.download_options.desktop_notify, &result) {
+        notify::desktop_notify("Download failed".to_owned(), format!("{}: {:#}", url, e)).await;
+    }
+
+    result
+}
+
+/// Download a livestream and its interstitials, returns whether everything was downloaded
+async fn download_livestream(
+    livestream: &Livestream,
+    args: &cli::Args,
+    output: &Path,
+    stopper: &Stopper,
+) -> Result<ExitCode> {
+    // Download stream
+    event!(Level::INFO, "Downloading stream to {:?}", output);
+    let result = livestream.download(output).await;
+    if let Some(reason) = stopper.reason() {
+        event!(Level::INFO, "Download {}", reason);
+    }
+
+    // Download interstitial assets unless stopped
+    if result.is_ok() && args.download_options.interstitials && !stopper.is_stopped() {
+        download_interstitials(livestream, args, output, stopper).await?;
+    }
+
+    // Save cookies even if download failed
+    livestream.save_cookies().context("error saving cookies")?;
 
-        tokio::spawn(async move {
-            stream.recv().await;
+    // Report partial success if any segments or streams are missing
+    result?;
+    if livestream.segments_failed() > 0 {
+        event!(
+            Level::WARN,
+            "{} segments failed to download, output may have gaps",
+            livestream.segments_failed()
+        );
+        return Ok(ExitCode::PartialSuccess);
+    }
+    if livestream.streams_failed() > 0 {
+        event!(
+            Level::WARN,
+            "{} streams failed, output may be missing renditions",
+            livestream.streams_failed()
+        );
+        return Ok(ExitCode::PartialSuccess);
+    }
+
+    Ok(ExitCode::Success)
+}
+
+/// Download the assets of interstitials found in the stream, each into its own directory
+async fn download_interstitials(
+    livestream: &Livestream,
+    args: &cli::Args,
+    output: &Path,
+    stopper: &Stopper,
+) -> Result<()> {
+    let assets = livestream
+        .interstitial_assets()
+        .await
+        .context("error resolving interstitial assets")?;
+
+    // Assets are downloaded as standalone streams without prompts or partial remuxes
+    let mut asset_args = args.clone();
+    asset_args.download_options.choose_stream = false;
+    asset_args.download_options.remux_interval = None;
+    asset_args.download_options.interstitials = false;
+
+    for (name, url) in assets {
+        if stopper.is_stopped() {
+            break;
+        }
+
+        let output = output.join("interstitials").join(&name);
+        event!(
+            Level::INFO,
+            "Downloading interstitial {} to {:?}",
+            name,
+            output
+        );
+        asset_args.m3u8_url = Some(url.clone());
+        let result = async {
+            let (asset, asset_stopper) = Livestream::new(&url, &asset_args).await?;
+
+            // Stop the asset download when the main download is stopped
+            let stopper = stopper.clone();
+            let forward_stop =
+                tokio::spawn(async move { stopper.forward_to(&asset_stopper).await });
+            let result = asset.download(&output).await;
+            forward_stop.abort();
+            result
+        }
+        .await;
+        if let Err(e) = result {
             event!(
                 Level::WARN,
-                "Stopping download... Press Ctrl-C again to force stop"
+                "Failed to download interstitial {}: {:?}",
+                name,
+                e
             );
-            stopper.stop().await;
+        }
+    }
 
-            tokio::spawn(async move {
-                stream.recv().await;
-                event!(Level::WARN, "Force stopping process");
-                std::process::exit(1);
-            });
-        });
+    Ok(())
+}
+
+#[tokio::main]
+async fn list_streams(args: cli::ListStreamsArgs) -> Result<()> {
+    livestream::set_lenient(args.network_options.lenient);
+    livestream::list_streams(&args).await
+}
+
+#[tokio::main]
+async fn probe(args: cli::ProbeArgs) -> Result<()> {
+    livestream::set_lenient(args.network_options.lenient);
+    livestream::probe(&args).await
+}
+
+/// Remux the segments of an earlier download without downloading
+#[tokio::main]
+async fn mux(args: cli::MuxArgs) -> Result<ExitCode> {
+    setup_ffmpeg(&args.mux_options);
+    upload::from_options(&args.mux_options)?;
+
+    event!(Level::INFO, "Remuxing segments in {:?}", args.dir);
+    livestream::remux_download(&args.dir, &args.mux_options).await?;
+
+    Ok(ExitCode::Success)
+}
+
+/// Set ffmpeg and ffprobe paths and exit if they are needed but not usable
+fn setup_ffmpeg(options: &cli::MuxOptions) {
+    ffmpeg::set_paths(options.ffmpeg_path.clone(), options.ffprobe_path.clone());
+    if !options.no_remux {
+        if let Err(e) = check_ffmpeg() {
+            event!(Level::ERROR, "{:?}", e);
+            ExitCode::FfmpegMissing.exit();
+        }
     }
+}
 
-    // Download stream
-    event!(Level::INFO, "Downloading stream to {:?}", output.as_ref());
-    livestream.download(output.as_ref()).await?;
+/// Check that ffmpeg and ffprobe are usable for remuxing
+fn check_ffmpeg() -> Result<()> {
+    let result = ffmpeg::check_version(ffmpeg::ffmpeg_path())
+        .and_then(|_| ffmpeg::check_version(ffmpeg::ffprobe_path()));
 
-    Ok(())
+    // Remux can fall back to native concat without ffmpeg
+    #[cfg(feature = "native-remux")]
+    if let Err(e) = result {
+        event!(Level::WARN, "{:#}", e);
+        return Ok(());
+    }
+
+    result.context(
+        "ffmpeg is required for remuxing, set --ffmpeg-path and --ffprobe-path or use --no-remux",
+    )
+}
+
+/// If the output is a remote url, upload to it and download into a generated local directory
+fn use_remote_output(output: &mut Option<PathBuf>, mux_options: &mut cli::MuxOptions) {
+    if let Some(url) = output.as_deref().and_then(upload::remote_output) {
+        event!(
+            Level::INFO,
+            "Uploading outputs to {}",
+            upload::without_credentials(&url)
+        );
+        mux_options.upload.get_or_insert(url);
+        *output = None;
+    }
 }
 
-fn gen_output_dir(output_dir: &Option<impl AsRef<Path>>) -> Result<PathBuf> {
-    let final_output_dir = if let Some(output_dir) = output_dir {
+/// Log to download.log in the output directory if requested, with everything logged so far
+fn start_log_file(output: &Path, options: &cli::DownloadOptions) -> Result<()> {
+    if !options.log_file {
+        log_file::disable_log_file();
+        return Ok(());
+    }
+    let path = output.join("download.log");
+    log_file::open_log_file(&path, options.log_file_size * 1024 * 1024)
+        .with_context(|| format!("error opening log file {:?}", path))
+}
+
+fn gen_output_dir(output: Option<&Path>, options: &cli::DownloadOptions) -> Result<PathBuf> {
+    let final_output_dir = if let Some(output_dir) = output {
         // If output directory already exists, prompt user to overwrite, otherwise exit
-        if output_dir.as_ref().is_dir() {
-            let response = inquire::Confirm::new(&format!(
+        if output_dir.is_dir() {
+            let response = if options.yes || options.no_input {
+                options.yes
+            } else {
+                inquire::Confirm::new(&format!(
                     "Found existing output directory {:?}, existing files may be overwritten.\nIs this OK?",
-                    output_dir.as_ref()
-                    ))
+                    output_dir
+                ))
                 .with_default(false)
-                .prompt()?;
+                .prompt()?
+            };
 
             if !response {
-                return Err(anyhow::anyhow!("Not downloading into existing directory"));
+                return Err(LivestreamDLError::Aborted(
+                    "not downloading into existing directory".into(),
+                )
+                .into());
             }
         }
 
-        output_dir.as_ref().to_path_buf()
+        output_dir.to_path_buf()
     } else {
         // Generate a path
         let now = time::OffsetDateTime::now_local()?;
@@ -112,19 +497,39 @@ fn gen_output_dir(output_dir: &Option<impl AsRef<Path>>) -> Result<PathBuf> {
     Ok(final_output_dir)
 }
 
-fn init_tracing() -> Result<()> {
+fn init_tracing(verbose: u8, quiet: u8) -> Result<()> {
     // Enable ANSI support on Windows for colors
     #[cfg(target_family = "windows")]
     let _ = ansi_term::enable_ansi_support();
 
-    // Log INFO to stdout
+    // Log INFO to stdout, or more or less depending on -v and -q. LIVESTREAM_DL_LOG replaces
+    // these directives if set
+    let directives = match (verbose, quiet) {
+        (0, 0) => "info".to_owned(),
+        (0, 1) => format!("info,{}=warn", livestream::PROGRESS_TARGET),
+        (0, _) => "warn".to_owned(),
+        (1, _) => "info,livestream_dl=debug".to_owned(),
+        _ => "debug,livestream_dl=trace".to_owned(),
+    };
     let stdout_log = tracing_subscriber::fmt::layer()
         .compact()
         .without_time()
-        .with_filter(EnvFilter::from_env("LIVESTREAM_DL_LOG").or(LevelFilter::INFO));
+        .with_writer(tui::StdoutWriter::default)
+        .with_filter(
+            EnvFilter::try_from_env("LIVESTREAM_DL_LOG")
+                .unwrap_or_else(|_| EnvFilter::new(directives)),
+        );
+
+    // Log DEBUG with timestamps to the log file, if one is opened
+    let file_log = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(log_file::LogWriter::default)
+        .with_filter(EnvFilter::new("info,livestream_dl=debug"));
 
     // Start logging
-    let subscriber = tracing_subscriber::Registry::default().with(stdout_log);
+    let subscriber = tracing_subscriber::Registry::default()
+        .with(stdout_log)
+        .with(file_log);
     tracing::subscriber::set_global_default(subscriber)?;
 
     Ok(())
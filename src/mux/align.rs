@@ -0,0 +1,194 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::RangeInclusive;
+
+use time::OffsetDateTime;
+use tracing::{event, Level};
+
+use crate::livestream::{Segment, SegmentFile, Stream};
+
+/// Discontinuity of a stream, the segments between two discontinuity tags
+#[derive(Debug)]
+struct Period {
+    discon_seq: u64,
+    seqs: RangeInclusive<u64>,
+    /// Wall clock start and end from program date times, if known
+    time: Option<(OffsetDateTime, OffsetDateTime)>,
+}
+
+/// Renumber the discontinuities of each stream after the overlapping discontinuity of the
+/// reference (main or video) stream, so streams whose discontinuity boundaries differ are muxed
+/// together. Discontinuities are matched by program date time if known, otherwise by media
+/// sequence numbers
+///
+/// If trim is set, segments entirely outside of the reference discontinuity's program date times
+/// are left out
+pub fn align_streams(
+    mut streams: HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>>,
+    trim: bool,
+) -> HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>> {
+    let reference = match reference_stream(&streams) {
+        Some(s) => s,
+        None => return streams,
+    };
+    let reference_periods = periods(&streams[&reference].clone().into_sorted_vec());
+
+    for (stream, segments) in streams.iter_mut() {
+        if *stream == reference {
+            continue;
+        }
+        let mut sorted = std::mem::take(segments).into_sorted_vec();
+
+        // Match each discontinuity to the reference discontinuity it overlaps the most
+        let mut matched = HashMap::new();
+        for period in periods(&sorted) {
+            let best = reference_periods
+                .iter()
+                .map(|r| (overlap(&period, r), r))
+                .filter(|(o, _)| *o > 0.0)
+                .max_by(|(a, _), (b, _)| a.total_cmp(b));
+            match best {
+                Some((_, r)) => {
+                    if r.discon_seq != period.discon_seq {
+                        event!(
+                            Level::INFO,
+                            "Aligning {} discontinuity {} with {} discontinuity {}",
+                            stream,
+                            period.discon_seq,
+                            reference,
+                            r.discon_seq
+                        );
+                    }
+                    matched.insert(period.discon_seq, r);
+                }
+                None => event!(
+                    Level::WARN,
+                    "{} discontinuity {} doesn't overlap the {} stream, it is muxed on its own",
+                    stream,
+                    period.discon_seq,
+                    reference
+                ),
+            }
+        }
+
+        // Several discontinuities matched to one are concatenated, timestamps may jump
+        let mut targets = HashSet::new();
+        for r in matched.values() {
+            if !targets.insert(r.discon_seq) {
+                event!(
+                    Level::WARN,
+                    "Several {} discontinuities overlap {} discontinuity {}, output may be out of sync",
+                    stream,
+                    reference,
+                    r.discon_seq
+                );
+            }
+        }
+        for r in &reference_periods {
+            if !targets.contains(&r.discon_seq) {
+                event!(
+                    Level::WARN,
+                    "{} stream has nothing for {} discontinuity {}",
+                    stream,
+                    reference,
+                    r.discon_seq
+                );
+            }
+        }
+
+        // Partial data outside of the matched reference discontinuity is trimmed if requested
+        let outside = |s: &Segment| {
+            let time = matched.get(&s.discon_seq).and_then(|r| r.time);
+            match (time, s.program_date_time) {
+                (Some((start, end)), Some(pdt)) => pdt + s.duration <= start || pdt >= end,
+                _ => false,
+            }
+        };
+        let outside_count = sorted.iter().filter(|(s, _)| outside(s)).count();
+        if outside_count > 0 && trim {
+            sorted.retain(|(s, _)| !outside(s));
+            event!(
+                Level::WARN,
+                "Trimmed {} {} segments outside of the {} stream",
+                outside_count,
+                stream,
+                reference
+            );
+        } else if outside_count > 0 {
+            event!(
+                Level::WARN,
+                "{} {} segments are outside of the {} stream, use --trim-unaligned to leave them out",
+                outside_count,
+                stream,
+                reference
+            );
+        }
+
+        for (segment, _) in sorted.iter_mut() {
+            if let Some(r) = matched.get(&segment.discon_seq) {
+                segment.discon_seq = r.discon_seq;
+            }
+        }
+        *segments = sorted.into();
+    }
+
+    streams
+}
+
+/// Stream the others are aligned to, the main stream or the first video stream. None if there
+/// is nothing to align
+fn reference_stream(
+    streams: &HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>>,
+) -> Option<Stream> {
+    if streams.len() < 2 {
+        return None;
+    }
+    if streams.contains_key(&Stream::Main) {
+        return Some(Stream::Main);
+    }
+    streams
+        .keys()
+        .filter(|s| matches!(s, Stream::Video { .. }))
+        .min_by_key(|s| s.file_name())
+        .cloned()
+}
+
+/// Discontinuities of sorted segments
+fn periods(segments: &[(Segment, SegmentFile)]) -> Vec<Period> {
+    let mut periods = Vec::new();
+    let mut start = 0;
+    for i in 1..=segments.len() {
+        if i < segments.len() && segments[i].0.discon_seq == segments[start].0.discon_seq {
+            continue;
+        }
+        let (first, last) = (&segments[start].0, &segments[i - 1].0);
+        let time = first
+            .program_date_time
+            .zip(last.program_date_time)
+            .map(|(start, end)| (start, end + last.duration));
+        periods.push(Period {
+            discon_seq: first.discon_seq,
+            seqs: first.seq..=last.seq,
+            time,
+        });
+        start = i;
+    }
+
+    periods
+}
+
+/// How much two discontinuities overlap, in seconds if both have program date times, otherwise
+/// in media sequence numbers
+fn overlap(a: &Period, b: &Period) -> f64 {
+    if let (Some((a_start, a_end)), Some((b_start, b_end))) = (a.time, b.time) {
+        return (a_end.min(b_end) - a_start.max(b_start))
+            .as_seconds_f64()
+            .max(0.0);
+    }
+    let start = *a.seqs.start().max(b.seqs.start());
+    let end = *a.seqs.end().min(b.seqs.end());
+    if start > end {
+        0.0
+    } else {
+        (end - start + 1) as f64
+    }
+}
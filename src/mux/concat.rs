@@ -1,181 +1,165 @@
-use std::borrow::Cow;
 use std::collections::{BinaryHeap, HashMap};
 use std::env;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::Result;
-use tokio::io::AsyncWriteExt;
-use tokio::{fs, process};
 use tracing::{event, Level};
 
-use crate::livestream::{MediaFormat, Segment, Stream};
+use crate::error::LivestreamDLError;
+use crate::ffmpeg::{self, ffmpeg_command};
+use crate::livestream::{Segment, SegmentFile, Stream};
 
-/// For each discontinuity, concatenate all streams
+/// Part of the output, a split of a discontinuity
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct Chunk {
+    pub discon_seq: u64,
+    /// Index of the split, counted from the start of the stream
+    pub part: u64,
+}
+
+/// For each discontinuity, concatenate all streams. If split_every is given, discontinuities are
+/// also split every split_every of cumulative segment duration
 pub async fn concat_streams<P: AsRef<Path>>(
-    downloaded_paths: &HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+    downloaded_paths: &HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>>,
     output_dir: P,
-) -> Result<HashMap<u64, Vec<(&Stream, PathBuf)>>> {
-    // Map discon seq -> Vec<(stream, concatenated path)>
-    let mut discons: HashMap<_, Vec<_>> = HashMap::new();
+    split_every: Option<Duration>,
+) -> Result<HashMap<Chunk, Vec<(&Stream, PathBuf)>>> {
+    // Map chunk -> Vec<(stream, concatenated path)>
+    let mut chunks: HashMap<_, Vec<_>> = HashMap::new();
 
-    // Loop through all streams and discontinuity sequences and concatenate them
+    // Loop through all streams and chunks and concatenate them
+    let file_names = Stream::file_names(downloaded_paths.keys());
     for (stream, segments) in downloaded_paths.iter() {
-        let mut segments_to_process: Vec<(&Segment, &PathBuf)> = Vec::new();
-        let mut cur_discon_seq = None;
-
         let segments = segments.clone().into_sorted_vec();
-        for (segment, path) in segments.iter() {
-            if cur_discon_seq.is_none() {
-                cur_discon_seq = Some(segment.discon_seq);
-            }
 
-            if cur_discon_seq.map(|x| x == segment.discon_seq).unwrap() {
-                // Add current segment to be processed
-                segments_to_process.push((segment, path));
-            } else {
-                // If discontinuity changed, concat all previous discontinuity segments
-                if !segments_to_process.is_empty() {
-                    let file_path = gen_concat_path(
-                        stream,
-                        segments_to_process[0].0,
-                        &output_dir,
-                        cur_discon_seq.unwrap(),
-                    )?;
-                    concat_segments(segments_to_process.as_slice(), &file_path).await?;
-                    discons
-                        .entry(cur_discon_seq.unwrap())
-                        .or_default()
-                        .push((stream, file_path));
-                }
-
-                // Reset segments to process, push current segment, and update current
-                // discontinuity sequence
-                segments_to_process.clear();
-                segments_to_process.push((segment, path));
-                cur_discon_seq = Some(segment.discon_seq);
+        // Assign each segment to a chunk by the duration of all segments before it
+        let mut elapsed = Duration::ZERO;
+        let mut groups: Vec<(Chunk, Vec<(&Segment, &SegmentFile)>)> = Vec::new();
+        for (segment, file) in segments.iter() {
+            let part = match split_every {
+                Some(d) if !d.is_zero() => (elapsed.as_secs_f64() / d.as_secs_f64()) as u64,
+                _ => 0,
+            };
+            elapsed += segment.duration;
+
+            let chunk = Chunk {
+                discon_seq: segment.discon_seq,
+                part,
+            };
+            match groups.last_mut() {
+                Some((c, group)) if *c == chunk => group.push((segment, file)),
+                _ => groups.push((chunk, vec![(segment, file)])),
             }
         }
 
-        // Concat last discontinuity
-        if !segments_to_process.is_empty() {
-            let d = cur_discon_seq.unwrap();
-            let file_path = gen_concat_path(stream, segments_to_process[0].0, &output_dir, d)?;
-            concat_segments(segments_to_process.as_slice(), &file_path).await?;
-            discons.entry(d).or_default().push((stream, file_path));
+        for (chunk, group) in groups {
+            let file_path = gen_concat_path(&file_names[stream], group[0].0, &output_dir, chunk)?;
+            concat_segments(group.as_slice(), &file_path).await?;
+            chunks.entry(chunk).or_default().push((stream, file_path));
         }
     }
 
-    Ok(discons)
+    Ok(chunks)
 }
 
 fn gen_concat_path(
-    stream: &Stream,
+    stream_name: &str,
     segment: &Segment,
     output_dir: impl AsRef<Path>,
-    d: u64,
+    chunk: Chunk,
 ) -> Result<PathBuf> {
     let ext = segment.format.extension();
-    let file_name = format!("{}_{:010}.{}", stream, d, ext);
+    let file_name = format!(
+        "{}_{:010}_{:04}.{}",
+        stream_name, chunk.discon_seq, chunk.part, ext
+    );
     let file_path = output_dir.as_ref().join(file_name);
     Ok(file_path)
 }
 
-async fn concat_segments<P: AsRef<Path>>(inputs: &[(&Segment, P)], output: P) -> Result<()> {
+/// Concatenate segments into a single file
+pub async fn concat_segments(inputs: &[(&Segment, &SegmentFile)], output: &Path) -> Result<()> {
+    let files: Vec<_> = inputs.iter().map(|(_, f)| (*f).clone()).collect();
     if should_use_ffmpeg_concat(inputs[0].0).await? {
-        ffmpeg_concat(inputs.iter().map(|(_, p)| p), &output).await
+        ffmpeg_concat(&files, output).await
     } else {
-        file_concat(inputs.iter().map(|(_, p)| p), &output).await
+        file_concat(files, output).await
     }
 }
 
-async fn file_concat<P: AsRef<Path>>(
-    input_paths: impl IntoIterator<Item = P>,
-    output: P,
-) -> Result<()> {
-    event!(
-        Level::INFO,
-        "File concat to temporary file {:?}",
-        output.as_ref()
-    );
+async fn file_concat(inputs: Vec<SegmentFile>, output: &Path) -> Result<()> {
+    event!(Level::INFO, "File concat to temporary file {:?}", output);
 
-    let mut file = fs::File::create(output.as_ref()).await?;
-    for path in input_paths {
-        file.write_all(&fs::read(path.as_ref()).await?).await?;
-    }
-    Ok(())
+    let output = output.to_owned();
+
+    // Copying between files with std::io::copy uses copy_file_range or sendfile where supported,
+    // so segments aren't read into memory, and copy-on-write filesystems can share their extents
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let disk_write = |e| LivestreamDLError::DiskWrite {
+            path: output.clone(),
+            source: e,
+        };
+
+        // Preallocate the output, compressed segments may make the estimate off so the output is
+        // truncated to what was written afterwards
+        let size = inputs
+            .iter()
+            .map(|f| f.size())
+            .sum::<std::io::Result<u64>>()?;
+        let mut file = std::fs::File::create(&output).map_err(disk_write)?;
+        file.set_len(size).map_err(disk_write)?;
+
+        let mut written = 0;
+        for input in inputs {
+            written += input.copy_to(&mut file).map_err(disk_write)?;
+        }
+        file.set_len(written).map_err(disk_write)?;
+        Ok(())
+    })
+    .await?
 }
 
-async fn ffmpeg_concat<P: AsRef<Path>>(
-    input_paths: impl IntoIterator<Item = P>,
-    output: P,
-) -> Result<()> {
+async fn ffmpeg_concat(inputs: &[SegmentFile], output: &Path) -> Result<()> {
     event!(
         Level::INFO,
         "ffmpeg concat demux to temporary file {:?}",
-        output.as_ref()
+        output
     );
 
     // Create concat text file
     let file = tempfile::NamedTempFile::new()?;
     let cwd = env::current_dir()?;
-    for path in input_paths {
-        let absolute_path = if path.as_ref().is_absolute() {
-            Cow::from(path.as_ref())
-        } else {
-            Cow::Owned(cwd.join(path))
-        };
-        writeln!(
-            file.as_file(),
-            "file '{}'",
-            absolute_path.as_ref().to_str().unwrap()
-        )?;
+    for input in inputs {
+        writeln!(file.as_file(), "file '{}'", input.ffmpeg_input(&cwd))?;
     }
 
     // Call ffmpeg to concat segments
-    let mut cmd = process::Command::new("ffmpeg");
-    cmd.arg("-y")
-        .arg("-f")
-        .arg("concat")
-        .arg("-safe")
-        .arg("0")
-        .arg("-i")
+    let mut cmd = ffmpeg_command();
+    cmd.arg("-y").arg("-f").arg("concat").arg("-safe").arg("0");
+    // Segments stored in pack files are read with the subfile protocol
+    if inputs.iter().any(|f| f.range.is_some()) {
+        cmd.arg("-protocol_whitelist").arg("file,subfile");
+    }
+    cmd.arg("-i")
         .arg(file.path())
         .arg("-c")
         .arg("copy")
         .arg("-fflags")
         .arg("+genpts")
-        .arg(output.as_ref())
+        .arg(output)
         .kill_on_drop(true);
 
-    event!(Level::TRACE, "{:?}", cmd);
-    let output = cmd.output().await?;
-    event!(
-        Level::TRACE,
-        "ffmpeg stdout: {:#?}",
-        String::from_utf8_lossy(&output.stdout)
-    );
-    event!(
-        Level::TRACE,
-        "ffmpeg stderr: {:#?}",
-        String::from_utf8_lossy(&output.stderr)
-    );
-
-    // Check ffmpeg exit status
-    if !output.status.success() {
-        return Err(anyhow::anyhow!("ffmpeg command failed"));
-    }
+    ffmpeg::run(&mut cmd).await?;
 
     Ok(())
 }
 
 /// Decide whether to use file or ffmpeg concat demuxer
 async fn should_use_ffmpeg_concat(segment: &Segment) -> Result<bool> {
-    #[allow(clippy::match_like_matches_macro)]
-    let use_ffmpeg = match segment.format {
-        MediaFormat::Mp3 => true,
-        _ => false,
-    };
+    // Raw audio elementary streams need ffmpeg to generate correct timestamps
+    let use_ffmpeg = segment.format.is_audio_elementary_stream();
 
     Ok(use_ffmpeg)
 }
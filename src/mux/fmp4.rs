@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+
+/// File type of muxed files if the first stream's initialization section has none
+const DEFAULT_FTYP: &[u8] = b"isom\0\0\x02\0isomiso6mp41";
+
+/// Muxes fragmented MPEG-4 streams into a single fragmented mp4. The tracks of every stream's
+/// initialization section are merged into one moov box, and the fragments of all streams are
+/// renumbered to be written interleaved
+pub struct Fmp4Muxer {
+    /// Output track ID and media timescale of each stream's tracks, by their track ID in the
+    /// stream
+    tracks: Vec<HashMap<u32, (u32, u32)>>,
+    /// Decode time of the last fragment of each stream, for fragments without a tfdt box
+    last_times: Vec<f64>,
+    /// Sequence number of the last written fragment
+    sequence: u32,
+}
+
+/// moof box of a fragment and the boxes following it up to its mdat
+pub struct Fragment {
+    pub data: Vec<u8>,
+    /// Decode time of the fragment's first sample in seconds
+    pub time: f64,
+    /// Offset of the mfhd sequence number in data
+    sequence_offset: usize,
+}
+
+impl Fmp4Muxer {
+    /// Create a muxer for streams with the given initialization sections, returns the muxer,
+    /// the header of the output (ftyp and moov boxes), and whether it has a video track
+    pub fn new(inits: &[&[u8]]) -> Result<(Self, Vec<u8>, bool)> {
+        let mut ftyp = None;
+        // Movie header of the first stream, the offset of its next_track_ID, and its timescale
+        let mut mvhd: Option<(Vec<u8>, usize, u32)> = None;
+        let mut traks = Vec::new();
+        let mut trexs = Vec::new();
+        let mut pssh = Vec::new();
+        let mut tracks = Vec::new();
+        let mut has_video = false;
+
+        for (i, init) in inits.iter().enumerate() {
+            if ftyp.is_none() {
+                ftyp = find_box(init, b"ftyp").map(|b| b.data.to_vec());
+            }
+            let moov = find_box(init, b"moov")
+                .with_context(|| format!("stream {} has no initialization section", i))?;
+            let stream_mvhd = moov.child(b"mvhd").context("moov has no mvhd box")?;
+            let timescale = read_u32(stream_mvhd.body(), versioned(&stream_mvhd, 12, 20))
+                .context("invalid mvhd box")?;
+            let output_timescale = match &mvhd {
+                Some((_, _, output_timescale)) => *output_timescale,
+                None => {
+                    let next_id = stream_mvhd.header_len + versioned(&stream_mvhd, 96, 108);
+                    mvhd = Some((stream_mvhd.data.to_vec(), next_id, timescale));
+                    timescale
+                }
+            };
+
+            // Renumber the stream's tracks after the tracks of earlier streams
+            let mut ids = HashMap::new();
+            for (_, trak) in moov.children().filter(|(_, b)| &b.kind == b"trak") {
+                let id = traks.len() as u32 + 1;
+                let renumbered = renumber_trak(&trak, id, timescale, output_timescale)?;
+                has_video |= renumbered.video;
+                ids.insert(renumbered.original_id, (id, renumbered.timescale));
+                traks.push(renumbered.data);
+            }
+
+            // Track defaults of fragments, keyed by the track ID in the stream
+            let stream_trexs: Vec<_> = moov
+                .child(b"mvex")
+                .map(|mvex| {
+                    mvex.children()
+                        .filter(|(_, b)| &b.kind == b"trex")
+                        .map(|(_, b)| b.data.to_vec())
+                        .collect()
+                })
+                .unwrap_or_default();
+            for (&original_id, &(id, _)) in &ids {
+                let mut trex = stream_trexs
+                    .iter()
+                    .find(|t| read_u32(t, 12) == Some(original_id))
+                    .cloned()
+                    .unwrap_or_else(|| default_trex(original_id));
+                write_u32(&mut trex, 12, id).context("invalid trex box")?;
+                trexs.push((id, trex));
+            }
+
+            pssh.extend(
+                moov.children()
+                    .filter(|(_, b)| &b.kind == b"pssh")
+                    .map(|(_, b)| b.data.to_vec()),
+            );
+            tracks.push(ids);
+        }
+
+        // Point the movie header at the next free track ID
+        let (mut mvhd, next_id, _) = mvhd.context("no streams to mux")?;
+        write_u32(&mut mvhd, next_id, traks.len() as u32 + 1).context("invalid mvhd box")?;
+        trexs.sort_by_key(|(id, _)| *id);
+        let mvex: Vec<u8> = trexs.into_iter().flat_map(|(_, trex)| trex).collect();
+
+        let mut moov = mvhd;
+        moov.extend(traks.concat());
+        moov.extend(make_box(b"mvex", &mvex));
+        moov.extend(pssh.concat());
+
+        let mut header = ftyp.unwrap_or_else(|| make_box(b"ftyp", DEFAULT_FTYP));
+        header.extend(make_box(b"moov", &moov));
+
+        let muxer = Self {
+            last_times: vec![0.0; tracks.len()],
+            tracks,
+            sequence: 0,
+        };
+        Ok((muxer, header, has_video))
+    }
+
+    /// Fragments of a segment of the stream at index stream, renumbered to the output's tracks.
+    /// Boxes outside of fragments such as initialization sections and segment indexes are left
+    /// out
+    pub fn fragments(&mut self, stream: usize, segment: &[u8]) -> Result<Vec<Fragment>> {
+        let mut fragments = Vec::new();
+        let mut moof_start = None;
+        let mut offset = 0;
+        for b in boxes(segment) {
+            let start = offset;
+            offset += b.data.len();
+            match &b.kind {
+                b"moof" => moof_start = Some(start),
+                b"mdat" => {
+                    if let Some(moof_start) = moof_start.take() {
+                        let data = segment[moof_start..offset].to_vec();
+                        let (mut fragment, time) = renumber_fragment(data, &self.tracks[stream])?;
+                        fragment.time = time.unwrap_or(self.last_times[stream]);
+                        self.last_times[stream] = fragment.time;
+                        fragments.push(fragment);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(fragments)
+    }
+
+    /// Bytes of a fragment to write next, numbered after the fragments written before it
+    pub fn next_fragment(&mut self, mut fragment: Fragment) -> Vec<u8> {
+        self.sequence += 1;
+        let offset = fragment.sequence_offset;
+        fragment.data[offset..offset + 4].copy_from_slice(&self.sequence.to_be_bytes());
+        fragment.data
+    }
+}
+
+/// trak box renumbered to the output's track IDs
+struct RenumberedTrak {
+    data: Vec<u8>,
+    /// Track ID in the stream
+    original_id: u32,
+    /// Media timescale of the track
+    timescale: u32,
+    video: bool,
+}
+
+/// Copy a trak box with its track ID replaced by id and its edit list rescaled from the stream's
+/// movie timescale to the output's
+fn renumber_trak(
+    trak: &Mp4Box,
+    id: u32,
+    movie_timescale: u32,
+    output_timescale: u32,
+) -> Result<RenumberedTrak> {
+    let mut data = trak.data.to_vec();
+    let mut original_id = None;
+    for (offset, child) in trak.children() {
+        match &child.kind {
+            b"tkhd" => {
+                let pos = offset + child.header_len + versioned(&child, 12, 20);
+                original_id = read_u32(&data, pos);
+                write_u32(&mut data, pos, id).context("invalid tkhd box")?;
+            }
+            b"edts" if movie_timescale != output_timescale => {
+                for (elst_offset, elst) in child.children().filter(|(_, b)| &b.kind == b"elst") {
+                    let pos = offset + elst_offset + elst.header_len;
+                    rescale_elst(&mut data[pos..], &elst, movie_timescale, output_timescale)
+                        .context("invalid elst box")?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mdia = trak.child(b"mdia").context("trak has no mdia box")?;
+    let mdhd = mdia.child(b"mdhd").context("mdia has no mdhd box")?;
+    let timescale = read_u32(mdhd.body(), versioned(&mdhd, 12, 20)).context("invalid mdhd box")?;
+    let video = mdia
+        .child(b"hdlr")
+        .and_then(|hdlr| hdlr.body().get(8..12))
+        .is_some_and(|handler| handler == b"vide");
+
+    Ok(RenumberedTrak {
+        data,
+        original_id: original_id.context("trak has no tkhd box")?,
+        timescale,
+        video,
+    })
+}
+
+/// Rescale the segment durations of an edit list, body is the elst box's body
+fn rescale_elst(body: &mut [u8], elst: &Mp4Box, from: u32, to: u32) -> Option<()> {
+    let entries = read_u32(body, 4)? as usize;
+    let entry_len = versioned(elst, 12, 20);
+    for i in 0..entries {
+        let pos = 8 + i * entry_len;
+        if elst.version() == 1 {
+            let duration = read_u64(body, pos)? as u128 * to as u128 / from.max(1) as u128;
+            body.get_mut(pos..pos + 8)?
+                .copy_from_slice(&(duration as u64).to_be_bytes());
+        } else {
+            let duration = read_u32(body, pos)? as u64 * to as u64 / from.max(1) as u64;
+            write_u32(body, pos, duration as u32)?;
+        }
+    }
+
+    Some(())
+}
+
+/// Replace the track IDs of a fragment's track fragments, returns the fragment and the decode
+/// time of its first track fragment with a tfdt box
+fn renumber_fragment(
+    mut data: Vec<u8>,
+    tracks: &HashMap<u32, (u32, u32)>,
+) -> Result<(Fragment, Option<f64>)> {
+    let (moof, _) = next_box(&data).context("invalid moof box")?;
+    let mut patches = Vec::new();
+    let mut sequence_offset = None;
+    let mut time = None;
+    for (offset, child) in moof.children() {
+        match &child.kind {
+            b"mfhd" => sequence_offset = Some(offset + child.header_len + 4),
+            b"traf" => {
+                let (tfhd_offset, tfhd) = child
+                    .children()
+                    .find(|(_, b)| &b.kind == b"tfhd")
+                    .context("traf has no tfhd box")?;
+                // Absolute data offsets would point into the segment instead of the output
+                let flags = read_u32(tfhd.body(), 0).context("invalid tfhd box")? & 0xffffff;
+                if flags & 0x1 != 0 {
+                    bail!("fragment has an absolute base data offset");
+                }
+                let original_id = read_u32(tfhd.body(), 4).context("invalid tfhd box")?;
+                let &(id, timescale) = tracks
+                    .get(&original_id)
+                    .with_context(|| format!("fragment of unknown track {}", original_id))?;
+                patches.push((offset + tfhd_offset + tfhd.header_len + 4, id));
+
+                if let (None, Some(tfdt)) = (time, child.child(b"tfdt")) {
+                    let decode_time = match tfdt.version() {
+                        1 => read_u64(tfdt.body(), 4),
+                        _ => read_u32(tfdt.body(), 4).map(u64::from),
+                    };
+                    time = decode_time.map(|t| t as f64 / timescale.max(1) as f64);
+                }
+            }
+            _ => {}
+        }
+    }
+    let sequence_offset = sequence_offset.context("moof has no mfhd box")?;
+
+    for (pos, id) in patches {
+        write_u32(&mut data, pos, id).context("invalid tfhd box")?;
+    }
+
+    let fragment = Fragment {
+        data,
+        time: 0.0,
+        sequence_offset,
+    };
+    Ok((fragment, time))
+}
+
+/// trex box without sample defaults, for tracks whose initialization section has none
+fn default_trex(id: u32) -> Vec<u8> {
+    let mut body = vec![0; 24];
+    body[4..8].copy_from_slice(&id.to_be_bytes());
+    body[8..12].copy_from_slice(&1_u32.to_be_bytes());
+    make_box(b"trex", &body)
+}
+
+/// MPEG-4 box in a buffer
+struct Mp4Box<'a> {
+    kind: [u8; 4],
+    /// Whole box including its header
+    data: &'a [u8],
+    header_len: usize,
+}
+
+impl<'a> Mp4Box<'a> {
+    fn body(&self) -> &'a [u8] {
+        &self.data[self.header_len..]
+    }
+
+    /// Version of a full box
+    fn version(&self) -> u8 {
+        self.body().first().copied().unwrap_or_default()
+    }
+
+    /// Child boxes of a container box with their offsets in the box
+    fn children(&self) -> impl Iterator<Item = (usize, Mp4Box<'a>)> {
+        let mut offset = self.header_len;
+        boxes(self.body()).map(move |b| {
+            let start = offset;
+            offset += b.data.len();
+            (start, b)
+        })
+    }
+
+    fn child(&self, kind: &[u8; 4]) -> Option<Mp4Box<'a>> {
+        self.children().map(|(_, b)| b).find(|b| &b.kind == kind)
+    }
+}
+
+/// Boxes in data, up to the first malformed box
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = Mp4Box<'_>> {
+    std::iter::from_fn(move || {
+        let (b, rest) = next_box(data)?;
+        data = rest;
+        Some(b)
+    })
+}
+
+/// First box in data and the data after it
+fn next_box(data: &[u8]) -> Option<(Mp4Box<'_>, &[u8])> {
+    let kind = data.get(4..8)?.try_into().ok()?;
+    // Box size includes the header, 1 means a 64 bit size follows, 0 means until the end
+    let (size, header_len) = match read_u32(data, 0)? {
+        0 => (data.len(), 8),
+        1 => (read_u64(data, 8)? as usize, 16),
+        size => (size as usize, 8),
+    };
+    if size < header_len || size > data.len() {
+        return None;
+    }
+    let (data, rest) = data.split_at(size);
+
+    let b = Mp4Box {
+        kind,
+        data,
+        header_len,
+    };
+    Some((b, rest))
+}
+
+fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<Mp4Box<'a>> {
+    boxes(data).find(|b| &b.kind == kind)
+}
+
+/// Offset of a field in the body of a full box, which depends on the box's version
+fn versioned(b: &Mp4Box, v0: usize, v1: usize) -> usize {
+    if b.version() == 1 {
+        v1
+    } else {
+        v0
+    }
+}
+
+fn make_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + body.len());
+    data.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+    data.extend_from_slice(kind);
+    data.extend_from_slice(body);
+    data
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + 8)?;
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) -> Option<()> {
+    data.get_mut(offset..offset + 4)?
+        .copy_from_slice(&value.to_be_bytes());
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_box(kind: &[u8; 4], version: u8, fields: &[u8]) -> Vec<u8> {
+        let mut body = vec![version, 0, 0, 0];
+        body.extend_from_slice(fields);
+        make_box(kind, &body)
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// Initialization section with one track
+    fn init(track_id: u32, timescale: u32, handler: &[u8; 4]) -> Vec<u8> {
+        // mvhd v0: times, timescale, duration, then fields up to next_track_ID
+        let mut mvhd = u32s(&[0, 0, 1000, 0]);
+        mvhd.resize(92, 0);
+        mvhd.extend(u32s(&[track_id + 1]));
+        let tkhd = full_box(b"tkhd", 0, &u32s(&[0, 0, track_id, 0, 0]));
+        let mdhd = full_box(b"mdhd", 0, &u32s(&[0, 0, timescale, 0, 0]));
+        let mut hdlr = u32s(&[0]);
+        hdlr.extend_from_slice(handler);
+        let hdlr = full_box(b"hdlr", 0, &hdlr);
+        let mdia = make_box(b"mdia", &[mdhd, hdlr].concat());
+        let trak = make_box(b"trak", &[tkhd, mdia].concat());
+        let trex = full_box(b"trex", 0, &u32s(&[track_id, 1, 0, 0, 0]));
+        let mvex = make_box(b"mvex", &trex);
+        let moov = [full_box(b"mvhd", 0, &mvhd), trak, mvex].concat();
+
+        [make_box(b"ftyp", b"iso6\0\0\0\0"), make_box(b"moov", &moov)].concat()
+    }
+
+    /// Segment with one fragment, the initialization section prepended like downloaded segments
+    fn segment(init: &[u8], sequence: u32, track_id: u32, decode_time: u32) -> Vec<u8> {
+        let mfhd = full_box(b"mfhd", 0, &u32s(&[sequence]));
+        let tfhd = full_box(b"tfhd", 0, &u32s(&[track_id]));
+        let tfdt = full_box(b"tfdt", 0, &u32s(&[decode_time]));
+        let traf = make_box(b"traf", &[tfhd, tfdt].concat());
+        let moof = make_box(b"moof", &[mfhd, traf].concat());
+        let styp = make_box(b"styp", b"msdh");
+        [init, &styp, &moof, &make_box(b"mdat", b"data")].concat()
+    }
+
+    fn track_ids(data: &[u8]) -> Vec<u32> {
+        let moof = find_box(data, b"moof").or_else(|| find_box(data, b"moov"));
+        moof.unwrap()
+            .children()
+            .filter_map(|(_, b)| match &b.kind {
+                b"traf" => read_u32(b.child(b"tfhd")?.body(), 4),
+                b"trak" => read_u32(b.child(b"tkhd")?.body(), 12),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merges_tracks_into_one_moov() {
+        let video = init(1, 90000, b"vide");
+        let audio = init(1, 48000, b"soun");
+        let (_, header, has_video) = Fmp4Muxer::new(&[&video, &audio]).unwrap();
+
+        assert!(has_video);
+        let kinds: Vec<_> = boxes(&header).map(|b| b.kind).collect();
+        assert_eq!(kinds, [*b"ftyp", *b"moov"]);
+        assert_eq!(track_ids(&header), [1, 2]);
+
+        let moov = find_box(&header, b"moov").unwrap();
+        let trex_ids: Vec<_> = moov
+            .child(b"mvex")
+            .unwrap()
+            .children()
+            .map(|(_, b)| read_u32(b.body(), 4).unwrap())
+            .collect();
+        assert_eq!(trex_ids, [1, 2]);
+        let mvhd = moov.child(b"mvhd").unwrap();
+        assert_eq!(read_u32(mvhd.body(), 96), Some(3));
+    }
+
+    #[test]
+    fn audio_only_has_no_video() {
+        let audio = init(1, 48000, b"soun");
+        let (_, _, has_video) = Fmp4Muxer::new(&[&audio]).unwrap();
+        assert!(!has_video);
+    }
+
+    #[test]
+    fn renumbers_fragments() {
+        let video = init(1, 90000, b"vide");
+        let audio = init(1, 48000, b"soun");
+        let (mut muxer, _, _) = Fmp4Muxer::new(&[&video, &audio]).unwrap();
+
+        let audio_fragments = muxer.fragments(1, &segment(&audio, 7, 1, 96000)).unwrap();
+        assert_eq!(audio_fragments.len(), 1);
+        assert_eq!(audio_fragments[0].time, 2.0);
+        let video_fragments = muxer.fragments(0, &segment(&video, 7, 1, 90000)).unwrap();
+        assert_eq!(video_fragments[0].time, 1.0);
+
+        // Sequence numbers count written fragments, initialization and styp boxes are left out
+        let first = muxer.next_fragment(video_fragments.into_iter().next().unwrap());
+        let second = muxer.next_fragment(audio_fragments.into_iter().next().unwrap());
+        for (data, sequence, track_id) in [(&first, 1, 1), (&second, 2, 2)] {
+            let kinds: Vec<_> = boxes(data).map(|b| b.kind).collect();
+            assert_eq!(kinds, [*b"moof", *b"mdat"]);
+            let moof = find_box(data, b"moof").unwrap();
+            let mfhd = moof.child(b"mfhd").unwrap();
+            assert_eq!(read_u32(mfhd.body(), 4), Some(sequence));
+            assert_eq!(track_ids(data), [track_id]);
+        }
+    }
+
+    #[test]
+    fn rejects_absolute_data_offsets() {
+        let video = init(1, 90000, b"vide");
+        let (mut muxer, _, _) = Fmp4Muxer::new(&[&video]).unwrap();
+
+        let mfhd = full_box(b"mfhd", 0, &u32s(&[1]));
+        let mut tfhd = full_box(b"tfhd", 0, &u32s(&[1, 0, 0]));
+        tfhd[11] = 0x1;
+        let moof = make_box(b"moof", &[mfhd, make_box(b"traf", &tfhd)].concat());
+        let segment = [moof, make_box(b"mdat", b"data")].concat();
+        assert!(muxer.fragments(0, &segment).is_err());
+    }
+
+    #[test]
+    fn parses_large_boxes() {
+        let mut data = u32s(&[1]);
+        data.extend_from_slice(b"mdat");
+        data.extend(20_u64.to_be_bytes());
+        data.extend_from_slice(b"data");
+        data.extend(make_box(b"free", b""));
+
+        let kinds: Vec<_> = boxes(&data).map(|b| (b.kind, b.body().len())).collect();
+        assert_eq!(kinds, [(*b"mdat", 4), (*b"free", 0)]);
+    }
+}
@@ -1,64 +1,436 @@
+mod align;
 mod concat;
+#[cfg(feature = "native-remux")]
+mod fmp4;
+#[cfg(feature = "native-remux")]
+mod native;
+mod sprite;
+mod thumbnail;
 
 use std::collections::{BinaryHeap, HashMap};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::Result;
+use futures::{StreamExt, TryStreamExt};
 use isolang::Language;
+use itertools::Itertools;
 use oxilangtag::LanguageTag;
+use reqwest::Url;
 use serde::Deserialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 use tokio::{fs, process};
 use tracing::{event, Level};
 
-use self::concat::concat_streams;
-use crate::livestream::{Segment, Stream};
+use self::align::align_streams;
+use self::concat::{concat_streams, Chunk};
+pub use self::sprite::generate_sprite_sheets;
+pub use self::thumbnail::generate_thumbnails;
+use crate::cli::MuxOptions;
+use crate::error::LivestreamDLError;
+use crate::ffmpeg::{self, ffmpeg_command, ffprobe_command};
+use crate::livestream::{
+    Disposition, DownloadEvent, Events, Segment, SegmentFile, Stream, StreamInfo,
+};
+
+/// Options for remuxing downloaded streams
+#[derive(Clone, Debug)]
+pub struct RemuxOptions {
+    /// Maximum number of chunks to mux at once
+    pub jobs: usize,
+    /// Split output into files of this duration
+    pub split_every: Option<Duration>,
+    /// ffmpeg video encoder, "copy" to not re-encode
+    pub video_codec: String,
+    /// ffmpeg audio encoder, "copy" to not re-encode
+    pub audio_codec: String,
+    /// Extra ffmpeg arguments added before the output file
+    pub ffmpeg_args: Vec<String>,
+    /// Container level metadata of output files
+    pub metadata: Vec<(String, String)>,
+    /// Flush output files to disk after muxing
+    pub fsync: bool,
+    /// Extract closed captions embedded in the video to SRT files
+    pub extract_captions: bool,
+    /// Leave out segments of other streams outside of the main stream's discontinuities
+    pub trim_unaligned: bool,
+    /// Seconds added to the timestamps of separate audio streams
+    pub audio_offset: f64,
+    /// Measure the audio offset of outputs and re-mux them with corrected audio timestamps if it
+    /// exceeds this many seconds
+    pub max_av_offset: Option<f64>,
+    /// Receives a mux progress event for each output file
+    pub events: Option<Events>,
+    /// Write fragmented mp4 instead of moving the moov box to the start after muxing
+    pub fragmented: bool,
+}
+
+/// File written by remux
+#[derive(Debug)]
+pub struct RemuxedFile {
+    pub path: PathBuf,
+    /// Start of the audio minus start of the video in seconds, if measured
+    pub av_offset: Option<f64>,
+}
+
+impl Default for RemuxOptions {
+    fn default() -> Self {
+        Self {
+            jobs: 1,
+            split_every: None,
+            video_codec: "copy".into(),
+            audio_codec: "copy".into(),
+            ffmpeg_args: Vec::new(),
+            metadata: Vec::new(),
+            fsync: false,
+            extract_captions: false,
+            trim_unaligned: false,
+            audio_offset: 0.0,
+            max_av_offset: None,
+            events: None,
+            fragmented: false,
+        }
+    }
+}
+
+impl RemuxOptions {
+    pub fn new(
+        options: &MuxOptions,
+        fsync: bool,
+        source: Option<&Url>,
+        recorded: Option<OffsetDateTime>,
+    ) -> Result<Self> {
+        let mut remux_options = Self {
+            jobs: options
+                .mux_jobs
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())),
+            split_every: options.split_every.map(|m| Duration::from_secs(m * 60)),
+            fsync,
+            extract_captions: options.extract_captions,
+            trim_unaligned: options.trim_unaligned,
+            audio_offset: options.audio_offset,
+            max_av_offset: Some(options.max_av_offset),
+            fragmented: options.frag_output,
+            ..Default::default()
+        };
+
+        // Parse VIDEO:AUDIO codecs, either may be empty to copy
+        if let Some(recode) = &options.recode {
+            let (video, audio) = recode.split_once(':').unwrap_or((recode, ""));
+            if !video.is_empty() {
+                remux_options.video_codec = encoder_name(video).to_owned();
+            }
+            if !audio.is_empty() {
+                remux_options.audio_codec = encoder_name(audio).to_owned();
+            }
+        }
+
+        if let Some(args) = &options.ffmpeg_args {
+            remux_options.ffmpeg_args = shlex::split(args)
+                .ok_or_else(|| anyhow::anyhow!("invalid --ffmpeg-args: {}", args))?;
+        }
+
+        // Record title, recording date, and where the stream came from. Query strings are left
+        // out of the source URL since they often contain access tokens
+        if let Some(title) = &options.meta_title {
+            remux_options.metadata.push(("title".into(), title.clone()));
+        }
+        if let Some(recorded) = recorded {
+            let recorded = recorded.format(&Rfc3339)?;
+            remux_options
+                .metadata
+                .push(("creation_time".into(), recorded.clone()));
+            remux_options.metadata.push(("date".into(), recorded));
+        }
+        let comment = match (&options.meta_comment, source) {
+            (Some(c), _) => Some(c.clone()),
+            (None, Some(source)) => {
+                let mut source = source.clone();
+                source.set_query(None);
+                source.set_fragment(None);
+                Some(format!("Recorded from {}", source))
+            }
+            (None, None) => None,
+        };
+        if let Some(comment) = comment {
+            remux_options.metadata.push(("comment".into(), comment));
+        }
 
-/// Remux media files into a single mp4 file with ffmpeg
+        Ok(remux_options)
+    }
+}
+
+/// Map common codec names to ffmpeg encoders, other names are used as is
+fn encoder_name(codec: &str) -> &str {
+    match codec {
+        "h264" | "avc" => "libx264",
+        "h265" | "hevc" => "libx265",
+        "vp9" => "libvpx-vp9",
+        "av1" => "libaom-av1",
+        "opus" => "libopus",
+        "mp3" => "libmp3lame",
+        c => c,
+    }
+}
+
+/// Remux media files into a single mp4 (or m4a if there is no video) file with ffmpeg, returns the output files
 pub async fn remux(
-    downloaded_paths: HashMap<Stream, BinaryHeap<(Segment, PathBuf)>>,
+    downloaded_paths: HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>>,
+    stream_info: &HashMap<Stream, StreamInfo>,
     output_dir: &Path,
-) -> Result<()> {
-    // Get list of concatenated streams for each discontinuity
-    let discons = concat_streams(&downloaded_paths, &output_dir).await?;
-
-    // For each discontinuity, mux into a video file
-    for (discon_seq, concatted_streams) in &discons {
-        // Generate output name
-        const FILE_NAME: &str = "video";
-        let output_path = if discons.len() == 1 {
-            output_dir.join(FILE_NAME)
-        } else {
-            let file_name = FILE_NAME.to_string() + &format!("_{:010}", discon_seq);
-            output_dir.join(file_name)
-        }
-        .with_extension("mp4");
+    options: &RemuxOptions,
+) -> Result<Vec<RemuxedFile>> {
+    // Match up streams whose discontinuities differ so each output pairs the same period
+    let downloaded_paths = align_streams(downloaded_paths, options.trim_unaligned);
+
+    // Fall back to native muxing if ffmpeg is not available
+    #[cfg(feature = "native-remux")]
+    if !native::ffmpeg_available().await {
+        let paths = native::remux(downloaded_paths, output_dir).await?;
+        return Ok(paths
+            .into_iter()
+            .map(|path| RemuxedFile {
+                path,
+                av_offset: None,
+            })
+            .collect());
+    }
 
-        // Mux streams
-        mux_streams(concatted_streams, output_path).await?;
+    // Get list of concatenated streams for each discontinuity and split
+    let chunks = concat_streams(&downloaded_paths, &output_dir, options.split_every).await?;
+
+    // For each chunk, mux into a video file, running up to jobs ffmpeg processes at once
+    let multiple_discons = chunks.keys().map(|c| c.discon_seq).unique().count() > 1;
+    let multiple_parts = chunks.keys().map(|c| c.part).unique().count() > 1;
+    let mut muxes = Vec::new();
+    for (chunk, concatted_streams) in &chunks {
+        muxes.push(mux_chunk(
+            *chunk,
+            concatted_streams,
+            stream_info,
+            output_dir,
+            multiple_discons,
+            multiple_parts,
+            options,
+        ));
+    }
+    let total = muxes.len();
+    let mut muxes =
+        std::pin::pin!(futures::stream::iter(muxes).buffer_unordered(options.jobs.max(1)));
+    let mut outputs = Vec::new();
+    while let Some(file) = muxes.try_next().await? {
+        if let Some(events) = &options.events {
+            events.send(DownloadEvent::MuxProgress {
+                path: file.path.clone(),
+                done: outputs.len() + 1,
+                total,
+            });
+        }
+        outputs.push(file);
     }
 
     // Delete original concatenated files
-    for concatted_streams in discons.values() {
+    for concatted_streams in chunks.values() {
         for (_, path) in concatted_streams {
             event!(Level::TRACE, "Removing {}", path.to_string_lossy());
             fs::remove_file(path).await?;
         }
     }
 
+    outputs.sort_by(|a: &RemuxedFile, b| a.path.cmp(&b.path));
+    Ok(outputs)
+}
+
+/// Remux the segments downloaded so far into partial_dir, replacing the previous partial remux
+/// only after the new one succeeds
+pub async fn remux_partial(
+    downloaded_paths: HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>>,
+    stream_info: &HashMap<Stream, StreamInfo>,
+    partial_dir: &Path,
+) -> Result<()> {
+    event!(Level::INFO, "Partial remux to {:?}", partial_dir);
+    let tmp_dir = partial_dir.with_extension("tmp");
+    fs::create_dir_all(&tmp_dir).await?;
+    let options = RemuxOptions {
+        jobs: 1,
+        ..Default::default()
+    };
+    remux(downloaded_paths, stream_info, &tmp_dir, &options).await?;
+
+    if partial_dir.exists() {
+        fs::remove_dir_all(partial_dir).await?;
+    }
+    fs::rename(&tmp_dir, partial_dir).await?;
+
+    Ok(())
+}
+
+/// Delete partial remux outputs after the final remux
+pub async fn remove_partial_remux(partial_dir: &Path) -> Result<()> {
+    for dir in [partial_dir.to_owned(), partial_dir.with_extension("tmp")] {
+        if dir.exists() {
+            event!(Level::TRACE, "Removing {}", dir.to_string_lossy());
+            fs::remove_dir_all(dir).await?;
+        }
+    }
+
     Ok(())
 }
 
-/// Mux streams into a video file
+/// Mux the concatenated streams of a chunk into a video file, returns the output file
+async fn mux_chunk(
+    chunk: Chunk,
+    concatted_streams: &Vec<(&Stream, PathBuf)>,
+    stream_info: &HashMap<Stream, StreamInfo>,
+    output_dir: &Path,
+    multiple_discons: bool,
+    multiple_parts: bool,
+    options: &RemuxOptions,
+) -> Result<RemuxedFile> {
+    // Generate output name, use m4a if there is no video
+    let extension = if has_video(concatted_streams).await? {
+        "mp4"
+    } else {
+        "m4a"
+    };
+    let mut file_name = "video".to_string();
+    if multiple_discons {
+        file_name.push_str(&format!("_{:010}", chunk.discon_seq));
+    }
+    if multiple_parts {
+        file_name.push_str(&format!("_part{:04}", chunk.part));
+    }
+    let output_path = output_dir.join(&file_name).with_extension(extension);
+
+    // Mux streams into a temporary file, renamed once complete so an interrupted mux never
+    // leaves a truncated output
+    let tmp_path = output_dir.join(format!("{}.tmp.{}", file_name, extension));
+    let mux = async {
+        mux_streams(
+            concatted_streams,
+            stream_info,
+            &tmp_path,
+            options.audio_offset,
+            options,
+        )
+        .await?;
+        let av_offset = match options.max_av_offset {
+            Some(max) => {
+                correct_av_offset(concatted_streams, stream_info, &tmp_path, max, options).await?
+            }
+            None => None,
+        };
+        if options.fsync {
+            fs::File::open(&tmp_path).await?.sync_all().await?;
+        }
+        fs::rename(&tmp_path, &output_path).await?;
+        Ok(av_offset)
+    };
+    let av_offset = mux
+        .await
+        .map_err(|e: anyhow::Error| LivestreamDLError::MuxFailed {
+            output: output_path.clone(),
+            source: e,
+        })?;
+
+    // Convert fMP4 WebVTT subtitles to standalone WebVTT files
+    let file_names = Stream::file_names(concatted_streams.iter().map(|(s, _)| *s));
+    for (stream, path) in concatted_streams {
+        if matches!(stream, Stream::Subtitle { .. })
+            && path.extension().map(|e| e == "mp4").unwrap_or(false)
+        {
+            let vtt_path = output_path.with_extension(format!("{}.vtt", file_names[*stream]));
+            extract_webvtt(path, vtt_path).await?;
+        }
+    }
+
+    // Extract closed captions embedded in the video, a missing caption track is not an error
+    if options.extract_captions {
+        let video = concatted_streams
+            .iter()
+            .find(|(s, _)| matches!(s, Stream::Main | Stream::Video { .. }));
+        if let Some((_, path)) = video {
+            let srt_path = output_path.with_extension("cc.srt");
+            if let Err(e) = extract_captions(path, &srt_path).await {
+                event!(Level::WARN, "Failed to extract closed captions: {}", e);
+            }
+        }
+    }
+
+    Ok(RemuxedFile {
+        path: output_path,
+        av_offset,
+    })
+}
+
+/// Measure the audio offset of a muxed file and re-mux it with shifted audio timestamps if it
+/// exceeds max seconds, returns the final offset. Only separate audio streams can be shifted
+async fn correct_av_offset(
+    streams: &Vec<(&Stream, PathBuf)>,
+    stream_info: &HashMap<Stream, StreamInfo>,
+    path: &Path,
+    max: f64,
+    options: &RemuxOptions,
+) -> Result<Option<f64>> {
+    let offset = match probe_av_offset(path).await {
+        Ok(Some(o)) => o,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            event!(Level::WARN, "Failed to measure audio offset: {:?}", e);
+            return Ok(None);
+        }
+    };
+    let separate_audio = streams
+        .iter()
+        .any(|(s, _)| matches!(s, Stream::Audio { .. }));
+    if offset.abs() <= max {
+        return Ok(Some(offset));
+    }
+    if !separate_audio {
+        event!(
+            Level::WARN,
+            "Audio is {:.3}s off from video, it can't be corrected without a separate audio stream",
+            offset
+        );
+        return Ok(Some(offset));
+    }
+
+    event!(
+        Level::WARN,
+        "Audio is {:.3}s off from video, re-muxing with corrected audio timestamps",
+        offset
+    );
+    mux_streams(
+        streams,
+        stream_info,
+        path,
+        options.audio_offset - offset,
+        options,
+    )
+    .await?;
+
+    Ok(probe_av_offset(path).await.ok().flatten())
+}
+
+/// Mux streams into a video file, shifting the timestamps of separate audio streams by
+/// audio_offset seconds
 async fn mux_streams<P: AsRef<Path>>(
     streams: &Vec<(&Stream, PathBuf)>,
+    stream_info: &HashMap<Stream, StreamInfo>,
     output_path: P,
+    audio_offset: f64,
+    options: &RemuxOptions,
 ) -> Result<()> {
     // Call ffmpeg to remux video file
-    let mut cmd = process::Command::new("ffmpeg");
+    let mut cmd = ffmpeg_command();
     cmd.arg("-y").arg("-copyts");
 
     // Set ffmpeg input files
-    for (_, path) in streams {
+    for (stream, path) in streams {
+        if matches!(stream, Stream::Audio { .. }) && audio_offset != 0.0 {
+            cmd.arg("-itsoffset").arg(format!("{:.6}", audio_offset));
+        }
         cmd.arg("-i").arg(path);
     }
 
@@ -68,10 +440,21 @@ async fn mux_streams<P: AsRef<Path>>(
     }
 
     // Add metadata
-    add_metadata(&mut cmd, streams).await?;
+    add_metadata(&mut cmd, streams, stream_info).await?;
+    for (key, value) in &options.metadata {
+        cmd.arg("-metadata").arg(format!("{}={}", key, value));
+    }
 
     event!(Level::INFO, "ffmpeg mux to {:?}", output_path.as_ref());
 
+    // Fragmented files are playable before the end is written, others get their index moved to
+    // the start once muxing finishes
+    let movflags = if options.fragmented {
+        "+frag_keyframe+empty_moov+default_base_moof"
+    } else {
+        "+faststart"
+    };
+
     // Set remaining ffmpeg args and run ffmpeg
     cmd.arg("-muxpreload")
         .arg("0")
@@ -80,42 +463,148 @@ async fn mux_streams<P: AsRef<Path>>(
         .arg("-avoid_negative_ts")
         .arg("make_zero")
         .arg("-c:v")
-        .arg("copy")
+        .arg(&options.video_codec)
         .arg("-c:a")
-        .arg("copy")
+        .arg(&options.audio_codec)
         .arg("-c:s")
         .arg("mov_text")
         .arg("-dn")
         .arg("-movflags")
-        .arg("+faststart")
+        .arg(movflags)
+        .args(&options.ffmpeg_args)
         .arg(output_path.as_ref())
         .kill_on_drop(true);
 
-    event!(Level::TRACE, "{:?}", cmd);
-    let output = cmd.output().await?;
+    ffmpeg::run(&mut cmd).await?;
+
+    Ok(())
+}
+
+/// Check if any stream contains video
+async fn has_video(streams: &Vec<(&Stream, PathBuf)>) -> Result<bool> {
+    for (stream, path) in streams {
+        match stream {
+            Stream::Video { .. } => return Ok(true),
+            Stream::Main => {
+                let probed = probe_streams(path).await?;
+                if probed.iter().any(|s| s.codec_type == StreamType::Video) {
+                    return Ok(true);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Convert fMP4 WebVTT (wvtt) subtitles into a WebVTT file
+async fn extract_webvtt(input: impl AsRef<Path>, output_path: impl AsRef<Path>) -> Result<()> {
     event!(
-        Level::TRACE,
-        "ffmpeg stdout: {:#?}",
-        String::from_utf8_lossy(&output.stdout)
+        Level::INFO,
+        "ffmpeg extract subtitles to {:?}",
+        output_path.as_ref()
     );
+
+    let mut cmd = ffmpeg_command();
+    cmd.arg("-y")
+        .arg("-i")
+        .arg(input.as_ref())
+        .arg("-map")
+        .arg("0:s")
+        .arg("-c:s")
+        .arg("webvtt")
+        .arg(output_path.as_ref())
+        .kill_on_drop(true);
+
+    ffmpeg::run(&mut cmd).await?;
+
+    Ok(())
+}
+
+/// Extract CEA-608/708 closed captions of a video to an SRT file with the lavfi movie source,
+/// the file is removed if there were no captions
+async fn extract_captions(input: &Path, output_path: &Path) -> Result<()> {
     event!(
-        Level::TRACE,
-        "ffmpeg stderr: {:#?}",
-        String::from_utf8_lossy(&output.stderr)
+        Level::INFO,
+        "ffmpeg extract closed captions to {:?}",
+        output_path
     );
 
-    // Check ffmpeg exit status
-    if !output.status.success() {
-        return Err(anyhow::anyhow!("ffmpeg command failed"));
+    let mut cmd = ffmpeg_command();
+    cmd.arg("-y")
+        .arg("-f")
+        .arg("lavfi")
+        .arg("-i")
+        .arg(format!(
+            "movie={}[out0+subcc]",
+            lavfi_escape(&input.to_string_lossy())
+        ))
+        .arg("-map")
+        .arg("0:s")
+        .arg("-c:s")
+        .arg("srt")
+        .arg(output_path)
+        .kill_on_drop(true);
+
+    ffmpeg::run(&mut cmd).await?;
+
+    if fs::metadata(output_path).await?.len() == 0 {
+        event!(Level::INFO, "No closed captions found");
+        fs::remove_file(output_path).await?;
     }
 
     Ok(())
 }
 
-/// Pass stream names and languages to ffmpeg command
-async fn add_metadata(cmd: &mut process::Command, streams: &Vec<(&Stream, PathBuf)>) -> Result<()> {
+/// Escape a filter option value inside a filtergraph, both levels of ffmpeg escaping are needed
+fn lavfi_escape(value: &str) -> String {
+    let escape = |s: &str, special: &[char]| {
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    };
+    let value = escape(value, &['\\', '\'', ':']);
+    escape(&value, &['\\', '\'', '[', ']', ',', ';'])
+}
+
+/// Pass stream names, languages, and codec info to ffmpeg command
+async fn add_metadata(
+    cmd: &mut process::Command,
+    streams: &Vec<(&Stream, PathBuf)>,
+    stream_info: &HashMap<Stream, StreamInfo>,
+) -> Result<()> {
+    // Alternative media carried inside the main stream
+    let has_main = streams.iter().any(|(s, _)| matches!(s, Stream::Main));
+    let muxed: &[Stream] = match stream_info.get(&Stream::Main) {
+        Some(info) if has_main => &info.muxed,
+        _ => &[],
+    };
+
+    // Only set dispositions of a stream type if the playlist flagged any of its alternative media,
+    // otherwise let ffmpeg choose default streams
+    let flagged_types: Vec<_> = streams
+        .iter()
+        .map(|(s, _)| *s)
+        .chain(muxed)
+        .filter_map(|s| match stream_metadata(s) {
+            Some((t, _, d)) if d.default || d.forced => Some(t),
+            _ => None,
+        })
+        .collect();
+
     // Closure to add stream metadata if available
-    let mut add_lang = |stream: &Stream, t, lang, count| {
+    let mut add_lang = |stream: &Stream, count: usize| {
+        let (t, lang, disposition) = match stream_metadata(stream) {
+            Some(m) => m,
+            None => return,
+        };
+
         // Language
         if let Some(l) = lang {
             if let Ok(l) = to_iso639_2(l) {
@@ -124,41 +613,80 @@ async fn add_metadata(cmd: &mut process::Command, streams: &Vec<(&Stream, PathBu
             }
         }
 
-        // Name
+        // Name, and codec info in handler name if available
         if let Some(n) = stream.name() {
+            let handler = match stream_info.get(stream) {
+                Some(info) if !info.is_empty() => format!("{} ({})", n, info),
+                _ => n.clone(),
+            };
             cmd.arg(format!("-metadata:s:{}:{}", t, count))
                 .arg(format!("title={}", n))
                 .arg(format!("-metadata:s:{}:{}", t, count))
-                .arg(format!("handler={}", n));
+                .arg(format!("handler={}", handler));
         }
 
-        count + 1
+        // Disposition
+        if flagged_types.contains(&t) {
+            cmd.arg(format!("-disposition:{}:{}", t, count))
+                .arg(disposition.ffmpeg_value());
+        }
     };
 
     // Set stream metadata
-    let mut video_count = 0;
-    let mut audio_count = 0;
-    let mut subtitle_count = 0;
+    let mut counts: HashMap<&str, usize> = HashMap::new();
     for (stream, p) in streams {
         match stream {
+            Stream::IFrames => (),
             Stream::Main => {
-                for stream in stream_type(p).await? {
-                    match stream {
-                        StreamType::Video => video_count += 1,
-                        StreamType::Audio => audio_count += 1,
-                        StreamType::Subtitle => subtitle_count += 1,
-                        _ => (),
+                let probed = probe_streams(p).await?;
+                for (t, stream_type) in [
+                    ("v", StreamType::Video),
+                    ("a", StreamType::Audio),
+                    ("s", StreamType::Subtitle),
+                ] {
+                    let substreams: Vec<_> = probed
+                        .iter()
+                        .filter(|s| s.codec_type == stream_type)
+                        .collect();
+                    let mut renditions: Vec<_> = muxed
+                        .iter()
+                        .filter(|s| matches!(stream_metadata(s), Some((m, _, _)) if m == t))
+                        .collect();
+
+                    // Match substreams to renditions by order if the counts agree, otherwise
+                    // only by language reported by ffprobe
+                    let by_order = substreams.len() == renditions.len();
+                    let count = counts.entry(t).or_default();
+                    for substream in substreams {
+                        let rendition = if by_order {
+                            Some(renditions.remove(0))
+                        } else {
+                            substream
+                                .language
+                                .as_ref()
+                                .and_then(|l| {
+                                    renditions.iter().position(|r| {
+                                        matches!(
+                                            stream_metadata(r),
+                                            Some((_, Some(rl), _)) if to_iso639_2(rl).ok().as_ref() == Some(l)
+                                        )
+                                    })
+                                })
+                                .map(|i| renditions.remove(i))
+                        };
+                        if let Some(r) = rendition {
+                            add_lang(r, *count);
+                        }
+                        *count += 1;
                     }
                 }
             }
-            Stream::Video { lang: l, .. } => {
-                video_count = add_lang(stream, "v", l.as_ref(), video_count);
-            }
-            Stream::Audio { lang: l, .. } => {
-                audio_count = add_lang(stream, "a", l.as_ref(), audio_count);
-            }
-            Stream::Subtitle { lang: l, .. } => {
-                subtitle_count = add_lang(stream, "s", l.as_ref(), subtitle_count);
+            _ => {
+                if let Some((t, _, _)) = stream_metadata(stream) {
+                    let count = counts.entry(t).or_default();
+                    add_lang(stream, *count);
+                    *count += 1;
+                }
             }
         }
     }
@@ -166,7 +694,23 @@ async fn add_metadata(cmd: &mut process::Command, streams: &Vec<(&Stream, PathBu
     Ok(())
 }
 
-#[derive(Deserialize, Debug)]
+/// ffmpeg stream specifier type, language, and disposition of alternative media
+fn stream_metadata(stream: &Stream) -> Option<(&'static str, Option<&String>, &Disposition)> {
+    match stream {
+        Stream::Video {
+            lang, disposition, ..
+        } => Some(("v", lang.as_ref(), disposition)),
+        Stream::Audio {
+            lang, disposition, ..
+        } => Some(("a", lang.as_ref(), disposition)),
+        Stream::Subtitle {
+            lang, disposition, ..
+        } => Some(("s", lang.as_ref(), disposition)),
+        Stream::Main | Stream::IFrames => None,
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq)]
 #[serde(from = "String")]
 enum StreamType {
     Video,
@@ -186,23 +730,39 @@ impl From<String> for StreamType {
     }
 }
 
-/// Get the types of streams in a media file
-async fn stream_type(stream_path: impl AsRef<Path>) -> Result<Vec<StreamType>> {
-    #[derive(Deserialize, Debug)]
-    struct FFProbeOuput {
-        streams: Vec<FFProbeStream>,
+/// Type and language tag of a stream in a media file, in ffprobe order
+#[derive(Deserialize, Debug)]
+struct ProbedStream {
+    codec_type: StreamType,
+    #[serde(default, rename = "tags", deserialize_with = "deserialize_language")]
+    language: Option<String>,
+}
+
+fn deserialize_language<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Tags {
+        language: Option<String>,
     }
+    let tags = Option::<Tags>::deserialize(deserializer)?;
+    Ok(tags.and_then(|t| t.language).filter(|l| l != "und"))
+}
+
+/// Get the types and languages of streams in a media file
+async fn probe_streams(stream_path: impl AsRef<Path>) -> Result<Vec<ProbedStream>> {
     #[derive(Deserialize, Debug)]
-    struct FFProbeStream {
-        codec_type: StreamType,
+    struct FFProbeOuput {
+        streams: Vec<ProbedStream>,
     }
 
     // Call ffprobe on input file
-    let mut cmd = process::Command::new("ffprobe");
+    let mut cmd = ffprobe_command();
     cmd.arg("-loglevel")
         .arg("quiet")
         .arg("-show_entries")
-        .arg("stream=codec_type")
+        .arg("stream=codec_type:stream_tags=language")
         .arg("-print_format")
         .arg("json")
         .arg(stream_path.as_ref())
@@ -223,14 +783,46 @@ async fn stream_type(stream_path: impl AsRef<Path>) -> Result<Vec<StreamType>> {
 
     let parsed_output: FFProbeOuput = serde_json::from_str(std::str::from_utf8(&output.stdout)?)?;
 
-    // Parse ffprobe output
-    let r = parsed_output
-        .streams
-        .into_iter()
-        .map(|stream| stream.codec_type)
-        .collect();
+    Ok(parsed_output.streams)
+}
+
+/// Start of the first audio stream minus start of the first video stream of a media file in
+/// seconds, None if it doesn't have both
+async fn probe_av_offset(path: &Path) -> Result<Option<f64>> {
+    #[derive(Deserialize, Debug)]
+    struct ProbedStart {
+        codec_type: StreamType,
+        start_time: Option<String>,
+    }
+    #[derive(Deserialize, Debug)]
+    struct FFProbeOuput {
+        streams: Vec<ProbedStart>,
+    }
+
+    let mut cmd = ffprobe_command();
+    cmd.arg("-loglevel")
+        .arg("quiet")
+        .arg("-show_entries")
+        .arg("stream=codec_type,start_time")
+        .arg("-print_format")
+        .arg("json")
+        .arg(path)
+        .kill_on_drop(true);
+
+    event!(Level::TRACE, "{:?}", cmd);
+    let output = cmd.output().await?;
+    let parsed_output: FFProbeOuput = serde_json::from_str(std::str::from_utf8(&output.stdout)?)?;
+    let start = |codec_type: StreamType| {
+        parsed_output
+            .streams
+            .iter()
+            .find(|s| s.codec_type == codec_type)
+            .and_then(|s| s.start_time.as_deref()?.parse::<f64>().ok())
+    };
 
-    Ok(r)
+    Ok(start(StreamType::Audio)
+        .zip(start(StreamType::Video))
+        .map(|(a, v)| a - v))
 }
 
 /// Convert rfc5646 language tag to iso639-3 format readable by ffmpeg
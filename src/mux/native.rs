@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tokio::fs;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tracing::{event, Level};
+
+use super::fmp4::Fmp4Muxer;
+use crate::ffmpeg::ffmpeg_command;
+use crate::livestream::{MediaFormat, Segment, SegmentFile, Stream};
+
+/// Check if ffmpeg can be run
+pub async fn ffmpeg_available() -> bool {
+    ffmpeg_command()
+        .arg("-version")
+        .kill_on_drop(true)
+        .output()
+        .await
+        .is_ok()
+}
+
+/// Remux without ffmpeg
+///
+/// Fragmented MPEG-4 streams of each discontinuity are muxed into one fragmented mp4 with a
+/// track per stream and interleaved fragments. Streams in other formats, or that fail to mux,
+/// are concatenated into their own files.
+pub async fn remux(
+    downloaded_paths: HashMap<Stream, BinaryHeap<(Segment, SegmentFile)>>,
+    output_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    event!(Level::WARN, "ffmpeg not found, remuxing natively");
+
+    // Group segments of each stream by discontinuity
+    let file_names = Stream::file_names(downloaded_paths.keys());
+    let mut discons: BTreeMap<u64, Vec<StreamSegments>> = BTreeMap::new();
+    for (stream, segments) in downloaded_paths {
+        for (segment, segment_file) in segments.into_sorted_vec() {
+            let streams = discons.entry(segment.discon_seq).or_default();
+            match streams.last_mut() {
+                Some(s) if s.stream == stream => s.segment_files.push(segment_file),
+                _ => streams.push(StreamSegments {
+                    stream: stream.clone(),
+                    format: segment.format,
+                    segment_files: vec![segment_file],
+                }),
+            }
+        }
+    }
+
+    let mut output_paths = Vec::new();
+    let multiple_discons = discons.len() > 1;
+    for (discon_seq, mut streams) in discons {
+        // The main stream goes first so its movie header and file type are used
+        streams.sort_by_key(|s| (s.stream != Stream::Main, file_names[&s.stream].clone()));
+        let suffix = if multiple_discons {
+            format!("_{:010}", discon_seq)
+        } else {
+            String::new()
+        };
+
+        let (fmp4, mut other): (Vec<_>, Vec<_>) = streams
+            .into_iter()
+            .partition(|s| s.format == MediaFormat::FMp4);
+        if !fmp4.is_empty() {
+            match mux_fmp4(&fmp4, output_dir, &suffix).await {
+                Ok(path) => output_paths.push(path),
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "Unable to mux fragmented mp4 streams, concatenating them separately: {}",
+                        e
+                    );
+                    other.extend(fmp4);
+                }
+            }
+        }
+
+        for s in other {
+            let name = format!("video_{}{}", file_names[&s.stream], suffix);
+            output_paths.push(concat(&s, output_dir, &name).await?);
+        }
+    }
+
+    output_paths.sort();
+    Ok(output_paths)
+}
+
+/// Downloaded segments of a stream in one discontinuity
+struct StreamSegments {
+    stream: Stream,
+    format: MediaFormat,
+    segment_files: Vec<SegmentFile>,
+}
+
+/// Mux fragmented MPEG-4 streams into video{suffix}.mp4, or m4a if there is no video
+async fn mux_fmp4(streams: &[StreamSegments], output_dir: &Path, suffix: &str) -> Result<PathBuf> {
+    // Each downloaded segment has its initialization section prepended
+    let mut pending = Vec::new();
+    for s in streams {
+        let mut segment_files = s.segment_files.iter();
+        let first = segment_files.next().unwrap().read().await?;
+        pending.push((segment_files, first));
+    }
+    let inits: Vec<_> = pending.iter().map(|(_, first)| first.as_slice()).collect();
+    let (mut muxer, header, has_video) = Fmp4Muxer::new(&inits)?;
+    let mut queues = Vec::new();
+    let mut segment_files = Vec::new();
+    for (i, (files, first)) in pending.into_iter().enumerate() {
+        queues.push(VecDeque::from(muxer.fragments(i, &first)?));
+        segment_files.push(files);
+    }
+
+    let extension = if has_video { "mp4" } else { "m4a" };
+    let output_path = output_dir.join(format!("video{}.{}", suffix, extension));
+    let tmp_path = output_dir.join(format!("video{}.tmp.{}", suffix, extension));
+    event!(Level::INFO, "Native mux to {:?}", output_path);
+
+    // Write the earliest fragment of all streams next, reading segments as they are needed
+    let written: Result<()> = async {
+        let mut file = BufWriter::new(fs::File::create(&tmp_path).await?);
+        file.write_all(&header).await?;
+        loop {
+            for (i, queue) in queues.iter_mut().enumerate() {
+                while queue.is_empty() {
+                    match segment_files[i].next() {
+                        Some(segment_file) => {
+                            let data = segment_file.read().await?;
+                            queue.extend(muxer.fragments(i, &data)?);
+                        }
+                        None => break,
+                    }
+                }
+            }
+
+            let next = queues
+                .iter_mut()
+                .filter(|queue| !queue.is_empty())
+                .min_by(|a, b| a[0].time.total_cmp(&b[0].time));
+            match next.and_then(|queue| queue.pop_front()) {
+                Some(fragment) => file.write_all(&muxer.next_fragment(fragment)).await?,
+                None => break,
+            }
+        }
+        file.flush().await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(e);
+    }
+    fs::rename(&tmp_path, &output_path).await?;
+
+    Ok(output_path)
+}
+
+/// Concatenate the segments of a stream into a file named name
+async fn concat(stream: &StreamSegments, output_dir: &Path, name: &str) -> Result<PathBuf> {
+    let output_path = output_dir
+        .join(name)
+        .with_extension(stream.format.extension());
+
+    event!(Level::INFO, "Native concat to {:?}", output_path);
+    let mut file = fs::File::create(&output_path).await?;
+    for (i, segment_file) in stream.segment_files.iter().enumerate() {
+        let data = segment_file.read().await?;
+        let data = if stream.format == MediaFormat::FMp4 && i > 0 {
+            strip_init_boxes(&data)
+        } else {
+            &data
+        };
+        file.write_all(data).await?;
+    }
+
+    Ok(output_path)
+}
+
+/// Skip leading ftyp and moov boxes of an MPEG-4 fragment, since each downloaded segment has
+/// its initialization section prepended
+fn strip_init_boxes(mut data: &[u8]) -> &[u8] {
+    while let Some(box_type) = data.get(4..8) {
+        if box_type != b"ftyp" && box_type != b"moov" {
+            break;
+        }
+
+        // Box size includes the header, 1 means a 64 bit size follows, 0 means until the end
+        let size = match u32::from_be_bytes(data[0..4].try_into().unwrap()) {
+            0 => data.len(),
+            1 => match data.get(8..16) {
+                Some(s) => u64::from_be_bytes(s.try_into().unwrap()) as usize,
+                None => break,
+            },
+            s => s as usize,
+        };
+        match data.get(size..) {
+            Some(rest) if size >= 8 => data = rest,
+            _ => break,
+        }
+    }
+
+    data
+}
@@ -0,0 +1,65 @@
+use std::collections::BinaryHeap;
+use std::path::Path;
+
+use anyhow::Result;
+use tokio::fs;
+use tracing::{event, Level};
+
+use super::concat::concat_segments;
+use crate::ffmpeg::{self, ffmpeg_command};
+use crate::livestream::{Segment, SegmentFile};
+
+/// Number of thumbnails per row and column of a sprite sheet
+const TILE_SIZE: usize = 10;
+
+/// Width of each thumbnail in a sprite sheet
+const THUMBNAIL_WIDTH: usize = 160;
+
+/// Generate thumbnail sprite sheets from downloaded I-frame segments with ffmpeg
+pub async fn generate_sprite_sheets(
+    segments: BinaryHeap<(Segment, SegmentFile)>,
+    output_dir: &Path,
+) -> Result<()> {
+    let segments = segments.into_sorted_vec();
+    let inputs: Vec<_> = segments.iter().map(|(s, p)| (s, p)).collect();
+    let first = match inputs.first() {
+        Some((s, _)) => s,
+        None => return Ok(()),
+    };
+
+    // Concat all I-frames into a temporary file
+    let iframes_path = output_dir
+        .join("iframes")
+        .with_extension(first.format.extension());
+    concat_segments(&inputs, &iframes_path).await?;
+
+    let output_path = output_dir.join("sprite_%04d.jpg");
+    event!(
+        Level::INFO,
+        "ffmpeg generate sprite sheets to {:?}",
+        output_path
+    );
+
+    // Call ffmpeg to tile I-frames into sprite sheets
+    let mut cmd = ffmpeg_command();
+    cmd.arg("-y")
+        .arg("-i")
+        .arg(&iframes_path)
+        .arg("-an")
+        .arg("-vf")
+        .arg(format!(
+            "scale={}:-2,tile={}x{}",
+            THUMBNAIL_WIDTH, TILE_SIZE, TILE_SIZE
+        ))
+        .arg(&output_path)
+        .kill_on_drop(true);
+
+    let result = ffmpeg::run(&mut cmd).await;
+
+    // Delete temporary concatenated file
+    event!(Level::TRACE, "Removing {}", iframes_path.to_string_lossy());
+    fs::remove_file(&iframes_path).await?;
+
+    result?;
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Deserialize;
+use tracing::{event, Level};
+
+use crate::ffmpeg::{self, ffmpeg_command, ffprobe_command};
+
+/// Generate thumbnails for each video with ffmpeg
+///
+/// If interval is given, save a thumbnail every interval minutes, otherwise save a single
+/// representative thumbnail from the middle of the video. Failures are logged but not returned
+/// because a missing thumbnail shouldn't fail the download.
+pub async fn generate_thumbnails(videos: &[PathBuf], interval: Option<u64>) {
+    for video in videos {
+        if let Err(e) = generate_thumbnail(video, interval).await {
+            event!(
+                Level::WARN,
+                "Failed to generate thumbnail for {:?}, reason: {}",
+                video,
+                e
+            );
+        }
+    }
+}
+
+async fn generate_thumbnail(video: &Path, interval: Option<u64>) -> Result<()> {
+    let mut cmd = ffmpeg_command();
+    cmd.arg("-y");
+
+    let output_path = match interval {
+        Some(minutes) => {
+            // Save every interval minutes
+            let file_name = format!(
+                "{}_%04d.jpg",
+                video.file_stem().unwrap_or_default().to_string_lossy()
+            );
+            cmd.arg("-i")
+                .arg(video)
+                .arg("-vf")
+                .arg(format!("fps=1/{}", minutes.max(1) * 60));
+            video.with_file_name(file_name)
+        }
+        None => {
+            // Pick a representative frame near the middle of the video
+            let seek = video_duration(video).await? / 2.0;
+            cmd.arg("-ss")
+                .arg(format!("{:.3}", seek))
+                .arg("-i")
+                .arg(video)
+                .arg("-vf")
+                .arg("thumbnail")
+                .arg("-frames:v")
+                .arg("1");
+            video.with_extension("jpg")
+        }
+    };
+
+    event!(Level::INFO, "ffmpeg thumbnail to {:?}", output_path);
+    cmd.arg("-an").arg(&output_path).kill_on_drop(true);
+
+    ffmpeg::run(&mut cmd).await?;
+
+    Ok(())
+}
+
+/// Get the duration of a media file in seconds
+async fn video_duration(path: &Path) -> Result<f64> {
+    #[derive(Deserialize, Debug)]
+    struct FFProbeOuput {
+        format: FFProbeFormat,
+    }
+    #[derive(Deserialize, Debug)]
+    struct FFProbeFormat {
+        duration: String,
+    }
+
+    // Call ffprobe on input file
+    let mut cmd = ffprobe_command();
+    cmd.arg("-loglevel")
+        .arg("quiet")
+        .arg("-show_entries")
+        .arg("format=duration")
+        .arg("-print_format")
+        .arg("json")
+        .arg(path)
+        .kill_on_drop(true);
+
+    event!(Level::TRACE, "{:?}", cmd);
+    let output = cmd.output().await?;
+
+    let parsed_output: FFProbeOuput = serde_json::from_str(std::str::from_utf8(&output.stdout)?)?;
+    Ok(parsed_output.format.duration.parse()?)
+}
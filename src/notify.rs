@@ -0,0 +1,39 @@
+use anyhow::Result;
+#[cfg(feature = "desktop-notify")]
+use tracing::{event, Level};
+
+/// Check that desktop notifications can be shown
+pub fn check_supported() -> Result<()> {
+    if cfg!(feature = "desktop-notify") {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "desktop notifications are not supported, livestream-dl was built without the \
+             desktop-notify feature"
+        ))
+    }
+}
+
+/// Show a desktop notification, failures are only logged
+pub async fn desktop_notify(summary: String, body: String) {
+    #[cfg(feature = "desktop-notify")]
+    {
+        let result = tokio::task::spawn_blocking(move || {
+            notify_rust::Notification::new()
+                .appname("livestream-dl")
+                .summary(&summary)
+                .body(&body)
+                .show()
+                .map(|_| ())
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => event!(Level::WARN, "Failed to show desktop notification: {}", e),
+            Err(e) => event!(Level::WARN, "Failed to show desktop notification: {}", e),
+        }
+    }
+
+    #[cfg(not(feature = "desktop-notify"))]
+    let _ = (summary, body);
+}
@@ -0,0 +1,367 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Stdout, Write};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use crossterm::event::{self as term_event, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Table};
+use ratatui::Terminal;
+use tracing::{event, Level};
+
+use crate::livestream::{format_bytes, format_duration, Stats, Stream, StreamStats};
+
+/// Interval between redraws of the dashboard
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Number of most recent log lines kept for the log pane
+const MAX_LOG_LINES: usize = 1000;
+
+/// Time span download speeds are averaged over
+const SPEED_WINDOW: Duration = Duration::from_secs(5);
+
+/// Widths of the stream table's columns
+const COLUMN_WIDTHS: [Constraint; 10] = [
+    Constraint::Percentage(20),
+    Constraint::Percentage(16),
+    Constraint::Length(9),
+    Constraint::Length(7),
+    Constraint::Length(13),
+    Constraint::Length(11),
+    Constraint::Length(10),
+    Constraint::Length(8),
+    Constraint::Length(8),
+    Constraint::Length(18),
+];
+
+/// Terminal the dashboard is drawn on while it is shown
+static TERMINAL: Mutex<Option<Terminal<CrosstermBackend<Stdout>>>> = Mutex::new(None);
+
+/// Log lines written while the dashboard is shown, None while logging to stdout
+static LOG_LINES: Mutex<Option<VecDeque<String>>> = Mutex::new(None);
+
+/// Download statistics of every recording shown on the dashboard, by name
+static RECORDINGS: Mutex<Vec<(String, Stats)>> = Mutex::new(Vec::new());
+
+/// Writer of the stdout logging layer, writes to the dashboard's log pane while it is shown
+#[derive(Default)]
+pub struct StdoutWriter;
+
+impl Write for StdoutWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut log_lines = LOG_LINES.lock().unwrap();
+        let lines = match &mut *log_lines {
+            Some(l) => l,
+            None => return io::stdout().write(buf),
+        };
+
+        for line in String::from_utf8_lossy(buf).lines() {
+            if lines.len() == MAX_LOG_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(strip_ansi(line));
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Full screen dashboard of the streams of every recording and the most recent log lines, shown
+/// until dropped
+pub struct Dashboard {
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Dashboard {
+    /// Take over the terminal and log to the dashboard instead of stdout. The terminal doesn't
+    /// send interrupt signals while the dashboard is shown, on_interrupt is called when Ctrl-C
+    /// or q is pressed instead
+    pub fn start(on_interrupt: impl Fn() + Send + 'static) -> Result<Self> {
+        let terminal = match enter_terminal() {
+            Ok(t) => t,
+            Err(e) => {
+                restore_terminal();
+                return Err(e.into());
+            }
+        };
+        *TERMINAL.lock().unwrap() = Some(terminal);
+        *LOG_LINES.lock().unwrap() = Some(VecDeque::new());
+
+        // Leave the dashboard before panic messages are printed
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            hook(info);
+        }));
+
+        let thread = std::thread::spawn(move || {
+            let mut view = View::default();
+            if let Err(e) = view.run(&on_interrupt) {
+                close();
+                event!(Level::WARN, "Dashboard failed: {:#}", e);
+            }
+        });
+
+        Ok(Self {
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Show a recording's streams on the dashboard
+pub fn watch(name: String, stats: Stats) {
+    RECORDINGS.lock().unwrap().push((name, stats));
+}
+
+/// Leave the dashboard and print the log lines it showed, does nothing if it isn't shown
+pub fn close() {
+    if TERMINAL.lock().unwrap().take().is_none() {
+        return;
+    }
+    restore_terminal();
+
+    if let Some(lines) = LOG_LINES.lock().unwrap().take() {
+        let mut stdout = io::stdout().lock();
+        for line in lines {
+            let _ = writeln!(stdout, "{}", line);
+        }
+    }
+}
+
+fn enter_terminal() -> io::Result<Terminal<CrosstermBackend<Stdout>>> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    terminal.hide_cursor()?;
+    terminal.clear()?;
+
+    Ok(terminal)
+}
+
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stdout(), LeaveAlternateScreen, crossterm::cursor::Show);
+}
+
+/// State of the dashboard between redraws
+#[derive(Default)]
+struct View {
+    /// Recent times and downloaded bytes of each stream, to calculate download speeds
+    samples: HashMap<(String, Stream), VecDeque<(Instant, u64)>>,
+    /// Number of log lines scrolled back from the most recent one
+    scroll: usize,
+    /// Number of log lines shown at once, scrolled by page
+    page: usize,
+}
+
+impl View {
+    /// Redraw and handle keys until the dashboard is closed
+    fn run(&mut self, on_interrupt: &dyn Fn()) -> Result<()> {
+        loop {
+            if !self.draw()? {
+                return Ok(());
+            }
+            if !term_event::poll(REFRESH_INTERVAL)? {
+                continue;
+            }
+            let key = match term_event::read()? {
+                Event::Key(k) if k.kind == KeyEventKind::Press => k,
+                _ => continue,
+            };
+            let lines = LOG_LINES.lock().unwrap().as_ref().map_or(0, |l| l.len());
+            match key.code {
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    on_interrupt()
+                }
+                KeyCode::Char('q') => on_interrupt(),
+                KeyCode::Up | KeyCode::Char('k') => self.scroll += 1,
+                KeyCode::Down | KeyCode::Char('j') => self.scroll = self.scroll.saturating_sub(1),
+                KeyCode::PageUp => self.scroll += self.page,
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.page),
+                KeyCode::Home | KeyCode::Char('g') => self.scroll = lines,
+                KeyCode::End | KeyCode::Char('G') => self.scroll = 0,
+                _ => {}
+            }
+            self.scroll = self.scroll.min(lines.saturating_sub(self.page));
+        }
+    }
+
+    /// Draw the dashboard, returns false if it was closed
+    fn draw(&mut self) -> Result<bool> {
+        let recordings = RECORDINGS.lock().unwrap().clone();
+        let retries: u64 = recordings.iter().map(|(_, s)| s.retries()).sum();
+        let elapsed = recordings
+            .iter()
+            .map(|(_, s)| s.elapsed().as_secs_f64())
+            .fold(0.0, f64::max);
+        let mut rows = Vec::new();
+        for (name, stats) in &recordings {
+            for (stream, s) in stats.streams() {
+                let speed = self.speed(name, &stream, s.bytes);
+                rows.push(stream_row(name, &stream, &s, speed));
+            }
+        }
+        let row_count = rows.len();
+        let title = format!(
+            " {} elapsed, {} network requests retried ",
+            format_duration(elapsed),
+            retries
+        );
+        let table = Table::new(rows)
+            .header(
+                Row::new(vec![
+                    "Recording",
+                    "Stream",
+                    "Segments",
+                    "Queued",
+                    "Speed",
+                    "Size",
+                    "Recorded",
+                    "Behind",
+                    "Reload",
+                    "Fail/Exp/Drop/Bad",
+                ])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+            )
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .widths(&COLUMN_WIDTHS);
+
+        let mut terminal = TERMINAL.lock().unwrap();
+        let terminal = match terminal.as_mut() {
+            Some(t) => t,
+            None => return Ok(false),
+        };
+        let scroll = self.scroll;
+        let mut page = self.page;
+        terminal.draw(|f| {
+            let area = f.size();
+            let table_height = (row_count as u16 + 3).min(area.height / 2);
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(table_height), Constraint::Min(3)].as_ref())
+                .split(area);
+            f.render_widget(table, chunks[0]);
+
+            page = chunks[1].height.saturating_sub(2) as usize;
+            f.render_widget(log_pane(page, scroll), chunks[1]);
+        })?;
+        self.page = page;
+
+        Ok(true)
+    }
+
+    /// Average download speed of a stream in bytes per second over the last few seconds
+    fn speed(&mut self, name: &str, stream: &Stream, bytes: u64) -> f64 {
+        let now = Instant::now();
+        let samples = self
+            .samples
+            .entry((name.to_owned(), stream.clone()))
+            .or_default();
+        samples.push_back((now, bytes));
+        while samples.len() > 2 && now - samples[1].0 >= SPEED_WINDOW {
+            samples.pop_front();
+        }
+
+        let (first_time, first_bytes) = samples[0];
+        let seconds = (now - first_time).as_secs_f64();
+        if seconds <= 0.0 {
+            return 0.0;
+        }
+        bytes.saturating_sub(first_bytes) as f64 / seconds
+    }
+}
+
+fn stream_row(name: &str, stream: &Stream, s: &StreamStats, speed: f64) -> Row<'static> {
+    let row = Row::new(vec![
+        name.to_owned(),
+        stream.to_string(),
+        s.segments_downloaded.to_string(),
+        s.queued.to_string(),
+        format!("{}/s", format_bytes(speed as u64)),
+        format_bytes(s.bytes),
+        format_duration(s.duration),
+        s.behind_live
+            .map_or_else(|| "-".to_owned(), |b| format!("{:.1}s", b)),
+        s.last_reload.map_or_else(
+            || "-".to_owned(),
+            |t| format!("{}s ago", t.elapsed().as_secs()),
+        ),
+        format!(
+            "{}/{}/{}/{}",
+            s.segments_failed, s.segments_expired, s.segments_dropped, s.segments_corrupt
+        ),
+    ]);
+
+    // Streams whose playlist fetcher failed are highlighted
+    if s.error.is_some() {
+        row.style(Style::default().fg(Color::Red))
+    } else {
+        row
+    }
+}
+
+/// Pane of height log lines, scrolled back by scroll lines from the most recent one
+fn log_pane(height: usize, scroll: usize) -> Paragraph<'static> {
+    let text = match LOG_LINES.lock().unwrap().as_ref() {
+        Some(lines) => {
+            let end = lines.len().saturating_sub(scroll);
+            let start = end.saturating_sub(height);
+            lines
+                .range(start..end)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        None => String::new(),
+    };
+    let title = if scroll > 0 {
+        format!(" Log ({} lines back, End to follow) ", scroll)
+    } else {
+        " Log (q to stop, arrows to scroll) ".to_owned()
+    };
+
+    Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(title))
+}
+
+/// Remove the color escape sequences the stdout logging layer adds
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        // Skip the escape sequence up to its final byte
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+
+    stripped
+}
@@ -0,0 +1,106 @@
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::{Context, Result};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use tokio::io::AsyncWriteExt;
+use tokio::process;
+use tracing::{event, Level};
+
+use super::{with_retries, without_credentials, Uploader};
+
+/// Uploads to FTP, FTPS, and SFTP servers with curl, which streams the file. Credentials are
+/// taken from the url or ~/.netrc
+#[derive(Debug)]
+pub struct CurlUploader {
+    /// Base url without credentials, with a path ending with /
+    base: Url,
+    /// Config passed to curl on stdin with the credentials of the url, so they aren't visible in
+    /// its arguments
+    config: Option<String>,
+}
+
+impl CurlUploader {
+    /// Fails if curl can't be run, so a missing curl is found before anything is downloaded
+    pub fn new(url: &Url) -> Result<Self> {
+        let output = std::process::Command::new("curl")
+            .arg("--version")
+            .output()
+            .with_context(|| {
+                format!(
+                    "unable to run curl, make sure it is installed to upload to {} servers",
+                    url.scheme()
+                )
+            })?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("curl --version failed"));
+        }
+
+        let mut base = without_credentials(url);
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        let config = match (url.username(), url.password()) {
+            ("", None) => None,
+            (user, password) => {
+                let mut credentials = percent_decode_str(user).decode_utf8()?.into_owned();
+                if let Some(p) = password {
+                    credentials.push(':');
+                    credentials.push_str(&percent_decode_str(p).decode_utf8()?);
+                }
+                Some(format!("user = \"{}\"\n", config_escape(&credentials)))
+            }
+        };
+
+        Ok(Self { base, config })
+    }
+}
+
+/// Escape a string to be quoted in a curl config
+fn config_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[async_trait::async_trait]
+impl Uploader for CurlUploader {
+    async fn upload(&self, path: &Path, name: &str) -> Result<()> {
+        let url = self.base.join(name)?;
+        event!(Level::INFO, "Uploading {:?} to {}", path, url);
+
+        let url = &url;
+        with_retries(|| async move {
+            let mut cmd = process::Command::new("curl");
+            cmd.args(["--silent", "--show-error", "--fail"])
+                .args(["--netrc-optional", "--ftp-create-dirs"])
+                .arg("--upload-file")
+                .arg(path)
+                .arg(url.as_str())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true);
+            if self.config.is_some() {
+                cmd.args(["--config", "-"]);
+            }
+            event!(Level::TRACE, "{:?}", cmd);
+
+            // stdin is closed after writing the config
+            let mut child = cmd.spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                if let Some(config) = &self.config {
+                    stdin.write_all(config.as_bytes()).await?;
+                }
+            }
+            let output = child.wait_with_output().await?;
+            if !output.status.success() {
+                return Err(anyhow::anyhow!(
+                    "curl failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(())
+        })
+        .await
+    }
+}
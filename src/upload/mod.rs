@@ -0,0 +1,118 @@
+mod curl;
+#[cfg(feature = "s3")]
+mod s3;
+mod webdav;
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::Url;
+use tracing::{event, Level};
+
+use self::curl::CurlUploader;
+use self::webdav::WebDavUploader;
+use crate::cli::MuxOptions;
+
+/// Schemes of upload targets that may be given as --output
+const REMOTE_SCHEMES: &[&str] = &[
+    "s3", "webdav", "webdavs", "dav", "davs", "ftp", "ftps", "sftp",
+];
+
+/// Number of attempts of each upload request before giving up
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait time before the first retry, doubled for each following retry
+const MIN_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Remote storage finished files are uploaded to
+#[async_trait::async_trait]
+pub trait Uploader: Debug + Send + Sync {
+    /// Upload the file at path as name, a relative path with / separators
+    async fn upload(&self, path: &Path, name: &str) -> Result<()>;
+}
+
+/// Uploader shared by all tasks of a download
+pub type SharedUploader = Arc<dyn Uploader>;
+
+/// Uploader for the --upload target, None if not uploading
+pub fn from_options(options: &MuxOptions) -> Result<Option<SharedUploader>> {
+    let url = match &options.upload {
+        Some(u) => u,
+        None => return Ok(None),
+    };
+
+    match url.scheme() {
+        #[cfg(feature = "s3")]
+        "s3" => Ok(Some(Arc::new(s3::S3Uploader::new(
+            url,
+            options.upload_endpoint.as_ref(),
+            &options.upload_region,
+        )?))),
+        #[cfg(not(feature = "s3"))]
+        "s3" => Err(anyhow::anyhow!(
+            "S3 uploads are not supported, livestream-dl was built without the s3 feature"
+        )),
+        "webdav" | "webdavs" | "dav" | "davs" => Ok(Some(Arc::new(WebDavUploader::new(url)?))),
+        "ftp" | "ftps" | "sftp" => Ok(Some(Arc::new(CurlUploader::new(url)?))),
+        s => Err(anyhow::anyhow!("unsupported upload target scheme {:?}", s)),
+    }
+}
+
+/// Upload target given as --output instead of a local directory
+pub fn remote_output(output: &Path) -> Option<Url> {
+    let url = Url::parse(output.to_str()?).ok()?;
+    REMOTE_SCHEMES.contains(&url.scheme()).then_some(url)
+}
+
+/// Url without its username and password, to be logged
+pub fn without_credentials(url: &Url) -> Url {
+    let mut url = url.clone();
+    // Only fails for urls that can't have credentials
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url
+}
+
+/// Run an upload request, retrying with exponential backoff on failure
+async fn with_retries<F, Fut, T>(mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match f().await {
+            Ok(r) => return Ok(r),
+            Err(e) if attempt >= MAX_ATTEMPTS => return Err(e),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Upload request failed, retrying ({}/{}): {}",
+                    attempt,
+                    MAX_ATTEMPTS - 1,
+                    e
+                );
+                tokio::time::sleep(MIN_RETRY_BACKOFF * 2_u32.pow(attempt - 1)).await;
+            }
+        }
+    }
+}
+
+/// Name of a file in the output directory, prefixed by the output directory's name so uploads
+/// of different recordings don't collide
+pub fn upload_name(output: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(output).unwrap_or(path);
+    output
+        .file_name()
+        .map(Path::new)
+        .into_iter()
+        .chain(relative.iter().map(Path::new))
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,326 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use reqwest::{Client, Method, Url};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tracing::{event, Level};
+
+use super::{with_retries, Uploader};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Size of each part of a multipart upload, files up to this size are uploaded at once
+const PART_SIZE: usize = 16 * 1024 * 1024;
+
+/// Characters kept as is when encoding uris for signing
+const UNRESERVED: &[u8] = b"-_.~";
+
+/// Uploads to an S3 compatible bucket (AWS S3, Backblaze B2, Cloudflare R2, MinIO) with
+/// signature version 4 and path style urls. Credentials are read from AWS_ACCESS_KEY_ID,
+/// AWS_SECRET_ACCESS_KEY, and optionally AWS_SESSION_TOKEN
+#[derive(Debug)]
+pub struct S3Uploader {
+    client: Client,
+    endpoint: Url,
+    region: String,
+    bucket: String,
+    /// Prefix of uploaded object keys, empty or ending with /
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl S3Uploader {
+    /// Uploader for an s3://bucket/prefix url. Uses AWS unless another endpoint is given
+    pub fn new(url: &Url, endpoint: Option<&Url>, region: &str) -> Result<Self> {
+        let bucket = url
+            .host_str()
+            .filter(|b| !b.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("no bucket in upload url {}", url))?
+            .to_owned();
+        let mut prefix = url.path().trim_matches('/').to_owned();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        let endpoint = match endpoint {
+            Some(e) => e.clone(),
+            None => Url::parse(&format!("https://s3.{}.amazonaws.com", region))?,
+        };
+        let env = |name: &str| {
+            std::env::var(name).with_context(|| format!("{} must be set for S3 uploads", name))
+        };
+
+        Ok(Self {
+            client: Client::new(),
+            endpoint,
+            region: region.to_owned(),
+            bucket,
+            prefix,
+            access_key: env("AWS_ACCESS_KEY_ID")?,
+            secret_key: env("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    /// Url of an object
+    fn object_url(&self, key: &str) -> Result<Url> {
+        let path = format!("{}/{}", self.bucket, key);
+        Ok(self.endpoint.join(&uri_encode(&path, false))?)
+    }
+
+    /// Send a signed request, retrying on failure. Returns the response headers and body
+    async fn send(
+        &self,
+        method: Method,
+        url: &Url,
+        body: &[u8],
+    ) -> Result<(reqwest::header::HeaderMap, String)> {
+        let method = &method;
+        with_retries(|| async move {
+            let resp = self
+                .sign(
+                    self.client.request(method.clone(), url.clone()),
+                    url,
+                    method,
+                    body,
+                )?
+                .body(body.to_vec())
+                .send()
+                .await?;
+            let status = resp.status();
+            let headers = resp.headers().clone();
+            let text = resp.text().await?;
+            if !status.is_success() {
+                return Err(anyhow::anyhow!("{} {}: {}", status, url, text));
+            }
+            Ok((headers, text))
+        })
+        .await
+    }
+
+    /// Add AWS signature version 4 headers to a request
+    fn sign(
+        &self,
+        request: reqwest::RequestBuilder,
+        url: &Url,
+        method: &Method,
+        body: &[u8],
+    ) -> Result<reqwest::RequestBuilder> {
+        let now = OffsetDateTime::now_utc();
+        let date = format!("{:04}{:02}{:02}", now.year(), now.month() as u8, now.day());
+        let timestamp = format!(
+            "{}T{:02}{:02}{:02}Z",
+            date,
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        let payload_hash = hex::encode(Sha256::digest(body));
+        let host = match url.port() {
+            Some(p) => format!("{}:{}", url.host_str().unwrap_or_default(), p),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+
+        // Headers are signed in sorted order
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", k, v.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let mut query: Vec<_> = url
+            .query_pairs()
+            .map(|(k, v)| (uri_encode(&k, true), uri_encode(&v, true)))
+            .collect();
+        query.sort();
+        let canonical_query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            url.path(),
+            canonical_query,
+            canonical_headers,
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = [date.as_str(), &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.secret_key).into_bytes(),
+                |key, part| hmac(&key, part.as_bytes()),
+            );
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let mut request = request.header("authorization", authorization);
+        for (k, v) in headers.into_iter().filter(|(k, _)| *k != "host") {
+            request = request.header(k, v);
+        }
+
+        Ok(request)
+    }
+
+    /// Upload a file in parts, for files larger than PART_SIZE
+    async fn upload_multipart(&self, file: &mut File, url: &Url, first: Vec<u8>) -> Result<()> {
+        let mut create_url = url.clone();
+        create_url.set_query(Some("uploads"));
+        let (_, body) = self.send(Method::POST, &create_url, &[]).await?;
+        let upload_id = xml_value(&body, "UploadId")
+            .ok_or_else(|| anyhow::anyhow!("no upload id in response: {}", body))?;
+
+        let result = async {
+            let mut etags = Vec::new();
+            let mut part = first;
+            while !part.is_empty() {
+                let mut part_url = url.clone();
+                part_url
+                    .query_pairs_mut()
+                    .append_pair("partNumber", &(etags.len() + 1).to_string())
+                    .append_pair("uploadId", &upload_id);
+                let (headers, _) = self.send(Method::PUT, &part_url, &part).await?;
+                let etag = headers
+                    .get("etag")
+                    .and_then(|v| v.to_str().ok())
+                    .ok_or_else(|| anyhow::anyhow!("no etag for part {}", etags.len() + 1))?;
+                etags.push(etag.to_owned());
+                part = read_part(file).await?;
+            }
+
+            let parts: String = etags
+                .iter()
+                .enumerate()
+                .map(|(i, etag)| {
+                    format!(
+                        "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                        i + 1,
+                        etag
+                    )
+                })
+                .collect();
+            let complete = format!(
+                "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+                parts
+            );
+            let mut complete_url = url.clone();
+            complete_url
+                .query_pairs_mut()
+                .append_pair("uploadId", &upload_id);
+            self.send(Method::POST, &complete_url, complete.as_bytes())
+                .await?;
+
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        // Don't leave the parts of a failed upload behind
+        if result.is_err() {
+            let mut abort_url = url.clone();
+            abort_url
+                .query_pairs_mut()
+                .append_pair("uploadId", &upload_id);
+            if let Err(e) = self.send(Method::DELETE, &abort_url, &[]).await {
+                event!(Level::WARN, "Failed to abort multipart upload: {}", e);
+            }
+        }
+
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl Uploader for S3Uploader {
+    async fn upload(&self, path: &Path, name: &str) -> Result<()> {
+        let key = format!("{}{}", self.prefix, name);
+        let url = self.object_url(&key)?;
+        event!(
+            Level::INFO,
+            "Uploading {:?} to s3://{}/{}",
+            path,
+            self.bucket,
+            key
+        );
+
+        let mut file = File::open(path).await?;
+        let first = read_part(&mut file).await?;
+        if first.len() < PART_SIZE {
+            self.send(Method::PUT, &url, &first).await?;
+        } else {
+            self.upload_multipart(&mut file, &url, first).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read up to PART_SIZE bytes, less only at the end of the file
+async fn read_part(file: &mut File) -> Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(PART_SIZE);
+    while part.len() < PART_SIZE {
+        let read = (&mut *file)
+            .take((PART_SIZE - part.len()) as u64)
+            .read_to_end(&mut part)
+            .await?;
+        if read == 0 {
+            break;
+        }
+    }
+
+    Ok(part)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent encode everything except unreserved characters, and / unless encode_slash
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b if b.is_ascii_alphanumeric() || UNRESERVED.contains(&b) => (b as char).to_string(),
+            b'/' if !encode_slash => "/".to_owned(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Text of the first element named tag in an XML response
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..end].to_owned())
+}
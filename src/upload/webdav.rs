@@ -0,0 +1,105 @@
+use std::path::Path;
+
+use anyhow::Result;
+use reqwest::{Client, Method, StatusCode, Url};
+use tokio::fs::File;
+use tracing::{event, Level};
+
+use super::{with_retries, Uploader};
+
+/// Uploads to a WebDAV server with PUT requests, creating missing collections. webdav:// urls
+/// use http and webdavs:// urls use https, credentials are taken from the url
+#[derive(Debug)]
+pub struct WebDavUploader {
+    client: Client,
+    /// Base url with an http(s) scheme and a path ending with /
+    base: Url,
+    credentials: Option<(String, Option<String>)>,
+}
+
+impl WebDavUploader {
+    pub fn new(url: &Url) -> Result<Self> {
+        let scheme = match url.scheme() {
+            "webdavs" | "davs" => "https",
+            _ => "http",
+        };
+        let mut path = url.path().to_owned();
+        if !path.ends_with('/') {
+            path.push('/');
+        }
+        let base = Url::parse(&format!(
+            "{}://{}{}",
+            scheme,
+            url.host_str().unwrap_or_default(),
+            url.port().map(|p| format!(":{}", p)).unwrap_or_default()
+        ))?
+        .join(&path)?;
+        let credentials = (!url.username().is_empty())
+            .then(|| (url.username().to_owned(), url.password().map(str::to_owned)));
+
+        Ok(Self {
+            client: Client::new(),
+            base,
+            credentials,
+        })
+    }
+
+    fn request(&self, method: Method, url: &Url) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, url.clone());
+        match &self.credentials {
+            Some((user, password)) => request.basic_auth(user, password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Create the collections containing name that don't exist yet
+    async fn create_parents(&self, name: &str) -> Result<()> {
+        let mut dir = String::new();
+        for part in name
+            .split('/')
+            .rev()
+            .skip(1)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+        {
+            dir.push_str(part);
+            dir.push('/');
+            let url = self.base.join(&dir)?;
+            let resp = self
+                .request(Method::from_bytes(b"MKCOL")?, &url)
+                .send()
+                .await?;
+            // Existing collections answer 405 Method Not Allowed
+            if !resp.status().is_success() && resp.status() != StatusCode::METHOD_NOT_ALLOWED {
+                return Err(anyhow::anyhow!("{} creating {}", resp.status(), url));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Uploader for WebDavUploader {
+    async fn upload(&self, path: &Path, name: &str) -> Result<()> {
+        let url = self.base.join(name)?;
+        event!(Level::INFO, "Uploading {:?} to {}", path, url);
+
+        let url = &url;
+        with_retries(|| async move {
+            self.create_parents(name).await?;
+            // The file is streamed instead of read into memory
+            let resp = self
+                .request(Method::PUT, url)
+                .body(File::open(path).await?)
+                .send()
+                .await?;
+            if !resp.status().is_success() {
+                return Err(anyhow::anyhow!("{} uploading to {}", resp.status(), url));
+            }
+            Ok(())
+        })
+        .await
+    }
+}
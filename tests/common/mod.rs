@@ -0,0 +1,374 @@
+//! Mock HLS server serving a synthetic live stream, and helpers to run livestream-dl against it
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockEncryptMut, KeyIvInit};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+
+/// Size of an MPEG-TS packet
+const TS_PACKET_SIZE: usize = 188;
+
+/// Number of MPEG-TS packets in each segment
+const PACKETS_PER_SEGMENT: usize = 3;
+
+/// Key of encrypted streams
+pub const KEY: [u8; 16] = *b"0123456789abcdef";
+
+/// Synthetic live stream served by MockHls
+#[derive(Clone, Debug)]
+pub struct StreamConfig {
+    /// Total number of segments before the playlist ends
+    pub segments: u64,
+    /// Number of segments in each live playlist
+    pub window: u64,
+    /// Duration of each segment in seconds, segments become available at this rate
+    pub segment_duration: f32,
+    /// Encrypt segments with AES-128 using the key of their key period and IVs from media
+    /// sequence numbers, or from key periods if keys rotate
+    pub encrypted: bool,
+    /// Start a new key period with its own key and initialization section every this many
+    /// segments
+    pub key_rotation_every: Option<u64>,
+    /// Serve fragmented MPEG-4 segments with an initialization section per key period, encrypted
+    /// along with the segments
+    pub fmp4: bool,
+    /// Start a new discontinuity every this many segments
+    pub discontinuity_every: Option<u64>,
+    /// Serve segments as byte ranges of a single file
+    pub byte_ranges: bool,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            segments: 6,
+            window: 3,
+            segment_duration: 0.5,
+            encrypted: false,
+            key_rotation_every: None,
+            fmp4: false,
+            discontinuity_every: None,
+            byte_ranges: false,
+        }
+    }
+}
+
+/// Content of segment seq, MPEG-TS sync bytes followed by the sequence number so segments can
+/// be told apart
+pub fn segment_data(seq: u64) -> Vec<u8> {
+    let mut data = vec![seq as u8; TS_PACKET_SIZE * PACKETS_PER_SEGMENT];
+    for packet in data.chunks_mut(TS_PACKET_SIZE) {
+        packet[0] = 0x47;
+    }
+    data
+}
+
+/// Content of fragmented MPEG-4 segment seq, a moof box filled with the sequence number
+fn fmp4_segment_data(seq: u64) -> Vec<u8> {
+    let mut data = vec![seq as u8; 64];
+    data[..8].copy_from_slice(b"\0\0\0\x40moof");
+    data
+}
+
+/// Initialization section of key period, an ftyp box filled with the period number
+fn init_data(period: u64) -> Vec<u8> {
+    let mut data = vec![period as u8; 32];
+    data[..8].copy_from_slice(b"\0\0\0\x20ftyp");
+    data
+}
+
+/// Content of segment seq as it should be saved, decrypted with its initialization prepended
+pub fn saved_data(config: &StreamConfig, seq: u64) -> Vec<u8> {
+    if config.fmp4 {
+        let mut data = init_data(key_period(config, seq));
+        data.extend(fmp4_segment_data(seq));
+        data
+    } else {
+        segment_data(seq)
+    }
+}
+
+/// Key period of segment seq
+fn key_period(config: &StreamConfig, seq: u64) -> u64 {
+    config.key_rotation_every.map_or(0, |n| seq / n)
+}
+
+/// Key of a key period, KEY for the first one
+fn key(period: u64) -> [u8; 16] {
+    let mut key = KEY;
+    key[15] = key[15].wrapping_add(period as u8);
+    key
+}
+
+/// IV of segment seq. With rotating keys it is given explicitly and shared by the key period,
+/// since it also encrypts the period's initialization section
+fn iv(config: &StreamConfig, seq: u64) -> [u8; 16] {
+    match config.key_rotation_every {
+        Some(_) => (key_period(config, seq) as u128 + 1).to_be_bytes(),
+        None => (seq as u128).to_be_bytes(),
+    }
+}
+
+/// Discontinuity sequence number of segment seq
+pub fn discon_seq(config: &StreamConfig, seq: u64) -> u64 {
+    config.discontinuity_every.map_or(0, |n| seq / n)
+}
+
+/// HTTP server serving a master playlist at /master.m3u8 with a single variant whose live
+/// media playlist at /media.m3u8 advances in real time. /nested.m3u8 is a master playlist whose
+/// variant is /master.m3u8. Keys and initialization sections of key periods are served at
+/// /key<period> and /init<period>.mp4
+pub struct MockHls {
+    addr: SocketAddr,
+}
+
+struct State {
+    config: StreamConfig,
+    start: Instant,
+}
+
+impl MockHls {
+    pub async fn start(config: StreamConfig) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(State {
+            config,
+            start: Instant::now(),
+        });
+
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let _ = handle(socket, &state).await;
+                });
+            }
+        });
+
+        Self { addr }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}/{}", self.addr, path)
+    }
+}
+
+/// Answer a single request and close the connection
+async fn handle(mut socket: TcpStream, state: &State) -> std::io::Result<()> {
+    // Read request head
+    let mut buf = Vec::new();
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let mut chunk = [0; 1024];
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let head = String::from_utf8_lossy(&buf).into_owned();
+    let path = head
+        .lines()
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .unwrap_or("/");
+    let path = path.split('?').next().unwrap_or(path);
+    let range = head.lines().find_map(|l| {
+        let (name, value) = l.split_once(':')?;
+        if !name.eq_ignore_ascii_case("range") {
+            return None;
+        }
+        let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+        Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+    });
+
+    let response = match route(path, state) {
+        Some(body) => match range {
+            Some((start, end)) if end < body.len() && start <= end => {
+                (206, body[start..=end].to_vec())
+            }
+            Some(_) => (416, Vec::new()),
+            None => (200, body),
+        },
+        None => (404, Vec::new()),
+    };
+
+    let (status, body) = response;
+    let head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(&body).await?;
+    socket.shutdown().await
+}
+
+/// Body served at path
+fn route(path: &str, state: &State) -> Option<Vec<u8>> {
+    let config = &state.config;
+    match path {
+        "/master.m3u8" => Some(
+            "#EXTM3U\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=100000,CODECS=\"avc1.64001f,mp4a.40.2\"\n\
+             media.m3u8\n"
+                .into(),
+        ),
+        "/nested.m3u8" => Some(
+            "#EXTM3U\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=100000\n\
+             master.m3u8\n"
+                .into(),
+        ),
+        "/media.m3u8" => Some(media_playlist(state).into_bytes()),
+        "/all.ts" if config.byte_ranges => Some(
+            (0..config.segments)
+                .flat_map(|s| served_segment(config, s))
+                .collect(),
+        ),
+        _ if path.starts_with("/key") => {
+            let period = path.strip_prefix("/key")?.parse().ok()?;
+            Some(key(period).to_vec())
+        }
+        _ if path.starts_with("/init") => {
+            let period = path
+                .strip_prefix("/init")?
+                .strip_suffix(".mp4")?
+                .parse()
+                .ok()?;
+            let data = init_data(period);
+            if !config.encrypted {
+                return Some(data);
+            }
+            let seq = period * config.key_rotation_every.unwrap_or(1);
+            Some(encrypt(config, seq, &data))
+        }
+        _ => {
+            let seq = path
+                .strip_prefix("/segment")?
+                .strip_suffix(".ts")?
+                .parse()
+                .ok()?;
+            (seq < config.segments && !config.byte_ranges).then(|| served_segment(config, seq))
+        }
+    }
+}
+
+/// Segment as served, encrypted if needed
+fn served_segment(config: &StreamConfig, seq: u64) -> Vec<u8> {
+    let data = if config.fmp4 {
+        fmp4_segment_data(seq)
+    } else {
+        segment_data(seq)
+    };
+    if !config.encrypted {
+        return data;
+    }
+    encrypt(config, seq, &data)
+}
+
+/// Encrypt data with the key and IV of segment seq
+fn encrypt(config: &StreamConfig, seq: u64, data: &[u8]) -> Vec<u8> {
+    let key = key(key_period(config, seq));
+    Aes128CbcEnc::new(&key.into(), &iv(config, seq).into()).encrypt_padded_vec_mut::<Pkcs7>(data)
+}
+
+/// Live media playlist with the segments available so far, ended once all are available
+fn media_playlist(state: &State) -> String {
+    let config = &state.config;
+    let elapsed = state.start.elapsed().as_secs_f32();
+    let available =
+        (config.window + (elapsed / config.segment_duration) as u64).min(config.segments);
+    let first = available.saturating_sub(config.window);
+
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:4\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n\
+         #EXT-X-DISCONTINUITY-SEQUENCE:{}\n",
+        config.segment_duration.ceil() as u64,
+        first,
+        discon_seq(config, first),
+    );
+
+    let segment_len = served_segment(config, 0).len();
+    for seq in first..available {
+        if seq != first && discon_seq(config, seq) != discon_seq(config, seq - 1) {
+            playlist.push_str("#EXT-X-DISCONTINUITY\n");
+        }
+        let period = key_period(config, seq);
+        if seq == first || period != key_period(config, seq - 1) {
+            if config.encrypted {
+                playlist.push_str(&format!("#EXT-X-KEY:METHOD=AES-128,URI=\"key{}\"", period));
+                if config.key_rotation_every.is_some() {
+                    playlist.push_str(&format!(",IV=0x{}", hex(&iv(config, seq))));
+                }
+                playlist.push('\n');
+            }
+            if config.fmp4 {
+                playlist.push_str(&format!("#EXT-X-MAP:URI=\"init{}.mp4\"\n", period));
+            }
+        }
+        playlist.push_str(&format!("#EXTINF:{:.3},\n", config.segment_duration));
+        if config.byte_ranges {
+            playlist.push_str(&format!(
+                "#EXT-X-BYTERANGE:{}@{}\nall.ts\n",
+                segment_len,
+                seq as usize * segment_len
+            ));
+        } else {
+            playlist.push_str(&format!("segment{}.ts\n", seq));
+        }
+    }
+
+    if available == config.segments {
+        playlist.push_str("#EXT-X-ENDLIST\n");
+    }
+    playlist
+}
+
+/// Lowercase hexadecimal representation of bytes
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Run livestream-dl download on url into output with extra args, without remuxing
+pub async fn download(url: &str, output: &Path, args: &[&str]) -> std::process::Output {
+    let run = tokio::process::Command::new(env!("CARGO_BIN_EXE_livestream-dl"))
+        .arg("download")
+        .arg(url)
+        .arg("-o")
+        .arg(output)
+        .args(["--no-remux", "-y"])
+        .args(args)
+        .kill_on_drop(true)
+        .output();
+    tokio::time::timeout(Duration::from_secs(60), run)
+        .await
+        .expect("download timed out")
+        .unwrap()
+}
+
+/// Saved segments of a stream by (discontinuity sequence, media sequence) number
+pub fn saved_segments(output: &Path, stream: &str) -> Vec<((u64, u64), PathBuf)> {
+    let mut segments: Vec<_> = std::fs::read_dir(output.join("segments").join(stream))
+        .unwrap()
+        .filter_map(|e| {
+            let path = e.unwrap().path();
+            let name = path
+                .file_stem()?
+                .to_str()?
+                .strip_prefix("segment_d")?
+                .to_owned();
+            let (d, s) = name.split_once('s')?;
+            Some(((d.parse().ok()?, s.parse().ok()?), path))
+        })
+        .collect();
+    segments.sort();
+    segments
+}
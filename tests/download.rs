@@ -0,0 +1,193 @@
+mod common;
+
+use std::process::Output;
+
+use clap::Parser;
+use common::{
+    discon_seq, download, saved_data, saved_segments, segment_data, MockHls, StreamConfig,
+};
+use livestream_dl::cli::Args;
+use livestream_dl::livestream::{DownloadEvent, Livestream, Stream};
+
+/// Download the mock stream's master playlist and check that every segment was saved intact
+/// with the right discontinuity
+async fn check_download(config: StreamConfig) {
+    check_download_from(config, "master.m3u8").await;
+}
+
+/// Download the mock stream from its playlist at the given path and check that every segment was
+/// saved intact with the right discontinuity
+async fn check_download_from(config: StreamConfig, playlist: &str) {
+    let server = MockHls::start(config.clone()).await;
+    let output = tempfile::tempdir().unwrap();
+    let output = output.path().join("out");
+
+    let result = download(&server.url(playlist), &output, &[]).await;
+    assert_success(&result);
+
+    let segments = saved_segments(&output, "main");
+    let ids: Vec<_> = segments.iter().map(|(id, _)| *id).collect();
+    let expected: Vec<_> = (0..config.segments)
+        .map(|s| (discon_seq(&config, s), s))
+        .collect();
+    assert_eq!(ids, expected);
+    for ((_, seq), path) in segments {
+        assert_eq!(
+            std::fs::read(path).unwrap(),
+            saved_data(&config, seq),
+            "segment {}",
+            seq
+        );
+    }
+}
+
+fn assert_success(result: &Output) {
+    assert!(
+        result.status.success(),
+        "download failed: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+}
+
+#[tokio::test]
+async fn live_playlist_rotation() {
+    check_download(StreamConfig::default()).await;
+}
+
+#[tokio::test]
+async fn aes_128_encryption() {
+    check_download(StreamConfig {
+        encrypted: true,
+        ..Default::default()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn rotating_keys() {
+    check_download(StreamConfig {
+        encrypted: true,
+        key_rotation_every: Some(2),
+        ..Default::default()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn rotating_key_cmaf() {
+    check_download(StreamConfig {
+        encrypted: true,
+        key_rotation_every: Some(2),
+        fmp4: true,
+        ..Default::default()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn cmaf_clear_initialization() {
+    check_download(StreamConfig {
+        key_rotation_every: Some(2),
+        fmp4: true,
+        ..Default::default()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn discontinuities() {
+    check_download(StreamConfig {
+        discontinuity_every: Some(2),
+        ..Default::default()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn byte_ranges() {
+    check_download(StreamConfig {
+        byte_ranges: true,
+        ..Default::default()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn media_and_nested_master_playlist_inputs() {
+    for playlist in ["media.m3u8", "nested.m3u8"] {
+        check_download_from(StreamConfig::default(), playlist).await;
+    }
+}
+
+#[tokio::test]
+async fn local_playlist_with_base_url() {
+    let server = MockHls::start(StreamConfig::default()).await;
+    let output = tempfile::tempdir().unwrap();
+    let playlist = output.path().join("local.m3u8");
+    let output = output.path().join("out");
+    std::fs::write(
+        &playlist,
+        "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:0\n\
+         #EXTINF:0.500,\nsegment0.ts\n#EXTINF:0.500,\nsegment1.ts\n#EXT-X-ENDLIST\n",
+    )
+    .unwrap();
+
+    let base_url = server.url("");
+    let result = download(
+        playlist.to_str().unwrap(),
+        &output,
+        &["--base-url", &base_url],
+    )
+    .await;
+    assert_success(&result);
+
+    let segments = saved_segments(&output, "main");
+    assert_eq!(segments.len(), 2);
+    for ((_, seq), path) in segments {
+        assert_eq!(std::fs::read(path).unwrap(), segment_data(seq));
+    }
+}
+
+#[tokio::test]
+async fn impersonated_client_builds() {
+    let server = MockHls::start(StreamConfig::default()).await;
+    let url = server.url("master.m3u8");
+
+    // reqwest rejects TLS configurations of another rustls version when building the client
+    for browser in ["chrome", "firefox", "safari"] {
+        let args =
+            Args::try_parse_from(["download", url.as_str(), "--impersonate", browser]).unwrap();
+        Livestream::new(args.playlist_url().unwrap(), &args)
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn download_events() {
+    let config = StreamConfig::default();
+    let server = MockHls::start(config.clone()).await;
+    let output = tempfile::tempdir().unwrap();
+    let url = server.url("master.m3u8");
+    let args = Args::try_parse_from(["download", url.as_str(), "--no-remux", "-y"]).unwrap();
+
+    let (livestream, _) = Livestream::new(args.playlist_url().unwrap(), &args)
+        .await
+        .unwrap();
+    let mut events = livestream.subscribe();
+    livestream.download(output.path()).await.unwrap();
+
+    let mut downloaded = Vec::new();
+    let mut ended = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        match event {
+            DownloadEvent::SegmentDownloaded { stream, seq, .. } => downloaded.push((stream, seq)),
+            DownloadEvent::StreamEnded { stream, error } => ended.push((stream, error)),
+            _ => {}
+        }
+    }
+    downloaded.sort_by_key(|(_, seq)| *seq);
+    let expected: Vec<_> = (0..config.segments).map(|s| (Stream::Main, s)).collect();
+    assert_eq!(downloaded, expected);
+    assert_eq!(ended, vec![(Stream::Main, None)]);
+}